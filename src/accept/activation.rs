@@ -0,0 +1,191 @@
+use std::net::SocketAddr;
+use std::os::unix::io::{FromRawFd, IntoRawFd, RawFd};
+use std::os::unix::net::UnixListener;
+use std::path::Path;
+
+// Listening sockets we got from whoever started us, systemd-style (see sd_listen_fds(3)). That
+// way the sockets outlive us, and connections that arrive while we restart wait in the backlog
+// instead of getting refused.
+//
+// We don't use socket names. Instead, a configured listener takes an inherited socket if it's
+// bound to the same address (or path), otherwise it binds a new one like usual.
+
+const SD_LISTEN_FDS_START: RawFd = 3;
+
+pub enum InheritedListener {
+    Tcp(std::net::TcpListener),
+    Unix(UnixListener),
+}
+
+#[derive(Default)]
+pub struct InheritedListeners {
+    listeners: Vec<InheritedListener>,
+}
+
+// How many fds were passed to us. LISTEN_PID guards against variables meant for our parent.
+fn listen_fd_count(listen_pid: Option<&str>, listen_fds: Option<&str>, our_pid: u32) -> usize {
+    if listen_pid.and_then(|p| p.parse::<u32>().ok()) != Some(our_pid) {
+        return 0;
+    }
+    listen_fds.and_then(|n| n.parse::<usize>().ok()).unwrap_or(0)
+}
+
+fn get_int_sockopt(fd: RawFd, name: libc::c_int) -> Option<libc::c_int> {
+    let mut value: libc::c_int = 0;
+    let mut len = std::mem::size_of::<libc::c_int>() as libc::socklen_t;
+    // Safe, we pass a buffer and its length.
+    let res = unsafe {
+        libc::getsockopt(
+            fd,
+            libc::SOL_SOCKET,
+            name,
+            &mut value as *mut libc::c_int as *mut libc::c_void,
+            &mut len,
+        )
+    };
+    if res == 0 {
+        Some(value)
+    } else {
+        None
+    }
+}
+
+// Any stream socket has an address, we want one that's listening.
+fn is_stream_listener(fd: RawFd) -> bool {
+    let is_stream = get_int_sockopt(fd, libc::SO_TYPE) == Some(libc::SOCK_STREAM);
+    is_stream && get_int_sockopt(fd, libc::SO_ACCEPTCONN) == Some(1)
+}
+
+// Takes ownership of the fd, closes it if it's not a listener we can use.
+fn inherit_fd(fd: RawFd) -> Option<InheritedListener> {
+    let listening = is_stream_listener(fd);
+    let tcp = unsafe { std::net::TcpListener::from_raw_fd(fd) };
+    if listening && tcp.local_addr().is_ok() {
+        return Some(InheritedListener::Tcp(tcp));
+    }
+    let unix = unsafe { UnixListener::from_raw_fd(tcp.into_raw_fd()) };
+    if listening && unix.local_addr().is_ok_and(|a| a.as_pathname().is_some()) {
+        return Some(InheritedListener::Unix(unix));
+    }
+    log::warn!(
+        "Inherited file descriptor {} is not a TCP or Unix socket listener, closing it",
+        fd
+    );
+    None
+}
+
+impl InheritedListeners {
+    // Clears the variables, so that processes we might start don't think the sockets are theirs.
+    // Changing the environment isn't thread-safe, so call this before starting any threads.
+    pub fn from_env() -> Self {
+        let var = |name| std::env::var(name).ok();
+        let count = listen_fd_count(
+            var("LISTEN_PID").as_deref(),
+            var("LISTEN_FDS").as_deref(),
+            std::process::id(),
+        );
+        for name in ["LISTEN_PID", "LISTEN_FDS", "LISTEN_FDNAMES"] {
+            std::env::remove_var(name);
+        }
+        Self::from_fds((0..count as RawFd).map(|i| SD_LISTEN_FDS_START + i))
+    }
+
+    fn from_fds(fds: impl Iterator<Item = RawFd>) -> Self {
+        Self {
+            listeners: fds.filter_map(inherit_fd).collect(),
+        }
+    }
+
+    fn take(&mut self, matches: impl Fn(&InheritedListener) -> bool) -> Option<InheritedListener> {
+        let idx = self.listeners.iter().position(matches)?;
+        Some(self.listeners.remove(idx))
+    }
+
+    pub fn take_tcp(&mut self, addr: SocketAddr) -> Option<std::net::TcpListener> {
+        let is_addr = |l: &InheritedListener| match l {
+            InheritedListener::Tcp(l) => l.local_addr().is_ok_and(|a| a == addr),
+            _ => false,
+        };
+        match self.take(is_addr) {
+            Some(InheritedListener::Tcp(l)) => Some(l),
+            _ => None,
+        }
+    }
+
+    pub fn take_unix(&mut self, path: &str) -> Option<UnixListener> {
+        let is_path = |l: &InheritedListener| match l {
+            InheritedListener::Unix(l) => l.local_addr().is_ok_and(|a| a.as_pathname() == Some(Path::new(path))),
+            _ => false,
+        };
+        match self.take(is_path) {
+            Some(InheritedListener::Unix(l)) => Some(l),
+            _ => None,
+        }
+    }
+}
+
+impl Drop for InheritedListeners {
+    fn drop(&mut self) {
+        for l in self.listeners.iter() {
+            match l {
+                InheritedListener::Tcp(l) => {
+                    log::warn!("No listener configured for inherited socket {:?}", l.local_addr())
+                }
+                InheritedListener::Unix(l) => {
+                    log::warn!("No listener configured for inherited socket {:?}", l.local_addr())
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_listen_fd_count() {
+        assert_eq!(listen_fd_count(Some("42"), Some("3"), 42), 3);
+        assert_eq!(listen_fd_count(Some("41"), Some("3"), 42), 0);
+        assert_eq!(listen_fd_count(None, Some("3"), 42), 0);
+        assert_eq!(listen_fd_count(Some("42"), None, 42), 0);
+        assert_eq!(listen_fd_count(Some("42"), Some("foo"), 42), 0);
+    }
+
+    #[test]
+    fn test_inherited_listeners_matched_by_address() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("replayserver.sock").to_str().unwrap().to_owned();
+        let tcp = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let tcp_addr = tcp.local_addr().unwrap();
+        let unix = UnixListener::bind(&path).unwrap();
+        let not_a_socket = std::fs::File::open(dir.path()).unwrap();
+        let udp = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        let udp_addr = udp.local_addr().unwrap();
+        let not_listening = std::net::TcpStream::connect(tcp_addr).unwrap();
+        let not_listening_addr = not_listening.local_addr().unwrap();
+        let fds = vec![
+            not_a_socket.into_raw_fd(),
+            udp.into_raw_fd(),
+            not_listening.into_raw_fd(),
+            unix.into_raw_fd(),
+            tcp.into_raw_fd(),
+        ];
+
+        let mut inherited = InheritedListeners::from_fds(fds.into_iter());
+        assert!(inherited.take_tcp("127.0.0.1:1".parse().unwrap()).is_none());
+        assert!(inherited.take_tcp(udp_addr).is_none());
+        assert!(inherited.take_tcp(not_listening_addr).is_none());
+        assert!(inherited.take_unix("/nonexistent.sock").is_none());
+        let tcp = inherited.take_tcp(tcp_addr).unwrap();
+        assert!(inherited.take_tcp(tcp_addr).is_none());
+        let unix = inherited.take_unix(&path).unwrap();
+
+        // They still work.
+        tcp.accept().unwrap();
+        let _client = std::net::TcpStream::connect(tcp_addr).unwrap();
+        tcp.accept().unwrap();
+        let _client = std::os::unix::net::UnixStream::connect(&path).unwrap();
+        unix.accept().unwrap();
+    }
+}
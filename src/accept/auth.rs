@@ -0,0 +1,229 @@
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+
+use crate::error::{ConnResult, ConnectionError};
+
+use super::header::{ConnectionHeader, ConnectionType, ReaderClass};
+
+type HmacSha256 = Hmac<Sha256>;
+
+fn mac(secret: &[u8], message: &str) -> HmacSha256 {
+    // HMAC accepts keys of any length.
+    let mut mac = HmacSha256::new_from_slice(secret).unwrap();
+    mac.update(message.as_bytes());
+    mac
+}
+
+// Writers have to prove they're in the game they're sending us a replay for. Otherwise a single
+// client with a couple of connections could outvote honest writers in the merge quorum.
+//
+// A writer token is a hex-encoded HMAC-SHA256 of "<game id>/<player id>", keyed with a secret we
+// share with the lobby server. The lobby server mints it when the game launches, the writer sends
+// it in a V2 connection header along with its player ID. We can check it without talking to
+// anyone.
+//
+// Readers are never checked here, see ReaderAuth. If no secret is configured, writers aren't
+// checked either.
+pub struct WriterAuth {
+    secret: Option<Vec<u8>>,
+}
+
+impl WriterAuth {
+    pub fn new(secret: Option<&str>) -> Self {
+        Self {
+            secret: secret.map(|s| s.as_bytes().to_vec()),
+        }
+    }
+
+    fn mac(secret: &[u8], game_id: u64, player_id: u64) -> HmacSha256 {
+        mac(secret, &format!("{}/{}", game_id, player_id))
+    }
+
+    pub fn mint_token(secret: &str, game_id: u64, player_id: u64) -> String {
+        hex::encode(Self::mac(secret.as_bytes(), game_id, player_id).finalize().into_bytes())
+    }
+
+    fn check_token(secret: &[u8], header: &ConnectionHeader) -> Result<(), &'static str> {
+        let player_id = header.fields.player_id.ok_or("no player ID")?;
+        let token = header.fields.auth_token.as_ref().ok_or("no token")?;
+        let token_bytes = hex::decode(token).map_err(|_| "malformed token")?;
+        Self::mac(secret, header.id, player_id)
+            .verify_slice(&token_bytes)
+            .map_err(|_| "invalid token")
+    }
+
+    // Returns whether the connection is a writer that proved who it is.
+    pub fn check(&self, header: &ConnectionHeader) -> ConnResult<bool> {
+        let secret = match &self.secret {
+            None => return Ok(false),
+            Some(s) => s,
+        };
+        if header.type_ != ConnectionType::Writer {
+            return Ok(false);
+        }
+        Self::check_token(secret, header).map_err(|e| ConnectionError::Unauthorized(e.into()))?;
+        Ok(true)
+    }
+}
+
+// Casters and referees can watch a game with a shorter delay than everyone else. To do that, they
+// send a token in the reader's V2 header. It's a hex-encoded HMAC-SHA256 of
+// "<game id>/privileged", keyed with a secret separate from the writer one, so that handing out
+// caster tokens doesn't let anyone write.
+//
+// A reader without a valid token is just a regular reader, there's no point in turning it away.
+pub struct ReaderAuth {
+    secret: Option<Vec<u8>>,
+}
+
+impl ReaderAuth {
+    pub fn new(secret: Option<&str>) -> Self {
+        Self {
+            secret: secret.map(|s| s.as_bytes().to_vec()),
+        }
+    }
+
+    fn mac(secret: &[u8], game_id: u64) -> HmacSha256 {
+        mac(secret, &format!("{}/privileged", game_id))
+    }
+
+    pub fn mint_token(secret: &str, game_id: u64) -> String {
+        hex::encode(Self::mac(secret.as_bytes(), game_id).finalize().into_bytes())
+    }
+
+    fn check_token(secret: &[u8], header: &ConnectionHeader) -> Result<(), &'static str> {
+        let token = header.fields.auth_token.as_ref().ok_or("no token")?;
+        let token_bytes = hex::decode(token).map_err(|_| "malformed token")?;
+        Self::mac(secret, header.id)
+            .verify_slice(&token_bytes)
+            .map_err(|_| "invalid token")
+    }
+
+    pub fn reader_class(&self, header: &ConnectionHeader) -> ReaderClass {
+        let secret = match &self.secret {
+            Some(s) if header.type_ == ConnectionType::Reader && header.fields.auth_token.is_some() => s,
+            _ => return ReaderClass::Regular,
+        };
+        match Self::check_token(secret, header) {
+            Ok(()) => ReaderClass::Privileged,
+            Err(e) => {
+                log::info!(
+                    "Reader for replay {} has {}, treating it as a regular reader",
+                    header.id,
+                    e
+                );
+                ReaderClass::Regular
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const SECRET: &str = "banana";
+
+    fn writer(id: u64, player_id: Option<u64>, token: Option<String>) -> ConnectionHeader {
+        let mut h = ConnectionHeader::new(ConnectionType::Writer, id, "foo".into());
+        h.fields.player_id = player_id;
+        h.fields.auth_token = token;
+        h
+    }
+
+    fn is_unauthorized(r: ConnResult<bool>) -> bool {
+        matches!(r, Err(ConnectionError::Unauthorized(..)))
+    }
+
+    #[test]
+    fn test_writer_auth_accepts_valid_token() {
+        let auth = WriterAuth::new(Some(SECRET));
+        let token = WriterAuth::mint_token(SECRET, 1, 42);
+        assert!(auth.check(&writer(1, Some(42), Some(token))).unwrap());
+    }
+
+    #[test]
+    fn test_writer_auth_token_binds_game_and_player() {
+        let auth = WriterAuth::new(Some(SECRET));
+        let token = WriterAuth::mint_token(SECRET, 1, 42);
+        assert!(is_unauthorized(auth.check(&writer(2, Some(42), Some(token.clone())))));
+        assert!(is_unauthorized(auth.check(&writer(1, Some(43), Some(token)))));
+    }
+
+    #[test]
+    fn test_writer_auth_rejects_other_secret() {
+        let auth = WriterAuth::new(Some(SECRET));
+        let token = WriterAuth::mint_token("apple", 1, 42);
+        assert!(is_unauthorized(auth.check(&writer(1, Some(42), Some(token)))));
+    }
+
+    #[test]
+    fn test_writer_auth_rejects_missing_or_malformed_data() {
+        let auth = WriterAuth::new(Some(SECRET));
+        let token = WriterAuth::mint_token(SECRET, 1, 42);
+        assert!(is_unauthorized(auth.check(&writer(1, None, Some(token)))));
+        assert!(is_unauthorized(auth.check(&writer(1, Some(42), None))));
+        assert!(is_unauthorized(auth.check(&writer(
+            1,
+            Some(42),
+            Some("not hex".into())
+        ))));
+        assert!(is_unauthorized(auth.check(&writer(1, Some(42), Some("".into())))));
+    }
+
+    #[test]
+    fn test_writer_auth_ignores_readers() {
+        let auth = WriterAuth::new(Some(SECRET));
+        let reader = ConnectionHeader::new(ConnectionType::Reader, 1, "foo".into());
+        assert!(!auth.check(&reader).unwrap());
+    }
+
+    #[test]
+    fn test_writer_auth_disabled_without_secret() {
+        let auth = WriterAuth::new(None);
+        assert!(!auth.check(&writer(1, None, None)).unwrap());
+    }
+
+    fn reader(id: u64, token: Option<String>) -> ConnectionHeader {
+        let mut h = ConnectionHeader::new(ConnectionType::Reader, id, "foo".into());
+        h.fields.auth_token = token;
+        h
+    }
+
+    #[test]
+    fn test_reader_auth_privileged_token() {
+        let auth = ReaderAuth::new(Some(SECRET));
+        let token = ReaderAuth::mint_token(SECRET, 1);
+        assert_eq!(
+            auth.reader_class(&reader(1, Some(token.clone()))),
+            ReaderClass::Privileged
+        );
+        assert_eq!(auth.reader_class(&reader(2, Some(token))), ReaderClass::Regular);
+        assert_eq!(auth.reader_class(&reader(1, None)), ReaderClass::Regular);
+        assert_eq!(
+            auth.reader_class(&reader(1, Some("not hex".into()))),
+            ReaderClass::Regular
+        );
+        let other = ReaderAuth::mint_token("apple", 1);
+        assert_eq!(auth.reader_class(&reader(1, Some(other))), ReaderClass::Regular);
+    }
+
+    #[test]
+    fn test_reader_auth_tokens_dont_mix_with_writer_tokens() {
+        let auth = ReaderAuth::new(Some(SECRET));
+        let writer_token = WriterAuth::mint_token(SECRET, 1, 42);
+        assert_eq!(auth.reader_class(&reader(1, Some(writer_token))), ReaderClass::Regular);
+        let reader_token = ReaderAuth::mint_token(SECRET, 1);
+        let mut h = writer(1, Some(42), Some(reader_token.clone()));
+        assert_eq!(auth.reader_class(&h), ReaderClass::Regular);
+        h.type_ = ConnectionType::Reader;
+        assert_eq!(auth.reader_class(&h), ReaderClass::Privileged);
+    }
+
+    #[test]
+    fn test_reader_auth_disabled_without_secret() {
+        let auth = ReaderAuth::new(None);
+        let token = ReaderAuth::mint_token(SECRET, 1);
+        assert_eq!(auth.reader_class(&reader(1, Some(token))), ReaderClass::Regular);
+    }
+}
@@ -1,8 +1,10 @@
+use std::collections::BTreeMap;
 use std::str::from_utf8;
 
-use tokio::io::AsyncReadExt;
+use tokio::io::{AsyncRead, AsyncReadExt};
 use tokio::time::Duration;
 
+use super::auth::{ReaderAuth, WriterAuth};
 use crate::error::bad_data;
 use crate::error::ConnResult;
 use crate::error::ConnectionError;
@@ -15,6 +17,45 @@ use crate::{server::connection::read_until_exact, some_error};
 pub enum ConnectionType {
     Reader = 1,
     Writer = 2,
+    // Asks for a list of running replays. Not assigned to any replay.
+    List = 3,
+}
+
+impl ConnectionType {
+    // For logs, metrics and the admin API.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ConnectionType::Reader => "reader",
+            ConnectionType::Writer => "writer",
+            ConnectionType::List => "list",
+        }
+    }
+}
+
+// What a reader gets to see. Privileged readers (casters, referees) proved it with a token and
+// watch with a shorter delay.
+#[derive(PartialEq, Eq, Debug, Clone, Copy, Default)]
+pub enum ReaderClass {
+    #[default]
+    Regular,
+    Privileged,
+}
+
+// Extra data that only versioned headers can carry. Legacy headers leave all of it empty.
+#[derive(PartialEq, Eq, Debug, Clone, Default)]
+pub struct HeaderFields {
+    pub client_version: Option<String>,
+    pub player_id: Option<u64>,
+    pub auth_token: Option<String>,
+    // Readers only. Byte position in the replay stream to start sending from, e.g. when resuming
+    // after a dropped connection.
+    pub resume_offset: Option<u64>,
+    // Readers only. Not sent by clients, we set it once we check the reader's token.
+    pub reader_class: ReaderClass,
+    // Writers only. Not sent by clients, set if the writer's token checks out.
+    pub authenticated: bool,
+    // Fields we don't interpret (yet). Kept so newer clients can talk to older servers.
+    pub options: BTreeMap<String, String>,
 }
 
 #[derive(PartialEq, Eq, Debug, Clone)]
@@ -22,6 +63,37 @@ pub struct ConnectionHeader {
     pub type_: ConnectionType,
     pub id: u64,
     pub name: String,
+    pub fields: HeaderFields,
+}
+
+impl ConnectionHeader {
+    pub fn new(type_: ConnectionType, id: u64, name: String) -> Self {
+        Self {
+            type_,
+            id,
+            name,
+            fields: HeaderFields::default(),
+        }
+    }
+}
+
+// A connection header comes in one of two flavours.
+//
+// Legacy header, sent by FA itself:
+//   "P/<id>/<name>\0" for writers, "G/<id>/<name>\0" for readers.
+// We also accept "L/\0" for listing running replays. Anything between "L/" and "\0" is ignored.
+//
+// Versioned header, for clients that need to tell us more:
+//   "V2" <field count: u8> <field>...
+// where each field is:
+//   <key length: u8> <key> <value length: u16 LE> <value>
+// Keys and values are UTF-8 and keys can't repeat. Keys "type" ("writer" / "reader" / "list"),
+// "id" and "name" are required, except for "list" which doesn't need the latter two. Keys
+// "version", "player_id", "token" and "offset" are optional and parsed into HeaderFields,
+// remaining keys are kept as options.
+enum HeaderMagic {
+    Legacy(ConnectionType),
+    V2,
 }
 
 pub mod header_reader {
@@ -29,12 +101,16 @@ pub mod header_reader {
 
     use super::*;
 
-    async fn read_type(conn: &mut Connection) -> ConnResult<ConnectionType> {
+    const MAX_V2_HEADER_SIZE: u64 = 4096;
+
+    async fn read_magic(conn: &mut Connection) -> ConnResult<HeaderMagic> {
         let mut buf: [u8; 2] = [0; 2];
         conn.read_exact(&mut buf).await?;
         match &buf {
-            b"P/" => Ok(ConnectionType::Writer),
-            b"G/" => Ok(ConnectionType::Reader),
+            b"P/" => Ok(HeaderMagic::Legacy(ConnectionType::Writer)),
+            b"G/" => Ok(HeaderMagic::Legacy(ConnectionType::Reader)),
+            b"L/" => Ok(HeaderMagic::Legacy(ConnectionType::List)),
+            b"V2" => Ok(HeaderMagic::V2),
             _ => Err(bad_data(format!("Invalid connection type: '{}'", pretty_bytes(&buf)))),
         }
     }
@@ -59,13 +135,103 @@ pub mod header_reader {
         Ok((id, name))
     }
 
+    async fn skip_game_data(conn: &mut Connection) -> ConnResult<()> {
+        let mut line = Vec::<u8>::new();
+        read_until_exact(&mut conn.take(1024), b'\0', &mut line)
+            .await
+            .map_err(|_| bad_data("Connection header is incomplete"))?;
+        Ok(())
+    }
+
+    async fn read_raw_v2_fields<T: AsyncRead + Unpin>(r: &mut T) -> std::io::Result<Vec<(Vec<u8>, Vec<u8>)>> {
+        let count = r.read_u8().await?;
+        let mut fields = Vec::new();
+        for _ in 0..count {
+            let key_len = r.read_u8().await?;
+            let mut key = vec![0; key_len as usize];
+            r.read_exact(&mut key).await?;
+            let value_len = r.read_u16_le().await?;
+            let mut value = vec![0; value_len as usize];
+            r.read_exact(&mut value).await?;
+            fields.push((key, value));
+        }
+        Ok(fields)
+    }
+
+    async fn read_v2_fields(conn: &mut Connection) -> ConnResult<BTreeMap<String, String>> {
+        let raw_fields = read_raw_v2_fields(&mut conn.take(MAX_V2_HEADER_SIZE))
+            .await
+            .map_err(|_| bad_data("Connection header is incomplete"))?;
+        let mut fields = BTreeMap::new();
+        for (key_bytes, value_bytes) in raw_fields.into_iter() {
+            let (key, value) = some_error!((String::from_utf8(key_bytes)?, String::from_utf8(value_bytes)?))
+                .map_err(|_| bad_data("Failed to decode connection header field"))?;
+            if fields.contains_key(&key) {
+                return Err(bad_data(format!("Duplicate connection header field '{}'", key)));
+            }
+            fields.insert(key, value);
+        }
+        Ok(fields)
+    }
+
+    pub(super) fn header_from_v2_fields(mut fields: BTreeMap<String, String>) -> ConnResult<ConnectionHeader> {
+        let mut required = |key: &str| {
+            fields
+                .remove(key)
+                .ok_or_else(|| bad_data(format!("Connection header is missing field '{}'", key)))
+        };
+        let type_ = match required("type")?.as_str() {
+            "writer" => ConnectionType::Writer,
+            "reader" => ConnectionType::Reader,
+            "list" => return Ok(ConnectionHeader::new(ConnectionType::List, 0, String::new())),
+            t => return Err(bad_data(format!("Invalid connection type: '{}'", t))),
+        };
+        let id = required("id")?
+            .parse::<u64>()
+            .map_err(|_| bad_data("Failed to parse replay ID"))?;
+        let name = required("name")?;
+        let mut optional_u64 = |key: &str, what: &str| match fields.remove(key) {
+            None => Ok(None),
+            Some(v) => v
+                .parse::<u64>()
+                .map(Some)
+                .map_err(|_| bad_data(format!("Failed to parse {}", what))),
+        };
+        let player_id = optional_u64("player_id", "player ID")?;
+        let resume_offset = optional_u64("offset", "resume offset")?;
+        let header_fields = HeaderFields {
+            client_version: fields.remove("version"),
+            player_id,
+            auth_token: fields.remove("token"),
+            resume_offset,
+            reader_class: ReaderClass::Regular,
+            authenticated: false,
+            options: fields,
+        };
+        Ok(ConnectionHeader {
+            type_,
+            id,
+            name,
+            fields: header_fields,
+        })
+    }
+
     async fn read_connection_header(conn: &mut Connection) -> ConnResult<ConnectionHeader> {
         // early EOF is most likely a connection with no data
-        let type_ = read_type(conn)
+        let magic = read_magic(conn)
             .await
             .map_err(|e| if e.is_eof() { ConnectionError::NoData } else { e })?;
-        let (id, name) = read_game_data(conn).await?;
-        Ok(ConnectionHeader { type_, id, name })
+        match magic {
+            HeaderMagic::Legacy(ConnectionType::List) => {
+                skip_game_data(conn).await?;
+                Ok(ConnectionHeader::new(ConnectionType::List, 0, String::new()))
+            }
+            HeaderMagic::Legacy(type_) => {
+                let (id, name) = read_game_data(conn).await?;
+                Ok(ConnectionHeader::new(type_, id, name))
+            }
+            HeaderMagic::V2 => header_from_v2_fields(read_v2_fields(conn).await?),
+        }
     }
 
     /* Cancellable. */
@@ -76,15 +242,65 @@ pub mod header_reader {
     }
 }
 
-pub async fn read_initial_header(conn: &mut Connection, until: Duration) -> ConnResult<()> {
-    match timeout(header_reader::read_and_set_connection_header(conn), until).await {
-        Some(res) => res,
-        None => Err(bad_data("Timed out while accepting connection")),
+// Websocket clients can skip the in-band header and put it in the URL instead:
+//   /replay/<id>/read[?name=<name>]
+//   /replay/<id>/write?name=<name>
+// Query parameters are interpreted like versioned header fields, so e.g. "token" and "offset"
+// work here too. For any path outside of /replay/ we expect the header in-band, like before.
+pub fn header_from_websocket_path(path: &str, query: Option<&str>) -> ConnResult<Option<ConnectionHeader>> {
+    let route = match path.strip_prefix("/replay/") {
+        None => return Ok(None),
+        Some(r) => r,
+    };
+    let (id, type_) = match route.split_once('/') {
+        Some((id, "read")) => (id, "reader"),
+        Some((id, "write")) => (id, "writer"),
+        _ => return Err(bad_data(format!("Invalid websocket path: '{}'", path))),
+    };
+    let mut fields = BTreeMap::new();
+    for (key, value) in form_urlencoded::parse(query.unwrap_or("").as_bytes()) {
+        if fields.insert(key.to_string(), value.to_string()).is_some() {
+            return Err(bad_data(format!("Duplicate connection header field '{}'", key)));
+        }
+    }
+    for (key, value) in [("type", type_), ("id", id)] {
+        if fields.insert(key.into(), value.into()).is_some() {
+            return Err(bad_data(format!("Duplicate connection header field '{}'", key)));
+        }
+    }
+    // Reader names don't mean anything, let browsers leave them out.
+    if type_ == "reader" {
+        fields.entry("name".into()).or_default();
+    }
+    header_reader::header_from_v2_fields(fields).map(Some)
+}
+
+pub async fn read_initial_header(
+    conn: &mut Connection,
+    until: Duration,
+    auth: &WriterAuth,
+    reader_auth: &ReaderAuth,
+) -> ConnResult<()> {
+    // Websocket connections might have gotten their header from the URL already.
+    if !conn.has_header() {
+        match timeout(header_reader::read_and_set_connection_header(conn), until).await {
+            Some(res) => res?,
+            None => return Err(bad_data("Timed out while accepting connection")),
+        }
+    }
+    let mut header = conn.get_header();
+    let authenticated = auth.check(&header)?;
+    let class = reader_auth.reader_class(&header);
+    if class != header.fields.reader_class || authenticated != header.fields.authenticated {
+        header.fields.reader_class = class;
+        header.fields.authenticated = authenticated;
+        conn.set_header(header);
     }
+    Ok(())
 }
 
 #[cfg(test)]
-mod test {
+pub mod test {
     use super::header_reader::read_and_set_connection_header;
     use super::*;
     use crate::util::test::setup_logging;
@@ -92,8 +308,8 @@ mod test {
     use std::io::Cursor;
     use tokio::io::BufReader;
 
-    fn conn_from_read_data(data: &'static [u8]) -> Connection {
-        let r = Box::new(BufReader::new(Cursor::new(data)));
+    fn conn_from_read_data(data: impl Into<Vec<u8>>) -> Connection {
+        let r = Box::new(BufReader::new(Cursor::new(data.into())));
         Connection::new_from(r, Box::new(tokio::io::sink()))
     }
 
@@ -190,7 +406,7 @@ mod test {
             data.extend(b"foo ");
         }
         data.extend(b"\0");
-        let mut c = conn_from_read_data(data.leak());
+        let mut c = conn_from_read_data(data);
         let err = read_and_set_connection_header(&mut c).await.err().unwrap();
         assert!(matches!(err, ConnectionError::BadData(..)));
     }
@@ -202,4 +418,241 @@ mod test {
         let err = read_and_set_connection_header(&mut c).await.err().unwrap();
         assert!(matches!(err, ConnectionError::BadData(..)));
     }
+
+    #[tokio::test]
+    async fn test_connection_header_legacy_has_no_fields() {
+        setup_logging();
+        let mut c = conn_from_read_data(b"P/1/foo\0");
+        read_and_set_connection_header(&mut c).await.unwrap();
+        assert_eq!(c.get_header().fields, HeaderFields::default());
+    }
+
+    #[tokio::test]
+    async fn test_connection_header_list() {
+        setup_logging();
+        for data in [b"L/\0" as &'static [u8], b"L/1/foo\0"] {
+            let mut c = conn_from_read_data(data);
+            read_and_set_connection_header(&mut c).await.unwrap();
+            assert_eq!(
+                c.get_header(),
+                ConnectionHeader::new(ConnectionType::List, 0, "".into())
+            );
+        }
+
+        let mut c = conn_from_read_data(b"L/");
+        let err = read_and_set_connection_header(&mut c).await.err().unwrap();
+        assert!(matches!(err, ConnectionError::BadData(..)));
+    }
+
+    fn v2_header(fields: &[(&str, &str)]) -> Vec<u8> {
+        let mut data = b"V2".to_vec();
+        data.push(fields.len() as u8);
+        for (k, v) in fields {
+            data.push(k.len() as u8);
+            data.extend(k.as_bytes());
+            data.extend((v.len() as u16).to_le_bytes());
+            data.extend(v.as_bytes());
+        }
+        data
+    }
+
+    #[tokio::test]
+    async fn test_connection_header_v2() {
+        setup_logging();
+        let data = v2_header(&[
+            ("type", "writer"),
+            ("id", "1"),
+            ("name", "foo"),
+            ("version", "2.0"),
+            ("player_id", "42"),
+            ("token", "abc"),
+            ("offset", "1000"),
+            ("frobnicate", "yes"),
+        ]);
+        let mut c = conn_from_read_data(data);
+        read_and_set_connection_header(&mut c).await.unwrap();
+        let h = c.get_header();
+        assert_eq!(h.type_, ConnectionType::Writer);
+        assert_eq!(h.id, 1);
+        assert_eq!(h.name, "foo");
+        assert_eq!(h.fields.client_version, Some("2.0".into()));
+        assert_eq!(h.fields.player_id, Some(42));
+        assert_eq!(h.fields.auth_token, Some("abc".into()));
+        assert_eq!(h.fields.resume_offset, Some(1000));
+        assert_eq!(h.fields.options.len(), 1);
+        assert_eq!(h.fields.options["frobnicate"], "yes");
+    }
+
+    #[tokio::test]
+    async fn test_connection_header_v2_minimal() {
+        setup_logging();
+        let data = v2_header(&[("name", "foo"), ("id", "1"), ("type", "reader")]);
+        let mut c = conn_from_read_data(data);
+        read_and_set_connection_header(&mut c).await.unwrap();
+        let h = c.get_header();
+        assert_eq!(h, ConnectionHeader::new(ConnectionType::Reader, 1, "foo".into()));
+    }
+
+    #[tokio::test]
+    async fn test_connection_header_v2_list() {
+        setup_logging();
+        let data = v2_header(&[("type", "list")]);
+        let mut c = conn_from_read_data(data);
+        read_and_set_connection_header(&mut c).await.unwrap();
+        assert_eq!(c.get_header().type_, ConnectionType::List);
+    }
+
+    #[tokio::test]
+    async fn test_connection_header_v2_missing_required_field() {
+        setup_logging();
+        let data = v2_header(&[("type", "reader"), ("id", "1")]);
+        let mut c = conn_from_read_data(data);
+        let err = read_and_set_connection_header(&mut c).await.err().unwrap();
+        assert!(matches!(err, ConnectionError::BadData(..)));
+    }
+
+    #[tokio::test]
+    async fn test_connection_header_v2_invalid_fields() {
+        setup_logging();
+        for fields in [
+            vec![("type", "spectator"), ("id", "1"), ("name", "foo")],
+            vec![("type", "reader"), ("id", "-1"), ("name", "foo")],
+            vec![("type", "reader"), ("id", "1"), ("name", "foo"), ("player_id", "bar")],
+            vec![("type", "reader"), ("id", "1"), ("name", "foo"), ("offset", "-5")],
+            vec![("type", "reader"), ("id", "1"), ("name", "foo"), ("id", "2")],
+        ] {
+            let data = v2_header(&fields);
+            let mut c = conn_from_read_data(data);
+            let err = read_and_set_connection_header(&mut c).await.err().unwrap();
+            assert!(matches!(err, ConnectionError::BadData(..)));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_connection_header_v2_short_data() {
+        setup_logging();
+        let data = v2_header(&[("type", "reader"), ("id", "1"), ("name", "foo")]);
+        for i in 2..data.len() {
+            let mut c = conn_from_read_data(&data[..i]);
+            let err = read_and_set_connection_header(&mut c).await.err().unwrap();
+            assert!(matches!(err, ConnectionError::BadData(..)));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_connection_header_v2_limit_overrun() {
+        setup_logging();
+        let long_name = "foo ".repeat(1024);
+        let data = v2_header(&[("type", "reader"), ("id", "1"), ("name", &long_name)]);
+        let mut c = conn_from_read_data(data);
+        let err = read_and_set_connection_header(&mut c).await.err().unwrap();
+        assert!(matches!(err, ConnectionError::BadData(..)));
+    }
+
+    #[tokio::test]
+    async fn test_initial_header_checks_writer_token() {
+        setup_logging();
+        let auth = WriterAuth::new(Some("banana"));
+        let reader_auth = ReaderAuth::new(None);
+        let timeout = Duration::from_secs(1);
+
+        let token = WriterAuth::mint_token("banana", 1, 42);
+        let data = v2_header(&[
+            ("type", "writer"),
+            ("id", "1"),
+            ("name", "foo"),
+            ("player_id", "42"),
+            ("token", &token),
+        ]);
+        let mut c = conn_from_read_data(data);
+        read_initial_header(&mut c, timeout, &auth, &reader_auth).await.unwrap();
+        assert!(c.get_header().fields.authenticated);
+
+        let mut c = conn_from_read_data(b"P/1/foo\0");
+        let err = read_initial_header(&mut c, timeout, &auth, &reader_auth)
+            .await
+            .err()
+            .unwrap();
+        assert!(matches!(err, ConnectionError::Unauthorized(..)));
+
+        let mut c = conn_from_read_data(b"G/1/foo\0");
+        read_initial_header(&mut c, timeout, &auth, &reader_auth).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_initial_header_sets_reader_class() {
+        setup_logging();
+        let auth = WriterAuth::new(None);
+        let reader_auth = ReaderAuth::new(Some("banana"));
+        let timeout = Duration::from_secs(1);
+
+        let token = ReaderAuth::mint_token("banana", 1);
+        let data = v2_header(&[("type", "reader"), ("id", "1"), ("name", "foo"), ("token", &token)]);
+        let mut c = conn_from_read_data(data);
+        read_initial_header(&mut c, timeout, &auth, &reader_auth).await.unwrap();
+        assert_eq!(c.get_header().fields.reader_class, ReaderClass::Privileged);
+
+        let mut c = conn_from_read_data(b"G/1/foo\0");
+        read_initial_header(&mut c, timeout, &auth, &reader_auth).await.unwrap();
+        assert_eq!(c.get_header().fields.reader_class, ReaderClass::Regular);
+    }
+
+    #[tokio::test]
+    async fn test_connection_header_v2_invalid_unicode() {
+        setup_logging();
+        let mut data = v2_header(&[("type", "reader"), ("id", "1"), ("name", "foo")]);
+        let last = data.len() - 1;
+        data[last] = b'\xc0';
+        let mut c = conn_from_read_data(data);
+        let err = read_and_set_connection_header(&mut c).await.err().unwrap();
+        assert!(matches!(err, ConnectionError::BadData(..)));
+    }
+
+    #[test]
+    fn test_header_from_websocket_path() {
+        let h = header_from_websocket_path("/replay/12/write", Some("name=foo%20bar&token=abc&offset=5"))
+            .unwrap()
+            .unwrap();
+        assert_eq!(h.type_, ConnectionType::Writer);
+        assert_eq!(h.id, 12);
+        assert_eq!(h.name, "foo bar");
+        assert_eq!(h.fields.auth_token, Some("abc".into()));
+        assert_eq!(h.fields.resume_offset, Some(5));
+
+        let h = header_from_websocket_path("/replay/12/read", None).unwrap().unwrap();
+        assert_eq!(h.type_, ConnectionType::Reader);
+        assert_eq!(h.name, "");
+
+        assert!(header_from_websocket_path("/", None).unwrap().is_none());
+        assert!(header_from_websocket_path("/something/else", Some("name=foo"))
+            .unwrap()
+            .is_none());
+    }
+
+    #[test]
+    fn test_header_from_websocket_path_invalid() {
+        for (path, query) in [
+            ("/replay/12/write", None),
+            ("/replay/12/write", Some("name=foo&name=bar")),
+            ("/replay/12/read", Some("id=13")),
+            ("/replay/foo/read", None),
+            ("/replay/12/delete", None),
+            ("/replay/12", None),
+        ] {
+            let err = header_from_websocket_path(path, query).err().unwrap();
+            assert!(matches!(err, ConnectionError::BadData(..)), "{} {:?}", path, query);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_initial_header_keeps_header_from_path() {
+        setup_logging();
+        let auth = WriterAuth::new(None);
+        let mut c = conn_from_read_data(b"G/2/bar\0");
+        c.set_header(ConnectionHeader::new(ConnectionType::Writer, 1, "foo".into()));
+        read_initial_header(&mut c, Duration::from_secs(1), &auth, &ReaderAuth::new(None))
+            .await
+            .unwrap();
+        assert_eq!(c.get_header().id, 1);
+    }
 }
@@ -0,0 +1,317 @@
+use std::collections::HashMap;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::{Arc, Mutex};
+
+use tokio::time::{Duration, Instant};
+
+use crate::config::ServerSettings;
+use crate::error::{ConnResult, ConnectionError};
+use crate::server::connection::Connection;
+
+// Limits on connections we accept, so that a single host can't eat all our file descriptors.
+// Accepted sockets can sit around for hours before sending a header, so without them that's
+// pretty easy to do.
+//
+// We limit:
+// * Connections from a single IP that are alive at the same time,
+// * New connections from a single IP in a second,
+// * Connections that haven't sent us their header yet, from all IPs together.
+//
+// Per-IP limits don't apply to connections we don't know the address of.
+
+#[derive(Default)]
+struct LimitState {
+    per_ip: HashMap<IpAddr, usize>,
+    rate_window_start: Option<Instant>,
+    new_per_ip: HashMap<IpAddr, u32>,
+    pending_headers: usize,
+}
+
+#[derive(Clone)]
+pub struct ConnectionLimits {
+    max_per_ip: Option<usize>,
+    max_new_per_ip_per_s: Option<u32>,
+    max_pending_headers: Option<usize>,
+    state: Arc<Mutex<LimitState>>,
+}
+
+// Held by a connection for its whole lifetime.
+pub struct IpSlot {
+    ip: IpAddr,
+    state: Arc<Mutex<LimitState>>,
+}
+
+impl Drop for IpSlot {
+    fn drop(&mut self) {
+        let mut state = self.state.lock().unwrap();
+        if let Some(count) = state.per_ip.get_mut(&self.ip) {
+            *count -= 1;
+            if *count == 0 {
+                state.per_ip.remove(&self.ip);
+            }
+        }
+    }
+}
+
+// Sockets a listener is still setting up (PROXY header, TLS and websocket handshakes), by IP.
+// Unlike the limits above this one is always on. A listener only sets up so many sockets at once,
+// and one host that opens a lot of them and goes quiet shouldn't take every slot.
+#[derive(Clone)]
+pub struct PendingSetups {
+    max_per_ip: usize,
+    per_ip: Arc<Mutex<HashMap<IpAddr, usize>>>,
+}
+
+// Held until the socket is set up.
+pub struct SetupSlot {
+    ip: IpAddr,
+    per_ip: Arc<Mutex<HashMap<IpAddr, usize>>>,
+}
+
+impl Drop for SetupSlot {
+    fn drop(&mut self) {
+        let mut per_ip = self.per_ip.lock().unwrap();
+        if let Some(count) = per_ip.get_mut(&self.ip) {
+            *count -= 1;
+            if *count == 0 {
+                per_ip.remove(&self.ip);
+            }
+        }
+    }
+}
+
+impl PendingSetups {
+    pub fn new(max_per_ip: usize) -> Self {
+        Self {
+            max_per_ip,
+            per_ip: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    pub fn start(&self, addr: Option<SocketAddr>) -> ConnResult<Option<SetupSlot>> {
+        let ip = match addr {
+            None => return Ok(None),
+            Some(a) => a.ip(),
+        };
+        let mut per_ip = self.per_ip.lock().unwrap();
+        let count = per_ip.entry(ip).or_insert(0);
+        if *count >= self.max_per_ip {
+            return Err(over_limit("too many connections being set up from one IP"));
+        }
+        *count += 1;
+        Ok(Some(SetupSlot {
+            ip,
+            per_ip: self.per_ip.clone(),
+        }))
+    }
+}
+
+// Held while we're waiting for a connection's header.
+pub struct PendingHeaderSlot {
+    state: Arc<Mutex<LimitState>>,
+}
+
+impl Drop for PendingHeaderSlot {
+    fn drop(&mut self) {
+        self.state.lock().unwrap().pending_headers -= 1;
+    }
+}
+
+fn over_limit(what: &str) -> ConnectionError {
+    ConnectionError::OverLimit(what.into())
+}
+
+impl ConnectionLimits {
+    pub fn new(config: &ServerSettings) -> Self {
+        Self {
+            max_per_ip: config.max_connections_per_ip,
+            max_new_per_ip_per_s: config.max_new_connections_per_ip_per_s,
+            max_pending_headers: config.max_pending_connection_headers,
+            state: Arc::new(Mutex::new(LimitState::default())),
+        }
+    }
+
+    fn check_rate(&self, state: &mut LimitState, ip: IpAddr) -> ConnResult<()> {
+        let max = match self.max_new_per_ip_per_s {
+            None => return Ok(()),
+            Some(m) => m,
+        };
+        // One-second windows. Forgetting everything at the end of a window keeps the map small.
+        let now = Instant::now();
+        match state.rate_window_start {
+            Some(start) if now - start < Duration::from_secs(1) => (),
+            _ => {
+                state.rate_window_start = Some(now);
+                state.new_per_ip.clear();
+            }
+        }
+        let count = state.new_per_ip.entry(ip).or_insert(0);
+        if *count >= max {
+            return Err(over_limit("too many new connections from one IP"));
+        }
+        *count += 1;
+        Ok(())
+    }
+
+    fn check_per_ip(&self, state: &mut LimitState, ip: IpAddr) -> ConnResult<()> {
+        let count = state.per_ip.get(&ip).copied().unwrap_or(0);
+        if self.max_per_ip.is_some_and(|m| count >= m) {
+            return Err(over_limit("too many connections from one IP"));
+        }
+        Ok(())
+    }
+
+    // Checks per-IP limits for a socket we haven't set up yet. If it fits, whoever holds the slot
+    // counts towards the limit.
+    pub fn admit_addr(&self, addr: Option<SocketAddr>) -> ConnResult<Option<IpSlot>> {
+        let ip = match addr {
+            None => return Ok(None),
+            Some(a) => a.ip(),
+        };
+        let mut state = self.state.lock().unwrap();
+        self.check_rate(&mut state, ip)?;
+        self.check_per_ip(&mut state, ip)?;
+        *state.per_ip.entry(ip).or_insert(0) += 1;
+        Ok(Some(IpSlot {
+            ip,
+            state: self.state.clone(),
+        }))
+    }
+
+    // Same for a connection, which holds on to its slot until it's dropped. Listeners admit
+    // sockets before the handshake, connections they already admitted are let through.
+    pub fn admit(&self, c: &mut Connection) -> ConnResult<()> {
+        if c.has_ip_slot() {
+            return Ok(());
+        }
+        if let Some(slot) = self.admit_addr(c.get_peer_addr())? {
+            c.set_ip_slot(slot);
+        }
+        Ok(())
+    }
+
+    pub fn start_reading_header(&self) -> ConnResult<PendingHeaderSlot> {
+        let mut state = self.state.lock().unwrap();
+        if self.max_pending_headers.is_some_and(|m| state.pending_headers >= m) {
+            return Err(over_limit("too many connections waiting to send a header"));
+        }
+        state.pending_headers += 1;
+        Ok(PendingHeaderSlot {
+            state: self.state.clone(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::config::test::default_config;
+    use crate::server::connection::test::test_connection;
+
+    fn limits(per_ip: Option<usize>, rate: Option<u32>, pending: Option<usize>) -> ConnectionLimits {
+        let mut config = default_config().server;
+        config.max_connections_per_ip = per_ip;
+        config.max_new_connections_per_ip_per_s = rate;
+        config.max_pending_connection_headers = pending;
+        ConnectionLimits::new(&config)
+    }
+
+    fn conn_from(addr: &str) -> Connection {
+        let (mut c, _r, _w) = test_connection();
+        c.set_peer_addr(Some(addr.parse().unwrap()));
+        c
+    }
+
+    fn is_over_limit<T>(r: ConnResult<T>) -> bool {
+        matches!(r, Err(ConnectionError::OverLimit(..)))
+    }
+
+    #[tokio::test]
+    async fn test_limits_concurrent_per_ip() {
+        let l = limits(Some(2), None, None);
+        let mut c1 = conn_from("10.0.0.1:1");
+        let mut c2 = conn_from("10.0.0.1:2");
+        let mut c3 = conn_from("10.0.0.1:3");
+        let mut other = conn_from("10.0.0.2:1");
+        l.admit(&mut c1).unwrap();
+        l.admit(&mut c2).unwrap();
+        assert!(is_over_limit(l.admit(&mut c3)));
+        l.admit(&mut other).unwrap();
+
+        drop(c1);
+        let mut c4 = conn_from("10.0.0.1:4");
+        l.admit(&mut c4).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_limits_new_connections_per_second() {
+        tokio::time::pause();
+        let l = limits(None, Some(2), None);
+        l.admit(&mut conn_from("10.0.0.1:1")).unwrap();
+        l.admit(&mut conn_from("10.0.0.1:2")).unwrap();
+        assert!(is_over_limit(l.admit(&mut conn_from("10.0.0.1:3"))));
+        l.admit(&mut conn_from("10.0.0.2:1")).unwrap();
+
+        tokio::time::advance(Duration::from_millis(1100)).await;
+        l.admit(&mut conn_from("10.0.0.1:4")).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_limits_pending_headers() {
+        let l = limits(None, None, Some(2));
+        let p1 = l.start_reading_header().unwrap();
+        let _p2 = l.start_reading_header().unwrap();
+        assert!(is_over_limit(l.start_reading_header()));
+        drop(p1);
+        l.start_reading_header().unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_limits_count_sockets_admitted_before_setup() {
+        let l = limits(Some(2), None, None);
+        let slot = l.admit_addr(Some("10.0.0.1:1".parse().unwrap())).unwrap();
+        let mut c1 = conn_from("10.0.0.1:1");
+        c1.set_ip_slot(slot.unwrap());
+        // Already counted, not counted twice.
+        l.admit(&mut c1).unwrap();
+        let _s2 = l.admit_addr(Some("10.0.0.1:2".parse().unwrap())).unwrap();
+        assert!(is_over_limit(l.admit_addr(Some("10.0.0.1:3".parse().unwrap()))));
+
+        drop(c1);
+        l.admit_addr(Some("10.0.0.1:4".parse().unwrap())).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_limits_skip_connections_without_address() {
+        let l = limits(Some(1), Some(1), None);
+        let (mut c1, _r1, _w1) = test_connection();
+        let (mut c2, _r2, _w2) = test_connection();
+        l.admit(&mut c1).unwrap();
+        l.admit(&mut c2).unwrap();
+    }
+
+    #[test]
+    fn test_pending_setups_per_ip() {
+        let p = PendingSetups::new(2);
+        let addr = |a: &str| Some(a.parse().unwrap());
+        let s1 = p.start(addr("10.0.0.1:1")).unwrap();
+        let _s2 = p.start(addr("10.0.0.1:2")).unwrap();
+        assert!(is_over_limit(p.start(addr("10.0.0.1:3"))));
+        p.start(addr("10.0.0.2:1")).unwrap();
+        assert!(p.start(None).unwrap().is_none());
+
+        drop(s1);
+        p.start(addr("10.0.0.1:4")).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_limits_unlimited_by_default() {
+        let l = limits(None, None, None);
+        let mut conns = Vec::new();
+        for i in 0..100 {
+            let mut c = conn_from(&format!("10.0.0.1:{}", i + 1));
+            l.admit(&mut c).unwrap();
+            conns.push((c, l.start_reading_header().unwrap()));
+        }
+    }
+}
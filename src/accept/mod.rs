@@ -1,2 +1,7 @@
+pub mod activation;
+pub mod auth;
 pub mod header;
+pub mod limits;
 pub mod producer;
+pub mod proxy;
+pub mod tls;
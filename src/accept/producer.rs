@@ -1,38 +1,565 @@
-use crate::server::{connection::Connection, websocket_stream::make_split_websocket_from_tcp};
-use futures::{Stream, StreamExt};
-use tokio::net::TcpListener;
-use tokio_stream::wrappers::TcpListenerStream;
+use std::net::SocketAddr;
+use std::os::unix::fs::FileTypeExt;
+use std::sync::Arc;
 
-pub async fn tcp_listen(addr: String) -> (impl Stream<Item = Connection>, u16) {
-    let listener = TcpListener::bind(addr).await.unwrap();
+use crate::error::{ConnResult, ConnectionError};
+use crate::metrics;
+use crate::server::connection::Connection;
+use crate::server::websocket_stream::{make_split_websocket, Keepalive};
+use crate::util::timeout::timeout;
+use futures::{future, Future, Stream, StreamExt};
+use tokio::io::BufReader;
+use tokio::net::{TcpListener, TcpStream, UnixListener, UnixStream};
+use tokio::time::Duration;
+use tokio_native_tls::TlsStream;
+use tokio_stream::wrappers::{TcpListenerStream, UnixListenerStream};
+
+use super::header::header_from_websocket_path;
+use super::limits::{ConnectionLimits, IpSlot, PendingSetups, SetupSlot};
+use super::proxy::read_proxy_header;
+use super::tls::ReloadableTls;
+
+// The proxy sends its header right away, we don't expect to wait long.
+const PROXY_HEADER_TIMEOUT: Duration = Duration::from_secs(5);
+// Setting up a socket (PROXY header, TLS and websocket handshakes) waits on the peer, so we set up
+// many at once. Otherwise one client that connects and goes quiet would hold up everyone else.
+// Past this many, new sockets wait in the listen backlog.
+const MAX_PENDING_SETUPS: usize = 256;
+// Out of the above, how many can come from one IP, see PendingSetups. Behind a proxy we only know
+// the IP once we read the PROXY header, but the proxy sends it right away.
+const MAX_PENDING_SETUPS_PER_IP: usize = 16;
+// For the whole setup. Nothing else limits the websocket handshake, and a client that never sends
+// an upgrade request shouldn't hold one of the slots above forever.
+const SETUP_TIMEOUT: Duration = Duration::from_secs(15);
+
+// What to do with a socket before it's a connection.
+#[derive(Clone, Default)]
+pub struct ListenerOptions {
+    pub proxy_protocol: bool,
+    pub tls: Option<Arc<ReloadableTls>>,
+    // Websocket only.
+    pub keepalive: Option<Keepalive>,
+    // Per-IP limits, checked as soon as we know the address so that a host over the limit can't
+    // tie up a handshake.
+    pub limits: Option<ConnectionLimits>,
+}
+
+enum Socket {
+    Plain(TcpStream),
+    Tls(TlsStream<TcpStream>),
+}
+
+// Address of whoever actually connected to us. If we're behind a proxy, we get it from the PROXY
+// header. If the proxy doesn't tell us (e.g. it's a health check), we use the proxy's address.
+async fn read_peer_addr(s: &mut TcpStream, proxy_protocol: bool) -> std::io::Result<Option<SocketAddr>> {
+    let direct_addr = s.peer_addr().ok();
+    if !proxy_protocol {
+        return Ok(direct_addr);
+    }
+    match timeout(read_proxy_header(s), PROXY_HEADER_TIMEOUT).await {
+        None => Err(std::io::Error::new(
+            std::io::ErrorKind::TimedOut,
+            "Timed out reading PROXY header",
+        )),
+        Some(addr) => Ok(addr?.or(direct_addr)),
+    }
+}
+
+struct PreparedSocket {
+    socket: Socket,
+    peer_addr: Option<SocketAddr>,
+    ip_slot: Option<IpSlot>,
+    // Keep it until the connection is set up.
+    setup_slot: Option<SetupSlot>,
+}
+
+// PROXY header comes first, TLS is between the client and us.
+async fn prepare_socket(
+    mut s: TcpStream,
+    options: &ListenerOptions,
+    pending: &PendingSetups,
+) -> ConnResult<PreparedSocket> {
+    let peer_addr = read_peer_addr(&mut s, options.proxy_protocol).await?;
+    let setup_slot = pending.start(peer_addr)?;
+    let ip_slot = match &options.limits {
+        None => None,
+        Some(l) => l.admit_addr(peer_addr)?,
+    };
+    let socket = match &options.tls {
+        None => Socket::Plain(s),
+        Some(tls) => Socket::Tls(tls.accept(s).await?),
+    };
+    Ok(PreparedSocket {
+        socket,
+        peer_addr,
+        ip_slot,
+        setup_slot,
+    })
+}
+
+fn setup_failed(e: ConnectionError) -> Option<Connection> {
+    log::info!("Failed to set up connection: {}", e);
+    // Server counts these once the connection's set up, we never get there.
+    if let ConnectionError::OverLimit(..) = e {
+        metrics::inc_served_conns(Some(e));
+    }
+    None
+}
+
+// Accepts sockets and sets them up concurrently with `setup`.
+fn listen<F, Fut>(listener: TcpListener, mut setup: F) -> (impl Stream<Item = Connection>, u16)
+where
+    F: FnMut(TcpStream, PendingSetups) -> Fut,
+    Fut: Future<Output = Option<Connection>>,
+{
     let port = listener.local_addr().unwrap().port();
-    (TcpListenerStream::new(listener).filter_map(|c| async {
+    let sockets = TcpListenerStream::new(listener).filter_map(|c| async move {
         match c {
             Err(e) => {
                 log::info!("Failed to accept connection: {}", e);
                 None
             }
-            Ok(s) => Some(Connection::new(s)),
+            Ok(s) => Some(s),
+        }
+    });
+    let pending = PendingSetups::new(MAX_PENDING_SETUPS_PER_IP);
+    let setup_with_timeout = move |s| {
+        let setting_up = setup(s, pending.clone());
+        async move {
+            match timeout(setting_up, SETUP_TIMEOUT).await {
+                None => {
+                    log::info!("Timed out setting up connection");
+                    None
+                }
+                Some(c) => c,
+            }
         }
-    }), port)
+    };
+    (
+        sockets
+            .map(setup_with_timeout)
+            .buffer_unordered(MAX_PENDING_SETUPS)
+            .filter_map(future::ready),
+        port,
+    )
 }
 
-pub async fn websocket_listen(addr: String) -> (impl Stream<Item = Connection>, u16) {
-    let listener = TcpListener::bind(addr).await.unwrap();
-    let port = listener.local_addr().unwrap().port();
-    (TcpListenerStream::new(listener).filter_map(|c| async {
+async fn setup_tcp_socket(s: TcpStream, options: ListenerOptions, pending: PendingSetups) -> Option<Connection> {
+    let PreparedSocket {
+        socket,
+        peer_addr,
+        ip_slot,
+        setup_slot: _setup_slot,
+    } = match prepare_socket(s, &options, &pending).await {
+        Err(e) => return setup_failed(e),
+        Ok(s) => s,
+    };
+    let mut c = match socket {
+        Socket::Plain(s) => Connection::new(s),
+        Socket::Tls(s) => {
+            let (r, w) = tokio::io::split(s);
+            Connection::new_from(Box::new(BufReader::new(r)), Box::new(w))
+        }
+    };
+    c.set_peer_addr(peer_addr);
+    if let Some(slot) = ip_slot {
+        c.set_ip_slot(slot);
+    }
+    Some(c)
+}
+
+async fn setup_websocket(s: TcpStream, options: ListenerOptions, pending: PendingSetups) -> Option<Connection> {
+    let PreparedSocket {
+        socket,
+        peer_addr,
+        ip_slot,
+        setup_slot: _setup_slot,
+    } = match prepare_socket(s, &options, &pending).await {
+        Err(e) => return setup_failed(e),
+        Ok(s) => s,
+    };
+    let ws = match socket {
+        Socket::Plain(s) => make_split_websocket(s, options.keepalive).await,
+        Socket::Tls(s) => make_split_websocket(s, options.keepalive).await,
+    };
+    let (r, w, uri) = match ws {
+        Err(e) => {
+            log::info!("Failed to create websocket: {}", e);
+            return None;
+        }
+        Ok(ws) => ws,
+    };
+    let mut c = Connection::new_from(r, w);
+    c.set_peer_addr(peer_addr);
+    if let Some(slot) = ip_slot {
+        c.set_ip_slot(slot);
+    }
+    match header_from_websocket_path(uri.path(), uri.query()) {
+        Err(e) => {
+            log::info!("Could not accept {}: {}", c, e);
+            metrics::inc_served_conns(Some(e));
+            None
+        }
+        Ok(header) => {
+            if let Some(h) = header {
+                c.set_header(h);
+            }
+            Some(c)
+        }
+    }
+}
+
+pub async fn tcp_listen(addr: String, options: ListenerOptions) -> (impl Stream<Item = Connection>, u16) {
+    tcp_listen_on(TcpListener::bind(addr).await.unwrap(), options)
+}
+
+// For sockets that are already bound, e.g. ones we inherited.
+pub fn tcp_listen_on(listener: TcpListener, options: ListenerOptions) -> (impl Stream<Item = Connection>, u16) {
+    listen(listener, move |s, p| setup_tcp_socket(s, options.clone(), p))
+}
+
+pub async fn websocket_listen(addr: String, options: ListenerOptions) -> (impl Stream<Item = Connection>, u16) {
+    websocket_listen_on(TcpListener::bind(addr).await.unwrap(), options)
+}
+
+pub fn websocket_listen_on(listener: TcpListener, options: ListenerOptions) -> (impl Stream<Item = Connection>, u16) {
+    listen(listener, move |s, p| setup_websocket(s, options.clone(), p))
+}
+
+// For local clients. There are no proxies or TLS in the way, and no peer address either.
+pub async fn unix_listen(path: String) -> impl Stream<Item = Connection> {
+    unix_listen_on(bind_unix(&path).await.unwrap())
+}
+
+async fn bind_unix(path: &str) -> std::io::Result<UnixListener> {
+    // A socket left over from a previous run makes binding fail, so we remove it. Sockets someone
+    // still listens on, e.g. a server that's draining, are theirs to keep.
+    if std::fs::symlink_metadata(path).is_ok_and(|m| m.file_type().is_socket()) {
+        match UnixStream::connect(path).await {
+            Err(e) if e.kind() == std::io::ErrorKind::ConnectionRefused => {
+                let _ = std::fs::remove_file(path);
+            }
+            Ok(_) => {
+                let msg = format!("Someone is already listening on {}", path);
+                return Err(std::io::Error::new(std::io::ErrorKind::AddrInUse, msg));
+            }
+            Err(_) => (),
+        }
+    }
+    UnixListener::bind(path)
+}
+
+pub fn unix_listen_on(listener: UnixListener) -> impl Stream<Item = Connection> {
+    UnixListenerStream::new(listener).filter_map(|c| async move {
         match c {
             Err(e) => {
                 log::info!("Failed to accept connection: {}", e);
                 None
             }
-            Ok(s) => match make_split_websocket_from_tcp(s).await {
-                Err(e) => {
-                    log::info!("Failed to create websocket: {}", e);
-                    None
-                }
-                Ok((r, w)) => Some(Connection::new_from(r, w)),
-            },
+            Ok(s) => {
+                let (r, w) = s.into_split();
+                Some(Connection::new_from(Box::new(BufReader::new(r)), Box::new(w)))
+            }
+        }
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::accept::header::header_reader::read_and_set_connection_header;
+    use crate::accept::header::ConnectionType;
+    use crate::accept::tls::test::{test_tls, tls_client};
+    use crate::config::test::default_config;
+    use futures::SinkExt;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::{join, select};
+    use tokio_websockets::{ClientBuilder, Message};
+
+    fn proxied() -> ListenerOptions {
+        ListenerOptions {
+            proxy_protocol: true,
+            tls: None,
+            keepalive: None,
+            limits: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_tcp_listen_reads_proxy_header() {
+        let (conns, port) = tcp_listen("127.0.0.1:0".into(), proxied()).await;
+        let mut client = TcpStream::connect(format!("127.0.0.1:{}", port)).await.unwrap();
+        client
+            .write_all(b"PROXY TCP4 10.1.2.3 127.0.0.1 4321 15000\r\nG/1/foo\0")
+            .await
+            .unwrap();
+        let mut c = Box::pin(conns).next().await.unwrap();
+        assert_eq!(c.get_peer_addr(), Some("10.1.2.3:4321".parse().unwrap()));
+        read_and_set_connection_header(&mut c).await.unwrap();
+        assert_eq!(c.get_header().id, 1);
+    }
+
+    #[tokio::test]
+    async fn test_tcp_listen_without_proxy_uses_socket_addr() {
+        let (conns, port) = tcp_listen("127.0.0.1:0".into(), Default::default()).await;
+        let client = TcpStream::connect(format!("127.0.0.1:{}", port)).await.unwrap();
+        let c = Box::pin(conns).next().await.unwrap();
+        assert_eq!(c.get_peer_addr(), Some(client.local_addr().unwrap()));
+    }
+
+    #[tokio::test]
+    async fn test_tcp_listen_drops_connections_without_proxy_header() {
+        let (conns, port) = tcp_listen("127.0.0.1:0".into(), proxied()).await;
+        let mut bad_client = TcpStream::connect(format!("127.0.0.1:{}", port)).await.unwrap();
+        bad_client.write_all(b"G/1/foo\0 and then some").await.unwrap();
+        let mut good_client = TcpStream::connect(format!("127.0.0.1:{}", port)).await.unwrap();
+        good_client.write_all(b"PROXY UNKNOWN\r\n").await.unwrap();
+        let c = Box::pin(conns).next().await.unwrap();
+        assert_eq!(c.get_peer_addr(), Some(good_client.local_addr().unwrap()));
+    }
+
+    #[tokio::test]
+    async fn test_tcp_listen_does_not_wait_for_idle_clients() {
+        let (conns, port) = tcp_listen("127.0.0.1:0".into(), proxied()).await;
+        let _idle_client = TcpStream::connect(format!("127.0.0.1:{}", port)).await.unwrap();
+        let mut good_client = TcpStream::connect(format!("127.0.0.1:{}", port)).await.unwrap();
+        good_client.write_all(b"PROXY UNKNOWN\r\n").await.unwrap();
+        // Well before the idle client times out.
+        let c = tokio::time::timeout(Duration::from_secs(1), Box::pin(conns).next())
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(c.get_peer_addr(), Some(good_client.local_addr().unwrap()));
+    }
+
+    #[tokio::test]
+    async fn test_tcp_listen_does_not_wait_for_idle_tls_clients() {
+        let options = ListenerOptions {
+            proxy_protocol: false,
+            tls: Some(test_tls()),
+            keepalive: None,
+            limits: None,
+        };
+        let (conns, port) = tcp_listen("127.0.0.1:0".into(), options).await;
+        let mut conns = Box::pin(conns);
+        let _idle_client = TcpStream::connect(format!("127.0.0.1:{}", port)).await.unwrap();
+        let good_client = async {
+            let s = TcpStream::connect(format!("127.0.0.1:{}", port)).await.unwrap();
+            let addr = s.local_addr().unwrap();
+            (tls_client(s).await, addr)
+        };
+        // Well before the idle client's handshake times out.
+        let conn = tokio::time::timeout(Duration::from_secs(1), conns.next());
+        let (c, (_s, addr)) = join!(conn, good_client);
+        assert_eq!(c.unwrap().unwrap().get_peer_addr(), Some(addr));
+    }
+
+    #[tokio::test]
+    async fn test_tcp_listen_limits_pending_setups_per_ip() {
+        let options = ListenerOptions {
+            tls: Some(test_tls()),
+            ..Default::default()
+        };
+        let (conns, port) = tcp_listen("127.0.0.1:0".into(), options).await;
+        let mut conns = Box::pin(conns);
+        let addr: SocketAddr = format!("127.0.0.1:{}", port).parse().unwrap();
+        let mut idle_clients = Vec::new();
+        for _ in 0..MAX_PENDING_SETUPS_PER_IP {
+            idle_clients.push(TcpStream::connect(addr).await.unwrap());
+        }
+        // Well before the setup would time out.
+        let mut over_limit = TcpStream::connect(addr).await.unwrap();
+        let mut buf = [0; 1];
+        let reading = tokio::time::timeout(Duration::from_secs(1), over_limit.read(&mut buf));
+        select! {
+            _ = conns.next() => panic!("Idle clients should not become connections"),
+            r = reading => assert_eq!(r.unwrap().unwrap(), 0),
+        }
+
+        // Others can still connect.
+        let good_client = async {
+            let socket = tokio::net::TcpSocket::new_v4().unwrap();
+            socket.bind("127.0.0.2:0".parse().unwrap()).unwrap();
+            let s = socket.connect(addr).await.unwrap();
+            let addr = s.local_addr().unwrap();
+            (tls_client(s).await, addr)
+        };
+        let conn = tokio::time::timeout(Duration::from_secs(1), conns.next());
+        let (c, (_s, addr)) = join!(conn, good_client);
+        assert_eq!(c.unwrap().unwrap().get_peer_addr(), Some(addr));
+    }
+
+    #[tokio::test]
+    async fn test_websocket_listen_drops_clients_that_never_upgrade() {
+        tokio::time::pause();
+        let (conns, port) = websocket_listen("127.0.0.1:0".into(), Default::default()).await;
+        let mut conns = Box::pin(conns);
+        let mut idle_client = TcpStream::connect(format!("127.0.0.1:{}", port)).await.unwrap();
+        let mut buf = [0; 1];
+        select! {
+            _ = conns.next() => panic!("Idle client should not become a connection"),
+            r = idle_client.read(&mut buf) => assert_eq!(r.unwrap(), 0),
         }
-    }), port)
+    }
+
+    #[tokio::test]
+    async fn test_websocket_listen_checks_per_ip_limit_before_handshake() {
+        let mut config = default_config().server;
+        config.max_connections_per_ip = Some(1);
+        let options = ListenerOptions {
+            limits: Some(ConnectionLimits::new(&config)),
+            ..Default::default()
+        };
+        let (conns, port) = websocket_listen("127.0.0.1:0".into(), options).await;
+        let mut conns = Box::pin(conns);
+        let _idle_client = TcpStream::connect(format!("127.0.0.1:{}", port)).await.unwrap();
+        let mut over_limit = TcpStream::connect(format!("127.0.0.1:{}", port)).await.unwrap();
+        // Well before the setup would time out.
+        let mut buf = [0; 1];
+        let reading = tokio::time::timeout(Duration::from_secs(1), over_limit.read(&mut buf));
+        select! {
+            _ = conns.next() => panic!("Neither client should become a connection"),
+            r = reading => assert_eq!(r.unwrap().unwrap(), 0),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_tcp_listen_with_tls() {
+        let options = ListenerOptions {
+            proxy_protocol: true,
+            tls: Some(test_tls()),
+            keepalive: None,
+            limits: None,
+        };
+        let (conns, port) = tcp_listen("127.0.0.1:0".into(), options).await;
+        let mut conns = Box::pin(conns);
+        let client = async {
+            let mut s = TcpStream::connect(format!("127.0.0.1:{}", port)).await.unwrap();
+            s.write_all(b"PROXY TCP4 10.1.2.3 127.0.0.1 4321 15000\r\n")
+                .await
+                .unwrap();
+            let mut s = tls_client(s).await;
+            s.write_all(b"G/1/foo\0").await.unwrap();
+            s
+        };
+        let (c, mut client) = join!(conns.next(), client);
+        let mut c = c.unwrap();
+        assert_eq!(c.get_peer_addr(), Some("10.1.2.3:4321".parse().unwrap()));
+        read_and_set_connection_header(&mut c).await.unwrap();
+        assert_eq!(c.get_header().id, 1);
+
+        c.write_all(b"hello").await.unwrap();
+        c.flush().await.unwrap();
+        let mut buf = [0; 5];
+        client.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"hello");
+    }
+
+    #[tokio::test]
+    async fn test_unix_listen_leaves_sockets_in_use_alone() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("replayserver.sock").to_str().unwrap().to_owned();
+        let _listener = std::os::unix::net::UnixListener::bind(&path).unwrap();
+
+        let err = bind_unix(&path).await.unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::AddrInUse);
+        tokio::net::UnixStream::connect(&path).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_websocket_listen_with_tls() {
+        let options = ListenerOptions {
+            proxy_protocol: false,
+            tls: Some(test_tls()),
+            keepalive: None,
+            limits: None,
+        };
+        let (conns, port) = websocket_listen("127.0.0.1:0".into(), options).await;
+        let mut conns = Box::pin(conns);
+        let client = async {
+            let s = TcpStream::connect(format!("127.0.0.1:{}", port)).await.unwrap();
+            let s = tls_client(s).await;
+            let uri = http::Uri::from_static("wss://localhost/");
+            let (mut ws, _) = ClientBuilder::from_uri(uri).connect_on(s).await.unwrap();
+            ws.send(Message::binary(&b"G/1/foo\0"[..])).await.unwrap();
+            ws
+        };
+        let (c, _ws) = join!(conns.next(), client);
+        let mut c = c.unwrap();
+        read_and_set_connection_header(&mut c).await.unwrap();
+        assert_eq!(c.get_header().id, 1);
+    }
+
+    #[tokio::test]
+    async fn test_websocket_listen_reads_header_from_path() {
+        let (conns, port) = websocket_listen("127.0.0.1:0".into(), Default::default()).await;
+        let mut conns = Box::pin(conns);
+        let connect = |path: &'static str| async move {
+            let uri: http::Uri = format!("ws://127.0.0.1:{}{}", port, path).parse().unwrap();
+            ClientBuilder::from_uri(uri).connect().await.unwrap().0
+        };
+
+        let (c, _ws) = join!(conns.next(), connect("/replay/1/write?name=foo"));
+        let c = c.unwrap();
+        assert_eq!(c.get_header().type_, ConnectionType::Writer);
+        assert_eq!(c.get_header().id, 1);
+        assert_eq!(c.get_header().name, "foo");
+
+        // Bad paths get dropped, legacy clients still send the header in-band.
+        let bad_ws = tokio::spawn(connect("/replay/1/write"));
+        let legacy_ws = async {
+            let _bad_ws = bad_ws.await.unwrap();
+            connect("/").await
+        };
+        let (c, mut ws) = join!(conns.next(), legacy_ws);
+        let mut c = c.unwrap();
+        assert!(!c.has_header());
+        ws.send(Message::binary(&b"G/2/bar\0"[..])).await.unwrap();
+        read_and_set_connection_header(&mut c).await.unwrap();
+        assert_eq!(c.get_header().type_, ConnectionType::Reader);
+        assert_eq!(c.get_header().id, 2);
+    }
+
+    #[tokio::test]
+    async fn test_unix_listen() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("replayserver.sock").to_str().unwrap().to_owned();
+        // Stale socket from a previous run.
+        drop(std::os::unix::net::UnixListener::bind(&path).unwrap());
+
+        let mut conns = Box::pin(unix_listen(path.clone()).await);
+        let mut client = tokio::net::UnixStream::connect(&path).await.unwrap();
+        client.write_all(b"G/1/foo\0").await.unwrap();
+        let mut c = conns.next().await.unwrap();
+        assert_eq!(c.get_peer_addr(), None);
+        read_and_set_connection_header(&mut c).await.unwrap();
+        assert_eq!(c.get_header().id, 1);
+
+        c.write_all(b"hello").await.unwrap();
+        c.flush().await.unwrap();
+        let mut buf = [0; 5];
+        client.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"hello");
+    }
+
+    #[tokio::test]
+    async fn test_tcp_listen_drops_failed_tls_handshakes() {
+        let options = ListenerOptions {
+            proxy_protocol: false,
+            tls: Some(test_tls()),
+            keepalive: None,
+            limits: None,
+        };
+        let (conns, port) = tcp_listen("127.0.0.1:0".into(), options).await;
+        let mut conns = Box::pin(conns);
+        let mut bad_client = TcpStream::connect(format!("127.0.0.1:{}", port)).await.unwrap();
+        bad_client.write_all(b"G/1/foo\0 but no TLS").await.unwrap();
+        let good_client = async {
+            let s = TcpStream::connect(format!("127.0.0.1:{}", port)).await.unwrap();
+            let addr = s.local_addr().unwrap();
+            (tls_client(s).await, addr)
+        };
+        let (c, (_s, addr)) = join!(conns.next(), good_client);
+        assert_eq!(c.unwrap().get_peer_addr(), Some(addr));
+    }
 }
@@ -0,0 +1,205 @@
+use std::io::{Error, ErrorKind, Result};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::str::from_utf8;
+
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+// PROXY protocol, as sent by HAProxy and friends in front of the actual connection data. Tells us
+// who really connected to the proxy. Spec: https://www.haproxy.org/download/2.9/doc/proxy-protocol.txt
+//
+// Version 1 is a text line:
+//   "PROXY TCP4 <src addr> <dst addr> <src port> <dst port>\r\n", or TCP6, or
+//   "PROXY UNKNOWN<anything>\r\n"
+// at most 107 bytes long.
+//
+// Version 2 is binary:
+//   <12 byte signature> <version/command: u8> <family/protocol: u8> <length: u16 BE> <addresses>
+// where addresses are (for IPv4) <src: 4> <dst: 4> <src port: u16 BE> <dst port: u16 BE>, or the
+// same with 16 byte addresses for IPv6. Anything after that (TLVs) we skip.
+//
+// We return None if the proxy doesn't tell us the address, e.g. for its own health checks.
+
+const V2_SIGNATURE: &[u8; 12] = b"\r\n\r\n\0\r\nQUIT\n";
+const V1_MAX_LENGTH: usize = 107;
+
+fn invalid(what: impl Into<String>) -> Error {
+    Error::new(ErrorKind::InvalidData, format!("Invalid PROXY header: {}", what.into()))
+}
+
+fn parse_v1_line(line: &[u8]) -> Result<Option<SocketAddr>> {
+    let line = from_utf8(line).map_err(|_| invalid("not UTF-8"))?;
+    let parts: Vec<&str> = line.split(' ').collect();
+    match parts.get(1) {
+        Some(&"UNKNOWN") => return Ok(None),
+        Some(&"TCP4") | Some(&"TCP6") => (),
+        _ => return Err(invalid("unknown protocol")),
+    }
+    if parts.len() != 6 {
+        return Err(invalid("wrong field count"));
+    }
+    let ip = parts[2].parse::<IpAddr>().map_err(|_| invalid("bad source address"))?;
+    let port = parts[4].parse::<u16>().map_err(|_| invalid("bad source port"))?;
+    Ok(Some(SocketAddr::new(ip, port)))
+}
+
+async fn read_v1<T: AsyncRead + Unpin>(s: &mut T, start: &[u8]) -> Result<Option<SocketAddr>> {
+    // We don't want to read anything past the header, so byte by byte it is.
+    let mut line = start.to_vec();
+    while !line.ends_with(b"\r\n") {
+        if line.len() >= V1_MAX_LENGTH {
+            return Err(invalid("line too long"));
+        }
+        line.push(s.read_u8().await?);
+    }
+    line.truncate(line.len() - 2);
+    parse_v1_line(&line)
+}
+
+fn parse_v2_addresses(family: u8, addrs: &[u8]) -> Result<Option<SocketAddr>> {
+    let short = || invalid("address block too short");
+    match family >> 4 {
+        0x1 => {
+            let a = addrs.get(..12).ok_or_else(short)?;
+            let ip = Ipv4Addr::new(a[0], a[1], a[2], a[3]);
+            let port = u16::from_be_bytes([a[8], a[9]]);
+            Ok(Some(SocketAddr::new(ip.into(), port)))
+        }
+        0x2 => {
+            let a = addrs.get(..36).ok_or_else(short)?;
+            let mut ip = [0; 16];
+            ip.copy_from_slice(&a[..16]);
+            let port = u16::from_be_bytes([a[32], a[33]]);
+            Ok(Some(SocketAddr::new(Ipv6Addr::from(ip).into(), port)))
+        }
+        // Unspecified or unix sockets, nothing useful for us.
+        _ => Ok(None),
+    }
+}
+
+async fn read_v2<T: AsyncRead + Unpin>(s: &mut T) -> Result<Option<SocketAddr>> {
+    let version_command = s.read_u8().await?;
+    let family = s.read_u8().await?;
+    let len = s.read_u16().await?;
+    let mut addrs = vec![0; len as usize];
+    s.read_exact(&mut addrs).await?;
+    if version_command >> 4 != 2 {
+        return Err(invalid("unknown version"));
+    }
+    match version_command & 0xf {
+        0x0 => Ok(None), // LOCAL, connection made by the proxy itself
+        0x1 => parse_v2_addresses(family, &addrs),
+        _ => Err(invalid("unknown command")),
+    }
+}
+
+pub async fn read_proxy_header<T: AsyncRead + Unpin>(s: &mut T) -> Result<Option<SocketAddr>> {
+    // Shortest valid v1 header, "PROXY UNKNOWN\r\n", is longer than the v2 signature.
+    let mut start = [0; 12];
+    s.read_exact(&mut start).await?;
+    if &start == V2_SIGNATURE {
+        read_v2(s).await
+    } else if start.starts_with(b"PROXY ") {
+        read_v1(s, &start).await
+    } else {
+        Err(invalid("no PROXY signature"))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    async fn read_all(mut data: &[u8]) -> (Result<Option<SocketAddr>>, Vec<u8>) {
+        let res = read_proxy_header(&mut data).await;
+        (res, data.to_vec())
+    }
+
+    fn v2(command: u8, family: u8, addrs: &[u8]) -> Vec<u8> {
+        let mut data = V2_SIGNATURE.to_vec();
+        data.push(0x20 | command);
+        data.push(family);
+        data.extend((addrs.len() as u16).to_be_bytes());
+        data.extend(addrs);
+        data
+    }
+
+    #[tokio::test]
+    async fn test_proxy_v1_tcp4() {
+        let (res, rest) = read_all(b"PROXY TCP4 192.168.0.1 192.168.0.11 56324 443\r\nG/1/foo\0").await;
+        assert_eq!(res.unwrap(), Some("192.168.0.1:56324".parse().unwrap()));
+        assert_eq!(rest, b"G/1/foo\0");
+    }
+
+    #[tokio::test]
+    async fn test_proxy_v1_tcp6() {
+        let (res, rest) = read_all(b"PROXY TCP6 ::1 ::2 1234 443\r\n").await;
+        assert_eq!(res.unwrap(), Some("[::1]:1234".parse().unwrap()));
+        assert!(rest.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_proxy_v1_unknown() {
+        let (res, rest) = read_all(b"PROXY UNKNOWN\r\nfoo").await;
+        assert_eq!(res.unwrap(), None);
+        assert_eq!(rest, b"foo");
+    }
+
+    #[tokio::test]
+    async fn test_proxy_v1_invalid() {
+        for data in [
+            b"PROXY TCP4 192.168.0.1 192.168.0.11 56324\r\n" as &[u8],
+            b"PROXY TCP4 banana 192.168.0.11 56324 443\r\n",
+            b"PROXY TCP4 192.168.0.1 192.168.0.11 99999 443\r\n",
+            b"PROXY UDP4 192.168.0.1 192.168.0.11 56324 443\r\n",
+            b"PROXY TCP4 192.168.0.1 192.168.0.11 56324 443",
+            b"G/1/foo\0 and some more data",
+        ] {
+            let (res, _) = read_all(data).await;
+            assert!(res.is_err());
+        }
+        let long_line = format!("PROXY UNKNOWN {}\r\n", "a".repeat(100));
+        let (res, _) = read_all(long_line.as_bytes()).await;
+        assert!(res.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_proxy_v2_tcp4() {
+        let mut data = v2(1, 0x11, &[10, 0, 0, 1, 10, 0, 0, 2, 0x12, 0x34, 0x01, 0xbb, 0xff, 0xff]);
+        data.extend(b"G/1/foo\0");
+        let (res, rest) = read_all(&data).await;
+        assert_eq!(res.unwrap(), Some("10.0.0.1:4660".parse().unwrap()));
+        assert_eq!(rest, b"G/1/foo\0");
+    }
+
+    #[tokio::test]
+    async fn test_proxy_v2_tcp6() {
+        let mut addrs = vec![0; 36];
+        addrs[15] = 1;
+        addrs[31] = 2;
+        addrs[32..34].copy_from_slice(&[0x12, 0x34]);
+        let (res, _) = read_all(&v2(1, 0x21, &addrs)).await;
+        assert_eq!(res.unwrap(), Some("[::1]:4660".parse().unwrap()));
+    }
+
+    #[tokio::test]
+    async fn test_proxy_v2_local() {
+        let mut data = v2(0, 0x00, &[]);
+        data.extend(b"foo");
+        let (res, rest) = read_all(&data).await;
+        assert_eq!(res.unwrap(), None);
+        assert_eq!(rest, b"foo");
+    }
+
+    #[tokio::test]
+    async fn test_proxy_v2_invalid() {
+        let short_addrs = v2(1, 0x11, &[10, 0, 0, 1]);
+        let bad_command = v2(5, 0x11, &[10, 0, 0, 1, 10, 0, 0, 2, 0x12, 0x34, 0x01, 0xbb]);
+        let mut bad_version = v2(1, 0x11, &[10, 0, 0, 1, 10, 0, 0, 2, 0x12, 0x34, 0x01, 0xbb]);
+        bad_version[12] = 0x11;
+        let truncated = &bad_command[..16];
+        for data in [&short_addrs[..], &bad_command[..], &bad_version[..], truncated] {
+            let (res, _) = read_all(data).await;
+            assert!(res.is_err());
+        }
+    }
+}
@@ -0,0 +1,244 @@
+use std::sync::{Arc, Mutex};
+use std::time::SystemTime;
+
+use openssl::{
+    pkey::{PKey, Private},
+    x509::X509,
+};
+use tokio::net::TcpStream;
+use tokio::time::{Duration, Instant};
+use tokio_native_tls::native_tls::{self, Identity};
+use tokio_native_tls::{TlsAcceptor, TlsStream};
+
+use crate::util::timeout::timeout;
+
+// TLS for our listeners. Certificates get renewed every now and then, so every once in a while
+// we accept a connection, we check if the certificate or key file changed, and if so, load them
+// again. That touches the file system, so it happens on a blocking thread, and the connection
+// that triggered it doesn't wait for it. If loading fails (e.g. we caught the files halfway
+// through being replaced), we keep using the old ones and try again next time.
+
+const TLS_HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+const CERT_CHECK_INTERVAL: Duration = Duration::from_secs(60);
+
+struct LoadedTls {
+    acceptor: TlsAcceptor,
+    modified: (Option<SystemTime>, Option<SystemTime>),
+}
+
+pub struct ReloadableTls {
+    cert_path: String,
+    key_path: String,
+    loaded: Mutex<LoadedTls>,
+    next_check: Mutex<Instant>,
+}
+
+fn modified_time(path: &str) -> Option<SystemTime> {
+    std::fs::metadata(path).and_then(|m| m.modified()).ok()
+}
+
+impl ReloadableTls {
+    pub fn new(cert_path: &str, key_path: &str) -> Result<Arc<Self>, String> {
+        let loaded = Self::load(cert_path, key_path)?;
+        Ok(Arc::new(Self {
+            cert_path: cert_path.into(),
+            key_path: key_path.into(),
+            loaded: Mutex::new(loaded),
+            next_check: Mutex::new(Instant::now() + CERT_CHECK_INTERVAL),
+        }))
+    }
+
+    fn load(cert_path: &str, key_path: &str) -> Result<LoadedTls, String> {
+        // Get times first, so that if files change while we load them, we'll load them again.
+        let modified = (modified_time(cert_path), modified_time(key_path));
+        let cert = std::fs::read(cert_path).map_err(|e| format!("Failed to read {}: {}", cert_path, e))?;
+        let key = std::fs::read(key_path).map_err(|e| format!("Failed to read {}: {}", key_path, e))?;
+        let invalid = |e: openssl::error::ErrorStack| format!("Invalid certificate or key: {}", e);
+        // Native TLS only takes PKCS#8 keys. OpenSSL reads the other usual formats (PKCS#1, SEC1)
+        // too, so we convert.
+        let key = PKey::private_key_from_pem(&key).map_err(invalid)?;
+        Self::check_key_matches_cert(&cert, &key)?;
+        let key = key.private_key_to_pem_pkcs8().map_err(invalid)?;
+        let identity = Identity::from_pkcs8(&cert, &key).map_err(|e| format!("Invalid certificate or key: {}", e))?;
+        let acceptor = native_tls::TlsAcceptor::new(identity).map_err(|e| format!("Failed to set up TLS: {}", e))?;
+        Ok(LoadedTls {
+            acceptor: acceptor.into(),
+            modified,
+        })
+    }
+
+    // Native TLS doesn't check this, we'd only find out at handshake time.
+    fn check_key_matches_cert(cert: &[u8], key: &PKey<Private>) -> Result<(), String> {
+        let matches = X509::from_pem(cert)
+            .and_then(|c| c.public_key())
+            .map(|c| c.public_eq(key))
+            .map_err(|e| format!("Invalid certificate or key: {}", e))?;
+        if !matches {
+            return Err("Private key does not match certificate".into());
+        }
+        Ok(())
+    }
+
+    pub fn acceptor(&self) -> TlsAcceptor {
+        self.loaded.lock().unwrap().acceptor.clone()
+    }
+
+    // Blocks on the file system. We don't hold the lock while loading, so handshakes can go on.
+    fn reload_if_changed(&self) {
+        let modified = (modified_time(&self.cert_path), modified_time(&self.key_path));
+        if modified == self.loaded.lock().unwrap().modified {
+            return;
+        }
+        match Self::load(&self.cert_path, &self.key_path) {
+            Ok(l) => {
+                log::info!("Reloaded TLS certificate from {}", self.cert_path);
+                *self.loaded.lock().unwrap() = l;
+            }
+            Err(e) => log::warn!("Failed to reload TLS certificate, keeping the old one: {}", e),
+        }
+    }
+
+    // Returns true at most once every CERT_CHECK_INTERVAL.
+    fn check_due(&self) -> bool {
+        let mut next_check = self.next_check.lock().unwrap();
+        let now = Instant::now();
+        if now < *next_check {
+            return false;
+        }
+        *next_check = now + CERT_CHECK_INTERVAL;
+        true
+    }
+
+    fn check_for_changes_if_due(self: &Arc<Self>) {
+        if !self.check_due() {
+            return;
+        }
+        let me = self.clone();
+        tokio::task::spawn_blocking(move || me.reload_if_changed());
+    }
+
+    pub async fn accept(self: &Arc<Self>, s: TcpStream) -> std::io::Result<TlsStream<TcpStream>> {
+        self.check_for_changes_if_due();
+        let acceptor = self.acceptor();
+        match timeout(acceptor.accept(s), TLS_HANDSHAKE_TIMEOUT).await {
+            None => Err(std::io::Error::new(
+                std::io::ErrorKind::TimedOut,
+                "TLS handshake timed out",
+            )),
+            Some(r) => r.map_err(std::io::Error::other),
+        }
+    }
+}
+
+#[cfg(test)]
+pub mod test {
+    use super::*;
+    use crate::util::test::get_file_path;
+    use tokio::io::{AsyncRead, AsyncWrite};
+    use tokio::join;
+
+    pub fn test_tls() -> Arc<ReloadableTls> {
+        ReloadableTls::new(&get_file_path("tls/cert.pem"), &get_file_path("tls/key.pem")).unwrap()
+    }
+
+    pub async fn tls_client<S: AsyncRead + AsyncWrite + Unpin>(s: S) -> TlsStream<S> {
+        let connector = native_tls::TlsConnector::builder()
+            .danger_accept_invalid_certs(true)
+            .build()
+            .unwrap();
+        tokio_native_tls::TlsConnector::from(connector)
+            .connect("localhost", s)
+            .await
+            .unwrap()
+    }
+
+    fn cert_der(name: &str) -> Vec<u8> {
+        let pem = std::fs::read(get_file_path(name)).unwrap();
+        native_tls::Certificate::from_pem(&pem).unwrap().to_der().unwrap()
+    }
+
+    // Does a handshake over an in-memory pipe, returns the certificate we got.
+    async fn served_cert(tls: &ReloadableTls) -> Vec<u8> {
+        let (server_side, client_side) = tokio::io::duplex(16384);
+        let acceptor = tls.acceptor();
+        let (server, client) = join! {
+            acceptor.accept(server_side),
+            tls_client(client_side),
+        };
+        server.unwrap();
+        let cert = client.get_ref().peer_certificate().unwrap().unwrap();
+        cert.to_der().unwrap()
+    }
+
+    #[test]
+    fn test_tls_invalid_files() {
+        let cert = get_file_path("tls/cert.pem");
+        let key = get_file_path("tls/key.pem");
+        assert!(ReloadableTls::new("/nonexistent/cert.pem", &key).is_err());
+        assert!(ReloadableTls::new(&cert, "/nonexistent/key.pem").is_err());
+        assert!(ReloadableTls::new(&cert, &get_file_path("tls/other_key.pem")).is_err());
+        assert!(ReloadableTls::new(&cert, &get_file_path("example_config.yml")).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_tls_handshake() {
+        let tls = test_tls();
+        assert_eq!(served_cert(&tls).await, cert_der("tls/cert.pem"));
+    }
+
+    #[tokio::test]
+    async fn test_tls_accepts_pkcs1_and_ec_keys() {
+        let rsa = ReloadableTls::new(&get_file_path("tls/cert.pem"), &get_file_path("tls/rsa_key.pem")).unwrap();
+        assert_eq!(served_cert(&rsa).await, cert_der("tls/cert.pem"));
+        let ec = ReloadableTls::new(&get_file_path("tls/ec_cert.pem"), &get_file_path("tls/ec_key.pem")).unwrap();
+        assert_eq!(served_cert(&ec).await, cert_der("tls/ec_cert.pem"));
+    }
+
+    // Copies a test file over another one. Files get their own modification times, so that
+    // changes are noticed even if the file system's timestamps are coarse.
+    fn replace_file(name: &str, target: &std::path::Path, version: u64) {
+        std::fs::copy(get_file_path(name), target).unwrap();
+        let modified = SystemTime::UNIX_EPOCH + std::time::Duration::from_secs(version);
+        std::fs::File::options()
+            .write(true)
+            .open(target)
+            .unwrap()
+            .set_modified(modified)
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_tls_reloads_changed_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let cert = dir.path().join("cert.pem");
+        let key = dir.path().join("key.pem");
+        replace_file("tls/cert.pem", &cert, 1);
+        replace_file("tls/key.pem", &key, 1);
+        let tls = ReloadableTls::new(cert.to_str().unwrap(), key.to_str().unwrap()).unwrap();
+        assert_eq!(served_cert(&tls).await, cert_der("tls/cert.pem"));
+
+        // Half-replaced files. We should keep serving the old certificate.
+        replace_file("tls/other_cert.pem", &cert, 2);
+        tls.reload_if_changed();
+        assert_eq!(served_cert(&tls).await, cert_der("tls/cert.pem"));
+
+        replace_file("tls/other_key.pem", &key, 2);
+        tls.reload_if_changed();
+        assert_eq!(served_cert(&tls).await, cert_der("tls/other_cert.pem"));
+    }
+
+    #[tokio::test]
+    async fn test_tls_checks_for_changes_at_most_once_a_minute() {
+        tokio::time::pause();
+        let tls = test_tls();
+        assert!(!tls.check_due());
+
+        tokio::time::advance(CERT_CHECK_INTERVAL / 2).await;
+        assert!(!tls.check_due());
+        tokio::time::advance(CERT_CHECK_INTERVAL / 2).await;
+        assert!(tls.check_due());
+        assert!(!tls.check_due());
+        tokio::time::advance(CERT_CHECK_INTERVAL).await;
+        assert!(tls.check_due());
+    }
+}
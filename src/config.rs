@@ -1,5 +1,7 @@
 use std::{
+    collections::HashMap,
     env::{self, VarError},
+    net::IpAddr,
     sync::Arc,
     time::Duration,
 };
@@ -28,14 +30,167 @@ mod float_to_duration {
     }
 }
 
+mod optional_float_to_duration {
+    use super::*;
+    use serde::{Deserialize, Deserializer};
+
+    pub fn deserialize<'de, D>(deserializer: D) -> Result<Option<Duration>, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        #[derive(Deserialize)]
+        struct Wrapper(#[serde(with = "float_to_duration")] Duration);
+        let w = Option::<Wrapper>::deserialize(deserializer)?;
+        Ok(w.map(|Wrapper(d)| d))
+    }
+}
+
+mod float_map_to_duration {
+    use super::*;
+    use serde::{Deserialize, Deserializer};
+
+    pub fn deserialize<'de, D>(deserializer: D) -> Result<HashMap<String, Duration>, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        #[derive(Deserialize)]
+        struct Wrapper(#[serde(with = "float_to_duration")] Duration);
+        let m = HashMap::<String, Wrapper>::deserialize(deserializer)?;
+        Ok(m.into_iter().map(|(k, Wrapper(d))| (k, d)).collect())
+    }
+}
+
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum ListenerProtocol {
+    Tcp,
+    Ws,
+    Unix,
+}
+
+// How we merge writers' streams into one, see merge_strategy.rs.
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "kebab-case")]
+pub enum MergeStrategyKind {
+    #[default]
+    Quorum,
+    LongestStreamWins,
+    TrustedWriterFirst,
+}
+
+#[derive(Debug, Deserialize, Clone, PartialEq, Eq)]
+pub struct ListenerSettings {
+    pub protocol: ListenerProtocol,
+    // IP address to bind to for TCP and websocket, socket path for Unix. IP defaults to 0.0.0.0.
+    pub address: Option<String>,
+    // Required for TCP and websocket, unused for Unix.
+    pub port: Option<u16>,
+    #[serde(default)]
+    pub proxy_protocol: bool,
+    #[serde(default)]
+    pub tls: bool,
+}
+
+impl ListenerSettings {
+    fn new(protocol: ListenerProtocol, address: Option<String>, port: Option<u16>) -> Self {
+        Self {
+            protocol,
+            address,
+            port,
+            proxy_protocol: false,
+            tls: false,
+        }
+    }
+
+    fn validate(&self) -> Result<(), String> {
+        match self.protocol {
+            ListenerProtocol::Unix => {
+                if self.address.is_none() {
+                    return Err("Unix listeners need a socket path as their address".into());
+                }
+                if self.proxy_protocol || self.tls {
+                    return Err("Unix listeners don't support PROXY protocol or TLS".into());
+                }
+            }
+            ListenerProtocol::Tcp | ListenerProtocol::Ws => {
+                if self.port.is_none() {
+                    return Err("TCP and websocket listeners need a port".into());
+                }
+                if let Some(a) = &self.address {
+                    a.parse::<IpAddr>()
+                        .map_err(|_| format!("Listener address '{}' is not an IP address", a))?;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
 #[derive(Debug, Deserialize, Clone, PartialEq, Eq)]
 pub struct ServerSettings {
+    // Listeners can be set up in two ways: with a list of listeners, or with the keys below for a
+    // single listener of each protocol. Both can be used at once. See all_listeners().
+    #[serde(default)]
+    pub listeners: Vec<ListenerSettings>,
     pub port: Option<u16>,
     pub websocket_port: Option<u16>,
+    // Path of a Unix domain socket to listen on, for clients on the same host.
+    pub unix_socket_path: Option<String>,
+    // Whether connections on given port start with a PROXY protocol header.
+    #[serde(default)]
+    pub tcp_proxy_protocol: bool,
+    #[serde(default)]
+    pub websocket_proxy_protocol: bool,
+    // Whether connections on given port use TLS.
+    #[serde(default)]
+    pub tcp_tls: bool,
+    #[serde(default)]
+    pub websocket_tls: bool,
+    pub tls_cert_path: Option<String>,
+    pub tls_key_path: Option<String>,
     pub prometheus_port: u16,
+    // Port of the admin HTTP API. Only listens on localhost.
+    pub admin_port: Option<u16>,
     pub worker_threads: u32,
     #[serde(with = "float_to_duration")]
     pub connection_accept_timeout_s: Duration,
+    pub writer_token_secret: Option<String>,
+    // Readers with a token minted with this secret watch with privileged_delay_s instead.
+    pub privileged_reader_token_secret: Option<String>,
+    pub max_connections_per_ip: Option<usize>,
+    pub max_new_connections_per_ip_per_s: Option<u32>,
+    pub max_pending_connection_headers: Option<usize>,
+    pub max_connections: Option<usize>,
+    pub max_bytes_in_flight: Option<usize>,
+    #[serde(default, with = "optional_float_to_duration")]
+    pub websocket_ping_interval_s: Option<Duration>,
+    #[serde(default, with = "optional_float_to_duration")]
+    pub websocket_pong_timeout_s: Option<Duration>,
+    #[serde(default, with = "optional_float_to_duration")]
+    pub drain_timeout_s: Option<Duration>,
+}
+
+impl ServerSettings {
+    pub fn all_listeners(&self) -> Vec<ListenerSettings> {
+        let mut listeners = Vec::new();
+        if let Some(p) = self.port {
+            let mut l = ListenerSettings::new(ListenerProtocol::Tcp, None, Some(p));
+            l.proxy_protocol = self.tcp_proxy_protocol;
+            l.tls = self.tcp_tls;
+            listeners.push(l);
+        }
+        if let Some(p) = self.websocket_port {
+            let mut l = ListenerSettings::new(ListenerProtocol::Ws, None, Some(p));
+            l.proxy_protocol = self.websocket_proxy_protocol;
+            l.tls = self.websocket_tls;
+            listeners.push(l);
+        }
+        if let Some(path) = &self.unix_socket_path {
+            listeners.push(ListenerSettings::new(ListenerProtocol::Unix, Some(path.clone()), None));
+        }
+        listeners.extend(self.listeners.iter().cloned());
+        listeners
+    }
 }
 
 #[derive(Debug, Deserialize, Clone, PartialEq, Eq)]
@@ -52,6 +207,8 @@ pub struct DatabaseSettings {
 pub struct StorageSettings {
     pub vault_path: String,
     pub compression_level: u32,
+    // Where running replays are spooled, so they survive a crash. Not spooled if not set.
+    pub spool_path: Option<String>,
 }
 
 #[derive(Debug, Deserialize, Clone, PartialEq, Eq)]
@@ -62,10 +219,31 @@ pub struct ReplaySettings {
     pub time_with_zero_writers_to_end_replay_s: Duration,
     #[serde(with = "float_to_duration")]
     pub delay_s: Duration,
+    // Delays of matchmaker games, by queue name. We look up the game's queue when its replay starts.
+    #[serde(default, with = "float_map_to_duration")]
+    pub matchmaker_queue_delays_s: HashMap<String, Duration>,
+    // Delays of other games, by featured mod. Games that match neither use delay_s.
+    #[serde(default, with = "float_map_to_duration")]
+    pub featured_mod_delays_s: HashMap<String, Duration>,
+    // Delay for privileged readers, capped by the replay's delay. Required if there can be any.
+    #[serde(default, with = "optional_float_to_duration")]
+    pub privileged_delay_s: Option<Duration>,
     #[serde(with = "float_to_duration")]
     pub update_interval_s: Duration,
+    #[serde(default)]
+    pub merge_strategy: MergeStrategyKind,
     pub merge_quorum_size: usize,
     pub stream_comparison_distance_b: usize,
+    // Whether the quorum strategy only merges whole commands.
+    #[serde(default)]
+    pub merge_whole_commands: bool,
+    // How long merge_quorum_size writers have to agree on a header before we go with the most
+    // common one. If not set, the first header wins and we merge every writer anyway.
+    #[serde(default, with = "optional_float_to_duration")]
+    pub header_consensus_timeout_s: Option<Duration>,
+    // How long a reader can wait for a replay that didn't start yet.
+    #[serde(default, with = "optional_float_to_duration")]
+    pub reader_wait_for_replay_s: Option<Duration>,
 }
 
 pub type Settings = Arc<InnerSettings>;
@@ -99,9 +277,35 @@ impl InnerSettings {
             .add_source(File::with_name(&config_file[..]))
             .build()?;
         let ret: Self = c.try_deserialize()?;
-        if ret.server.port.is_none() && ret.server.websocket_port.is_none() {
+        let listeners = ret.server.all_listeners();
+        if listeners.is_empty() {
             return Err(ConfigError::Message(
-                "At least one of port, websocket_port must be set in server configuration.".into(),
+                "At least one of listeners, port, websocket_port, unix_socket_path must be set in server configuration."
+                    .into(),
+            ));
+        }
+        for l in listeners.iter() {
+            l.validate().map_err(ConfigError::Message)?;
+        }
+        let wants_tls = listeners.iter().any(|l| l.tls);
+        if wants_tls && (ret.server.tls_cert_path.is_none() || ret.server.tls_key_path.is_none()) {
+            return Err(ConfigError::Message(
+                "tls_cert_path and tls_key_path must be set in server configuration to use TLS.".into(),
+            ));
+        }
+        // Merging follows privileged readers too, a delay of zero would make it useless.
+        if ret.server.privileged_reader_token_secret.is_some() && ret.replay.privileged_delay_s.is_none() {
+            return Err(ConfigError::Message(
+                "privileged_delay_s must be set in replay configuration to use privileged_reader_token_secret.".into(),
+            ));
+        }
+        // Without tokens nobody is trusted, so there's nothing to prefer.
+        if ret.replay.merge_strategy == MergeStrategyKind::TrustedWriterFirst
+            && ret.server.writer_token_secret.is_none()
+        {
+            return Err(ConfigError::Message(
+                "writer_token_secret must be set in server configuration to use the trusted-writer-first merge strategy."
+                    .into(),
             ));
         }
         Ok(ret)
@@ -117,11 +321,30 @@ pub mod test {
     pub fn default_config() -> InnerSettings {
         InnerSettings {
             server: ServerSettings {
+                listeners: Vec::new(),
                 port: Some(15000),
                 websocket_port: Some(15001),
+                unix_socket_path: None,
+                tcp_proxy_protocol: false,
+                websocket_proxy_protocol: false,
+                tcp_tls: false,
+                websocket_tls: false,
+                tls_cert_path: None,
+                tls_key_path: None,
                 prometheus_port: 8001,
+                admin_port: None,
                 worker_threads: 8,
                 connection_accept_timeout_s: Duration::from_secs(7200),
+                writer_token_secret: None,
+                privileged_reader_token_secret: None,
+                max_connections_per_ip: None,
+                max_new_connections_per_ip_per_s: None,
+                max_pending_connection_headers: None,
+                max_connections: None,
+                max_bytes_in_flight: None,
+                websocket_ping_interval_s: None,
+                websocket_pong_timeout_s: None,
+                drain_timeout_s: None,
             },
             database: DatabaseSettings {
                 pool_size: 8,
@@ -134,14 +357,22 @@ pub mod test {
             storage: StorageSettings {
                 vault_path: "/tmp/foo".into(),
                 compression_level: 10,
+                spool_path: None,
             },
             replay: ReplaySettings {
                 forced_timeout_s: Duration::from_secs(3600 * 6),
                 time_with_zero_writers_to_end_replay_s: Duration::from_secs(10),
                 delay_s: Duration::from_secs(60 * 5),
+                matchmaker_queue_delays_s: HashMap::new(),
+                featured_mod_delays_s: HashMap::new(),
+                privileged_delay_s: None,
                 update_interval_s: Duration::from_secs(1),
+                merge_strategy: MergeStrategyKind::Quorum,
                 merge_quorum_size: 2,
                 stream_comparison_distance_b: 4096,
+                merge_whole_commands: false,
+                header_consensus_timeout_s: None,
+                reader_wait_for_replay_s: None,
             },
         }
     }
@@ -154,6 +385,20 @@ pub mod test {
         assert_eq!(conf, default_config());
     }
 
+    #[test]
+    fn test_example_config_game_delays() {
+        let conf_file = get_file_path("test_configs/game_delays.yml");
+        let password = String::from("banana"); // File does not have a password entry
+        let conf = InnerSettings::do_from_env(Ok(conf_file), Ok(password)).unwrap();
+        let mut def = default_config();
+        def.replay.matchmaker_queue_delays_s = HashMap::from([
+            ("ladder1v1".into(), Duration::from_secs(600)),
+            ("tmm2v2".into(), Duration::from_secs_f64(450.5)),
+        ]);
+        def.replay.featured_mod_delays_s = HashMap::from([("coop".into(), Duration::from_secs(120))]);
+        assert_eq!(conf, def);
+    }
+
     #[test]
     fn test_example_config_optional_websocket_port() {
         let conf_file = get_file_path("test_configs/optional_websocket_port.yml");
@@ -181,6 +426,69 @@ pub mod test {
         InnerSettings::do_from_env(Ok(conf_file), Ok(password)).expect_err("Not defining any connection ports should be an error");
     }
 
+    #[test]
+    fn test_example_config_tls_needs_certificate() {
+        let conf_file = get_file_path("test_configs/invalid_tls_without_certificate.yml");
+        let password = String::from("banana"); // File does not have a password entry
+        InnerSettings::do_from_env(Ok(conf_file), Ok(password))
+            .expect_err("Enabling TLS without a certificate should be an error");
+    }
+
+    #[test]
+    fn test_example_config_privileged_secret_needs_delay() {
+        let conf_file = get_file_path("test_configs/invalid_privileged_secret_without_delay.yml");
+        let password = String::from("banana"); // File does not have a password entry
+        InnerSettings::do_from_env(Ok(conf_file), Ok(password))
+            .expect_err("Privileged reader tokens without a privileged delay should be an error");
+    }
+
+    #[test]
+    fn test_example_config_trusted_writer_first_needs_secret() {
+        let conf_file = get_file_path("test_configs/invalid_trusted_writer_first_without_secret.yml");
+        let password = String::from("banana"); // File does not have a password entry
+        InnerSettings::do_from_env(Ok(conf_file), Ok(password))
+            .expect_err("Trusted writer first merge strategy without a writer secret should be an error");
+    }
+
+    #[test]
+    fn test_example_config_listeners() {
+        let conf_file = get_file_path("test_configs/listeners.yml");
+        let password = String::from("banana"); // File does not have a password entry
+        let conf = InnerSettings::do_from_env(Ok(conf_file), Ok(password)).unwrap();
+        let mut ws = ListenerSettings::new(ListenerProtocol::Ws, Some("::".into()), Some(15003));
+        ws.proxy_protocol = true;
+        let expected = vec![
+            ListenerSettings::new(ListenerProtocol::Tcp, None, Some(15000)),
+            ListenerSettings::new(ListenerProtocol::Tcp, Some("127.0.0.1".into()), Some(15002)),
+            ws,
+            ListenerSettings::new(ListenerProtocol::Unix, Some("/run/replayserver.sock".into()), None),
+        ];
+        assert_eq!(conf.server.all_listeners(), expected);
+    }
+
+    #[test]
+    fn test_example_config_listener_needs_port() {
+        let conf_file = get_file_path("test_configs/invalid_listener_without_port.yml");
+        let password = String::from("banana"); // File does not have a password entry
+        InnerSettings::do_from_env(Ok(conf_file), Ok(password))
+            .expect_err("Websocket listener without a port should be an error");
+    }
+
+    #[test]
+    fn test_listener_validation() {
+        let mut unix = ListenerSettings::new(ListenerProtocol::Unix, None, None);
+        assert!(unix.validate().is_err());
+        unix.address = Some("/tmp/foo.sock".into());
+        assert!(unix.validate().is_ok());
+        unix.tls = true;
+        assert!(unix.validate().is_err());
+
+        let mut tcp = ListenerSettings::new(ListenerProtocol::Tcp, Some("localhost".into()), Some(15000));
+        assert!(tcp.validate().is_err());
+        tcp.address = Some("::1".into());
+        assert!(tcp.validate().is_ok());
+    }
+
     #[test]
     fn test_config_needs_password() {
         let conf_file = get_file_path("example_config.yml");
@@ -27,6 +27,13 @@ pub struct GameStatRow {
     pub file_name: Option<String>,
 }
 
+// What kind of game it is. Matchmaker games have a queue.
+#[derive(sqlx::FromRow, Debug, Clone, PartialEq, Eq)]
+pub struct GameCategoryRow {
+    pub matchmaker_queue: Option<String>,
+    pub featured_mod: Option<String>,
+}
+
 #[derive(sqlx::FromRow, Debug, PartialEq, Eq)]
 pub struct PlayerCount {
     pub count: i64, // In db it's signed BIGINT
@@ -159,6 +166,38 @@ impl Database {
         }
     }
 
+    pub async fn get_game_category(&self, id: u64) -> Result<Option<GameCategoryRow>, SaveError> {
+        let query = "
+            SELECT
+                `matchmaker_queue`.`technical_name` AS matchmaker_queue,
+                `game_featuredMods`.`gamemod` AS featured_mod
+            FROM `game_stats`
+            INNER JOIN `game_featuredMods`
+              ON `game_stats`.`gameMod` = `game_featuredMods`.`id`
+            LEFT JOIN `matchmaker_queue_game`
+              ON `matchmaker_queue_game`.`game_stats_id` = `game_stats`.`id`
+            LEFT JOIN `matchmaker_queue`
+              ON `matchmaker_queue`.`id` = `matchmaker_queue_game`.`matchmaker_queue_id`
+            WHERE `game_stats`.`id` = ?
+        ";
+        Ok(sqlx::query_as::<_, GameCategoryRow>(query)
+            .bind(id)
+            .fetch_optional(&self.pool)
+            .await?)
+    }
+
+    pub async fn get_game_host(&self, id: u64) -> Result<Option<u32>, SaveError> {
+        let query = "
+            SELECT `game_stats`.`host`
+            FROM `game_stats`
+            WHERE `game_stats`.`id` = ?
+        ";
+        Ok(sqlx::query_scalar::<_, u32>(query)
+            .bind(id)
+            .fetch_optional(&self.pool)
+            .await?)
+    }
+
     pub async fn update_game_stats(
         &self,
         id: u64,
@@ -270,6 +309,24 @@ pub mod test {
             },
         ];
         assert_eq!(players_to_map(players), players_to_map(expected_players));
+
+        assert_eq!(db.get_game_host(1000).await.unwrap(), Some(1));
+    }
+
+    #[cfg_attr(not(feature = "local_db_tests"), ignore)]
+    #[tokio::test]
+    async fn test_db_game_category() {
+        let db = get_db();
+        let custom = db.get_game_category(1000).await.unwrap().unwrap();
+        assert_eq!(custom.matchmaker_queue, None);
+        assert_eq!(custom.featured_mod, Some("faf".into()));
+
+        let ladder = db.get_game_category(1020).await.unwrap().unwrap();
+        assert_eq!(ladder.matchmaker_queue, Some("ladder1v1".into()));
+        assert_eq!(ladder.featured_mod, Some("ladder1v1".into()));
+
+        assert_eq!(db.get_game_category(1100).await.unwrap().unwrap().featured_mod, None);
+        assert_eq!(db.get_game_category(9999).await.unwrap(), None);
     }
 
     #[cfg_attr(not(feature = "local_db_tests"), ignore)]
@@ -481,6 +538,13 @@ pub mod test {
             ])
         });
         faux::when!(mock_db.update_game_stats).then(|(_id, _ticks, _saved)| Ok(()));
+        faux::when!(mock_db.get_game_category).then(|_id| {
+            Ok(Some(GameCategoryRow {
+                matchmaker_queue: None,
+                featured_mod: Some("faf".into()),
+            }))
+        });
+        faux::when!(mock_db.get_game_host).then(|_id| Ok(Some(1)));
         mock_db
     }
 }
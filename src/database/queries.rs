@@ -3,7 +3,7 @@ use std::collections::BTreeMap;
 
 use crate::error::SaveError;
 
-use super::database::Database;
+use super::database::{Database, GameCategoryRow};
 
 pub type GameTeams = BTreeMap<i8, Vec<String>>;
 pub struct GameStats {
@@ -77,6 +77,17 @@ impl Queries {
         Ok(ret)
     }
 
+    // None if the game isn't in the database.
+    pub async fn get_game_category(&self, id: u64) -> Result<Option<GameCategoryRow>, SaveError> {
+        self.db.get_game_category(id).await
+    }
+
+    // Login ID of the player hosting the game.
+    pub async fn get_game_host(&self, id: u64) -> Result<Option<u64>, SaveError> {
+        let host = self.db.get_game_host(id).await?;
+        Ok(host.map(u64::from))
+    }
+
     pub async fn update_game_stats(
         &self,
         id: u64,
@@ -148,6 +159,12 @@ mod test {
         assert!(stats.game_end > 1577836800); // 2021-01-01 00:00:00
     }
 
+    #[tokio::test]
+    async fn test_game_host() {
+        let q = Queries::new(mock_database());
+        assert_eq!(q.get_game_host(1).await.unwrap(), Some(1));
+    }
+
     #[tokio::test]
     async fn test_game_mod_versions() {
         let q = Queries::new(mock_database());
@@ -10,6 +10,14 @@ pub enum ConnectionError {
     IO(#[from] std::io::Error),
     #[error("Could not assign connection to replay")]
     CannotAssignToReplay,
+    #[error("Writer authentication failed: {0}")]
+    Unauthorized(String),
+    #[error("Connection limit exceeded: {0}")]
+    OverLimit(String),
+    #[error("Player {0} already has a writer on this replay")]
+    DuplicateWriter(u64),
+    #[error("Server is overloaded")]
+    Overloaded,
 }
 
 // Some helpers.
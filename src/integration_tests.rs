@@ -32,7 +32,8 @@ mod test {
         config.server.connection_accept_timeout_s = Duration::from_millis(10);
 
         let token = CancellationToken::new();
-        let (server, _) = server_with_real_deps(Arc::new(config), token.child_token()).await;
+        let (server, _) =
+            server_with_real_deps(Arc::new(config), token.child_token(), Default::default()).await;
         let mut ended_too_early = true;
 
         let wait = async {
@@ -149,11 +150,12 @@ mod test {
         config.server.connection_accept_timeout_s = Duration::from_millis(50);
 
         let token = CancellationToken::new();
-        let (server, port_info) = server_with_real_deps(Arc::new(config), token.child_token()).await;
+        let (server, port_info) =
+            server_with_real_deps(Arc::new(config), token.child_token(), Default::default()).await;
 
         let sent_replay = get_file("example");
-        let replay_writer = tcp_writer(port_info.tcp.unwrap(), b"P/2000/foo\0", sent_replay.clone());
-        let replay_reader = tcp_reader(port_info.tcp.unwrap(), b"G/2000/foo\0");
+        let replay_writer = tcp_writer(port_info.tcp().unwrap(), b"P/2000/foo\0", sent_replay.clone());
+        let replay_reader = tcp_reader(port_info.tcp().unwrap(), b"G/2000/foo\0");
 
         let exit_server = async move || {
             tokio::time::sleep(Duration::from_secs(1)).await;
@@ -204,11 +206,12 @@ mod test {
         config.server.connection_accept_timeout_s = Duration::from_millis(50);
 
         let token = CancellationToken::new();
-        let (server, port_info) = server_with_real_deps(Arc::new(config), token.child_token()).await;
+        let (server, port_info) =
+            server_with_real_deps(Arc::new(config), token.child_token(), Default::default()).await;
 
         let sent_replay = get_file("example");
-        let replay_writer = websocket_writer(port_info.websocket.unwrap(), b"P/2001/foo\0", sent_replay.clone());
-        let replay_reader = websocket_reader(port_info.websocket.unwrap(), b"G/2001/foo\0");
+        let replay_writer = websocket_writer(port_info.websocket().unwrap(), b"P/2001/foo\0", sent_replay.clone());
+        let replay_reader = websocket_reader(port_info.websocket().unwrap(), b"G/2001/foo\0");
 
         let exit_server = async move || {
             tokio::time::sleep(Duration::from_secs(1)).await;
@@ -260,10 +263,15 @@ mod test {
         config.server.connection_accept_timeout_s = Duration::from_millis(50);
 
         let token = CancellationToken::new();
-        let (server, port_info) = server_with_real_deps(Arc::new(config), token.child_token()).await;
+        let (server, port_info) =
+            server_with_real_deps(Arc::new(config), token.child_token(), Default::default()).await;
 
         let sent_replay = get_file("example");
-        let replay_writer = websocket_writer_end_with_normal_closure(port_info.websocket.unwrap(), b"P/2001/foo\0", sent_replay.clone());
+        let replay_writer = websocket_writer_end_with_normal_closure(
+            port_info.websocket().unwrap(),
+            b"P/2001/foo\0",
+            sent_replay.clone(),
+        );
 
         let exit_server = async move || {
             tokio::time::sleep(Duration::from_secs(1)).await;
@@ -309,9 +317,10 @@ mod test {
         config.server.connection_accept_timeout_s = Duration::from_millis(50);
 
         let token = CancellationToken::new();
-        let (server, port_info) = server_with_real_deps(Arc::new(config), token.child_token()).await;
+        let (server, port_info) =
+            server_with_real_deps(Arc::new(config), token.child_token(), Default::default()).await;
 
-        let replay_writer = websocket_writer_abnormal_closure(port_info.websocket.unwrap(), b"P/2001/foo\0");
+        let replay_writer = websocket_writer_abnormal_closure(port_info.websocket().unwrap(), b"P/2001/foo\0");
 
         let exit_server = async move || {
             tokio::time::sleep(Duration::from_secs(1)).await;
@@ -1,5 +1,7 @@
+use faf_rust_replayserver::accept::activation::InheritedListeners;
 use faf_rust_replayserver::server::server::run_server;
-use faf_rust_replayserver::util::process::{setup_process_exit_on_panic, wait_for_signals};
+use faf_rust_replayserver::util::process::{setup_process_exit_on_panic, wait_for_drain_signal, wait_for_signals};
+use faf_rust_replayserver::util::timeout::cancellable;
 use tokio::join;
 
 use faf_rust_replayserver::config::{InnerSettings, Settings};
@@ -10,8 +12,9 @@ const VERSION: &str = env!("CARGO_PKG_VERSION");
 // Here we do all things that are necessarily global. That is:
 // * Loading configuration (because it's needed for Prometheus),
 // * Prometheus, which we could make non-global with some effort, but I don't think it's worth it,
-// * Signal handlers.
-async fn do_run_server() {
+// * Signal handlers,
+// * Sockets we inherited, since we take them from the environment.
+async fn do_run_server(inherited: InheritedListeners) {
     let config = match InnerSettings::from_env() {
         Err(e) => {
             log::info!("Failed to load config: {}", e);
@@ -20,24 +23,29 @@ async fn do_run_server() {
         Ok(o) => o,
     };
 
-    let port_str = |v: Option<u16>| v.map(|e| format!("{}", e)).unwrap_or("None".into());
-    log::info!(
-        "TCP socket port: {}\nWebsocket port: {}\nPrometheus port: {}",
-        port_str(config.server.port),
-        port_str(config.server.websocket_port),
-        config.server.prometheus_port
-    );
+    log::info!("Prometheus port: {}", config.server.prometheus_port);
     if !start_prometheus_server(&config) {
         return;
     }
     let shutdown_token = CancellationToken::new();
-    let f1 = run_server(config, shutdown_token.clone());
+    let drain_token = CancellationToken::new();
+    // After draining, the server exits without a signal.
+    let server_done = CancellationToken::new();
+    let f1 = async {
+        run_server(config, inherited, shutdown_token.clone(), drain_token.clone()).await;
+        server_done.cancel();
+    };
     let f2 = async {
         wait_for_signals().await;
         log::debug!("Received a SIGINT or SIGTERM, shutting down");
         shutdown_token.cancel();
     };
-    join!(f1, f2);
+    let f3 = async {
+        wait_for_drain_signal().await;
+        log::info!("Received a SIGUSR2, draining");
+        drain_token.cancel();
+    };
+    join!(f1, cancellable(f2, &server_done), cancellable(f3, &server_done));
 }
 
 fn start_prometheus_server(config: &Settings) -> bool {
@@ -70,9 +78,11 @@ pub fn main() {
     configure_logging();
     log::info!("Server version {}.", VERSION);
     setup_process_exit_on_panic();
+    // Before the runtime starts any threads.
+    let inherited = InheritedListeners::from_env();
     let local_loop = tokio::runtime::Builder::new_current_thread()
         .enable_all()
         .build()
         .unwrap();
-    local_loop.block_on(do_run_server());
+    local_loop.block_on(do_run_server(inherited));
 }
@@ -1,7 +1,7 @@
 use lazy_static::lazy_static;
 use prometheus_exporter::prometheus::{
-    register_int_counter, register_int_counter_vec, register_int_gauge, register_int_gauge_vec, IntCounter,
-    IntCounterVec, IntGauge, IntGaugeVec,
+    register_histogram, register_int_counter, register_int_counter_vec, register_int_gauge, register_int_gauge_vec,
+    Histogram, IntCounter, IntCounterVec, IntGauge, IntGaugeVec,
 };
 
 use crate::error::ConnectionError;
@@ -29,6 +29,23 @@ lazy_static! {
         "Number of replays ran to completion."
     )
     .unwrap();
+    pub static ref LOAD_SHEDDING: IntCounterVec = register_int_counter_vec!(
+        "replayserver_load_shedding_total",
+        "Readers we rejected or disconnected because the server was overloaded.",
+        &["decision"]
+    )
+    .unwrap();
+    pub static ref BYTES_IN_FLIGHT: IntGauge = register_int_gauge!(
+        "replayserver_bytes_in_flight",
+        "Bytes readers that are not catching up still have to receive."
+    )
+    .unwrap();
+    pub static ref WEBSOCKET_PING_RTT: Histogram = register_histogram!(
+        "replayserver_websocket_ping_rtt_seconds",
+        "Round-trip time of websocket keepalive pings.",
+        vec![0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0]
+    )
+    .unwrap();
     pub static ref SAVED_REPLAYS: IntCounter = register_int_counter!(
         "replayserver_saved_replay_files_total",
         "Total replays successfully saved to disk."
@@ -44,6 +61,10 @@ pub fn inc_served_conns(res: Option<ConnectionError>) {
             ConnectionError::BadData(..) => "Bad data",
             ConnectionError::IO { .. } => "I/O error",
             ConnectionError::CannotAssignToReplay => "No replay matched",
+            ConnectionError::Unauthorized(..) => "Unauthorized writer",
+            ConnectionError::OverLimit(..) => "Over connection limit",
+            ConnectionError::DuplicateWriter(..) => "Duplicate writer",
+            ConnectionError::Overloaded => "Server overloaded",
         },
     };
     SERVED_CONNS.with_label_values(&[label]).inc();
@@ -0,0 +1,244 @@
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+
+use tokio::time::Duration;
+use tokio_util::sync::CancellationToken;
+
+use crate::{config::ServerSettings, metrics};
+
+// When we're overloaded, recording games matters more than letting people watch them. This keeps
+// track of server-wide load, shared between all worker threads, so that we can shed readers when
+// we're over budget:
+// * New readers are rejected,
+// * Once per tick, the reader that's stuck with the most data gets disconnected.
+// Writers are never refused.
+//
+// Load is measured as:
+// * Connections assigned to replays,
+// * Bytes in flight, that is, bytes that readers who are not catching up still have to receive.
+//   A reader whose backlog shrinks is doing fine, even if it has a lot to go, e.g. after joining
+//   a long game. One whose backlog doesn't shrink can't keep up, and its backlog counts.
+pub type LoadBudget = Arc<InnerLoadBudget>;
+
+pub struct InnerLoadBudget {
+    max_connections: Option<usize>,
+    max_bytes_in_flight: Option<usize>,
+    connections: AtomicUsize,
+    bytes_in_flight: AtomicUsize,
+    readers: Mutex<HashMap<u64, Arc<ReaderLoad>>>,
+    next_reader_id: AtomicU64,
+}
+
+// Counts a connection towards the budget until dropped.
+pub struct BudgetedConnection {
+    budget: LoadBudget,
+}
+
+impl Drop for BudgetedConnection {
+    fn drop(&mut self) {
+        self.budget.connections.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+struct ReaderLoad {
+    stuck_bytes: AtomicUsize,
+    disconnect: CancellationToken,
+}
+
+// A reader's share of bytes in flight. Lets the budget disconnect the reader, wherever it lives.
+pub struct TrackedReader {
+    budget: LoadBudget,
+    id: u64,
+    load: Arc<ReaderLoad>,
+}
+
+impl TrackedReader {
+    pub fn set_stuck_bytes(&self, bytes: usize) {
+        let old = self.load.stuck_bytes.swap(bytes, Ordering::Relaxed);
+        self.budget.update_bytes_in_flight(old, bytes);
+    }
+}
+
+impl Drop for TrackedReader {
+    fn drop(&mut self) {
+        self.budget.readers.lock().unwrap().remove(&self.id);
+        self.set_stuck_bytes(0);
+    }
+}
+
+impl InnerLoadBudget {
+    pub fn new(config: &ServerSettings) -> LoadBudget {
+        Arc::new(Self {
+            max_connections: config.max_connections,
+            max_bytes_in_flight: config.max_bytes_in_flight,
+            connections: AtomicUsize::new(0),
+            bytes_in_flight: AtomicUsize::new(0),
+            readers: Mutex::new(HashMap::new()),
+            next_reader_id: AtomicU64::new(0),
+        })
+    }
+
+    pub fn track_connection(self: &Arc<Self>) -> BudgetedConnection {
+        self.connections.fetch_add(1, Ordering::Relaxed);
+        BudgetedConnection { budget: self.clone() }
+    }
+
+    // Cancels the token if we decide to drop the reader.
+    pub fn track_reader(self: &Arc<Self>, disconnect: CancellationToken) -> TrackedReader {
+        let id = self.next_reader_id.fetch_add(1, Ordering::Relaxed);
+        let load = Arc::new(ReaderLoad {
+            stuck_bytes: AtomicUsize::new(0),
+            disconnect,
+        });
+        self.readers.lock().unwrap().insert(id, load.clone());
+        TrackedReader {
+            budget: self.clone(),
+            id,
+            load,
+        }
+    }
+
+    fn update_bytes_in_flight(&self, old: usize, new: usize) {
+        if new > old {
+            self.bytes_in_flight.fetch_add(new - old, Ordering::Relaxed);
+        } else {
+            self.bytes_in_flight.fetch_sub(old - new, Ordering::Relaxed);
+        }
+        metrics::BYTES_IN_FLIGHT.set(self.bytes_in_flight.load(Ordering::Relaxed) as i64);
+    }
+
+    fn bytes_over_budget(&self) -> bool {
+        self.max_bytes_in_flight
+            .is_some_and(|m| self.bytes_in_flight.load(Ordering::Relaxed) > m)
+    }
+
+    // Whether there's room for one more reader.
+    pub fn can_admit_reader(&self) -> bool {
+        let connections_full = self
+            .max_connections
+            .is_some_and(|m| self.connections.load(Ordering::Relaxed) >= m);
+        !connections_full && !self.bytes_over_budget()
+    }
+
+    // Whether we should start dropping existing readers.
+    pub fn is_overloaded(&self) -> bool {
+        let too_many_connections = self
+            .max_connections
+            .is_some_and(|m| self.connections.load(Ordering::Relaxed) > m);
+        too_many_connections || self.bytes_over_budget()
+    }
+
+    // Drops at most one reader, the one stuck with the most data. Readers that are catching up
+    // are left alone.
+    fn shed_slowest_reader(&self) {
+        if !self.is_overloaded() {
+            return;
+        }
+        let readers = self.readers.lock().unwrap();
+        let slowest = readers
+            .values()
+            .filter(|r| !r.disconnect.is_cancelled())
+            .max_by_key(|r| r.stuck_bytes.load(Ordering::Relaxed));
+        let stuck_bytes = slowest.map_or(0, |r| r.stuck_bytes.load(Ordering::Relaxed));
+        if let Some(r) = slowest.filter(|_| stuck_bytes > 0) {
+            log::info!("Server overloaded, dropping a reader stuck {} bytes behind", stuck_bytes);
+            metrics::LOAD_SHEDDING.with_label_values(&["reader_disconnected"]).inc();
+            r.disconnect.cancel();
+        }
+    }
+
+    // Runs for as long as the server does. There's one of these for the whole server, so an
+    // overloaded tick costs us one reader, not one per replay.
+    pub async fn shed_load(&self, interval: Duration) {
+        loop {
+            tokio::time::sleep(interval).await;
+            self.shed_slowest_reader();
+        }
+    }
+}
+
+#[cfg(test)]
+pub mod test {
+    use super::*;
+    use crate::config::test::default_config;
+    use crate::util::timeout::until;
+
+    pub fn budget(max_connections: Option<usize>, max_bytes_in_flight: Option<usize>) -> LoadBudget {
+        let mut config = default_config().server;
+        config.max_connections = max_connections;
+        config.max_bytes_in_flight = max_bytes_in_flight;
+        InnerLoadBudget::new(&config)
+    }
+
+    #[test]
+    fn test_budget_unlimited() {
+        let b = budget(None, None);
+        let _conns: Vec<_> = (0..100).map(|_| b.track_connection()).collect();
+        let r = b.track_reader(CancellationToken::new());
+        r.set_stuck_bytes(1 << 40);
+        assert!(b.can_admit_reader());
+        assert!(!b.is_overloaded());
+    }
+
+    #[test]
+    fn test_budget_connections() {
+        let b = budget(Some(2), None);
+        let c1 = b.track_connection();
+        let _c2 = b.track_connection();
+        assert!(!b.can_admit_reader());
+        assert!(!b.is_overloaded());
+        let _c3 = b.track_connection();
+        assert!(b.is_overloaded());
+        drop(c1);
+        assert!(!b.is_overloaded());
+        assert!(!b.can_admit_reader());
+    }
+
+    #[test]
+    fn test_budget_bytes_in_flight() {
+        let b = budget(None, Some(1000));
+        let r1 = b.track_reader(CancellationToken::new());
+        let r2 = b.track_reader(CancellationToken::new());
+        r1.set_stuck_bytes(600);
+        r2.set_stuck_bytes(600);
+        assert!(b.is_overloaded());
+        assert!(!b.can_admit_reader());
+        r1.set_stuck_bytes(100);
+        assert!(!b.is_overloaded());
+        assert!(b.can_admit_reader());
+        r1.set_stuck_bytes(600);
+        drop(r2);
+        assert!(!b.is_overloaded());
+    }
+
+    #[tokio::test]
+    async fn test_budget_sheds_one_stuck_reader_per_tick() {
+        tokio::time::pause();
+        let b = budget(None, Some(1000));
+        let tokens: Vec<_> = (0..3).map(|_| CancellationToken::new()).collect();
+        let r1 = b.track_reader(tokens[0].clone());
+        let mut r2 = Some(b.track_reader(tokens[1].clone()));
+        let _r3 = b.track_reader(tokens[2].clone());
+        r1.set_stuck_bytes(700);
+        r2.as_ref().unwrap().set_stuck_bytes(900);
+        // The third one is catching up, so it's not stuck, no matter how far behind it is.
+
+        let tick = Duration::from_secs(1);
+        let ticks = async {
+            tokio::time::sleep(tick / 2).await;
+            assert!(tokens.iter().all(|t| !t.is_cancelled()));
+            tokio::time::sleep(tick).await;
+            assert!(!tokens[0].is_cancelled());
+            assert!(tokens[1].is_cancelled());
+            r2.take();
+            tokio::time::sleep(tick).await;
+            assert!(!tokens[0].is_cancelled());
+            r1.set_stuck_bytes(1200);
+            tokio::time::sleep(tick).await;
+            assert!(tokens[0].is_cancelled());
+            assert!(!tokens[2].is_cancelled());
+        };
+        until(ticks, b.shed_load(tick)).await;
+    }
+}
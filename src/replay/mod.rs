@@ -1,3 +1,4 @@
+pub mod budget;
 pub mod receive;
 mod replay;
 mod replays;
@@ -6,5 +7,5 @@ pub mod save;
 pub mod send;
 mod streams;
 
-pub use crate::replay::replay::Replay;
-pub use crate::replay::replays::Replays;
+pub use crate::replay::replay::{ConnectionInfo, Replay, ReplayCommand, ReplayDetails, ReplayInfo};
+pub use crate::replay::replays::{ReplayQuery, Replays};
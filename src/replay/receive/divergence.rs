@@ -0,0 +1,155 @@
+use faf_replay_parser::has_frame;
+use faf_replay_parser::scfa::ReplayCommand;
+use faf_replay_parser::version::Command;
+use serde::Serialize;
+
+use crate::replay::streams::{MergedReplay, ReplayStream, WriterReplay};
+use crate::util::buf_traits::ChunkedBufExt;
+
+// When a replay diverges from the canonical stream C, we'd like to know why. Usually it's one of:
+// * Truncation - the writer stopped sending data early, possibly in the middle of a command.
+// * Desync - the writer sent valid commands, just not the same ones. That's what happens when a
+//   player's game desyncs, their game state and checksums (see the VerifyChecksum command) stop
+//   agreeing with everyone else's.
+// * Garbage - the writer sent something that's not a command stream.
+//
+// To tell, we decode the replay's commands at the point of divergence. Up to that point the
+// replay's data is the same as C's, so C's framing tells us where the command we diverged in
+// starts. We also check a few commands after it, since garbage sometimes happens to look like a
+// valid command.
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum DivergenceKind {
+    Desync,
+    Truncation,
+    Garbage,
+}
+
+// Frame size is a u16.
+const MAX_FRAME_SIZE: usize = u16::MAX as usize;
+const COMMANDS_TO_CHECK: usize = 4;
+
+// Call before the replay's data is discarded.
+pub fn classify_divergence(canon: &MergedReplay, replay: &WriterReplay, offset: usize) -> DivergenceKind {
+    if offset >= replay.data_len() {
+        return DivergenceKind::Truncation;
+    }
+    // If C isn't a command stream before the divergence, then neither is the replay.
+    let start = match canon.command_at(offset) {
+        Some(c) => c.start,
+        None => return DivergenceKind::Garbage,
+    };
+    let mut data = Vec::new();
+    for chunk in canon.get_data().iter_chunks(start, offset) {
+        data.extend_from_slice(chunk);
+    }
+    let end = std::cmp::min(replay.data_len(), start + COMMANDS_TO_CHECK * MAX_FRAME_SIZE);
+    for chunk in replay.get_data().iter_chunks(offset, end) {
+        data.extend_from_slice(chunk);
+    }
+
+    let mut pos = 0;
+    for _ in 0..COMMANDS_TO_CHECK {
+        let frame = &data[pos..];
+        match has_frame(frame) {
+            Err(_) => return DivergenceKind::Garbage,
+            Ok(false) => break,
+            Ok(true) => {
+                let size = u16::from_le_bytes([frame[1], frame[2]]) as usize;
+                if ReplayCommand::parse_command_data(frame[0], &frame[3..size]).is_err() {
+                    return DivergenceKind::Garbage;
+                }
+                pos += size;
+            }
+        }
+    }
+    if pos == 0 && replay.is_finished() {
+        // Ended in the middle of the command.
+        return DivergenceKind::Truncation;
+    }
+    // If we don't have all of the command yet, we go by its header.
+    DivergenceKind::Desync
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::replay::receive::test::{advance, verify_checksum};
+
+    fn writer(data: &[u8], finished: bool) -> WriterReplay {
+        let mut w = WriterReplay::new();
+        w.add_data(data);
+        if finished {
+            w.finish();
+        }
+        w
+    }
+
+    fn canon(data: &[u8]) -> MergedReplay {
+        let mut c = MergedReplay::new();
+        c.add_data(&writer(data, false), data.len());
+        c
+    }
+
+    fn stream(commands: &[Vec<u8>]) -> Vec<u8> {
+        commands.concat()
+    }
+
+    #[test]
+    fn test_divergence_truncation() {
+        let data = stream(&[advance(1), verify_checksum(1, 1), advance(1)]);
+        let c = canon(&data);
+        assert_eq!(
+            classify_divergence(&c, &writer(&data[..7], true), 7),
+            DivergenceKind::Truncation
+        );
+        // Stopped in the middle of the command it diverged in.
+        let other = stream(&[advance(1), verify_checksum(2, 1)]);
+        assert_eq!(
+            classify_divergence(&c, &writer(&other[..12], true), 10),
+            DivergenceKind::Truncation
+        );
+    }
+
+    #[test]
+    fn test_divergence_desync() {
+        let c = canon(&stream(&[advance(1), verify_checksum(1, 1), advance(1)]));
+        let other = stream(&[advance(1), verify_checksum(2, 1), advance(1)]);
+        assert_eq!(
+            classify_divergence(&c, &writer(&other, true), 10),
+            DivergenceKind::Desync
+        );
+        // We don't have the whole command yet, but it looks fine.
+        assert_eq!(
+            classify_divergence(&c, &writer(&other[..12], false), 10),
+            DivergenceKind::Desync
+        );
+    }
+
+    #[test]
+    fn test_divergence_garbage() {
+        let c = canon(&stream(&[advance(1), verify_checksum(1, 1), advance(1)]));
+        let mut other = stream(&[advance(1)]);
+        other.extend([0xff; 40]);
+        assert_eq!(
+            classify_divergence(&c, &writer(&other, false), 7),
+            DivergenceKind::Garbage
+        );
+
+        // Valid command, but it's followed by garbage.
+        let mut other = stream(&[advance(1), verify_checksum(2, 1)]);
+        other.extend([0xff; 10]);
+        assert_eq!(
+            classify_divergence(&c, &writer(&other, false), 10),
+            DivergenceKind::Garbage
+        );
+
+        // Advance can't be 6 bytes long.
+        let mut other = stream(&[advance(1)]);
+        other.extend([0, 6, 0, 1, 2, 3]);
+        assert_eq!(
+            classify_divergence(&c, &writer(&other, false), 7),
+            DivergenceKind::Garbage
+        );
+    }
+}
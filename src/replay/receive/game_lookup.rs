@@ -0,0 +1,109 @@
+use std::sync::Arc;
+
+use crate::config::{MergeStrategyKind, Settings};
+use crate::database::queries::Queries;
+use crate::util::timeout::timeout;
+
+use tokio::join;
+use tokio::time::Duration;
+
+use super::replay_delay::DelayLookup;
+
+// Don't hold up a starting replay for long if the database is struggling. We look everything up
+// at once, so this is how long a replay can wait in total.
+pub(super) const GAME_LOOKUP_TIMEOUT: Duration = Duration::from_secs(5);
+
+// What we need to know about a game before we start merging its replay.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct GameInfo {
+    pub delay: Duration,
+    // Login ID of the game's host, if we looked it up.
+    pub host: Option<u64>,
+}
+
+// Finds out a game's host when its replay starts. We only need it if we give its replay priority
+// when merging.
+#[derive(Clone)]
+pub struct HostLookup {
+    queries: Option<Arc<Queries>>,
+}
+
+impl HostLookup {
+    pub fn new(queries: Arc<Queries>, config: &Settings) -> Self {
+        Self {
+            queries: Some(queries).filter(|_| config.replay.merge_strategy == MergeStrategyKind::TrustedWriterFirst),
+        }
+    }
+
+    pub async fn resolve(&self, id: u64) -> Option<u64> {
+        let queries = self.queries.as_ref()?;
+        match timeout(queries.get_game_host(id), GAME_LOOKUP_TIMEOUT).await {
+            Some(Ok(host)) => host,
+            Some(Err(e)) => {
+                log::info!("Failed to fetch host of replay {}: {}", id, e);
+                None
+            }
+            None => {
+                log::info!("Timed out fetching host of replay {}", id);
+                None
+            }
+        }
+    }
+}
+
+// Everything we look up about a game when its replay starts.
+#[derive(Clone)]
+pub struct GameLookup {
+    delays: DelayLookup,
+    hosts: HostLookup,
+}
+
+impl GameLookup {
+    pub fn new(queries: Arc<Queries>, config: &Settings) -> Self {
+        Self {
+            delays: DelayLookup::new(queries.clone(), config),
+            hosts: HostLookup::new(queries, config),
+        }
+    }
+
+    pub async fn resolve(&self, id: u64) -> GameInfo {
+        let (delay, host) = join!(self.delays.resolve(id), self.hosts.resolve(id));
+        GameInfo { delay, host }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::config::test::default_config;
+    use crate::database::database::Database;
+    use crate::error::SaveError;
+
+    #[tokio::test]
+    async fn test_host_lookup() {
+        let mut config = default_config();
+        config.replay.merge_strategy = MergeStrategyKind::TrustedWriterFirst;
+        let mut db = Database::faux();
+        faux::when!(db.get_game_host).then(|id| match id {
+            1 => Ok(Some(42)),
+            2 => Ok(None),
+            _ => Err(SaveError::DatabaseError(sqlx::Error::RowNotFound)),
+        });
+        let lookup = HostLookup::new(Arc::new(Queries::new(db)), &Arc::new(config));
+        assert_eq!(lookup.resolve(1).await, Some(42));
+        assert_eq!(lookup.resolve(2).await, None);
+        assert_eq!(lookup.resolve(3).await, None);
+    }
+
+    #[tokio::test]
+    async fn test_game_lookup_disabled() {
+        let mut config = default_config();
+        config.replay.delay_s = Duration::from_secs(60);
+        // Would panic if called.
+        let db = Database::faux();
+        let lookup = GameLookup::new(Arc::new(Queries::new(db)), &Arc::new(config));
+        let info = lookup.resolve(1).await;
+        assert_eq!(info.delay, Duration::from_secs(60));
+        assert_eq!(info.host, None);
+    }
+}
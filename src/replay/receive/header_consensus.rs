@@ -0,0 +1,269 @@
+use faf_replay_parser::lua::LuaObject;
+use sha2::{Digest, Sha256};
+use tokio::time::{Duration, Instant};
+
+use crate::replay::streams::ReplayHeader;
+
+// Headers decide which map, mods and armies viewers load, so we don't want the first writer to
+// connect to decide it for everyone. Writers vote with their headers instead. We pick a header
+// once enough writers agree on it, or go with the most popular one once we've waited long enough.
+// Writers with any other header are not merged.
+//
+// Headers can't be compared byte-for-byte, since key order in their lua tables is not
+// deterministic. We parse them and hash their contents with tables sorted.
+#[derive(PartialEq, Eq, Debug, Clone, Copy)]
+pub struct HeaderFingerprint([u8; 32]);
+
+// Unicode and byte strings compare equal in faf-replay-parser, so we hash them the same way.
+fn hash_lua(h: &mut Sha256, o: &LuaObject) {
+    match o {
+        LuaObject::Float(f) => {
+            h.update([0]);
+            h.update(f.to_le_bytes());
+        }
+        LuaObject::String(s) => hash_bytes(h, 1, s.as_bytes()),
+        LuaObject::Unicode(s) => hash_bytes(h, 1, s.as_bytes()),
+        LuaObject::Nil => h.update([2]),
+        LuaObject::Bool(b) => h.update([3, *b as u8]),
+        LuaObject::Table(t) => {
+            let mut entries: Vec<_> = t.iter().map(|(k, v)| (lua_digest(k), lua_digest(v))).collect();
+            entries.sort_unstable();
+            h.update([4]);
+            h.update((entries.len() as u64).to_le_bytes());
+            for (k, v) in entries {
+                h.update(k);
+                h.update(v);
+            }
+        }
+    }
+}
+
+fn lua_digest(o: &LuaObject) -> [u8; 32] {
+    let mut h = Sha256::new();
+    hash_lua(&mut h, o);
+    h.finalize().into()
+}
+
+fn hash_bytes(h: &mut Sha256, tag: u8, b: &[u8]) {
+    h.update([tag]);
+    h.update((b.len() as u64).to_le_bytes());
+    h.update(b);
+}
+
+impl HeaderFingerprint {
+    pub fn of(header: &ReplayHeader) -> Self {
+        let mut h = Sha256::new();
+        match faf_replay_parser::parser::parse_header(&mut &header.data[..]) {
+            Ok(p) => {
+                h.update([0]);
+                hash_bytes(&mut h, 1, p.scfa_version.as_bytes());
+                hash_bytes(&mut h, 1, p.replay_version.as_bytes());
+                hash_bytes(&mut h, 1, p.map_file.as_bytes());
+                hash_lua(&mut h, &p.mods);
+                hash_lua(&mut h, &p.scenario);
+                let mut players: Vec<_> = p.players.iter().collect();
+                players.sort_unstable();
+                for (name, timeouts) in players {
+                    hash_bytes(&mut h, 1, name.as_bytes());
+                    h.update(timeouts.to_le_bytes());
+                }
+                h.update([p.cheats_enabled as u8]);
+                let mut armies: Vec<_> = p.armies.iter().map(|(k, v)| (*k, lua_digest(v))).collect();
+                armies.sort_unstable();
+                for (k, v) in armies {
+                    h.update([k]);
+                    h.update(v);
+                }
+                h.update(p.seed.to_le_bytes());
+            }
+            // We read the header already, so that's unlikely. Such headers only agree with
+            // identical ones.
+            Err(e) => {
+                log::debug!("Failed to parse replay header: {}", e);
+                h.update([1]);
+                h.update(&header.data);
+            }
+        }
+        Self(h.finalize().into())
+    }
+}
+
+pub struct HeaderConsensus {
+    quorum: usize,
+    timeout: Duration,
+    deadline: Option<Instant>,
+    // Headers in order of arrival, with vote counts.
+    votes: Vec<(HeaderFingerprint, usize)>,
+    chosen: Option<HeaderFingerprint>,
+}
+
+impl HeaderConsensus {
+    pub fn new(quorum: usize, timeout: Duration) -> Self {
+        Self {
+            quorum,
+            timeout,
+            deadline: None,
+            votes: Vec::new(),
+            chosen: None,
+        }
+    }
+
+    // Returns true if that decided the header.
+    pub fn vote(&mut self, f: HeaderFingerprint) -> bool {
+        if self.deadline.is_none() {
+            self.deadline = Some(Instant::now() + self.timeout);
+        }
+        let count = match self.votes.iter_mut().find(|(v, _)| *v == f) {
+            Some((_, c)) => {
+                *c += 1;
+                *c
+            }
+            None => {
+                self.votes.push((f, 1));
+                1
+            }
+        };
+        if self.chosen.is_none() && (count >= self.quorum || self.timeout.is_zero()) {
+            self.chosen = Some(f);
+            return true;
+        }
+        self.decide_if_timed_out()
+    }
+
+    // Most votes wins, ties go to whoever came first.
+    pub fn force_decision(&mut self) {
+        if self.chosen.is_some() {
+            return;
+        }
+        let mut best: Option<&(HeaderFingerprint, usize)> = None;
+        for vote in self.votes.iter() {
+            match best {
+                Some((_, c)) if *c >= vote.1 => (),
+                _ => best = Some(vote),
+            }
+        }
+        self.chosen = best.map(|(f, _)| *f);
+    }
+
+    fn decide_if_timed_out(&mut self) -> bool {
+        match self.deadline {
+            Some(d) if self.chosen.is_none() && d <= Instant::now() => {
+                self.force_decision();
+                true
+            }
+            _ => false,
+        }
+    }
+
+    // Whether writers with this header get merged. None if we didn't decide yet.
+    pub fn verdict(&mut self, f: &HeaderFingerprint) -> Option<bool> {
+        self.decide_if_timed_out();
+        self.chosen.map(|c| c == *f)
+    }
+
+    // When we'll decide at the latest. None if nobody voted yet.
+    pub fn deadline(&self) -> Option<Instant> {
+        self.deadline
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::util::test::get_file;
+    use faf_replay_parser::body_offset;
+
+    fn fingerprint(n: u8) -> HeaderFingerprint {
+        HeaderFingerprint([n; 32])
+    }
+
+    fn example_header() -> ReplayHeader {
+        let data = get_file("example");
+        let offset = body_offset(&data).unwrap();
+        ReplayHeader {
+            data: data[..offset].to_vec(),
+        }
+    }
+
+    #[test]
+    fn test_fingerprint_of_same_header() {
+        let header = example_header();
+        assert_eq!(HeaderFingerprint::of(&header), HeaderFingerprint::of(&example_header()));
+    }
+
+    #[test]
+    fn test_fingerprint_of_different_headers() {
+        let header = example_header();
+        let mut other = example_header();
+        // Last 4 bytes are the random seed.
+        let len = other.data.len();
+        other.data[len - 1] ^= 1;
+        assert_ne!(HeaderFingerprint::of(&header), HeaderFingerprint::of(&other));
+
+        let garbage = ReplayHeader { data: vec![1, 2, 3] };
+        assert_ne!(HeaderFingerprint::of(&header), HeaderFingerprint::of(&garbage));
+        assert_eq!(
+            HeaderFingerprint::of(&garbage),
+            HeaderFingerprint::of(&ReplayHeader { data: vec![1, 2, 3] })
+        );
+    }
+
+    #[test]
+    fn test_fingerprint_ignores_table_order() {
+        let mut t1 = faf_replay_parser::lua::LuaTable::new();
+        t1.insert(LuaObject::Unicode("a".into()), LuaObject::Float(1.0));
+        t1.insert(LuaObject::Unicode("b".into()), LuaObject::Bool(true));
+        let mut t2 = faf_replay_parser::lua::LuaTable::new();
+        t2.insert(LuaObject::Unicode("b".into()), LuaObject::Bool(true));
+        t2.insert(LuaObject::Unicode("a".into()), LuaObject::Float(1.0));
+        assert_eq!(lua_digest(&LuaObject::Table(t1)), lua_digest(&LuaObject::Table(t2)));
+    }
+
+    #[tokio::test]
+    async fn test_consensus_waits_for_quorum() {
+        let mut consensus = HeaderConsensus::new(2, Duration::from_secs(10));
+        assert!(!consensus.vote(fingerprint(1)));
+        assert!(!consensus.vote(fingerprint(2)));
+        assert_eq!(consensus.verdict(&fingerprint(1)), None);
+        assert!(consensus.vote(fingerprint(2)));
+        assert_eq!(consensus.verdict(&fingerprint(1)), Some(false));
+        assert_eq!(consensus.verdict(&fingerprint(2)), Some(true));
+        // Late votes don't change anything.
+        assert!(!consensus.vote(fingerprint(1)));
+        assert_eq!(consensus.verdict(&fingerprint(1)), Some(false));
+    }
+
+    #[tokio::test]
+    async fn test_consensus_falls_back_after_timeout() {
+        tokio::time::pause();
+        let mut consensus = HeaderConsensus::new(3, Duration::from_secs(10));
+        assert_eq!(consensus.deadline(), None);
+        consensus.vote(fingerprint(1));
+        consensus.vote(fingerprint(2));
+        consensus.vote(fingerprint(2));
+        assert_eq!(consensus.deadline(), Some(Instant::now() + Duration::from_secs(10)));
+        assert_eq!(consensus.verdict(&fingerprint(2)), None);
+
+        tokio::time::advance(Duration::from_secs(11)).await;
+        assert_eq!(consensus.verdict(&fingerprint(1)), Some(false));
+        assert_eq!(consensus.verdict(&fingerprint(2)), Some(true));
+    }
+
+    #[tokio::test]
+    async fn test_consensus_fallback_prefers_first_header() {
+        let mut consensus = HeaderConsensus::new(3, Duration::from_secs(10));
+        consensus.vote(fingerprint(1));
+        consensus.vote(fingerprint(2));
+        consensus.force_decision();
+        assert_eq!(consensus.verdict(&fingerprint(1)), Some(true));
+    }
+
+    #[tokio::test]
+    async fn test_consensus_without_timeout_takes_first_header() {
+        let mut consensus = HeaderConsensus::new(2, Duration::ZERO);
+        assert!(consensus.vote(fingerprint(1)));
+        consensus.vote(fingerprint(2));
+        assert_eq!(consensus.verdict(&fingerprint(1)), Some(true));
+        assert_eq!(consensus.verdict(&fingerprint(2)), Some(false));
+    }
+}
@@ -0,0 +1,282 @@
+use std::{cell::RefCell, cmp::Ordering, collections::BTreeMap, ops::Deref, rc::Rc};
+
+use crate::replay::streams::{MReplayRef, MergedReplay, ReplayStreamRef, WReplayRef};
+use crate::util::buf_traits::ChunkedBufExt;
+
+use super::divergence::classify_divergence;
+use super::merge_report::MergeEvents;
+use super::merge_strategy::{MergeStrategy, WriterTrust};
+
+// A simpler alternative to the quorum strategy for when waiting for replays to agree doesn't work
+// well, e.g. in games with two players. Instead, we pick one replay - the leader - and copy its
+// data into C. Which replay gets to lead is up to a ranking, see longest_stream_merge_strategy.rs
+// and trusted_writer_merge_strategy.rs.
+//
+// We use R and C as defined in merge_strategy.rs, and "matches" and "diverges" as defined in
+// quorum_merge_strategy.rs. Every time a replay changes:
+// * We compare all replays with C. Diverged replays are dropped. We remember how much of each
+//   replay we compared, so every byte is compared once, and discard data we won't need anymore.
+// * The leader is the best ranked replay that matches C and is longer than C.
+// * We append the leader's data up to its delayed position to C. This way we pick the leader as
+//   late as we can, right when readers are allowed to see the data.
+//
+// As in the quorum strategy, "delayed position" means the privileged one. C's regular delayed
+// position is the highest regular delayed position among replays we didn't drop.
+//
+// A leader that stalls can't hold us up for long. Its delayed position catches up with its data,
+// we merge all of it, then it's no longer longer than C and someone else leads.
+//
+// Of course, this means we trust the leader completely. If it sends garbage, garbage ends up in C.
+
+// What a ranking gets to look at when picking a leader.
+pub struct LeaderCandidate {
+    pub trust: WriterTrust,
+    pub data_len: usize,
+}
+
+// Greater is better.
+pub type LeaderRanking = fn(&LeaderCandidate, &LeaderCandidate) -> Ordering;
+
+struct ReplayState {
+    r: WReplayRef,
+    trust: WriterTrust,
+    data_matching_canon: usize,
+}
+
+impl ReplayState {
+    fn candidate(&self) -> LeaderCandidate {
+        LeaderCandidate {
+            trust: self.trust,
+            data_len: self.r.data_len(),
+        }
+    }
+}
+
+pub struct LeaderMergeStrategy {
+    token: u64,
+    // Ordered, so that we break ties in favour of replays that connected first.
+    replays: BTreeMap<u64, ReplayState>,
+    canonical_stream: MReplayRef,
+    ranking: LeaderRanking,
+    events: MergeEvents,
+}
+
+impl LeaderMergeStrategy {
+    pub fn new(ranking: LeaderRanking) -> Self {
+        Self {
+            token: 0,
+            replays: BTreeMap::new(),
+            canonical_stream: Rc::new(RefCell::new(MergedReplay::new())),
+            ranking,
+            events: MergeEvents::default(),
+        }
+    }
+
+    fn canon_data_len(&self) -> usize {
+        self.canonical_stream.data_len()
+    }
+
+    // Returns the offset at which the replay diverged, if it did.
+    fn compare_with_canon(&self, replay: &mut ReplayState) -> Option<usize> {
+        let canon_len = self.canon_data_len();
+        let replay_len = replay.r.data_len();
+        if replay_len < canon_len && replay.r.is_finished() {
+            return Some(replay_len);
+        }
+        let compare_until = std::cmp::min(canon_len, replay_len);
+        if replay.data_matching_canon < compare_until {
+            let common_prefix = replay.r.get_data().common_prefix_from_to(
+                self.canonical_stream.get_data().deref(),
+                replay.data_matching_canon,
+                Some(compare_until),
+            );
+            if common_prefix < compare_until {
+                return Some(common_prefix);
+            }
+            replay.data_matching_canon = common_prefix;
+            replay.r.borrow_mut().discard(common_prefix);
+        }
+        None
+    }
+
+    fn drop_diverged_replays(&mut self) {
+        let mut replays = std::mem::take(&mut self.replays);
+        let mut diverged = Vec::new();
+        replays.retain(|id, replay| match self.compare_with_canon(replay) {
+            None => true,
+            Some(offset) => {
+                let kind = classify_divergence(&self.canonical_stream.borrow(), &replay.r.borrow(), offset);
+                log::debug!(
+                    "Dropping replay stream {}, diverged at offset {}: {:?}",
+                    id,
+                    offset,
+                    kind
+                );
+                replay.r.borrow_mut().discard_all();
+                diverged.push((*id, offset, kind));
+                false
+            }
+        });
+        self.replays = replays;
+        for (id, offset, kind) in diverged {
+            self.events.replay_diverged(id, offset, kind);
+        }
+    }
+
+    fn pick_leader(&self) -> Option<&ReplayState> {
+        let canon_len = self.canon_data_len();
+        let mut leader: Option<&ReplayState> = None;
+        for replay in self.replays.values().filter(|r| r.r.data_len() > canon_len) {
+            leader = match leader {
+                Some(l) if (self.ranking)(&replay.candidate(), &l.candidate()) != Ordering::Greater => Some(l),
+                _ => Some(replay),
+            };
+        }
+        leader
+    }
+
+    fn merge_from_leader(&mut self) {
+        let canon_len = self.canon_data_len();
+        let leader = match self.pick_leader() {
+            Some(l) => l,
+            None => return,
+        };
+        let leader_delayed_len = leader.r.privileged_delayed_data_len();
+        if leader_delayed_len <= canon_len {
+            return;
+        }
+        let mut canon = self.canonical_stream.borrow_mut();
+        canon.add_data(leader.r.borrow().deref(), leader_delayed_len);
+        canon.advance_privileged_delayed_data(leader_delayed_len);
+    }
+
+    fn update_delayed_position(&mut self) {
+        let delayed = self.replays.values().map(|r| r.r.delayed_data_len()).max().unwrap_or(0);
+        let delayed = std::cmp::min(delayed, self.canon_data_len());
+        if delayed > self.canonical_stream.delayed_data_len() {
+            self.canonical_stream.borrow_mut().advance_delayed_data(delayed);
+        }
+    }
+
+    fn update(&mut self) {
+        self.drop_diverged_replays();
+        self.merge_from_leader();
+        self.update_delayed_position();
+    }
+}
+
+impl MergeStrategy for LeaderMergeStrategy {
+    fn replay_added(&mut self, r: WReplayRef, trust: WriterTrust) -> u64 {
+        let token = self.token;
+        self.token += 1;
+        let replay = ReplayState {
+            r,
+            trust,
+            data_matching_canon: 0,
+        };
+        self.replays.insert(token, replay);
+        token
+    }
+
+    fn replay_removed(&mut self, id: u64) {
+        self.update();
+        // Might have been dropped already.
+        self.replays.remove(&id);
+    }
+
+    fn replay_header_added(&mut self, id: u64) {
+        // Accept the first header we get, they all agree.
+        let replay = match self.replays.get(&id) {
+            Some(r) => r,
+            None => return,
+        };
+        let header = replay.r.borrow_mut().take_header();
+        let mut canonical_stream = self.canonical_stream.borrow_mut();
+        if canonical_stream.get_header().is_none() {
+            canonical_stream.add_header(header);
+        }
+    }
+
+    fn replay_data_updated(&mut self, _id: u64) {
+        self.update();
+    }
+
+    fn finish(&mut self) {
+        // All replays are removed, and each one had all its data delayed before it was, so there's
+        // nothing left to merge. Let regular readers catch up.
+        debug_assert!(self.replays.is_empty());
+        let data_len = self.canon_data_len();
+        if data_len > self.canonical_stream.delayed_data_len() {
+            self.canonical_stream.borrow_mut().advance_delayed_data(data_len);
+        }
+        self.canonical_stream.borrow_mut().finish();
+    }
+
+    fn get_merged_replay(&self) -> MReplayRef {
+        self.canonical_stream.clone()
+    }
+
+    fn events(&self) -> &MergeEvents {
+        &self.events
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::replay::receive::longest_stream_merge_strategy::by_length;
+    use crate::replay::receive::test::{add_writer, end, merged_data, write};
+
+    #[test]
+    fn test_leader_strategy_waits_for_delayed_data() {
+        let mut strat = LeaderMergeStrategy::new(by_length);
+        let w = add_writer(&mut strat, WriterTrust::Unverified);
+
+        w.r.borrow_mut().add_data(&[1, 2, 3, 4]);
+        w.r.borrow_mut().set_privileged_delayed_data_len(3);
+        strat.replay_data_updated(w.token);
+        let merged = strat.get_merged_replay();
+        assert_eq!(merged.data_len(), 3);
+        assert_eq!(merged.privileged_delayed_data_len(), 3);
+        assert_eq!(merged.delayed_data_len(), 0);
+
+        w.r.borrow_mut().set_delayed_data_len(2);
+        strat.replay_data_updated(w.token);
+        assert_eq!(merged.data_len(), 3);
+        assert_eq!(merged.delayed_data_len(), 2);
+
+        end(&mut strat, &w);
+        strat.finish();
+        assert_eq!(merged_data(&strat), vec![1, 2, 3, 4]);
+        assert_eq!(merged.delayed_data_len(), 4);
+        assert!(merged.is_finished());
+        assert_eq!(merged.borrow().get_header().unwrap().data, vec![1, 3, 3, 7]);
+    }
+
+    #[test]
+    fn test_leader_strategy_drops_diverged_replays() {
+        let mut strat = LeaderMergeStrategy::new(by_length);
+        let w1 = add_writer(&mut strat, WriterTrust::Unverified);
+        let w2 = add_writer(&mut strat, WriterTrust::Unverified);
+
+        write(&mut strat, &w1, &[1, 2, 3, 4]);
+        // Longer, but too late.
+        write(&mut strat, &w2, &[1, 2, 9, 9, 9, 9, 9]);
+        write(&mut strat, &w1, &[5]);
+        end(&mut strat, &w2);
+        end(&mut strat, &w1);
+        strat.finish();
+        assert_eq!(merged_data(&strat), vec![1, 2, 3, 4, 5]);
+        assert_eq!(strat.events().divergences[&w2.token].offset, 2);
+    }
+
+    #[test]
+    fn test_leader_strategy_without_replays() {
+        let mut strat = LeaderMergeStrategy::new(by_length);
+        let w = add_writer(&mut strat, WriterTrust::Unverified);
+        end(&mut strat, &w);
+        strat.finish();
+        assert!(merged_data(&strat).is_empty());
+        assert!(strat.get_merged_replay().is_finished());
+    }
+}
@@ -0,0 +1,113 @@
+use std::cmp::Ordering;
+
+use super::leader_merge_strategy::{LeaderCandidate, LeaderMergeStrategy};
+
+// Follows whichever replay has the most data. Meant for games with few players, where the quorum
+// strategy rarely has two replays that agree and ends up merging byte by byte. See
+// leader_merge_strategy.rs for how merging works.
+pub(super) fn by_length(a: &LeaderCandidate, b: &LeaderCandidate) -> Ordering {
+    a.data_len.cmp(&b.data_len)
+}
+
+pub fn longest_stream_merge_strategy() -> LeaderMergeStrategy {
+    LeaderMergeStrategy::new(by_length)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::replay::receive::merge_strategy::{MergeStrategy, WriterTrust};
+    use crate::replay::receive::test::{add_writer, end, merged_data, write};
+    use crate::replay::streams::ReplayStreamRef;
+
+    #[test]
+    fn test_longest_stream_gets_all_data_of_one() {
+        let mut strat = longest_stream_merge_strategy();
+        let w = add_writer(&mut strat, WriterTrust::Unverified);
+        write(&mut strat, &w, &[1, 2, 3, 4]);
+        write(&mut strat, &w, &[5, 6]);
+        end(&mut strat, &w);
+        strat.finish();
+        assert_eq!(merged_data(&strat), vec![1, 2, 3, 4, 5, 6]);
+    }
+
+    #[test]
+    fn test_longest_stream_wins_over_shorter() {
+        let mut strat = longest_stream_merge_strategy();
+        let w1 = add_writer(&mut strat, WriterTrust::Unverified);
+        let w2 = add_writer(&mut strat, WriterTrust::Unverified);
+
+        // Neither is delayed yet, so we don't pick one.
+        w1.r.borrow_mut().add_data(&[1, 2, 3]);
+        strat.replay_data_updated(w1.token);
+        w2.r.borrow_mut().add_data(&[1, 2, 7, 8, 9]);
+        strat.replay_data_updated(w2.token);
+        assert!(merged_data(&strat).is_empty());
+
+        w1.r.borrow_mut().set_delayed_data_len(3);
+        strat.replay_data_updated(w1.token);
+        w2.r.borrow_mut().set_delayed_data_len(2);
+        strat.replay_data_updated(w2.token);
+        assert_eq!(merged_data(&strat), vec![1, 2]);
+
+        end(&mut strat, &w1);
+        end(&mut strat, &w2);
+        strat.finish();
+        assert_eq!(merged_data(&strat), vec![1, 2, 7, 8, 9]);
+    }
+
+    #[test]
+    fn test_longest_stream_takes_over_when_leader_stalls() {
+        let mut strat = longest_stream_merge_strategy();
+        let w1 = add_writer(&mut strat, WriterTrust::Unverified);
+        let w2 = add_writer(&mut strat, WriterTrust::Unverified);
+
+        write(&mut strat, &w1, &[1, 2, 3, 4]);
+        write(&mut strat, &w2, &[1, 2]);
+        assert_eq!(merged_data(&strat), vec![1, 2, 3, 4]);
+
+        // w1 stalls without disconnecting, w2 catches up and goes on.
+        write(&mut strat, &w2, &[3, 4, 5, 6]);
+        assert_eq!(merged_data(&strat), vec![1, 2, 3, 4, 5, 6]);
+        assert_eq!(strat.get_merged_replay().delayed_data_len(), 6);
+
+        end(&mut strat, &w2);
+        end(&mut strat, &w1);
+        strat.finish();
+        assert_eq!(merged_data(&strat), vec![1, 2, 3, 4, 5, 6]);
+    }
+
+    #[test]
+    fn test_longest_stream_ties_go_to_first_writer() {
+        let mut strat = longest_stream_merge_strategy();
+        let w1 = add_writer(&mut strat, WriterTrust::Unverified);
+        let w2 = add_writer(&mut strat, WriterTrust::Authenticated);
+
+        w1.r.borrow_mut().add_data(&[1, 2, 3]);
+        w2.r.borrow_mut().add_data(&[1, 5, 6]);
+        w1.r.borrow_mut().set_delayed_data_len(3);
+        w2.r.borrow_mut().set_delayed_data_len(3);
+        strat.replay_data_updated(w2.token);
+
+        end(&mut strat, &w2);
+        end(&mut strat, &w1);
+        strat.finish();
+        assert_eq!(merged_data(&strat), vec![1, 2, 3]);
+    }
+
+    #[test]
+    fn test_longest_stream_ignores_replays_ending_early() {
+        let mut strat = longest_stream_merge_strategy();
+        let w1 = add_writer(&mut strat, WriterTrust::Unverified);
+        let w2 = add_writer(&mut strat, WriterTrust::Unverified);
+
+        write(&mut strat, &w1, &[1, 2, 3, 4]);
+        write(&mut strat, &w2, &[1, 2]);
+        end(&mut strat, &w2);
+        write(&mut strat, &w1, &[5]);
+        end(&mut strat, &w1);
+        strat.finish();
+        assert_eq!(merged_data(&strat), vec![1, 2, 3, 4, 5]);
+        assert!(strat.get_merged_replay().is_finished());
+    }
+}
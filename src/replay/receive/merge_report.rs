@@ -0,0 +1,217 @@
+use std::collections::HashMap;
+use std::fmt::Display;
+
+use serde::Serialize;
+use tokio::time::{Duration, Instant};
+
+use super::divergence::DivergenceKind;
+use crate::replay::streams::{MergedReplay, ReplayStream};
+
+// When people report a broken replay, we want to know what the merge strategy saw: which writers
+// disagreed with the rest and where, and whether the strategy had trouble picking data. Strategies
+// note down what happened in MergeEvents, the merger adds what it knows about writers and we get a
+// MergeReport. It's logged when a replay ends and saved next to it.
+
+// What a strategy noticed while merging. Replays are identified by strategy tokens.
+#[derive(Default, Debug, Clone, PartialEq, Eq)]
+pub struct MergeEvents {
+    pub divergences: HashMap<u64, DivergencePoint>,
+    pub stalemates: StalemateStats,
+    // Size of the last quorum we merged from, for strategies that use one.
+    pub final_quorum_size: Option<usize>,
+}
+
+impl MergeEvents {
+    // A replay only diverges once, we only keep the first call.
+    pub fn replay_diverged(&mut self, id: u64, offset: usize, kind: DivergenceKind) {
+        self.divergences.entry(id).or_insert(DivergencePoint { offset, kind });
+    }
+}
+
+// Where a replay diverged from the canonical stream, and why we think it did.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DivergencePoint {
+    pub offset: usize,
+    pub kind: DivergenceKind,
+}
+
+#[derive(Serialize, Default, Debug, Clone, PartialEq, Eq)]
+pub struct StalemateStats {
+    pub count: u32,
+    #[serde(serialize_with = "as_secs")]
+    pub total_time: Duration,
+    #[serde(serialize_with = "as_secs")]
+    pub longest: Duration,
+    // Stalemates resolved with fewer replays agreeing than we wanted.
+    pub fallback_resolutions: u32,
+    #[serde(skip)]
+    started: Option<Instant>,
+}
+
+fn as_secs<S: serde::Serializer>(d: &Duration, s: S) -> Result<S::Ok, S::Error> {
+    s.serialize_f64(d.as_secs_f64())
+}
+
+impl StalemateStats {
+    pub fn entered(&mut self) {
+        self.started = Some(Instant::now());
+    }
+
+    pub fn resolved(&mut self, fallback: bool) {
+        // We start out in a stalemate until writers send data we can merge. That one doesn't count.
+        let started = match self.started.take() {
+            Some(s) => s,
+            None => return,
+        };
+        let time = started.elapsed();
+        self.count += 1;
+        self.total_time += time;
+        self.longest = std::cmp::max(self.longest, time);
+        if fallback {
+            self.fallback_resolutions += 1;
+        }
+    }
+}
+
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct Divergence {
+    pub offset: usize, // In replay body, same as the tick index.
+    pub tick: Option<u32>,
+    pub kind: DivergenceKind,
+}
+
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct WriterReport {
+    pub name: String,
+    pub player_id: Option<u64>,
+    pub bytes: usize, // Replay body only.
+    pub header_accepted: bool,
+    pub diverged: Option<Divergence>,
+}
+
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct MergeReport {
+    pub merged_bytes: usize,
+    pub writers: Vec<WriterReport>,
+    pub stalemates: StalemateStats,
+    pub final_quorum_size: Option<usize>,
+}
+
+// What the merger knows about a writer once it's done.
+pub struct WriterSummary {
+    pub name: String,
+    pub player_id: Option<u64>,
+    pub bytes: usize,
+    pub token: Option<u64>,
+    pub header_accepted: bool,
+}
+
+impl MergeReport {
+    pub fn new(writers: Vec<WriterSummary>, events: &MergeEvents, merged: &MergedReplay) -> Self {
+        let writers = writers
+            .into_iter()
+            .map(|w| {
+                let diverged = w.token.and_then(|t| events.divergences.get(&t)).map(|d| Divergence {
+                    offset: d.offset,
+                    tick: merged.tick_at(d.offset),
+                    kind: d.kind,
+                });
+                WriterReport {
+                    name: w.name,
+                    player_id: w.player_id,
+                    bytes: w.bytes,
+                    header_accepted: w.header_accepted,
+                    diverged,
+                }
+            })
+            .collect();
+        Self {
+            merged_bytes: merged.data_len(),
+            writers,
+            stalemates: events.stalemates.clone(),
+            final_quorum_size: events.final_quorum_size,
+        }
+    }
+
+    pub fn to_json(&self) -> serde_json::Result<Vec<u8>> {
+        serde_json::to_vec(self)
+    }
+}
+
+impl Display for MergeReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let json = serde_json::to_string(self).map_err(|_| std::fmt::Error)?;
+        f.write_str(&json)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::replay::streams::WriterReplay;
+
+    #[tokio::test]
+    async fn test_stalemate_stats() {
+        tokio::time::pause();
+        let mut stats = StalemateStats::default();
+        stats.resolved(false);
+        assert_eq!(stats.count, 0);
+
+        stats.entered();
+        tokio::time::advance(Duration::from_secs(2)).await;
+        stats.resolved(false);
+        stats.entered();
+        tokio::time::advance(Duration::from_secs(3)).await;
+        stats.resolved(true);
+        assert_eq!(stats.count, 2);
+        assert_eq!(stats.total_time, Duration::from_secs(5));
+        assert_eq!(stats.longest, Duration::from_secs(3));
+        assert_eq!(stats.fallback_resolutions, 1);
+    }
+
+    #[test]
+    fn test_merge_report() {
+        let mut writer = WriterReplay::new();
+        writer.add_data(&[1, 4, 0, 1, 0, 7, 0, 1, 0, 0, 0, 1, 4, 0, 1]);
+        let mut merged = MergedReplay::new();
+        merged.add_data(&writer, 15);
+
+        let mut events = MergeEvents::default();
+        events.replay_diverged(1, 12, DivergenceKind::Desync);
+        events.replay_diverged(1, 13, DivergenceKind::Garbage);
+        events.final_quorum_size = Some(2);
+        let summary = |name: &str, bytes, token: Option<u64>, header_accepted| WriterSummary {
+            name: name.into(),
+            player_id: token.map(|t| t + 10),
+            bytes,
+            token,
+            header_accepted,
+        };
+        let writers = vec![
+            summary("foo", 15, Some(0), true),
+            summary("bar", 20, Some(1), true),
+            summary("baz", 3, None, false),
+        ];
+        let report = MergeReport::new(writers, &events, &merged);
+        let json: serde_json::Value = serde_json::from_slice(&report.to_json().unwrap()).unwrap();
+        assert_eq!(
+            json,
+            serde_json::json!({
+                "merged_bytes": 15,
+                "writers": [
+                    {"name": "foo", "player_id": 10, "bytes": 15, "header_accepted": true, "diverged": null},
+                    {
+                        "name": "bar",
+                        "player_id": 11,
+                        "bytes": 20,
+                        "header_accepted": true,
+                        "diverged": {"offset": 12, "tick": 1, "kind": "desync"},
+                    },
+                    {"name": "baz", "player_id": null, "bytes": 3, "header_accepted": false, "diverged": null},
+                ],
+                "stalemates": {"count": 0, "total_time": 0.0, "longest": 0.0, "fallback_resolutions": 0},
+                "final_quorum_size": 2,
+            })
+        );
+    }
+}
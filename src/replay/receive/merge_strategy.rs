@@ -1,10 +1,18 @@
+use crate::config::{MergeStrategyKind, Settings};
 use crate::replay::{streams::MReplayRef, streams::WReplayRef};
 
+use super::longest_stream_merge_strategy::longest_stream_merge_strategy;
+use super::merge_report::MergeEvents;
+use super::quorum_merge_strategy::QuorumMergeStrategy;
+use super::trusted_writer_merge_strategy::trusted_writer_merge_strategy;
+
 // An interface for a way to merge replays into one canonical replay. High level rationale goes
 // like this:
 // * A replay has a header and a body. A header should contain the same data for all replays, but
 //   key order in a header is non-deterministic, so they all differ byte-for-byte. We read, parse
-//   and handle those separately so they're out of our way.
+//   and handle those separately so they're out of our way. Writers can vote on the header first
+//   (see header_consensus.rs) and we only add replays whose header won. Either way, any header
+//   will do.
 // * The strategy gets called when a replay is added, removed, its header is read, or its data /
 //   delayed data position change. It should produce data for a merged replay.
 //
@@ -29,12 +37,38 @@ use crate::replay::{streams::MReplayRef, streams::WReplayRef};
 //     air and not doing anything after finishing C.
 //  * After all replays are added, processed and removed, finish() is called. At finish(),
 //    strategy should merge all outstanding data.
+//
+// Replays are added together with how much we trust their writer. Most strategies don't care.
+// Strategies also note down what went wrong while merging, see merge_report.rs.
 pub trait MergeStrategy {
     /* We use IDs to identify replays. */
-    fn replay_added(&mut self, w: WReplayRef) -> u64;
+    fn replay_added(&mut self, w: WReplayRef, trust: WriterTrust) -> u64;
     fn replay_removed(&mut self, id: u64);
     fn replay_header_added(&mut self, id: u64);
     fn replay_data_updated(&mut self, id: u64);
     fn finish(&mut self);
     fn get_merged_replay(&self) -> MReplayRef;
+    fn events(&self) -> &MergeEvents;
+}
+
+// Ordered from least to most trusted.
+#[derive(PartialEq, Eq, PartialOrd, Ord, Debug, Clone, Copy)]
+pub enum WriterTrust {
+    Unverified,
+    // Writer had a valid token.
+    Authenticated,
+    // Authenticated writer that's also hosting the game.
+    Host,
+}
+
+pub fn new_merge_strategy(config: &Settings) -> Box<dyn MergeStrategy> {
+    match config.replay.merge_strategy {
+        MergeStrategyKind::Quorum => Box::new(QuorumMergeStrategy::new(
+            config.replay.merge_quorum_size,
+            config.replay.stream_comparison_distance_b,
+            config.replay.merge_whole_commands,
+        )),
+        MergeStrategyKind::LongestStreamWins => Box::new(longest_stream_merge_strategy()),
+        MergeStrategyKind::TrustedWriterFirst => Box::new(trusted_writer_merge_strategy()),
+    }
 }
@@ -1,61 +1,204 @@
-use std::{cell::RefCell, rc::Rc};
+use std::{
+    cell::{Cell, RefCell},
+    collections::HashSet,
+    rc::Rc,
+};
 
 use tokio::select;
+use tokio::sync::Notify;
 use tokio_util::sync::CancellationToken;
 
 use crate::{
+    accept::header::ConnectionHeader,
     config::Settings,
-    error::ConnResult,
+    error::{bad_data, ConnResult, ConnectionError},
     replay::streams::MReplayRef,
-    replay::streams::{read_data, read_header, WriterReplay},
+    replay::streams::{read_data, read_header, ReplayStreamRef, WReplayRef, WriterReplay},
     server::connection::Connection,
     util::timeout::cancellable,
 };
 
-use super::{merge_strategy::MergeStrategy, quorum_merge_strategy::QuorumMergeStrategy, replay_delay::StreamDelay};
+use super::game_lookup::GameInfo;
+use super::header_consensus::{HeaderConsensus, HeaderFingerprint};
+use super::merge_report::{MergeReport, WriterSummary};
+use super::merge_strategy::{new_merge_strategy, MergeStrategy, WriterTrust};
+use super::replay_delay::StreamDelay;
 
 pub struct ReplayMerger {
     shutdown_token: CancellationToken,
-    // Will be a boxed trait if ever needed.
-    merge_strategy: RefCell<QuorumMergeStrategy>,
+    merge_strategy: RefCell<Box<dyn MergeStrategy>>,
+    // Only if configured. Without it every writer is merged and the first header wins.
+    header_consensus: Option<RefCell<HeaderConsensus>>,
+    header_decided: Notify,
     stream_delay: StreamDelay,
+    host: Option<u64>,
+    // Authenticated players that have a writer on this replay. A token only proves who a player
+    // is, one player with many connections could still outvote everyone else.
+    writing_players: RefCell<HashSet<u64>>,
+    // Writers that are done, for the merge report.
+    writer_summaries: RefCell<Vec<WriterSummary>>,
 }
 
 impl ReplayMerger {
-    pub fn new(shutdown_token: CancellationToken, config: Settings) -> Self {
-        let stream_delay = StreamDelay::new(config.replay.delay_s, config.replay.update_interval_s);
-        let merge_strategy = RefCell::new(QuorumMergeStrategy::new(
-            config.replay.merge_quorum_size,
-            config.replay.stream_comparison_distance_b,
-        ));
+    pub fn new(shutdown_token: CancellationToken, game: GameInfo, config: Settings) -> Self {
+        // Privileged delay only matters if someone can be a privileged reader. Tracking it also
+        // makes merging more eager, so we don't do it otherwise. Constructor of config guarantees
+        // it's set if there's a secret.
+        let privileged_delay = config
+            .server
+            .privileged_reader_token_secret
+            .as_ref()
+            .and(config.replay.privileged_delay_s);
+        let stream_delay = StreamDelay::new(game.delay, privileged_delay, config.replay.update_interval_s);
+        let merge_strategy = RefCell::new(new_merge_strategy(&config));
+        let header_consensus = config
+            .replay
+            .header_consensus_timeout_s
+            .map(|t| RefCell::new(HeaderConsensus::new(config.replay.merge_quorum_size, t)));
         Self {
             shutdown_token,
             merge_strategy,
+            header_consensus,
+            header_decided: Notify::new(),
             stream_delay,
+            host: game.host,
+            writing_players: RefCell::new(HashSet::new()),
+            writer_summaries: RefCell::new(Vec::new()),
+        }
+    }
+
+    // Player IDs are only worth anything if the writer proved them with a token.
+    fn writer_trust(&self, header: &ConnectionHeader) -> WriterTrust {
+        if !header.fields.authenticated {
+            WriterTrust::Unverified
+        } else if header.fields.player_id.is_some() && header.fields.player_id == self.host {
+            WriterTrust::Host
+        } else {
+            WriterTrust::Authenticated
+        }
+    }
+
+    fn writing_player(header: &ConnectionHeader) -> Option<u64> {
+        header.fields.player_id.filter(|_| header.fields.authenticated)
+    }
+
+    // An authenticated player gets one writer at a time. It can come back once its writer is
+    // gone.
+    pub fn admit_writer(&self, header: &ConnectionHeader) -> ConnResult<()> {
+        if let Some(p) = Self::writing_player(header) {
+            if !self.writing_players.borrow_mut().insert(p) {
+                return Err(ConnectionError::DuplicateWriter(p));
+            }
         }
+        Ok(())
     }
 
+    fn vote_for_header(&self, f: HeaderFingerprint) {
+        if let Some(c) = &self.header_consensus {
+            if c.borrow_mut().vote(f) {
+                self.header_decided.notify_waiters();
+            }
+        }
+    }
+
+    fn header_verdict(&self, f: &HeaderFingerprint) -> Option<bool> {
+        match &self.header_consensus {
+            Some(c) => c.borrow_mut().verdict(f),
+            None => Some(true),
+        }
+    }
+
+    async fn wait_for_header_verdict(&self, f: &HeaderFingerprint) -> bool {
+        loop {
+            if let Some(verdict) = self.header_verdict(f) {
+                return verdict;
+            }
+            // No verdict means there's a consensus, and we voted, so there's a deadline.
+            let deadline = self.header_consensus.as_ref().unwrap().borrow().deadline().unwrap();
+            select! {
+                _ = self.header_decided.notified() => (),
+                _ = tokio::time::sleep_until(deadline) => (),
+            }
+        }
+    }
+
+    fn add_to_merge_strategy(&self, replay: &WReplayRef, trust: WriterTrust) -> u64 {
+        let mut merge_strategy = self.merge_strategy.borrow_mut();
+        let token = merge_strategy.replay_added(replay.clone(), trust);
+        merge_strategy.replay_header_added(token);
+        merge_strategy.replay_data_updated(token);
+        token
+    }
+
+    // We only merge a writer once we know its header is the right one. Until then, we just
+    // collect its data.
     pub async fn handle_connection(&self, c: &mut Connection) {
         let replay = Rc::new(RefCell::new(WriterReplay::new()));
-        let token = self.merge_strategy.borrow_mut().replay_added(replay.clone());
+        let trust = self.writer_trust(&c.get_header());
+        let fingerprint = Cell::new(None);
+        let token = Cell::new(None);
 
-        let merge_strategy_update_data = || self.merge_strategy.borrow_mut().replay_data_updated(token);
+        let merge_strategy_update_data = || {
+            if let Some(t) = token.get() {
+                self.merge_strategy.borrow_mut().replay_data_updated(t);
+            }
+        };
 
         let read_from_connection = async {
             read_header(replay.clone(), c).await?;
-            self.merge_strategy.borrow_mut().replay_header_added(token);
+            let f = HeaderFingerprint::of(replay.borrow().get_header().unwrap());
+            fingerprint.set(Some(f));
+            self.vote_for_header(f);
+            let join_merging = async {
+                if !self.wait_for_header_verdict(&f).await {
+                    return Err(bad_data("Replay header disagrees with other writers"));
+                }
+                token.set(Some(self.add_to_merge_strategy(&replay, trust)));
+                std::future::pending().await
+            };
             select! {
                 _ = read_data(replay.clone(), c) => (),
                 _ = self.stream_delay.update_replay_timestamp(&replay, &merge_strategy_update_data) => (),
+                res = join_merging => res?,
             };
             ConnResult::Ok(())
         };
-        cancellable(read_from_connection, &self.shutdown_token).await;
+        if let Some(Err(e)) = cancellable(read_from_connection, &self.shutdown_token).await {
+            log::debug!("Writer {} stopped: {}", c, e);
+        }
+
+        // Writer might've ended before we picked a header. It still counts if its header wins.
+        if let (Some(f), None) = (fingerprint.get(), token.get()) {
+            if cancellable(self.wait_for_header_verdict(&f), &self.shutdown_token)
+                .await
+                .is_none()
+            {
+                if let Some(c) = &self.header_consensus {
+                    c.borrow_mut().force_decision();
+                }
+            }
+            if self.header_verdict(&f) == Some(true) {
+                token.set(Some(self.add_to_merge_strategy(&replay, trust)));
+            }
+        }
 
         self.stream_delay.set_final_replay_timestamp(&replay);
         merge_strategy_update_data();
         replay.borrow_mut().finish();
-        self.merge_strategy.borrow_mut().replay_removed(token);
+        if let Some(t) = token.get() {
+            self.merge_strategy.borrow_mut().replay_removed(t);
+        }
+        let header = c.get_header();
+        if let Some(p) = Self::writing_player(&header) {
+            self.writing_players.borrow_mut().remove(&p);
+        }
+        self.writer_summaries.borrow_mut().push(WriterSummary {
+            name: header.name,
+            player_id: header.fields.player_id,
+            bytes: replay.data_len(),
+            token: token.get(),
+            header_accepted: token.get().is_some(),
+        });
     }
 
     pub fn finalize(&self) {
@@ -65,6 +208,181 @@ impl ReplayMerger {
     pub fn get_merged_replay(&self) -> MReplayRef {
         self.merge_strategy.borrow().get_merged_replay()
     }
+
+    // Call once we're done merging.
+    pub fn merge_report(&self) -> MergeReport {
+        let writers = self.writer_summaries.take();
+        let merge_strategy = self.merge_strategy.borrow();
+        let merged = merge_strategy.get_merged_replay();
+        let merged = merged.borrow();
+        MergeReport::new(writers, merge_strategy.events(), &merged)
+    }
 }
 
-// TODO merger tests.
+#[cfg(test)]
+mod test {
+    use std::sync::Arc;
+
+    use faf_replay_parser::body_offset;
+    use tokio::io::{AsyncWriteExt, DuplexStream};
+    use tokio::join;
+    use tokio::time::{sleep, Duration, Instant};
+
+    use super::*;
+    use crate::accept::header::ConnectionType;
+    use crate::config::test::default_config;
+    use crate::server::connection::test::test_connection;
+    use crate::util::test::{get_file, setup_logging};
+
+    fn merger(quorum: usize, header_timeout: Option<Duration>) -> ReplayMerger {
+        let mut config = default_config();
+        config.replay.merge_quorum_size = quorum;
+        config.replay.header_consensus_timeout_s = header_timeout;
+        let game = GameInfo {
+            delay: config.replay.delay_s,
+            host: None,
+        };
+        ReplayMerger::new(CancellationToken::new(), game, Arc::new(config))
+    }
+
+    // Example replay's header and body, and the same header with a different random seed.
+    fn example_replay() -> (Vec<u8>, Vec<u8>, Vec<u8>) {
+        let data = get_file("example");
+        let offset = body_offset(&data).unwrap();
+        let header = data[..offset].to_vec();
+        let mut other_header = header.clone();
+        // Last 4 bytes are the random seed.
+        other_header[offset - 1] ^= 1;
+        (header, data[offset..].to_vec(), other_header)
+    }
+
+    fn writer(name: &str) -> (Connection, DuplexStream) {
+        let (mut c, _r, w) = test_connection();
+        c.set_header(ConnectionHeader::new(ConnectionType::Writer, 1, name.into()));
+        (c, w)
+    }
+
+    async fn write_replay(mut w: DuplexStream, header: &[u8], body: &[u8]) {
+        w.write_all(header).await.unwrap();
+        w.write_all(body).await.unwrap();
+    }
+
+    fn accepted_headers(merger: &ReplayMerger) -> Vec<(String, bool)> {
+        let mut writers: Vec<_> = merger
+            .merge_report()
+            .writers
+            .into_iter()
+            .map(|w| (w.name, w.header_accepted))
+            .collect();
+        writers.sort();
+        writers
+    }
+
+    #[tokio::test]
+    async fn test_merger_excludes_header_that_lost_the_vote() {
+        setup_logging();
+        tokio::time::pause();
+        let merger = merger(2, Some(Duration::from_secs(60)));
+        let (header, body, other_header) = example_replay();
+
+        let (mut c1, w1) = writer("foo");
+        let (mut c2, w2) = writer("bar");
+        let (mut c3, w3) = writer("baz");
+        join! {
+            merger.handle_connection(&mut c1),
+            merger.handle_connection(&mut c2),
+            merger.handle_connection(&mut c3),
+            write_replay(w1, &header, &body),
+            write_replay(w2, &header, &body),
+            // We stop reading from writers we don't merge, so this one can't send everything.
+            write_replay(w3, &other_header, &body[..1000]),
+        };
+        merger.finalize();
+
+        let merged = merger.get_merged_replay();
+        assert_eq!(merged.borrow().get_header().unwrap().data, header);
+        assert_eq!(merged.data_len(), body.len());
+        assert_eq!(
+            accepted_headers(&merger),
+            vec![("bar".into(), true), ("baz".into(), false), ("foo".into(), true)]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_merger_without_consensus_merges_everyone() {
+        setup_logging();
+        tokio::time::pause();
+        let merger = merger(2, None);
+        let (header, body, other_header) = example_replay();
+
+        let (mut c1, w1) = writer("foo");
+        let (mut c2, w2) = writer("bar");
+        let second_writer = async {
+            sleep(Duration::from_secs(1)).await;
+            join!(merger.handle_connection(&mut c2), write_replay(w2, &header, &body));
+        };
+        join! {
+            merger.handle_connection(&mut c1),
+            write_replay(w1, &other_header, &body),
+            second_writer,
+        };
+        merger.finalize();
+
+        // First header wins, but it doesn't keep anyone out.
+        let merged = merger.get_merged_replay();
+        assert_eq!(merged.borrow().get_header().unwrap().data, other_header);
+        assert_eq!(merged.data_len(), body.len());
+        assert_eq!(accepted_headers(&merger), vec![("bar".into(), true), ("foo".into(), true)]);
+    }
+
+    #[tokio::test]
+    async fn test_merger_merges_writer_that_ended_before_header_won() {
+        setup_logging();
+        tokio::time::pause();
+        let merger = merger(2, Some(Duration::from_secs(60)));
+        let (header, body, _) = example_replay();
+        let start = Instant::now();
+
+        let (mut c1, w1) = writer("foo");
+        let (mut c2, w2) = writer("bar");
+        let second_writer = async {
+            sleep(Duration::from_secs(5)).await;
+            join!(merger.handle_connection(&mut c2), write_replay(w2, &header, &body));
+        };
+        join! {
+            merger.handle_connection(&mut c1),
+            write_replay(w1, &header, &body),
+            second_writer,
+        };
+        merger.finalize();
+
+        // Second writer decided it, we didn't wait for the timeout.
+        assert!(start.elapsed() < Duration::from_secs(60));
+        assert_eq!(merger.get_merged_replay().data_len(), body.len());
+        assert_eq!(accepted_headers(&merger), vec![("bar".into(), true), ("foo".into(), true)]);
+    }
+
+    #[tokio::test]
+    async fn test_merger_admits_one_writer_per_player() {
+        setup_logging();
+        tokio::time::pause();
+        let merger = merger(1, Some(Duration::from_secs(60)));
+        let (header, body, _) = example_replay();
+
+        let (mut c, w) = writer("foo");
+        let mut h = c.get_header();
+        h.fields.player_id = Some(5);
+        h.fields.authenticated = true;
+        c.set_header(h.clone());
+        merger.admit_writer(&h).unwrap();
+        assert!(matches!(merger.admit_writer(&h), Err(ConnectionError::DuplicateWriter(5))));
+
+        // Unauthenticated writers can't claim to be anyone.
+        let mut unverified = h.clone();
+        unverified.fields.authenticated = false;
+        merger.admit_writer(&unverified).unwrap();
+
+        join!(merger.handle_connection(&mut c), write_replay(w, &header, &body));
+        merger.admit_writer(&h).unwrap();
+    }
+}
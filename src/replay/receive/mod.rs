@@ -1,5 +1,16 @@
+mod divergence;
+mod game_lookup;
+mod header_consensus;
+mod leader_merge_strategy;
+mod longest_stream_merge_strategy;
+mod merge_report;
 mod merge_strategy;
 mod merger;
 mod quorum_merge_strategy;
 mod replay_delay;
+#[cfg(test)]
+pub mod test;
+mod trusted_writer_merge_strategy;
+pub use self::game_lookup::{GameInfo, GameLookup};
+pub use self::merge_report::MergeReport;
 pub use self::merger::ReplayMerger;
@@ -2,12 +2,15 @@ use std::{cell::RefCell, collections::HashMap, collections::HashSet, ops::Deref,
 
 use crate::replay::streams::ReplayStream;
 use crate::replay::streams::ReplayStreamRef;
+use crate::replay::streams::{frame_size, FRAME_HEADER_SIZE};
 use crate::{
     replay::streams::MReplayRef, replay::streams::MergedReplay, replay::streams::WReplayRef,
     util::buf_traits::ChunkedBufExt,
 };
 
-use super::merge_strategy::MergeStrategy;
+use super::divergence::classify_divergence;
+use super::merge_report::MergeEvents;
+use super::merge_strategy::{MergeStrategy, WriterTrust};
 
 // This merge strategy tries to merge replays in such a way that at least N replays agree on the
 // merged data. To do that, it selects a subset of N replays called a quorum and compares their
@@ -20,7 +23,15 @@ use super::merge_strategy::MergeStrategy;
 // * R and C as defined in merge_strategy.rs, ignoring headers.
 // * Parameters:
 //   * stream_cmp_distance, in bytes,
-//   * target_quorum_size, in count.
+//   * target_quorum_size, in count,
+//   * whole_commands, a flag.
+//
+// By default we merge bytes without looking at them, so C can end in the middle of a command if
+// replays disagree there. With whole_commands set, we read the command framing of data we merge
+// (see tick_index.rs) and only ever extend C by whole commands. The "next byte" we talk about
+// below is then the next command, replays whose next command is not a valid frame diverge, and so
+// do replays that finish in the middle of one. C then always decodes cleanly, but if replays
+// aren't command streams at all, we never merge anything.
 
 // For a replay r in R, we define:
 // * r matches C iff C's data is a prefix of r's data.
@@ -56,19 +67,71 @@ impl ReplayState {
             .common_prefix_from_to(other.r.get_data().deref(), start, end)
     }
 
+    // Caller makes sure there's a frame header's worth of data at pos.
+    fn frame_size_at(&self, pos: usize) -> Option<usize> {
+        let data = self.r.get_data();
+        frame_size(&[data.at(pos), data.at(pos + 1), data.at(pos + 2)])
+    }
+
+    // What r has right after C, assuming it matches C.
+    fn next_piece(&self, canon_length: usize, whole_commands: bool) -> NextPiece {
+        let replay_len = self.r.data_len();
+        if !whole_commands {
+            return if replay_len > canon_length {
+                NextPiece::Complete(canon_length + 1)
+            } else {
+                NextPiece::Incomplete
+            };
+        }
+        if replay_len < canon_length + FRAME_HEADER_SIZE {
+            return NextPiece::Incomplete;
+        }
+        match self.frame_size_at(canon_length) {
+            None => NextPiece::Invalid,
+            Some(size) if canon_length + size <= replay_len => NextPiece::Complete(canon_length + size),
+            Some(_) => NextPiece::Incomplete,
+        }
+    }
+
+    // End of the last whole command between start, which is a command boundary, and end.
+    fn last_command_boundary(&self, start: usize, end: usize) -> usize {
+        let mut pos = start;
+        while pos + FRAME_HEADER_SIZE <= end {
+            match self.frame_size_at(pos) {
+                Some(size) if pos + size <= end => pos += size,
+                _ => break,
+            }
+        }
+        pos
+    }
+
     fn match_start_optimization(&self, canon_length: usize) -> usize {
         canon_length.saturating_sub(self.stream_cmp_distance)
     }
 
     // We only check whether a replay diverges in a stalemate state, and only when r has at least
-    // as much data as C. We also make an optimization: we only compare a maximum of
-    // stream_cmp_distance bytes and if these match, we assume that r does not diverge.
-    fn diverges_at_stalemate(&self, c: &MergedReplay) -> bool {
-        assert!(self.r.data_len() >= c.data_len());
+    // as much data as C or is finished. We also make an optimization: we only compare a maximum
+    // of stream_cmp_distance bytes and if these match, we assume that r does not diverge.
+    //
+    // Returns the offset at which r diverges, if it does. If r is shorter than C and we found no
+    // other difference, that's where r ends.
+    fn divergence_at_stalemate(&self, c: &MergedReplay) -> Option<usize> {
+        assert!(self.r.data_len() >= c.data_len() || self.r.is_finished());
+        let compare_until = std::cmp::min(self.r.data_len(), c.data_len());
         let optimized_match_start = self.match_start_optimization(c.data_len());
         let match_start = std::cmp::max(self.data_matching_canon, optimized_match_start);
-        let match_len = self.r.get_data().common_prefix_from(c.get_data(), match_start);
-        match_len != c.data_len()
+        let match_len = if match_start < compare_until {
+            self.r
+                .get_data()
+                .common_prefix_from_to(c.get_data(), match_start, Some(compare_until))
+        } else {
+            compare_until
+        };
+        if match_len != c.data_len() {
+            Some(match_len)
+        } else {
+            None
+        }
     }
 
     // Mark replay as diverged. Discards all data. Strategy removes the replay from useful sets.
@@ -94,6 +157,29 @@ impl ReplayState {
     }
 }
 
+// A piece is what we extend C by in a stalemate: a byte, or a command with whole_commands set.
+enum NextPiece {
+    Complete(usize), // Where the piece ends.
+    Incomplete,
+    Invalid,
+}
+
+// Bytes are the common case, we don't allocate for them.
+#[derive(PartialEq, Eq, Hash, PartialOrd, Ord, Clone)]
+enum Piece {
+    Byte(u8),
+    Command(Vec<u8>),
+}
+
+impl Piece {
+    fn as_bytes(&self) -> &[u8] {
+        match self {
+            Piece::Byte(b) => std::slice::from_ref(b),
+            Piece::Command(c) => c,
+        }
+    }
+}
+
 // Now, the actual merge strategy.
 //
 
@@ -102,19 +188,23 @@ struct SharedState {
     stream_cmp_distance: usize,
     delayed_data_started: bool,
     target_quorum_size: usize,
+    whole_commands: bool,
     replays: HashMap<u64, ReplayState>,
     canonical_stream: MReplayRef,
+    events: MergeEvents,
 }
 
 impl SharedState {
-    fn new(target_quorum_size: usize, stream_cmp_distance: usize) -> Self {
+    fn new(target_quorum_size: usize, stream_cmp_distance: usize, whole_commands: bool) -> Self {
         Self {
             token: 0,
             stream_cmp_distance,
             delayed_data_started: false,
             target_quorum_size,
+            whole_commands,
             replays: HashMap::new(),
             canonical_stream: Rc::new(RefCell::new(MergedReplay::new())),
+            events: MergeEvents::default(),
         }
     }
 
@@ -138,6 +228,17 @@ impl SharedState {
         self.canonical_stream.data_len()
     }
 
+    fn set_diverged(&mut self, token: u64, offset: usize) {
+        let kind = classify_divergence(
+            &self.canonical_stream.borrow(),
+            &self.get_replay(token).r.borrow(),
+            offset,
+        );
+        log::debug!("Replay stream {} diverged at offset {}: {:?}", token, offset, kind);
+        self.get_mut_replay(token).set_diverged();
+        self.events.replay_diverged(token, offset, kind);
+    }
+
     fn canon_delayed_data_len(&self) -> usize {
         self.canonical_stream.delayed_data_len()
     }
@@ -150,6 +251,10 @@ impl SharedState {
         }
     }
 
+    fn canon_privileged_delayed_data_len(&self) -> usize {
+        self.canonical_stream.privileged_delayed_data_len()
+    }
+
     fn update_canon_delayed_data_len(&mut self, mut hint: usize) {
         hint = std::cmp::min(hint, self.canon_data_len());
         if hint <= self.canon_delayed_data_len() {
@@ -157,6 +262,14 @@ impl SharedState {
         }
         self.canonical_stream.borrow_mut().advance_delayed_data(hint);
     }
+
+    fn update_canon_privileged_delayed_data_len(&mut self, mut hint: usize) {
+        hint = std::cmp::min(hint, self.canon_data_len());
+        if hint <= self.canon_privileged_delayed_data_len() {
+            return;
+        }
+        self.canonical_stream.borrow_mut().advance_privileged_delayed_data(hint);
+    }
 }
 
 // The strategy switches between two states - a quorum and a stalemate.
@@ -173,7 +286,7 @@ impl SharedState {
 
 pub struct MergeStalemateState {
     s: SharedState,
-    candidates: HashMap<u8, Vec<u64>>,
+    candidates: HashMap<Piece, Vec<u64>>,
     reserve: HashSet<u64>,
 }
 
@@ -190,7 +303,8 @@ pub struct MergeStalemateState {
 //   * Every replay outside I diverges from C.
 //   * Replays from I are distributed between Cand and Res according to above rules.
 //
-// * Whether a stalemate can be resolved is decided as follows:
+// * Whether a stalemate can be resolved is decided as follows (see QUORUM below for what "delayed
+//   position" means):
 //   * Stalemate stays unresolved as long as no delayed positions have ever been set. (This is a
 //     hack to prevent too eager stalemate resolution at the start, when replays are still being
 //     added.)
@@ -206,15 +320,16 @@ pub struct MergeStalemateState {
 //   * Notice that all replays in G match C and all replays outside G and Res diverge from C.
 
 impl MergeStalemateState {
-    fn new(target_quorum_size: usize, stream_cmp_distance: usize) -> Self {
+    fn new(target_quorum_size: usize, stream_cmp_distance: usize, whole_commands: bool) -> Self {
         Self {
-            s: SharedState::new(target_quorum_size, stream_cmp_distance),
+            s: SharedState::new(target_quorum_size, stream_cmp_distance, whole_commands),
             candidates: HashMap::new(),
             reserve: HashSet::new(),
         }
     }
 
-    fn from_quorum(shared: SharedState, reserve: HashSet<u64>) -> Self {
+    fn from_quorum(mut shared: SharedState, reserve: HashSet<u64>) -> Self {
+        shared.events.stalemates.entered();
         let mut me = Self {
             s: shared,
             candidates: HashMap::new(),
@@ -226,12 +341,8 @@ impl MergeStalemateState {
         me
     }
 
-    fn insert_candidate(&mut self, byte: u8, id: u64) {
-        if self.candidates.get(&byte).is_none() {
-            self.candidates.insert(byte, Vec::new());
-        }
-        let cands_for_byte = self.candidates.get_mut(&byte).unwrap();
-        cands_for_byte.push(id);
+    fn insert_candidate(&mut self, piece: Piece, id: u64) {
+        self.candidates.entry(piece).or_default().push(id);
     }
 
     fn try_move_replay_to_candidates(&mut self, id: u64) {
@@ -239,27 +350,50 @@ impl MergeStalemateState {
         let replay = self.s.get_replay(id);
         let replay_len = replay.r.data_len();
         let replay_is_finished = replay.r.is_finished();
+        let next_piece = replay.next_piece(canon_data_len, self.s.whole_commands);
 
-        if replay_len <= canon_data_len {
+        if let NextPiece::Incomplete = next_piece {
             if replay_is_finished {
-                let replay = self.s.get_mut_replay(id);
-                replay.set_diverged();
-                self.reserve.remove(&id); // Replay finished short. Diverges.
+                // Replay finished short or differs from C. Diverges. If it's the same as C, we're
+                // done with it anyway. If it ended in the middle of a command, it did so right
+                // after C.
+                let divergence = replay.divergence_at_stalemate(&self.s.canonical_stream.borrow());
+                match divergence {
+                    Some(offset) => self.s.set_diverged(id, offset),
+                    None if replay_len > canon_data_len => self.s.set_diverged(id, canon_data_len),
+                    None => self.s.get_mut_replay(id).set_diverged(),
+                }
+                self.reserve.remove(&id);
             }
             return; // Replay is short, still in reserve.
         }
 
         self.reserve.remove(&id);
-        let diverges = replay.diverges_at_stalemate(&self.s.canonical_stream.borrow());
-        let replay = self.s.get_mut_replay(id);
+        let divergence = replay.divergence_at_stalemate(&self.s.canonical_stream.borrow());
 
-        if diverges {
-            replay.set_diverged();
-        } else {
-            // Replay matches and can become a candidate.
-            replay.set_matching(canon_data_len);
-            let next_byte = replay.r.get_data().at(canon_data_len);
-            self.insert_candidate(next_byte, id);
+        match (divergence, next_piece) {
+            (Some(offset), _) => self.s.set_diverged(id, offset),
+            (None, NextPiece::Complete(piece_end)) => {
+                // Replay matches and can become a candidate.
+                let whole_commands = self.s.whole_commands;
+                let replay = self.s.get_mut_replay(id);
+                replay.set_matching(canon_data_len);
+                let piece = {
+                    let data = replay.r.get_data();
+                    if whole_commands {
+                        let mut command = Vec::with_capacity(piece_end - canon_data_len);
+                        for chunk in data.iter_chunks(canon_data_len, piece_end) {
+                            command.extend_from_slice(chunk);
+                        }
+                        Piece::Command(command)
+                    } else {
+                        Piece::Byte(data.at(canon_data_len))
+                    }
+                };
+                self.insert_candidate(piece, id);
+            }
+            // Matches C, but what comes next is not a command.
+            _ => self.s.set_diverged(id, canon_data_len),
         }
     }
 
@@ -276,7 +410,7 @@ impl MergeStalemateState {
         }
 
         // Delayed data check.
-        if !self.s.delayed_data_started && self.s.get_replay(id).r.delayed_data_len() > 0 {
+        if !self.s.delayed_data_started && self.s.get_replay(id).r.privileged_delayed_data_len() > 0 {
             self.s.delayed_data_started = true;
         }
     }
@@ -310,25 +444,35 @@ impl MergeStalemateState {
 
         // Sort by stream count, then by longest stream.
         let replay_len = |&id| self.s.get_replay(id).r.data_len();
-        let best_byte = *self
+        let best_piece = self
             .candidates
             .iter()
             .map(|(k, v)| (v.len(), v.iter().map(replay_len).max().unwrap(), k))
             .max()
             .unwrap()
-            .2;
+            .2
+            .clone();
 
-        let good_replays = self.candidates.remove(&best_byte).unwrap();
+        let good_replays = self.candidates.remove(&best_piece).unwrap();
+        // We ran out of reserve, so we settle for fewer replays.
+        let fallback = good_replays.len() < self.s.target_quorum_size;
+        self.s.events.stalemates.resolved(fallback);
 
-        // Advance replay by 1 byte
+        // Advance replay by the piece
         let good_replay = *good_replays.get(0).unwrap();
         let byte_pos = self.s.canon_data_len();
-        self.s.append_canon_data(good_replay, byte_pos + 1);
+        self.s.append_canon_data(good_replay, byte_pos + best_piece.as_bytes().len());
 
         // Discard all diverging replays
-        for (_, v) in self.candidates.iter() {
-            for id in v.iter() {
-                self.s.get_mut_replay(*id).set_diverged();
+        for (piece, ids) in self.candidates.iter() {
+            let same = piece
+                .as_bytes()
+                .iter()
+                .zip(best_piece.as_bytes())
+                .take_while(|(a, b)| a == b)
+                .count();
+            for id in ids {
+                self.s.set_diverged(*id, byte_pos + same);
             }
         }
         MergeQuorumState::from_stalemate(self.s, good_replays, self.reserve)
@@ -339,6 +483,11 @@ impl MergeStalemateState {
 // Have a set of replays Q that matches C. When constructed, merge replays in quorum and add the
 // common prefix to C. Update C's delayed position as a minimum of Q's replays' delayed positions.
 // Once C's delayed position reaches end of merged data, enter a stalemate.
+//
+// Replays have two delayed positions, a regular one and a privileged one that's never behind it
+// (see replay_delay.rs). When we say "delayed position" in this strategy, we mean the privileged
+// one, and we update C's regular delayed position alongside it. If there are no privileged
+// readers, the two are the same.
 
 pub struct MergeQuorumState {
     s: SharedState,
@@ -366,7 +515,7 @@ pub struct MergeQuorumState {
 //   * Notice that all replays outside Res are diverged.
 
 impl MergeQuorumState {
-    fn from_stalemate(shared: SharedState, mut good_replays: Vec<u64>, mut reserve: HashSet<u64>) -> Self {
+    fn from_stalemate(mut shared: SharedState, mut good_replays: Vec<u64>, mut reserve: HashSet<u64>) -> Self {
         debug_assert!(!good_replays.is_empty());
 
         // Take longest replays
@@ -377,6 +526,7 @@ impl MergeQuorumState {
         let new_quorum_len = std::cmp::min(good_replays.len(), shared.target_quorum_size);
         let new_quorum: HashSet<u64> = good_replays.drain(..new_quorum_len).collect();
         reserve.extend(good_replays.drain(..));
+        shared.events.final_quorum_size = Some(new_quorum_len);
 
         let mut me = MergeQuorumState {
             s: shared,
@@ -404,7 +554,10 @@ impl MergeQuorumState {
     }
 
     fn update_delayed_position(&mut self) {
-        self.s.update_canon_delayed_data_len(self.quorum_delayed_position());
+        let (delayed, privileged) = self.quorum_delayed_position();
+        // Privileged first, it can't fall behind the regular one.
+        self.s.update_canon_privileged_delayed_data_len(privileged);
+        self.s.update_canon_delayed_data_len(delayed);
     }
 
     // We chose minimum rather than maximum due to delayed position behaviour. When a replay ends,
@@ -416,16 +569,20 @@ impl MergeQuorumState {
     // only move the delayed position to the end once all quorum replays finish.
     // Using a minimum here is fine. Delayed data always catches up with normal data, even if a
     // replay stalls, so we'll always eventually switch to a stalemate.
-    fn quorum_delayed_position(&self) -> usize {
-        self.quorum
-            .iter()
-            .map(|id| self.s.get_replay(*id).r.delayed_data_len())
-            .min()
-            .unwrap()
+    //
+    // Same goes for the privileged delayed position. Since it's ahead of the regular one, it's the
+    // one that decides when we have to enter a stalemate.
+    fn quorum_delayed_position(&self) -> (usize, usize) {
+        let min_of =
+            |f: fn(&WReplayRef) -> usize| self.quorum.iter().map(|id| f(&self.s.get_replay(*id).r)).min().unwrap();
+        (
+            min_of(|r| r.delayed_data_len()),
+            min_of(|r| r.privileged_delayed_data_len()),
+        )
     }
 
     fn has_to_enter_stalemate(&self) -> bool {
-        self.s.canon_delayed_data_len() >= self.s.canon_data_len()
+        self.s.canon_privileged_delayed_data_len() >= self.s.canon_data_len()
     }
 
     fn enter_stalemate(self) -> MergeStalemateState {
@@ -474,6 +631,10 @@ impl MergeQuorumState {
             }
             common_prefix = shortest.common_prefix_from_to(self.s.get_replay(*id), cmp_start, Some(common_prefix));
         }
+        if self.s.whole_commands {
+            // C always ends at a command boundary, so that's where we start.
+            common_prefix = shortest.last_command_boundary(cmp_start, common_prefix);
+        }
         common_prefix
     }
 }
@@ -497,8 +658,12 @@ macro_rules! both {
 }
 
 impl QuorumMergeStrategy {
-    pub fn new(target_quorum_size: usize, stream_cmp_distance: usize) -> Self {
-        Self::Stalemate(MergeStalemateState::new(target_quorum_size, stream_cmp_distance))
+    pub fn new(target_quorum_size: usize, stream_cmp_distance: usize, whole_commands: bool) -> Self {
+        Self::Stalemate(MergeStalemateState::new(
+            target_quorum_size,
+            stream_cmp_distance,
+            whole_commands,
+        ))
     }
 
     fn should_change_state(&self) -> bool {
@@ -523,7 +688,7 @@ impl QuorumMergeStrategy {
 }
 
 impl MergeStrategy for QuorumMergeStrategy {
-    fn replay_added(&mut self, r: WReplayRef) -> u64 {
+    fn replay_added(&mut self, r: WReplayRef, _trust: WriterTrust) -> u64 {
         let token = both!(self, s => s.add_replay(r));
         self.work_state_until_stable();
         token
@@ -535,7 +700,7 @@ impl MergeStrategy for QuorumMergeStrategy {
     }
 
     fn replay_header_added(&mut self, id: u64) {
-        // Accept the first header we get.
+        // Accept the first header we get, they all agree.
         // As a bonus, this guarantees that canonical stream will be in data stage once we start
         // merging data.
         let replay = both!(self, s => s.s.get_replay(id));
@@ -555,6 +720,10 @@ impl MergeStrategy for QuorumMergeStrategy {
         both!(self, s => s.s.canonical_stream.clone())
     }
 
+    fn events(&self) -> &MergeEvents {
+        both!(self, s => &s.s.events)
+    }
+
     fn finish(&mut self) {
         // We know that delayed position for all replays is at the end of their data.
         // If we were in a quorum state, then delayed position of merged replay would equal its
@@ -566,10 +735,13 @@ impl MergeStrategy for QuorumMergeStrategy {
             Self::Swapping => panic!(),
             Self::Quorum(..) => panic!("Expected to finish merge strategy in a stalemate"),
             Self::Stalemate(s) => {
-                // Not in a quorum. Delayed replay position must be equal to its data len.
+                // Not in a quorum. Privileged delayed replay position must be equal to its data
+                // len. Regular one might lag behind if the quorum ended early on the privileged
+                // position, let it catch up.
                 let data_len = s.s.canon_data_len();
-                let position = s.s.canon_delayed_data_len();
+                let position = s.s.canon_privileged_delayed_data_len();
                 debug_assert_eq!(data_len, position);
+                s.s.update_canon_delayed_data_len(data_len);
                 // All replays are finished, so Res is empty.
                 debug_assert!(s.reserve.is_empty());
                 // should_change_state() returns false, so stalemate cannot be resolved.
@@ -600,6 +772,8 @@ impl MergeStrategy for QuorumMergeStrategy {
 // The final stalemate has empty Cand. According to invariants, it means there are no replays that
 // match C and are longer than C. However, C is always a prefix of at least one replay. That replay
 // matches C by definition, therefore it's not longer than C, therefore it's equal to C. QED
+// With whole_commands, replays that are longer than C but end in the middle of a command diverge
+// too, so C is only equal to one of them up to its last whole command.
 //
 // Our third claim broadly says that C will never equal a replay that split off from others alone
 // with its own data. That's pretty easy.
@@ -655,20 +829,24 @@ mod tests {
     use crate::util::buf_traits::ReadAtExt;
     use crate::util::test::setup_logging;
     use crate::{
-        replay::receive::merge_strategy::MergeStrategy, replay::streams::ReplayHeader, replay::streams::WriterReplay,
+        replay::receive::divergence::DivergenceKind,
+        replay::receive::merge_strategy::{MergeStrategy, WriterTrust},
+        replay::receive::test::{add_writer, advance, end, merged_data, set_command_source, write},
+        replay::streams::ReplayHeader,
+        replay::streams::WriterReplay,
         util::buf_traits::ChunkedBuf,
     };
     use std::{cell::RefCell, io::Read, rc::Rc};
 
     fn strat() -> QuorumMergeStrategy {
-        QuorumMergeStrategy::new(2, 4096)
+        QuorumMergeStrategy::new(2, 4096, false)
     }
 
     #[test]
     fn test_strategy_ends_stream_when_finalized() {
         let mut strat = strat();
         let stream1 = Rc::new(RefCell::new(WriterReplay::new()));
-        let token1 = strat.replay_added(stream1.clone());
+        let token1 = strat.replay_added(stream1.clone(), WriterTrust::Unverified);
         stream1.borrow_mut().finish();
         strat.replay_removed(token1);
         strat.finish();
@@ -686,8 +864,8 @@ mod tests {
         let stream2 = Rc::new(RefCell::new(WriterReplay::new()));
         stream2.borrow_mut().add_header(ReplayHeader { data: vec![1, 3, 3, 7] });
 
-        let token1 = strat.replay_added(stream1.clone());
-        let token2 = strat.replay_added(stream2.clone());
+        let token1 = strat.replay_added(stream1.clone(), WriterTrust::Unverified);
+        let token2 = strat.replay_added(stream2.clone(), WriterTrust::Unverified);
         strat.replay_header_added(token2);
 
         stream1.borrow_mut().finish();
@@ -707,7 +885,7 @@ mod tests {
         let stream1 = Rc::new(RefCell::new(WriterReplay::new()));
         stream1.borrow_mut().add_header(ReplayHeader { data: vec![1, 3, 3, 7] });
 
-        let token1 = strat.replay_added(stream1.clone());
+        let token1 = strat.replay_added(stream1.clone(), WriterTrust::Unverified);
         strat.replay_header_added(token1);
 
         stream1.borrow_mut().add_data(&[1, 2, 3, 4]);
@@ -735,6 +913,39 @@ mod tests {
         assert_eq!(out_buf, &[1, 2, 3, 4, 5, 6, 7, 8]);
     }
 
+    #[test]
+    fn test_strategy_tracks_privileged_delay() {
+        let mut strat = strat();
+        let stream1 = Rc::new(RefCell::new(WriterReplay::new()));
+        stream1.borrow_mut().add_header(ReplayHeader { data: vec![1, 3, 3, 7] });
+
+        let token1 = strat.replay_added(stream1.clone(), WriterTrust::Unverified);
+        strat.replay_header_added(token1);
+
+        // Privileged delay alone is enough to start merging.
+        stream1.borrow_mut().add_data(&[1, 2, 3, 4]);
+        stream1.borrow_mut().set_privileged_delayed_data_len(3);
+        strat.replay_data_updated(token1);
+        let out_stream_ref = strat.get_merged_replay();
+        assert_eq!(out_stream_ref.delayed_data_len(), 0);
+        assert_eq!(out_stream_ref.privileged_delayed_data_len(), 3);
+
+        stream1.borrow_mut().add_data(&[5, 6]);
+        stream1.borrow_mut().set_privileged_delayed_data_len(6);
+        stream1.borrow_mut().set_delayed_data_len(2);
+        strat.replay_data_updated(token1);
+        assert_eq!(out_stream_ref.data_len(), 6);
+        assert_eq!(out_stream_ref.delayed_data_len(), 2);
+        assert_eq!(out_stream_ref.privileged_delayed_data_len(), 6);
+
+        // Regular readers catch up once we're done.
+        stream1.borrow_mut().finish();
+        strat.replay_removed(token1);
+        strat.finish();
+        assert_eq!(out_stream_ref.delayed_data_len(), 6);
+        assert!(out_stream_ref.is_finished());
+    }
+
     #[test]
     fn test_strategy_gets_common_prefix_of_all() {
         let mut strat = strat();
@@ -743,8 +954,8 @@ mod tests {
 
         stream1.borrow_mut().add_header(ReplayHeader { data: vec![1, 3, 3, 7] });
         stream2.borrow_mut().add_header(ReplayHeader { data: vec![1, 3, 3, 7] });
-        let token1 = strat.replay_added(stream1.clone());
-        let token2 = strat.replay_added(stream2.clone());
+        let token1 = strat.replay_added(stream1.clone(), WriterTrust::Unverified);
+        let token2 = strat.replay_added(stream2.clone(), WriterTrust::Unverified);
         strat.replay_header_added(token1);
         strat.replay_header_added(token2);
 
@@ -787,6 +998,13 @@ mod tests {
         } else {
             assert_eq!(out_buf[3..8], [4, 5, 6, 7, 8]);
         }
+
+        // Nobody agreed with the replay we picked in the end.
+        let events = strat.events();
+        let offsets: Vec<_> = events.divergences.values().map(|d| d.offset).collect();
+        assert_eq!(offsets, vec![3]);
+        assert_eq!(events.stalemates.fallback_resolutions, 1);
+        assert_eq!(events.final_quorum_size, Some(1));
     }
 
     #[test]
@@ -797,8 +1015,8 @@ mod tests {
 
         stream1.borrow_mut().add_header(ReplayHeader { data: vec![1, 3, 3, 7] });
         stream2.borrow_mut().add_header(ReplayHeader { data: vec![1, 3, 3, 7] });
-        let token1 = strat.replay_added(stream1.clone());
-        let token2 = strat.replay_added(stream2.clone());
+        let token1 = strat.replay_added(stream1.clone(), WriterTrust::Unverified);
+        let token2 = strat.replay_added(stream2.clone(), WriterTrust::Unverified);
         strat.replay_header_added(token1);
         strat.replay_header_added(token2);
 
@@ -829,7 +1047,7 @@ mod tests {
     // FIXME tweak so we can test small comparison cutoffs.
     fn simple_fuzzing_round() {
         let mut rng = rand::rng();
-        let mut strat = QuorumMergeStrategy::new(2, 512);
+        let mut strat = QuorumMergeStrategy::new(2, 512, false);
         let count = 8;
         let chunk = 4;
         let replay_len = 400;
@@ -843,7 +1061,7 @@ mod tests {
         for _ in 0..count {
             let stream = Rc::new(RefCell::new(WriterReplay::new()));
             stream.borrow_mut().add_header(ReplayHeader { data: vec![1, 3, 3, 7] });
-            let token = strat.replay_added(stream.clone());
+            let token = strat.replay_added(stream.clone(), WriterTrust::Unverified);
             strat.replay_header_added(token);
             let data: Vec<u8> = Vec::new();
             streams.push((stream, token, data));
@@ -952,6 +1170,79 @@ mod tests {
         assert!(datas.contains(&merged_data));
     }
 
+    #[test]
+    fn test_strategy_merges_whole_commands() {
+        let mut strat = QuorumMergeStrategy::new(2, 4096, true);
+        let w1 = add_writer(&mut strat, WriterTrust::Unverified);
+        let w2 = add_writer(&mut strat, WriterTrust::Unverified);
+        let w3 = add_writer(&mut strat, WriterTrust::Unverified);
+        let good = [advance(1), set_command_source(6), advance(1)].concat();
+        let bad = [advance(1), set_command_source(5), advance(1)].concat();
+
+        write(&mut strat, &w1, &bad);
+        write(&mut strat, &w2, &good);
+        // Replays disagree in the middle of the second command, we don't merge any of it.
+        assert_eq!(merged_data(&strat), advance(1));
+
+        write(&mut strat, &w3, &good);
+        assert_eq!(merged_data(&strat), good);
+        for w in [&w1, &w2, &w3] {
+            end(&mut strat, w);
+        }
+        strat.finish();
+        assert_eq!(merged_data(&strat), good);
+
+        let divergences = &strat.events().divergences;
+        assert_eq!(divergences.len(), 1);
+        assert_eq!(divergences[&w1.token].offset, 10);
+        assert_eq!(divergences[&w1.token].kind, DivergenceKind::Desync);
+    }
+
+    #[test]
+    fn test_strategy_drops_partial_commands() {
+        let partial = [advance(1), vec![1, 4, 0]].concat();
+        let merge_partial = |whole_commands| {
+            let mut strat = QuorumMergeStrategy::new(2, 4096, whole_commands);
+            let w1 = add_writer(&mut strat, WriterTrust::Unverified);
+            let w2 = add_writer(&mut strat, WriterTrust::Unverified);
+            write(&mut strat, &w1, &partial);
+            write(&mut strat, &w2, &partial);
+            end(&mut strat, &w1);
+            end(&mut strat, &w2);
+            strat.finish();
+            let kinds: Vec<_> = strat
+                .events()
+                .divergences
+                .values()
+                .map(|d| (d.offset, d.kind))
+                .collect();
+            (merged_data(&strat), kinds)
+        };
+        assert_eq!(merge_partial(false), (partial.clone(), vec![]));
+        assert_eq!(
+            merge_partial(true),
+            (advance(1), vec![(7, DivergenceKind::Truncation); 2])
+        );
+    }
+
+    #[test]
+    fn test_strategy_does_not_merge_garbage_commands() {
+        let mut strat = QuorumMergeStrategy::new(2, 4096, true);
+        let w1 = add_writer(&mut strat, WriterTrust::Unverified);
+        let w2 = add_writer(&mut strat, WriterTrust::Unverified);
+        let data = [advance(1), vec![200, 1, 2, 3, 4, 5], advance(1)].concat();
+        write(&mut strat, &w1, &data);
+        write(&mut strat, &w2, &data);
+        end(&mut strat, &w1);
+        end(&mut strat, &w2);
+        strat.finish();
+        assert_eq!(merged_data(&strat), advance(1));
+        assert_eq!(strat.events().divergences.len(), 2);
+        for d in strat.events().divergences.values() {
+            assert_eq!((d.offset, d.kind), (7, DivergenceKind::Garbage));
+        }
+    }
+
     #[cfg_attr(not(feature = "fuzzing_tests"), ignore)]
     #[test]
     fn test_strategy_simple_fuzzing() {
@@ -1,10 +1,17 @@
-use std::collections::VecDeque;
+use std::collections::{HashMap, VecDeque};
+use std::sync::Arc;
 
+use crate::config::Settings;
+use crate::database::database::GameCategoryRow;
+use crate::database::queries::Queries;
 use crate::replay::streams::ReplayStreamRef;
 use crate::replay::streams::WReplayRef;
+use crate::util::timeout::timeout;
 
 use tokio::time::Duration;
 
+use super::game_lookup::GAME_LOOKUP_TIMEOUT;
+
 pub struct PositionHistory {
     sleep_s: Duration,
     history_size: usize,
@@ -47,33 +54,158 @@ impl PositionHistory {
 
 pub struct StreamDelay {
     delay_s: Duration,
+    // Shorter delay for privileged readers. None if there can't be any, then we don't track it.
+    privileged_delay_s: Option<Duration>,
     sleep_s: Duration,
 }
 
 impl StreamDelay {
-    pub fn new(delay_s: Duration, sleep_s: Duration) -> Self {
-        Self { delay_s, sleep_s }
+    pub fn new(delay_s: Duration, privileged_delay_s: Option<Duration>, sleep_s: Duration) -> Self {
+        Self {
+            delay_s,
+            privileged_delay_s: privileged_delay_s.map(|d| d.min(delay_s)),
+            sleep_s,
+        }
     }
 
     pub async fn update_replay_timestamp(&self, replay: &WReplayRef, on_update: &dyn Fn()) -> ! {
         let mut pos_queue = PositionHistory::new(self.delay_s, self.sleep_s);
-        let mut prev_current = 0;
-        let mut prev_delayed = 0;
+        let mut privileged_pos_queue = self.privileged_delay_s.map(|d| PositionHistory::new(d, self.sleep_s));
+        let mut prev = (0, 0, 0);
         loop {
             let current = replay.data_len();
             let delayed = pos_queue.push_and_get_delayed(current);
+            let privileged = match privileged_pos_queue.as_mut() {
+                Some(q) => q.push_and_get_delayed(current),
+                None => delayed,
+            };
+            replay.borrow_mut().set_privileged_delayed_data_len(privileged);
             replay.borrow_mut().set_delayed_data_len(delayed);
-            if (current, delayed) != (prev_current, prev_delayed) {
+            if (current, delayed, privileged) != prev {
                 on_update();
             }
-            prev_current = current;
-            prev_delayed = delayed;
+            prev = (current, delayed, privileged);
             pos_queue.wait_cycle().await;
         }
     }
 
     pub fn set_final_replay_timestamp(&self, replay: &WReplayRef) {
         let final_len = replay.data_len();
+        replay.borrow_mut().set_privileged_delayed_data_len(final_len);
         replay.borrow_mut().set_delayed_data_len(final_len);
     }
 }
+
+// Finds out a replay's delay when it starts. Some kinds of games can have their own delay, e.g.
+// ranked matchmaker games a long one and coop games a short one. We look up what kind of game it
+// is in the database, otherwise we use the configured delay.
+#[derive(Clone)]
+pub struct DelayLookup {
+    queries: Option<Arc<Queries>>,
+    default_delay: Duration,
+    matchmaker_queue_delays: HashMap<String, Duration>,
+    featured_mod_delays: HashMap<String, Duration>,
+}
+
+impl DelayLookup {
+    pub fn new(queries: Arc<Queries>, config: &Settings) -> Self {
+        let matchmaker_queue_delays = config.replay.matchmaker_queue_delays_s.clone();
+        let featured_mod_delays = config.replay.featured_mod_delays_s.clone();
+        let needs_lookup = !matchmaker_queue_delays.is_empty() || !featured_mod_delays.is_empty();
+        Self {
+            queries: Some(queries).filter(|_| needs_lookup),
+            default_delay: config.replay.delay_s,
+            matchmaker_queue_delays,
+            featured_mod_delays,
+        }
+    }
+
+    // Matchmaker games first, their featured mod can be the same as custom games'.
+    fn delay_for(&self, category: &GameCategoryRow) -> Duration {
+        let by_queue = category
+            .matchmaker_queue
+            .as_ref()
+            .and_then(|q| self.matchmaker_queue_delays.get(q));
+        let by_mod = category
+            .featured_mod
+            .as_ref()
+            .and_then(|m| self.featured_mod_delays.get(m));
+        by_queue.or(by_mod).copied().unwrap_or(self.default_delay)
+    }
+
+    pub async fn resolve(&self, id: u64) -> Duration {
+        let queries = match &self.queries {
+            None => return self.default_delay,
+            Some(q) => q,
+        };
+        match timeout(queries.get_game_category(id), GAME_LOOKUP_TIMEOUT).await {
+            Some(Ok(Some(c))) => {
+                let delay = self.delay_for(&c);
+                log::debug!("Replay {} is a {:?} game, delay is {}s", id, c, delay.as_secs());
+                delay
+            }
+            Some(Ok(None)) => {
+                log::info!("Game of replay {} is not in the database, using default delay", id);
+                self.default_delay
+            }
+            Some(Err(e)) => {
+                log::info!("Failed to fetch delay of replay {}, using default: {}", id, e);
+                self.default_delay
+            }
+            None => {
+                log::info!("Timed out fetching delay of replay {}, using default", id);
+                self.default_delay
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::config::test::default_config;
+    use crate::database::database::Database;
+    use crate::error::SaveError;
+
+    fn category(queue: Option<&str>, featured_mod: Option<&str>) -> GameCategoryRow {
+        GameCategoryRow {
+            matchmaker_queue: queue.map(Into::into),
+            featured_mod: featured_mod.map(Into::into),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_delay_lookup() {
+        let mut config = default_config();
+        config.replay.delay_s = Duration::from_secs(60);
+        config.replay.matchmaker_queue_delays_s = HashMap::from([("ladder1v1".into(), Duration::from_secs(600))]);
+        config.replay.featured_mod_delays_s = HashMap::from([
+            ("ladder1v1".into(), Duration::from_secs(10)),
+            ("coop".into(), Duration::from_secs(30)),
+        ]);
+        let mut db = Database::faux();
+        faux::when!(db.get_game_category).then(|id| match id {
+            1 => Ok(Some(category(Some("ladder1v1"), Some("ladder1v1")))),
+            2 => Ok(Some(category(None, Some("coop")))),
+            3 => Ok(Some(category(Some("tmm2v2"), Some("faf")))),
+            4 => Ok(None),
+            _ => Err(SaveError::DatabaseError(sqlx::Error::RowNotFound)),
+        });
+        let lookup = DelayLookup::new(Arc::new(Queries::new(db)), &Arc::new(config));
+        assert_eq!(lookup.resolve(1).await, Duration::from_secs(600));
+        assert_eq!(lookup.resolve(2).await, Duration::from_secs(30));
+        assert_eq!(lookup.resolve(3).await, Duration::from_secs(60));
+        assert_eq!(lookup.resolve(4).await, Duration::from_secs(60));
+        assert_eq!(lookup.resolve(5).await, Duration::from_secs(60));
+    }
+
+    #[tokio::test]
+    async fn test_delay_lookup_disabled() {
+        let mut config = default_config();
+        config.replay.delay_s = Duration::from_secs(60);
+        // Would panic if called.
+        let db = Database::faux();
+        let lookup = DelayLookup::new(Arc::new(Queries::new(db)), &Arc::new(config));
+        assert_eq!(lookup.resolve(1).await, Duration::from_secs(60));
+    }
+}
@@ -0,0 +1,61 @@
+// Helpers for tests that build replay commands or drive merge strategies by hand.
+use std::{cell::RefCell, io::Read, rc::Rc};
+
+use crate::replay::streams::{ReplayHeader, ReplayStreamRef, WReplayRef, WriterReplay};
+use crate::util::buf_traits::ReadAtExt;
+
+use super::merge_strategy::{MergeStrategy, WriterTrust};
+
+// Commands are a type byte, then a u16 LE length of the whole command, then arguments.
+pub fn advance(ticks: u32) -> Vec<u8> {
+    let mut v = vec![0, 7, 0];
+    v.extend(ticks.to_le_bytes());
+    v
+}
+
+pub fn set_command_source(source: u8) -> Vec<u8> {
+    vec![1, 4, 0, source]
+}
+
+pub fn verify_checksum(digest: u8, tick: u32) -> Vec<u8> {
+    let mut v = vec![3, 23, 0];
+    v.extend([digest; 16]);
+    v.extend(tick.to_le_bytes());
+    v
+}
+
+pub struct TestWriter {
+    pub r: WReplayRef,
+    pub token: u64,
+}
+
+pub fn add_writer(strat: &mut dyn MergeStrategy, trust: WriterTrust) -> TestWriter {
+    let r = Rc::new(RefCell::new(WriterReplay::new()));
+    r.borrow_mut().add_header(ReplayHeader { data: vec![1, 3, 3, 7] });
+    let token = strat.replay_added(r.clone(), trust);
+    strat.replay_header_added(token);
+    TestWriter { r, token }
+}
+
+// Appends data that's immediately visible to readers.
+pub fn write(strat: &mut dyn MergeStrategy, w: &TestWriter, data: &[u8]) {
+    w.r.borrow_mut().add_data(data);
+    let len = w.r.data_len();
+    w.r.borrow_mut().set_delayed_data_len(len);
+    strat.replay_data_updated(w.token);
+}
+
+pub fn end(strat: &mut dyn MergeStrategy, w: &TestWriter) {
+    let len = w.r.data_len();
+    w.r.borrow_mut().set_delayed_data_len(len);
+    strat.replay_data_updated(w.token);
+    w.r.borrow_mut().finish();
+    strat.replay_removed(w.token);
+}
+
+pub fn merged_data(strat: &dyn MergeStrategy) -> Vec<u8> {
+    let mut out = Vec::new();
+    let merged = strat.get_merged_replay();
+    merged.get_data().reader().read_to_end(&mut out).unwrap();
+    out
+}
@@ -0,0 +1,98 @@
+use std::cmp::Ordering;
+
+use super::leader_merge_strategy::{LeaderCandidate, LeaderMergeStrategy};
+
+// Follows the most trusted replay, preferring the host's, then other writers with valid tokens
+// (see accept/auth.rs). Among equally trusted replays, the longest one wins. Untrusted replays
+// only lead when no trusted replay has data we haven't merged. See leader_merge_strategy.rs for
+// how merging works.
+fn by_trust(a: &LeaderCandidate, b: &LeaderCandidate) -> Ordering {
+    a.trust.cmp(&b.trust).then(a.data_len.cmp(&b.data_len))
+}
+
+pub fn trusted_writer_merge_strategy() -> LeaderMergeStrategy {
+    LeaderMergeStrategy::new(by_trust)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::replay::receive::merge_strategy::{MergeStrategy, WriterTrust};
+    use crate::replay::receive::test::{add_writer, end, merged_data, write};
+    use crate::replay::streams::ReplayStreamRef;
+
+    #[test]
+    fn test_trusted_writer_beats_longer_untrusted() {
+        let mut strat = trusted_writer_merge_strategy();
+        let w1 = add_writer(&mut strat, WriterTrust::Unverified);
+        let w2 = add_writer(&mut strat, WriterTrust::Authenticated);
+
+        w1.r.borrow_mut().add_data(&[1, 2, 3, 4, 5, 6]);
+        w2.r.borrow_mut().add_data(&[1, 2, 7, 8]);
+        w1.r.borrow_mut().set_delayed_data_len(6);
+        w2.r.borrow_mut().set_delayed_data_len(4);
+        strat.replay_data_updated(w1.token);
+        assert_eq!(merged_data(&strat), vec![1, 2, 7, 8]);
+
+        end(&mut strat, &w1);
+        end(&mut strat, &w2);
+        strat.finish();
+        assert_eq!(merged_data(&strat), vec![1, 2, 7, 8]);
+    }
+
+    #[test]
+    fn test_host_beats_other_trusted_writers() {
+        let mut strat = trusted_writer_merge_strategy();
+        let w1 = add_writer(&mut strat, WriterTrust::Authenticated);
+        let w2 = add_writer(&mut strat, WriterTrust::Host);
+        let w3 = add_writer(&mut strat, WriterTrust::Authenticated);
+
+        w1.r.borrow_mut().add_data(&[1, 2, 3, 4, 5]);
+        w2.r.borrow_mut().add_data(&[1, 9, 9]);
+        w3.r.borrow_mut().add_data(&[1, 2, 3, 4, 5, 6]);
+        for w in [&w1, &w2, &w3] {
+            let len = w.r.data_len();
+            w.r.borrow_mut().set_delayed_data_len(len);
+        }
+        strat.replay_data_updated(w1.token);
+
+        for w in [&w1, &w2, &w3] {
+            end(&mut strat, w);
+        }
+        strat.finish();
+        assert_eq!(merged_data(&strat), vec![1, 9, 9]);
+    }
+
+    #[test]
+    fn test_longest_of_equally_trusted_wins() {
+        let mut strat = trusted_writer_merge_strategy();
+        let w1 = add_writer(&mut strat, WriterTrust::Authenticated);
+        let w2 = add_writer(&mut strat, WriterTrust::Authenticated);
+
+        write(&mut strat, &w1, &[1, 2]);
+        write(&mut strat, &w2, &[1, 2, 3, 4]);
+        write(&mut strat, &w1, &[5, 6, 7]);
+        assert_eq!(merged_data(&strat), vec![1, 2, 3, 4]);
+
+        end(&mut strat, &w1);
+        end(&mut strat, &w2);
+        strat.finish();
+        assert_eq!(merged_data(&strat), vec![1, 2, 3, 4]);
+    }
+
+    #[test]
+    fn test_untrusted_writer_leads_once_trusted_ones_run_out() {
+        let mut strat = trusted_writer_merge_strategy();
+        let w1 = add_writer(&mut strat, WriterTrust::Unverified);
+        let w2 = add_writer(&mut strat, WriterTrust::Host);
+
+        write(&mut strat, &w1, &[1, 2, 3, 4]);
+        write(&mut strat, &w2, &[1, 2]);
+        // Host left early, so the rest of the game comes from w1.
+        end(&mut strat, &w2);
+        write(&mut strat, &w1, &[5, 6]);
+        end(&mut strat, &w1);
+        strat.finish();
+        assert_eq!(merged_data(&strat), vec![1, 2, 3, 4, 5, 6]);
+    }
+}
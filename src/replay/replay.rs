@@ -1,11 +1,21 @@
-use std::{cell::Cell, fmt::Display};
+use std::collections::HashMap;
+use std::time::{SystemTime, UNIX_EPOCH};
+use std::{
+    cell::{Cell, RefCell},
+    fmt::Display,
+};
 
 use tokio::join;
+use tokio::sync::Notify;
 use tokio::time::Duration;
 use tokio_util::sync::CancellationToken;
 
-use super::{receive::ReplayMerger, save::ReplaySaver, send::ReplaySender};
+use super::{
+    budget::LoadBudget, receive::GameInfo, receive::ReplayMerger, save::ReplaySaver, save::SpoolLock,
+    send::ReplaySender,
+};
 use crate::error::ConnectionError;
+use crate::replay::streams::ReplayStream;
 use crate::{
     accept::header::ConnectionType,
     config::Settings,
@@ -15,17 +25,63 @@ use crate::{
     util::{empty_counter::EmptyCounter, timeout::cancellable},
 };
 
+// A snapshot of a running replay's state, for listing replays.
+#[derive(serde::Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct ReplayInfo {
+    pub id: u64,
+    pub start_time: u64, // Unix timestamp, seconds
+    pub writers: usize,
+    pub readers: usize,
+    pub received_bytes: usize,
+    pub delayed_bytes: usize,
+    pub accepts_connections: bool,
+}
+
+#[derive(serde::Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct ConnectionInfo {
+    pub id: String,
+    #[serde(rename = "type")]
+    pub type_: String,
+    pub name: String,
+    pub peer_addr: Option<String>,
+}
+
+// Replay info plus its connections, for looking at a single replay.
+#[derive(serde::Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct ReplayDetails {
+    #[serde(flatten)]
+    pub info: ReplayInfo,
+    pub connections: Vec<ConnectionInfo>,
+}
+
+// Things an admin can do to a running replay.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ReplayCommand {
+    // End the replay right away, same as when it times out.
+    ForceEnd,
+    // Don't let new connections in. Connected writers can keep writing.
+    StopAcceptingWriters,
+    // Save what we have so far, marked incomplete. Writers stay connected, the replay gets saved
+    // for good once it ends, as usual.
+    SaveNow,
+}
+
 pub struct Replay {
     id: u64,
+    start_time: SystemTime,
     merger: ReplayMerger,
     sender: ReplaySender,
     saver: ReplaySaver,
     replay_timeout_token: CancellationToken,
+    write_phase_token: CancellationToken,
     writer_connection_count: EmptyCounter,
     reader_connection_count: EmptyCounter,
     time_with_zero_writers_to_end_replay: Duration,
     forced_timeout: Duration,
     should_stop_accepting_connections: Cell<bool>,
+    connections: RefCell<HashMap<String, ConnectionInfo>>,
+    budget: LoadBudget,
+    snapshot_requested: Notify,
 }
 
 impl Display for Replay {
@@ -35,29 +91,49 @@ impl Display for Replay {
 }
 
 impl Replay {
-    pub fn new(id: u64, shutdown_token: CancellationToken, config: Settings, saver: ReplaySaver) -> Self {
+    pub fn new(
+        id: u64,
+        game: GameInfo,
+        shutdown_token: CancellationToken,
+        config: Settings,
+        saver: ReplaySaver,
+        budget: LoadBudget,
+    ) -> Self {
         let writer_connection_count = EmptyCounter::new();
         let reader_connection_count = EmptyCounter::new();
         let should_stop_accepting_connections = Cell::new(false);
         let time_with_zero_writers_to_end_replay = config.replay.time_with_zero_writers_to_end_replay_s;
         let forced_timeout = config.replay.forced_timeout_s;
+        let update_interval = config.replay.update_interval_s;
         let replay_timeout_token = shutdown_token.child_token();
+        // Cancelling this disconnects writers and moves on to saving the replay.
+        let write_phase_token = replay_timeout_token.child_token();
 
-        let merger = ReplayMerger::new(replay_timeout_token.clone(), config);
+        let merger = ReplayMerger::new(write_phase_token.clone(), game, config);
         let merged_replay = merger.get_merged_replay();
-        let sender = ReplaySender::new(merged_replay, replay_timeout_token.clone());
+        let sender = ReplaySender::new(
+            merged_replay,
+            replay_timeout_token.clone(),
+            budget.clone(),
+            update_interval,
+        );
 
         Self {
             id,
+            start_time: SystemTime::now(),
             merger,
             sender,
             saver,
             replay_timeout_token,
+            write_phase_token,
             writer_connection_count,
             reader_connection_count,
             time_with_zero_writers_to_end_replay,
             forced_timeout,
             should_stop_accepting_connections,
+            connections: RefCell::new(HashMap::new()),
+            budget,
+            snapshot_requested: Notify::new(),
         }
     }
 
@@ -76,19 +152,54 @@ impl Replay {
         let wait = self
             .writer_connection_count
             .wait_until_empty_for(self.time_with_zero_writers_to_end_replay);
-        cancellable(wait, &self.replay_timeout_token).await;
+        cancellable(wait, &self.write_phase_token).await;
     }
 
-    async fn regular_lifetime(&self) {
-        log::info!("{} started", self);
-        metrics::RUNNING_REPLAYS.inc();
+    async fn merging(&self) {
         self.wait_until_there_were_no_writers_for_a_while().await;
         self.should_stop_accepting_connections.set(true);
         log::debug!("{} stopped accepting connections", self);
         self.writer_connection_count.wait_until_empty().await;
+    }
+
+    // Once we're done merging, the replay's saved for good, so snapshots would only get in the way.
+    // We don't stop one that's being saved, it's finished first.
+    async fn saving_snapshots(&self, done_merging: &CancellationToken) {
+        while cancellable(self.snapshot_requested.notified(), done_merging)
+            .await
+            .is_some()
+        {
+            log::info!("{} saving a snapshot", self);
+            self.saver.save_snapshot(self.merger.get_merged_replay(), self.id).await;
+        }
+    }
+
+    // Merged data is spooled until we're done merging. After that it's saved right away.
+    async fn merging_and_spooling(&self) -> Option<SpoolLock> {
+        let done_merging = CancellationToken::new();
+        let merging = async {
+            self.merging().await;
+            done_merging.cancel();
+        };
+        let spooling = self
+            .saver
+            .spool_replay(self.merger.get_merged_replay(), self.id, done_merging.clone());
+        join!(merging, spooling, self.saving_snapshots(&done_merging)).1
+    }
+
+    async fn regular_lifetime(&self) {
+        log::info!("{} started", self);
+        metrics::RUNNING_REPLAYS.inc();
+        let spool_lock = self.merging_and_spooling().await;
         self.merger.finalize();
         log::debug!("{} finished merging data", self);
-        self.saver.save_replay(self.merger.get_merged_replay(), self.id).await;
+        let report = self.merger.merge_report();
+        log::info!("{} merge report: {}", self, report);
+        self.saver
+            .save_replay(self.merger.get_merged_replay(), self.id, report)
+            .await;
+        // The spool file is gone now, let go of its lock.
+        drop(spool_lock);
         self.reader_connection_count.wait_until_empty().await;
         log::info!("{} ended", self);
         // Cancel to return from timeout
@@ -101,16 +212,83 @@ impl Replay {
         join! {
             self.regular_lifetime(),
             self.timeout(),
+            self.sender.track_load(),
         };
     }
 
+    pub fn info(&self) -> ReplayInfo {
+        let merged_replay = self.merger.get_merged_replay();
+        let merged_replay = merged_replay.borrow();
+        ReplayInfo {
+            id: self.id,
+            start_time: self
+                .start_time
+                .duration_since(UNIX_EPOCH)
+                .map(|d| d.as_secs())
+                .unwrap_or(0),
+            writers: self.writer_connection_count.count(),
+            readers: self.reader_connection_count.count(),
+            received_bytes: merged_replay.header_len() + merged_replay.data_len(),
+            delayed_bytes: merged_replay.header_len() + merged_replay.delayed_data_len(),
+            accepts_connections: !self.should_stop_accepting_connections.get(),
+        }
+    }
+
+    pub fn details(&self) -> ReplayDetails {
+        let mut connections: Vec<_> = self.connections.borrow().values().cloned().collect();
+        connections.sort_by(|a, b| (&a.type_, &a.name, &a.id).cmp(&(&b.type_, &b.name, &b.id)));
+        ReplayDetails {
+            info: self.info(),
+            connections,
+        }
+    }
+
+    pub fn run_command(&self, command: ReplayCommand) {
+        log::info!("{} got admin command {:?}", self, command);
+        match command {
+            ReplayCommand::ForceEnd => self.replay_timeout_token.cancel(),
+            ReplayCommand::StopAcceptingWriters => self.should_stop_accepting_connections.set(true),
+            ReplayCommand::SaveNow => self.snapshot_requested.notify_one(),
+        }
+    }
+
+    fn connection_info(c: &Connection) -> ConnectionInfo {
+        let header = c.get_header();
+        ConnectionInfo {
+            id: c.get_id().into(),
+            type_: header.type_.as_str().into(),
+            name: header.name,
+            peer_addr: c.get_peer_addr().map(|a| a.to_string()),
+        }
+    }
+
     pub async fn handle_connection(&self, mut c: Connection) -> ConnResult<()> {
         log::debug!("{} started handling {}", self, c);
         if self.should_stop_accepting_connections.get() {
             log::info!("{} dropped {} because its write phase is over", self, c);
             return Err(ConnectionError::CannotAssignToReplay);
         }
-        match c.get_header().type_ {
+        let type_ = c.get_header().type_;
+        // Server answers these itself, they never reach a replay.
+        if type_ == ConnectionType::List {
+            return Err(ConnectionError::CannotAssignToReplay);
+        }
+        if type_ == ConnectionType::Reader && !self.budget.can_admit_reader() {
+            log::info!("{} dropped {} because the server is overloaded", self, c);
+            metrics::LOAD_SHEDDING.with_label_values(&["reader_rejected"]).inc();
+            return Err(ConnectionError::Overloaded);
+        }
+        if type_ == ConnectionType::Writer {
+            if let Err(e) = self.merger.admit_writer(&c.get_header()) {
+                log::info!("{} dropped {}: {}", self, c, e);
+                return Err(e);
+            }
+        }
+        let _budgeted = self.budget.track_connection();
+        self.connections
+            .borrow_mut()
+            .insert(c.get_id().into(), Self::connection_info(&c));
+        match type_ {
             ConnectionType::Writer => {
                 self.writer_connection_count.inc();
                 self.merger.handle_connection(&mut c).await;
@@ -121,7 +299,9 @@ impl Replay {
                 self.sender.handle_connection(&mut c).await;
                 self.reader_connection_count.dec();
             }
+            ConnectionType::List => unreachable!(),
         }
+        self.connections.borrow_mut().remove(c.get_id());
         log::debug!("{} finished handling {}", self, c);
         Ok(())
     }
@@ -134,15 +314,26 @@ mod test {
     use tokio::io::{AsyncReadExt, AsyncWriteExt};
 
     use super::*;
+    use crate::database::{database::test::mock_database, queries::Queries};
+    use crate::replay::budget::test::budget;
+    use crate::replay::save::directory::test::test_directory;
     use crate::util::test::sleep_s;
+    use crate::util::timeout::until;
     use crate::{
         accept::header::ConnectionHeader,
-        config::test::default_config,
+        config::{test::default_config, InnerSettings},
         replay::save::InnerReplaySaver,
         server::connection::test::test_connection,
         util::test::{compare_bufs, get_file, setup_logging},
     };
 
+    fn game_info(config: &InnerSettings) -> GameInfo {
+        GameInfo {
+            delay: config.replay.delay_s,
+            host: None,
+        }
+    }
+
     #[tokio::test]
     async fn test_replay_forced_timeout() {
         setup_logging();
@@ -150,20 +341,24 @@ mod test {
 
         let mut mock_saver = InnerReplaySaver::faux();
         faux::when!(mock_saver.save_replay).then(|_| ());
+        faux::when!(mock_saver.spool_replay).then(|_| None);
 
         let token = CancellationToken::new();
         let mut config = default_config();
         config.replay.forced_timeout_s = Duration::from_secs(3600);
 
         let (mut c, _r, _w) = test_connection();
-        let c_header = ConnectionHeader {
-            type_: ConnectionType::Writer,
-            id: 1,
-            name: "foo".into(),
-        };
+        let c_header = ConnectionHeader::new(ConnectionType::Writer, 1, "foo".into());
         c.set_header(c_header);
 
-        let replay = Replay::new(1, token, Arc::new(config), Arc::new(mock_saver));
+        let replay = Replay::new(
+            1,
+            game_info(&config),
+            token,
+            Arc::new(config),
+            Arc::new(mock_saver),
+            budget(None, None),
+        );
 
         let replay_ended = Cell::new(false);
         let run_replay = async {
@@ -192,23 +387,23 @@ mod test {
 
         let mut mock_saver = InnerReplaySaver::faux();
         faux::when!(mock_saver.save_replay).then(|_| ());
+        faux::when!(mock_saver.spool_replay).then(|_| None);
         let token = CancellationToken::new();
         let config = default_config();
 
         let (mut c_read, mut reader, mut _w) = test_connection();
         let (mut c_write, _r, mut writer) = test_connection();
-        c_write.set_header(ConnectionHeader {
-            type_: ConnectionType::Writer,
-            id: 1,
-            name: "foo".into(),
-        });
-        c_read.set_header(ConnectionHeader {
-            type_: ConnectionType::Reader,
-            id: 1,
-            name: "foo".into(),
-        });
-
-        let replay = Replay::new(1, token, Arc::new(config), Arc::new(mock_saver));
+        c_write.set_header(ConnectionHeader::new(ConnectionType::Writer, 1, "foo".into()));
+        c_read.set_header(ConnectionHeader::new(ConnectionType::Reader, 1, "foo".into()));
+
+        let replay = Replay::new(
+            1,
+            game_info(&config),
+            token,
+            Arc::new(config),
+            Arc::new(mock_saver),
+            budget(None, None),
+        );
         let run_replay = async {
             (join! {
                 replay.lifetime(),
@@ -239,6 +434,223 @@ mod test {
         compare_bufs(example_replay_file, received_replay_file);
     }
 
+    #[tokio::test]
+    async fn test_replay_rejects_readers_but_not_writers_when_overloaded() {
+        setup_logging();
+        tokio::time::pause();
+
+        let mut mock_saver = InnerReplaySaver::faux();
+        faux::when!(mock_saver.save_replay).then(|_| ());
+        faux::when!(mock_saver.spool_replay).then(|_| None);
+        let token = CancellationToken::new();
+        let config = default_config();
+        let replay = Replay::new(
+            1,
+            game_info(&config),
+            token,
+            Arc::new(config),
+            Arc::new(mock_saver),
+            budget(Some(1), None),
+        );
+
+        let (mut c1, _r1, w1) = test_connection();
+        let (mut c2, _r2, _w2) = test_connection();
+        let (mut c3, _r3, w3) = test_connection();
+        c1.set_header(ConnectionHeader::new(ConnectionType::Writer, 1, "foo".into()));
+        c2.set_header(ConnectionHeader::new(ConnectionType::Reader, 1, "foo".into()));
+        c3.set_header(ConnectionHeader::new(ConnectionType::Writer, 1, "foo".into()));
+
+        join! {
+            replay.lifetime(),
+            async {
+                replay.handle_connection(c1).await.unwrap();
+            },
+            async {
+                sleep_s(1).await;
+                let res = replay.handle_connection(c2).await;
+                assert!(matches!(res.unwrap_err(), ConnectionError::Overloaded));
+            },
+            async {
+                sleep_s(2).await;
+                replay.handle_connection(c3).await.unwrap();
+            },
+            async {
+                sleep_s(3).await;
+                assert_eq!(replay.writer_connection_count.count(), 2);
+                drop(w1);
+                drop(w3);
+            },
+        };
+    }
+
+    #[tokio::test]
+    async fn test_replay_admits_one_writer_per_player_at_a_time() {
+        setup_logging();
+        tokio::time::pause();
+
+        let mut mock_saver = InnerReplaySaver::faux();
+        faux::when!(mock_saver.save_replay).then(|_| ());
+        faux::when!(mock_saver.spool_replay).then(|_| None);
+        let token = CancellationToken::new();
+        let config = default_config();
+        let replay = Replay::new(
+            1,
+            game_info(&config),
+            token,
+            Arc::new(config),
+            Arc::new(mock_saver),
+            budget(None, None),
+        );
+
+        let player_writer = || {
+            let (mut c, r, w) = test_connection();
+            let mut header = ConnectionHeader::new(ConnectionType::Writer, 1, "foo".into());
+            header.fields.player_id = Some(42);
+            header.fields.authenticated = true;
+            c.set_header(header);
+            (c, r, w)
+        };
+        let (c1, _r1, w1) = player_writer();
+        let (c2, _r2, _w2) = player_writer();
+        let (c3, _r3, w3) = player_writer();
+
+        join! {
+            replay.lifetime(),
+            async {
+                replay.handle_connection(c1).await.unwrap();
+            },
+            async {
+                sleep_s(1).await;
+                let res = replay.handle_connection(c2).await;
+                assert!(matches!(res.unwrap_err(), ConnectionError::DuplicateWriter(42)));
+                drop(w1);
+            },
+            async {
+                sleep_s(2).await;
+                replay.handle_connection(c3).await.unwrap();
+            },
+            async {
+                sleep_s(3).await;
+                assert_eq!(replay.writer_connection_count.count(), 1);
+                drop(w3);
+            },
+        };
+    }
+
+    #[tokio::test]
+    async fn test_replay_drops_slowest_reader_when_overloaded() {
+        setup_logging();
+        tokio::time::pause();
+
+        let mut mock_saver = InnerReplaySaver::faux();
+        faux::when!(mock_saver.save_replay).then(|_| ());
+        faux::when!(mock_saver.spool_replay).then(|_| None);
+        let token = CancellationToken::new();
+        let mut config = default_config();
+        config.replay.delay_s = Duration::from_secs(1);
+        let shedding_interval = config.replay.update_interval_s;
+        let b = budget(None, Some(20000));
+        let replay = Replay::new(
+            1,
+            game_info(&config),
+            token,
+            Arc::new(config),
+            Arc::new(mock_saver),
+            b.clone(),
+        );
+
+        let (mut c_write, _r, mut writer) = test_connection();
+        let (mut c_fast, mut fast_reader, _w1) = test_connection();
+        let (mut c_slow, mut slow_reader, _w2) = test_connection();
+        c_write.set_header(ConnectionHeader::new(ConnectionType::Writer, 1, "foo".into()));
+        c_fast.set_header(ConnectionHeader::new(ConnectionType::Reader, 1, "foo".into()));
+        c_slow.set_header(ConnectionHeader::new(ConnectionType::Reader, 1, "foo".into()));
+
+        let example_replay_file = get_file("example");
+        let mut fast_data = Vec::new();
+        let mut slow_data = Vec::new();
+        join! {
+            until(replay.lifetime(), b.shed_load(shedding_interval)),
+            async { replay.handle_connection(c_write).await.unwrap() },
+            async { replay.handle_connection(c_fast).await.unwrap() },
+            async { replay.handle_connection(c_slow).await.unwrap() },
+            async {
+                for data in example_replay_file.chunks(100) {
+                    writer.write_all(data).await.unwrap();
+                    tokio::time::sleep(Duration::from_millis(20)).await;
+                }
+                drop(writer);
+            },
+            async { fast_reader.read_to_end(&mut fast_data).await.unwrap(); },
+            async {
+                // Don't read anything until the writer is done.
+                sleep_s(15).await;
+                slow_reader.read_to_end(&mut slow_data).await.unwrap();
+            },
+        };
+        compare_bufs(&example_replay_file, &fast_data);
+        assert!(slow_data.len() < example_replay_file.len());
+    }
+
+    #[tokio::test]
+    async fn test_replay_keeps_late_reader_that_catches_up() {
+        setup_logging();
+        tokio::time::pause();
+
+        let mut mock_saver = InnerReplaySaver::faux();
+        faux::when!(mock_saver.save_replay).then(|_| ());
+        faux::when!(mock_saver.spool_replay).then(|_| None);
+        let token = CancellationToken::new();
+        let mut config = default_config();
+        config.replay.delay_s = Duration::from_secs(1);
+        let shedding_interval = config.replay.update_interval_s;
+        // Way less than the whole replay, which the reader starts out behind by.
+        let b = budget(None, Some(2000));
+        let replay = Replay::new(
+            1,
+            game_info(&config),
+            token,
+            Arc::new(config),
+            Arc::new(mock_saver),
+            b.clone(),
+        );
+
+        let (mut c_write, _r, mut writer) = test_connection();
+        let (mut c_read, mut reader, _w) = test_connection();
+        c_write.set_header(ConnectionHeader::new(ConnectionType::Writer, 1, "foo".into()));
+        c_read.set_header(ConnectionHeader::new(ConnectionType::Reader, 1, "foo".into()));
+
+        let example_replay_file = get_file("example");
+        let mut data = Vec::new();
+        join! {
+            until(replay.lifetime(), b.shed_load(shedding_interval)),
+            async { replay.handle_connection(c_write).await.unwrap() },
+            async {
+                writer.write_all(&example_replay_file).await.unwrap();
+                sleep_s(15).await;
+                drop(writer);
+            },
+            async {
+                sleep_s(5).await;
+                replay.handle_connection(c_read).await.unwrap();
+            },
+            async {
+                sleep_s(5).await;
+                // Slow enough to take a few shedding intervals to catch up.
+                let mut buf = vec![0; 5000];
+                loop {
+                    let read = reader.read(&mut buf).await.unwrap();
+                    if read == 0 {
+                        break;
+                    }
+                    data.extend_from_slice(&buf[..read]);
+                    tokio::time::sleep(Duration::from_millis(500)).await;
+                }
+            },
+        };
+        compare_bufs(&example_replay_file, &data);
+    }
+
     #[tokio::test]
     async fn test_replay_stops_accepting_connections() {
         setup_logging();
@@ -246,35 +658,27 @@ mod test {
 
         let mut mock_saver = InnerReplaySaver::faux();
         faux::when!(mock_saver.save_replay).then(|_| ());
+        faux::when!(mock_saver.spool_replay).then(|_| None);
         let token = CancellationToken::new();
         let mut config = default_config();
         config.replay.time_with_zero_writers_to_end_replay_s = Duration::from_secs(2);
-        let replay = Replay::new(1, token, Arc::new(config), Arc::new(mock_saver));
+        let replay = Replay::new(
+            1,
+            game_info(&config),
+            token,
+            Arc::new(config),
+            Arc::new(mock_saver),
+            budget(None, None),
+        );
 
         let (mut c1, _r1, mut w1) = test_connection();
         let (mut c2, mut r2, w2) = test_connection();
         let (mut c3, _r3, w3) = test_connection();
         let (mut c4, mut r4, w4) = test_connection();
-        c1.set_header(ConnectionHeader {
-            type_: ConnectionType::Writer,
-            id: 1,
-            name: "foo".into(),
-        });
-        c2.set_header(ConnectionHeader {
-            type_: ConnectionType::Reader,
-            id: 1,
-            name: "foo".into(),
-        });
-        c3.set_header(ConnectionHeader {
-            type_: ConnectionType::Writer,
-            id: 1,
-            name: "foo".into(),
-        });
-        c4.set_header(ConnectionHeader {
-            type_: ConnectionType::Reader,
-            id: 1,
-            name: "foo".into(),
-        });
+        c1.set_header(ConnectionHeader::new(ConnectionType::Writer, 1, "foo".into()));
+        c2.set_header(ConnectionHeader::new(ConnectionType::Reader, 1, "foo".into()));
+        c3.set_header(ConnectionHeader::new(ConnectionType::Writer, 1, "foo".into()));
+        c4.set_header(ConnectionHeader::new(ConnectionType::Reader, 1, "foo".into()));
 
         let example_replay_file = get_file("example");
         let replay_is_over = std::cell::Cell::new(false);
@@ -311,12 +715,19 @@ mod test {
                     sleep_s(6).await;
                     assert_eq!(replay.writer_connection_count.count(), 1);
                     assert_eq!(replay.reader_connection_count.count(), 1);
+                    let info = replay.info();
+                    assert_eq!((info.id, info.writers, info.readers), (1, 1, 1));
+                    assert!(info.accepts_connections);
                 },
                 async {sleep_s(7).await; w1.write_all(&example_replay_file).await.unwrap(); drop(w1);},
                 async {
                     sleep_s(10).await;
                     assert_eq!(replay.writer_connection_count.count(), 0);
                     assert_eq!(replay.reader_connection_count.count(), 1);
+                    let info = replay.info();
+                    assert_eq!((info.writers, info.readers), (0, 1));
+                    assert!(!info.accepts_connections);
+                    assert_eq!(info.received_bytes, example_replay_file.len());
                 },
                 async {
                     sleep_s(15).await;
@@ -364,4 +775,59 @@ mod test {
             events,
         };
     }
+
+    #[tokio::test]
+    async fn test_replay_spools_merged_data() {
+        setup_logging();
+        tokio::time::pause();
+
+        let spool_dir = tempfile::tempdir().unwrap();
+        let mut config = default_config();
+        config.storage.spool_path = Some(spool_dir.path().to_str().unwrap().into());
+        config.replay.delay_s = Duration::from_secs(0);
+        let config = Arc::new(config);
+        let db = Arc::new(Queries::new(mock_database()));
+        let saver = InnerReplaySaver::new(db, test_directory(), &config);
+        let replay = Replay::new(
+            2,
+            game_info(&config),
+            CancellationToken::new(),
+            config,
+            saver,
+            budget(None, None),
+        );
+
+        let (mut c, _r, mut writer) = test_connection();
+        c.set_header(ConnectionHeader::new(ConnectionType::Writer, 2, "foo".into()));
+        let example_replay_file = get_file("example");
+        let example_body = get_file("example_body");
+        let header_len = example_replay_file.len() - example_body.len();
+        let mut expected = (header_len as u32).to_le_bytes().to_vec();
+        expected.extend_from_slice(&example_replay_file);
+        let spool_path = spool_dir.path().join("2.spool");
+
+        join! {
+            replay.lifetime(),
+            async {
+                replay.handle_connection(c).await.unwrap();
+            },
+            async {
+                writer.write_all(&example_replay_file).await.unwrap();
+                // Spool files are written on blocking threads, so we can't tell how much paused
+                // time passes until they are.
+                let mut spooled = Vec::new();
+                for _ in 0..100 {
+                    sleep_s(1).await;
+                    spooled = tokio::fs::read(&spool_path).await.unwrap_or_default();
+                    if spooled.len() == expected.len() {
+                        break;
+                    }
+                }
+                compare_bufs(&expected, spooled);
+                drop(writer);
+            },
+        };
+        // Saved replays don't need to be spooled anymore.
+        assert!(!spool_path.exists());
+    }
 }
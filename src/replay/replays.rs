@@ -1,68 +1,529 @@
+use std::cell::RefCell;
+use std::collections::HashMap;
 use std::rc::{Rc, Weak};
 
-use futures::{stream, Stream, StreamExt};
+use futures::{future, stream, Stream, StreamExt};
+use tokio::join;
+use tokio::sync::mpsc::{unbounded_channel, UnboundedSender};
+use tokio::sync::oneshot;
+use tokio::time::Duration;
+use tokio_stream::wrappers::UnboundedReceiverStream;
 use tokio_util::sync::CancellationToken;
 use weak_table::WeakValueHashMap;
 
-use super::{save::ReplaySaver, Replay};
+use super::{
+    budget::LoadBudget,
+    receive::{GameInfo, GameLookup},
+    save::ReplaySaver,
+    send::send_saved_replay,
+};
+use super::{Replay, ReplayCommand, ReplayDetails, ReplayInfo};
 use crate::error::ConnectionError;
+use crate::util::timeout::{cancellable, timeout};
 use crate::{accept::header::ConnectionType, metrics};
 use crate::{config::Settings, server::connection::Connection};
 
+// Things a worker thread can be asked about its replays. It answers for as long as it runs.
+pub enum ReplayQuery {
+    ListReplays(oneshot::Sender<Vec<ReplayInfo>>),
+    ReplayDetails(u64, oneshot::Sender<Option<ReplayDetails>>),
+    // Answers whether the replay was running.
+    Command(u64, ReplayCommand, oneshot::Sender<bool>),
+}
+
+type ReplayMap = WeakValueHashMap<u64, Weak<Replay>>;
+
+enum Message {
+    Connection(Box<Connection>),
+    // We know what we need about a replay's game, so we can start it.
+    StartReplay(u64, GameInfo),
+    // Server won't send us anything else.
+    WorkerClosed,
+}
+
 enum Assignment {
     Connection(Connection, Rc<Replay>),
     NewReplay(Rc<Replay>),
+    LookUpGame(u64, GameLookup, UnboundedSender<Message>),
+    // Replay might be saved already, or we might wait for it to start.
+    ReaderWithoutReplay(
+        Connection,
+        ReplaySaver,
+        Option<(oneshot::Receiver<Rc<Replay>>, Duration)>,
+        CancellationToken,
+    ),
 }
 
 pub struct Replays {
-    replays: WeakValueHashMap<u64, Weak<Replay>>,
-    new_replay: Box<dyn Fn(u64) -> Replay>,
+    // Shared with whoever answers queries.
+    replays: Rc<RefCell<ReplayMap>>,
+    // Readers waiting for a replay to start. Their senders get closed when they give up.
+    waiting_readers: HashMap<u64, Vec<oneshot::Sender<Rc<Replay>>>>,
+    reader_wait_for_replay: Option<Duration>,
+    // Replays we're looking up the game of, with connections that arrived in the meantime.
+    starting_replays: HashMap<u64, Vec<Connection>>,
+    games: GameLookup,
+    // Set while we handle messages.
+    start_replay: Option<UnboundedSender<Message>>,
+    new_replay: Box<dyn Fn(u64, GameInfo) -> Replay>,
+    saver: ReplaySaver,
+    budget: LoadBudget,
+    shutdown_token: CancellationToken,
 }
 
 impl Replays {
-    pub fn new(shutdown_token: CancellationToken, config: Settings, saver: ReplaySaver) -> Self {
-        let replay_builder = move |rid| Replay::new(rid, shutdown_token.clone(), config.clone(), saver.clone());
+    pub fn new(
+        shutdown_token: CancellationToken,
+        config: Settings,
+        saver: ReplaySaver,
+        budget: LoadBudget,
+        games: GameLookup,
+    ) -> Self {
+        let reader_wait_for_replay = config.replay.reader_wait_for_replay_s;
+        let replay_budget = budget.clone();
+        let replay_saver = saver.clone();
+        let replay_shutdown_token = shutdown_token.clone();
+        let replay_builder = move |rid, game| {
+            Replay::new(
+                rid,
+                game,
+                replay_shutdown_token.clone(),
+                config.clone(),
+                replay_saver.clone(),
+                replay_budget.clone(),
+            )
+        };
         Self {
-            replays: WeakValueHashMap::new(),
+            replays: Rc::new(RefCell::new(WeakValueHashMap::new())),
+            waiting_readers: HashMap::new(),
+            reader_wait_for_replay,
+            starting_replays: HashMap::new(),
+            games,
+            start_replay: None,
             new_replay: Box::new(replay_builder),
+            saver,
+            budget,
+            shutdown_token,
         }
     }
 
     fn assign_connection_to_replay(&mut self, c: Connection) -> Vec<Assignment> {
         let conn_header = c.get_header();
-        let mut assignments = vec![];
 
-        let replay = match self.replays.get(&conn_header.id) {
-            Some(r) => r,
-            None => {
-                if conn_header.type_ == ConnectionType::Reader {
-                    log::info!("{} asked for replay {}, which is not running", c, conn_header.id);
-                    metrics::inc_served_conns(Some(ConnectionError::CannotAssignToReplay));
-                    return vec![];
+        if conn_header.type_ == ConnectionType::Reader && !self.budget.can_admit_reader() {
+            log::info!("{} rejected, server is overloaded", c);
+            metrics::LOAD_SHEDDING.with_label_values(&["reader_rejected"]).inc();
+            metrics::inc_served_conns(Some(ConnectionError::Overloaded));
+            return vec![];
+        }
+
+        let replay = self.replays.borrow().get(&conn_header.id);
+        if let Some(r) = replay {
+            return vec![Assignment::Connection(c, r)];
+        }
+        if let Some(conns) = self.starting_replays.get_mut(&conn_header.id) {
+            conns.push(c);
+            return vec![];
+        }
+        if conn_header.type_ == ConnectionType::Reader {
+            return self.reader_without_replay(c, conn_header.id);
+        }
+        self.starting_replays.insert(conn_header.id, vec![c]);
+        // We always have a sender while handling connections.
+        let start_replay = self.start_replay.clone().unwrap();
+        vec![Assignment::LookUpGame(conn_header.id, self.games.clone(), start_replay)]
+    }
+
+    fn start_replay(&mut self, id: u64, game: GameInfo) -> Vec<Assignment> {
+        let r = Rc::new((self.new_replay)(id, game));
+        self.replays.borrow_mut().insert(id, r.clone());
+        for waiter in self.waiting_readers.remove(&id).unwrap_or_default() {
+            waiter.send(r.clone()).ok();
+        }
+        let mut assignments = vec![Assignment::NewReplay(r.clone())];
+        for c in self.starting_replays.remove(&id).unwrap_or_default() {
+            assignments.push(Assignment::Connection(c, r.clone()));
+        }
+        assignments
+    }
+
+    fn reader_without_replay(&mut self, c: Connection, id: u64) -> Vec<Assignment> {
+        let waiting = self.reader_wait_for_replay.map(|wait| {
+            // Good time to forget readers that gave up.
+            self.waiting_readers.retain(|_, waiters| {
+                waiters.retain(|w| !w.is_closed());
+                !waiters.is_empty()
+            });
+            let (s, r) = oneshot::channel();
+            self.waiting_readers.entry(id).or_default().push(s);
+            (r, wait)
+        });
+        vec![Assignment::ReaderWithoutReplay(
+            c,
+            self.saver.clone(),
+            waiting,
+            self.shutdown_token.clone(),
+        )]
+    }
+
+    fn handle_message(&mut self, m: Message) -> Vec<Assignment> {
+        match m {
+            Message::Connection(c) => self.assign_connection_to_replay(*c),
+            Message::StartReplay(id, game) => self.start_replay(id, game),
+            Message::WorkerClosed => {
+                // Once replays we're starting start, there'll be no more messages.
+                self.start_replay = None;
+                vec![]
+            }
+        }
+    }
+
+    fn answer_query(replays: &ReplayMap, q: ReplayQuery) {
+        // Askers might have given up, that's fine.
+        match q {
+            ReplayQuery::ListReplays(s) => {
+                s.send(replays.values().map(|r| r.info()).collect()).ok();
+            }
+            ReplayQuery::ReplayDetails(id, s) => {
+                s.send(replays.get(&id).map(|r| r.details())).ok();
+            }
+            ReplayQuery::Command(id, command, s) => {
+                let replay = replays.get(&id);
+                if let Some(r) = &replay {
+                    r.run_command(command);
                 }
-                let r = Rc::new((self.new_replay)(conn_header.id));
-                self.replays.insert(conn_header.id, r.clone());
-                assignments.push(Assignment::NewReplay(r.clone()));
-                r
+                s.send(replay.is_some()).ok();
+            }
+        }
+    }
+
+    async fn handle_reader_without_replay(
+        mut c: Connection,
+        saver: ReplaySaver,
+        waiting: Option<(oneshot::Receiver<Rc<Replay>>, Duration)>,
+        shutdown_token: CancellationToken,
+    ) {
+        let id = c.get_header().id;
+        match saver.open_saved_replay(id).await {
+            Ok(saved) => {
+                log::debug!("{} gets replay {} from the vault", c, id);
+                send_saved_replay(&mut c, saved).await;
+                metrics::inc_served_conns(None);
+                return;
+            }
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => {
+                log::info!("Failed to open saved replay {}: {}", id, e);
+            }
+            Err(_) => (),
+        }
+        let replay = match waiting {
+            Some((replay, wait)) => {
+                log::debug!("{} is waiting for replay {} to start", c, id);
+                cancellable(timeout(replay, wait), &shutdown_token)
+                    .await
+                    .flatten()
+                    .and_then(|r| r.ok())
             }
+            None => None,
         };
-        assignments.push(Assignment::Connection(c, replay));
-        assignments
+        match replay {
+            Some(r) => {
+                let res = r.handle_connection(c).await;
+                metrics::inc_served_conns(res.err());
+            }
+            None => {
+                log::info!("{} asked for replay {}, which is not running", c, id);
+                metrics::inc_served_conns(Some(ConnectionError::CannotAssignToReplay));
+            }
+        }
     }
 
     async fn handle_connection_or_replay_lifetime(a: Assignment) {
         match a {
             Assignment::NewReplay(r) => r.lifetime().await,
+            Assignment::LookUpGame(id, games, start_replay) => {
+                let game = games.resolve(id).await;
+                start_replay.send(Message::StartReplay(id, game)).ok();
+            }
             Assignment::Connection(c, r) => {
                 let res = r.handle_connection(c).await;
                 metrics::inc_served_conns(res.err());
             }
+            Assignment::ReaderWithoutReplay(c, saver, waiting, shutdown_token) => {
+                Self::handle_reader_without_replay(c, saver, waiting, shutdown_token).await
+            }
         }
     }
 
-    pub async fn handle_connections_and_replays(&mut self, cs: impl Stream<Item = Connection>) {
-        cs.flat_map(|c| stream::iter(self.assign_connection_to_replay(c)))
-            .for_each_concurrent(None, Self::handle_connection_or_replay_lifetime)
-            .await
+    // Returns once the server closes its connection stream and we're done with our replays. We
+    // answer queries until then.
+    pub async fn handle_connections_and_replays(
+        &mut self,
+        cs: impl Stream<Item = Connection>,
+        queries: impl Stream<Item = ReplayQuery>,
+    ) {
+        let (start_replay, replays_to_start) = unbounded_channel();
+        self.start_replay = Some(start_replay);
+        let from_server = cs
+            .map(|c| Message::Connection(Box::new(c)))
+            .chain(stream::once(async { Message::WorkerClosed }));
+        let from_us = UnboundedReceiverStream::new(replays_to_start);
+
+        let done = CancellationToken::new();
+        let replays = self.replays.clone();
+        let answer_queries = queries.take_until(done.cancelled()).for_each(|q| {
+            Self::answer_query(&replays.borrow(), q);
+            future::ready(())
+        });
+        let work = async {
+            stream::select(from_server, from_us)
+                .flat_map(|m| stream::iter(self.handle_message(m)))
+                .for_each_concurrent(None, Self::handle_connection_or_replay_lifetime)
+                .await;
+            done.cancel();
+        };
+        join!(work, answer_queries);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::Arc;
+
+    use async_stream::stream;
+    use tokio::io::{AsyncRead, AsyncReadExt, AsyncWriteExt};
+    use tokio::select;
+    use tokio::time::Instant;
+
+    use super::*;
+    use crate::accept::header::{ConnectionHeader, ReaderClass};
+    use crate::config::{test::default_config, InnerSettings};
+    use crate::database::{
+        database::{Database, GameCategoryRow},
+        queries::Queries,
+    };
+    use crate::replay::budget::test::budget;
+    use crate::replay::save::InnerReplaySaver;
+    use crate::server::connection::test::{test_connection, MockConnection};
+    use crate::util::test::{compare_bufs, get_file, setup_logging, sleep_ms, sleep_s};
+
+    fn test_replays(config: InnerSettings, saver: InnerReplaySaver, db: Database) -> Replays {
+        let config = Arc::new(config);
+        let games = GameLookup::new(Arc::new(Queries::new(db)), &config);
+        Replays::new(
+            CancellationToken::new(),
+            config,
+            Arc::new(saver),
+            budget(None, None),
+            games,
+        )
+    }
+
+    fn connection(type_: ConnectionType, id: u64) -> MockConnection {
+        let (mut c, r, w) = test_connection();
+        c.set_header(ConnectionHeader::new(type_, id, "foo".into()));
+        (c, r, w)
+    }
+
+    #[tokio::test]
+    async fn test_replays_serve_saved_replays_to_readers() {
+        setup_logging();
+        tokio::time::pause();
+
+        let mut saver = InnerReplaySaver::faux();
+        faux::when!(saver.open_saved_replay).then(|id| match id {
+            2 => Ok(Box::new(&b"foobarbaz"[..]) as Box<dyn AsyncRead + Unpin>),
+            _ => Err(std::io::ErrorKind::NotFound.into()),
+        });
+        let mut replays = test_replays(default_config(), saver, Database::faux());
+
+        let (c_saved, mut saved_reader, _w1) = connection(ConnectionType::Reader, 2);
+        let (c_missing, mut missing_reader, _w2) = connection(ConnectionType::Reader, 3);
+        replays
+            .handle_connections_and_replays(stream::iter(vec![c_saved, c_missing]), stream::pending())
+            .await;
+
+        let mut saved = Vec::new();
+        saved_reader.read_to_end(&mut saved).await.unwrap();
+        assert_eq!(saved, b"foobarbaz");
+        // Replay 3 is neither running nor saved, so its reader gets nothing.
+        let mut missing = Vec::new();
+        missing_reader.read_to_end(&mut missing).await.unwrap();
+        assert!(missing.is_empty());
+    }
+
+    fn saver_without_saved_replays() -> InnerReplaySaver {
+        let mut saver = InnerReplaySaver::faux();
+        faux::when!(saver.open_saved_replay).then(|_| Err(std::io::ErrorKind::NotFound.into()));
+        faux::when!(saver.save_replay).then(|_| ());
+        faux::when!(saver.spool_replay).then(|_| None);
+        saver
+    }
+
+    #[tokio::test]
+    async fn test_replays_readers_wait_for_replay_to_start() {
+        setup_logging();
+        tokio::time::pause();
+
+        let mut config = default_config();
+        config.replay.time_with_zero_writers_to_end_replay_s = Duration::from_millis(100);
+        config.replay.delay_s = Duration::from_secs(0);
+        config.replay.reader_wait_for_replay_s = Some(Duration::from_millis(500));
+        let mut replays = test_replays(config, saver_without_saved_replays(), Database::faux());
+
+        let (c_early_read, mut early_reader, _w1) = connection(ConnectionType::Reader, 2);
+        let (c_lonely_read, mut lonely_reader, _w2) = connection(ConnectionType::Reader, 3);
+        let (c_write, _r, mut writer) = connection(ConnectionType::Writer, 2);
+        let conns = stream! {
+            yield c_early_read;
+            yield c_lonely_read;
+            sleep_ms(200).await;
+            yield c_write;
+        };
+
+        let example_replay_file = get_file("example");
+        let replay_writing = async {
+            sleep_ms(200).await;
+            writer.write_all(&example_replay_file).await.unwrap();
+            drop(writer);
+        };
+        let mut received_replay_file = Vec::new();
+        let replay_reading = async {
+            early_reader.read_to_end(&mut received_replay_file).await.unwrap();
+        };
+        // Replay 3 never starts, so this reader gets dropped after waiting.
+        let start = Instant::now();
+        let mut lonely_received = Vec::new();
+        let lonely_reading = async {
+            lonely_reader.read_to_end(&mut lonely_received).await.unwrap();
+            assert!(start.elapsed() >= Duration::from_millis(500));
+        };
+
+        join! {
+            replays.handle_connections_and_replays(conns, stream::pending()),
+            replay_writing,
+            replay_reading,
+            lonely_reading,
+        };
+        compare_bufs(example_replay_file, received_replay_file);
+        assert!(lonely_received.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_replays_shutdown_does_not_wait_for_waiting_readers() {
+        setup_logging();
+        tokio::time::pause();
+
+        let mut config = default_config();
+        config.replay.reader_wait_for_replay_s = Some(Duration::from_secs(10));
+        let mut replays = test_replays(config, saver_without_saved_replays(), Database::faux());
+        let token = replays.shutdown_token.clone();
+
+        let (c_read, mut reader, _w) = connection(ConnectionType::Reader, 3);
+        let start = Instant::now();
+        let cancel_early = async {
+            sleep_ms(100).await;
+            token.cancel();
+        };
+        let mut received = Vec::new();
+        join! {
+            replays.handle_connections_and_replays(stream::iter(vec![c_read]), stream::pending()),
+            cancel_early,
+            async { reader.read_to_end(&mut received).await.unwrap() },
+        };
+        assert!(
+            start.elapsed() < Duration::from_secs(1),
+            "Replays should not wait for readers of replays that aren't running"
+        );
+        assert!(received.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_replays_use_per_game_delay() {
+        setup_logging();
+        tokio::time::pause();
+
+        let mut config = default_config();
+        config.replay.delay_s = Duration::from_secs(300);
+        config
+            .replay
+            .matchmaker_queue_delays_s
+            .insert("ladder1v1".into(), Duration::ZERO);
+        let mut db = Database::faux();
+        faux::when!(db.get_game_category).then(|_| {
+            Ok(Some(GameCategoryRow {
+                matchmaker_queue: Some("ladder1v1".into()),
+                featured_mod: Some("ladder1v1".into()),
+            }))
+        });
+        let mut replays = test_replays(config, saver_without_saved_replays(), db);
+
+        let (c_write, _r, mut writer) = connection(ConnectionType::Writer, 2);
+        let (c_read, mut reader, _w) = connection(ConnectionType::Reader, 2);
+        let conns = stream! {
+            yield c_write;
+            sleep_ms(100).await;
+            yield c_read;
+        };
+
+        let example_replay_file = get_file("example");
+        let replay_writing = async {
+            writer.write_all(&example_replay_file).await.unwrap();
+            std::future::pending::<()>().await;
+        };
+        // Game has no delay, so we get everything while it's still going.
+        let mut received_replay_file = vec![0; example_replay_file.len()];
+        let replay_reading = async {
+            reader.read_exact(&mut received_replay_file).await.unwrap();
+        };
+        select! {
+            _ = replays.handle_connections_and_replays(conns, stream::pending()) => (),
+            _ = replay_writing => (),
+            _ = replay_reading => (),
+            _ = sleep_s(5) => panic!("Replay data should not be delayed"),
+        }
+        compare_bufs(example_replay_file, received_replay_file);
+    }
+
+    #[tokio::test]
+    async fn test_replays_privileged_readers_skip_delay() {
+        setup_logging();
+        tokio::time::pause();
+
+        let mut config = default_config();
+        config.replay.delay_s = Duration::from_secs(300);
+        config.server.privileged_reader_token_secret = Some("banana".into());
+        config.replay.privileged_delay_s = Some(Duration::ZERO);
+        let mut replays = test_replays(config, saver_without_saved_replays(), Database::faux());
+
+        let (c_write, _r, mut writer) = connection(ConnectionType::Writer, 2);
+        // Token is checked when we read the header, so we just say it checked out.
+        let (mut c_read, mut reader, _w) = connection(ConnectionType::Reader, 2);
+        let mut header = c_read.get_header();
+        header.fields.reader_class = ReaderClass::Privileged;
+        c_read.set_header(header);
+        let conns = stream! {
+            yield c_write;
+            sleep_ms(100).await;
+            yield c_read;
+        };
+
+        let example_replay_file = get_file("example");
+        let replay_writing = async {
+            writer.write_all(&example_replay_file).await.unwrap();
+            std::future::pending::<()>().await;
+        };
+        // No privileged delay, so we get everything while the game is still going.
+        let mut received_replay_file = vec![0; example_replay_file.len()];
+        let replay_reading = async {
+            reader.read_exact(&mut received_replay_file).await.unwrap();
+        };
+        select! {
+            _ = replays.handle_connections_and_replays(conns, stream::pending()) => (),
+            _ = replay_writing => (),
+            _ = replay_reading => (),
+            _ = sleep_s(5) => panic!("Privileged reader should not be delayed"),
+        }
+        compare_bufs(example_replay_file, received_replay_file);
     }
 }
@@ -1,28 +1,51 @@
 use std::thread;
 use std::thread::JoinHandle;
 use tokio::sync::mpsc::{channel, Sender};
+use tokio::sync::oneshot;
 
 use tokio::sync::mpsc::Receiver;
 use tokio_stream::wrappers::ReceiverStream;
 use tokio_util::sync::CancellationToken;
 
-use crate::{config::Settings, replay::save::ReplaySaver, replay::Replays, server::connection::Connection};
+use crate::{
+    config::Settings,
+    replay::budget::{InnerLoadBudget, LoadBudget},
+    replay::receive::GameLookup,
+    replay::save::ReplaySaver,
+    replay::{ReplayCommand, ReplayDetails, ReplayInfo, ReplayQuery, Replays},
+    server::connection::Connection,
+};
+
+fn worker_index(id: u64, worker_count: usize) -> usize {
+    (id % worker_count as u64) as usize
+}
 
 fn handle_replays(
     config: Settings,
     shutdown_token: CancellationToken,
     saver: ReplaySaver,
-) -> impl FnOnce(Receiver<Connection>) + Clone + Send {
-    move |s| {
-        let mut replays = Replays::new(shutdown_token, config, saver);
+    budget: LoadBudget,
+    games: GameLookup,
+) -> impl FnOnce(Receiver<Connection>, Receiver<ReplayQuery>, usize) + Clone + Send {
+    move |s, queries, index| {
+        let worker_count = config.server.worker_threads as usize;
+        let mut replays = Replays::new(shutdown_token, config, saver.clone(), budget, games);
         let wrapper = ReceiverStream::new(s);
+        let queries = ReceiverStream::new(queries);
 
         let local_loop = tokio::runtime::Builder::new_current_thread()
             .enable_all()
             .build()
             .unwrap();
         local_loop.block_on(async {
-            replays.handle_connections_and_replays(wrapper).await;
+            // Save our replays left over from a crash first. If their games are still going, new
+            // connections for them would start spooling over them.
+            for id in saver.spooled_replays().await {
+                if worker_index(id, worker_count) == index {
+                    saver.recover_spooled_replay(id).await;
+                }
+            }
+            replays.handle_connections_and_replays(wrapper, queries).await;
         });
     }
 }
@@ -30,15 +53,22 @@ fn handle_replays(
 struct WorkerThread {
     handle: Option<JoinHandle<()>>,
     channel: Sender<Connection>,
+    // Closing this one doesn't stop the worker, so it's fine to hand out.
+    queries: Sender<ReplayQuery>,
 }
 
 impl WorkerThread {
-    fn new(work: impl FnOnce(Receiver<Connection>) + Send + 'static) -> Self {
+    fn new(
+        work: impl FnOnce(Receiver<Connection>, Receiver<ReplayQuery>, usize) + Send + 'static,
+        index: usize,
+    ) -> Self {
         let (s, r) = channel(1);
-        let handle = thread::spawn(move || work(r));
+        let (qs, qr) = channel(1);
+        let handle = thread::spawn(move || work(r, qr, index));
         Self {
             handle: Some(handle),
             channel: s,
+            queries: qs,
         }
     }
 
@@ -57,25 +87,42 @@ impl WorkerThread {
 
 pub struct ReplayRunner {
     replay_workers: Vec<WorkerThread>,
+    budget: LoadBudget,
 }
 
 // Distributes replay IDs among worker threads and gives them connections to handle.
 impl ReplayRunner {
-    pub fn new(config: Settings, shutdown_token: CancellationToken, saver: ReplaySaver) -> Self {
+    pub fn new(config: Settings, shutdown_token: CancellationToken, saver: ReplaySaver, games: GameLookup) -> Self {
         let count = config.server.worker_threads;
-        let handle_some_replays = handle_replays(config, shutdown_token, saver);
+        let budget = InnerLoadBudget::new(&config.server);
+        let handle_some_replays = handle_replays(config, shutdown_token, saver, budget.clone(), games);
         let mut replay_workers = Vec::new();
-        for _ in 0..count {
-            let worker = WorkerThread::new(handle_some_replays.clone());
+        for index in 0..count as usize {
+            let worker = WorkerThread::new(handle_some_replays.clone(), index);
             replay_workers.push(worker);
         }
-        Self { replay_workers }
+        Self { replay_workers, budget }
+    }
+
+    fn worker_for_replay(&self, id: u64) -> &WorkerThread {
+        &self.replay_workers[worker_index(id, self.replay_workers.len())]
     }
 
     pub async fn dispatch_connection(&self, conn: Connection) {
         let conn_info = conn.get_header();
-        let worker_to_pick = (conn_info.id % self.replay_workers.len() as u64) as usize;
-        self.replay_workers[worker_to_pick].dispatch(conn).await;
+        self.worker_for_replay(conn_info.id).dispatch(conn).await;
+    }
+
+    // Unlike the runner, this can outlive the workers.
+    pub fn inspector(&self) -> ReplayInspector {
+        ReplayInspector {
+            workers: self.replay_workers.iter().map(|w| w.queries.clone()).collect(),
+        }
+    }
+
+    // Shared by all workers.
+    pub fn budget(&self) -> LoadBudget {
+        self.budget.clone()
     }
 
     pub fn shutdown(self) {
@@ -84,3 +131,46 @@ impl ReplayRunner {
         }
     }
 }
+
+// Asks workers about their replays. Workers answer until they exit, including while they drain.
+// A worker that's gone has no replays left to tell us about.
+#[derive(Clone)]
+pub struct ReplayInspector {
+    workers: Vec<Sender<ReplayQuery>>,
+}
+
+impl ReplayInspector {
+    fn worker_for_replay(&self, id: u64) -> &Sender<ReplayQuery> {
+        &self.workers[worker_index(id, self.workers.len())]
+    }
+
+    // If the worker is gone, the query is dropped along with its answer's sender.
+    async fn ask(worker: &Sender<ReplayQuery>, q: ReplayQuery) {
+        worker.send(q).await.ok();
+    }
+
+    // Asks every worker in turn. Replays can start or end while we're asking, that's fine.
+    pub async fn list_replays(&self) -> Vec<ReplayInfo> {
+        let mut replays = Vec::new();
+        for worker in self.workers.iter() {
+            let (s, r) = oneshot::channel();
+            Self::ask(worker, ReplayQuery::ListReplays(s)).await;
+            replays.extend(r.await.unwrap_or_default());
+        }
+        replays.sort_by_key(|r| r.id);
+        replays
+    }
+
+    pub async fn replay_details(&self, id: u64) -> Option<ReplayDetails> {
+        let (s, r) = oneshot::channel();
+        Self::ask(self.worker_for_replay(id), ReplayQuery::ReplayDetails(id, s)).await;
+        r.await.ok().flatten()
+    }
+
+    // Returns false if the replay is not running.
+    pub async fn run_replay_command(&self, id: u64, command: ReplayCommand) -> bool {
+        let (s, r) = oneshot::channel();
+        Self::ask(self.worker_for_replay(id), ReplayQuery::Command(id, command, s)).await;
+        r.await.unwrap_or(false)
+    }
+}
@@ -1,6 +1,6 @@
 use std::path::PathBuf;
 
-use tokio::io::AsyncWrite;
+use tokio::io::{AsyncRead, AsyncWrite};
 
 #[cfg_attr(test, faux::create)]
 pub struct SavedReplayDirectory {
@@ -35,11 +35,93 @@ impl SavedReplayDirectory {
     }
 
     // Boxing so faux can work.
-    pub async fn touch_and_return_file(&self, replay_id: u64) -> std::io::Result<(Box<dyn AsyncWrite + Unpin>, PathBuf)> {
+    pub async fn touch_and_return_file(
+        &self,
+        replay_id: u64,
+    ) -> std::io::Result<(Box<dyn AsyncWrite + Unpin>, PathBuf)> {
+        self.touch_named_file(replay_id, format!("{}.fafreplay", replay_id))
+            .await
+    }
+
+    // A recovered replay goes where the complete one would, in case it never finishes. We mark it
+    // so that the complete one can replace it if it does.
+    pub async fn touch_and_return_recovered_file(
+        &self,
+        replay_id: u64,
+    ) -> std::io::Result<(Box<dyn AsyncWrite + Unpin>, PathBuf)> {
+        self.touch_named_file(replay_id, format!("{}.recovered", replay_id))
+            .await?;
+        self.touch_and_return_file(replay_id).await
+    }
+
+    // Removes a recovered replay and the given sidecar files, so the complete one can be saved in
+    // its place. Leaves replays that weren't recovered alone.
+    pub async fn remove_recovered_replay(&self, replay_id: u64, sidecars: &[&str]) -> std::io::Result<()> {
+        let dir = self.replay_path(replay_id);
+        let marker = dir.join(format!("{}.recovered", replay_id));
+        match tokio::fs::metadata(&marker).await {
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+            Err(e) => return Err(e),
+            Ok(_) => (),
+        }
+        let files = std::iter::once(format!("{}.fafreplay", replay_id))
+            .chain(sidecars.iter().map(|ext| format!("{}.{}", replay_id, ext)));
+        for name in files {
+            match tokio::fs::remove_file(dir.join(name)).await {
+                Err(e) if e.kind() != std::io::ErrorKind::NotFound => return Err(e),
+                _ => (),
+            }
+        }
+        // Last, so we try again if we fail halfway.
+        tokio::fs::remove_file(marker).await
+    }
+
+    // Extra files we keep next to a saved replay, e.g. "1234.tickindex.json".
+    pub async fn touch_and_return_sidecar_file(
+        &self,
+        replay_id: u64,
+        extension: &str,
+    ) -> std::io::Result<(Box<dyn AsyncWrite + Unpin>, PathBuf)> {
+        self.touch_named_file(replay_id, format!("{}.{}", replay_id, extension))
+            .await
+    }
+
+    // What we have so far of a replay that's still going. A new snapshot replaces the old one.
+    pub async fn replace_and_return_snapshot_file(
+        &self,
+        replay_id: u64,
+    ) -> std::io::Result<(Box<dyn AsyncWrite + Unpin>, PathBuf)> {
+        let mut target = self.replay_path(replay_id);
+        tokio::fs::create_dir_all(&target).await?;
+        target.push(format!("{}.snapshot.fafreplay", replay_id));
+        Ok((Box::new(tokio::fs::File::create(target.clone()).await?), target))
+    }
+
+    pub async fn remove_snapshot_file(&self, replay_id: u64) {
+        let mut target = self.replay_path(replay_id);
+        target.push(format!("{}.snapshot.fafreplay", replay_id));
+        match tokio::fs::remove_file(&target).await {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => {
+                log::warn!("Failed to remove snapshot of replay {}: {}", replay_id, e);
+            }
+            _ => (),
+        }
+    }
+
+    pub async fn open_replay_file(&self, replay_id: u64) -> std::io::Result<Box<dyn AsyncRead + Unpin>> {
+        let mut target = self.replay_path(replay_id);
+        target.push(format!("{}.fafreplay", replay_id));
+        Ok(Box::new(tokio::fs::File::open(target).await?))
+    }
+
+    async fn touch_named_file(
+        &self,
+        replay_id: u64,
+        basename: String,
+    ) -> std::io::Result<(Box<dyn AsyncWrite + Unpin>, PathBuf)> {
         let mut target = self.replay_path(replay_id);
         tokio::fs::create_dir_all(&target).await?;
 
-        let basename = format!("{}.fafreplay", replay_id);
         target.push(basename);
         Ok((Box::new(
             tokio::fs::OpenOptions::new()
@@ -76,6 +158,26 @@ pub mod test {
         assert_eq!(path_1, PathBuf::from("/tmp/foo/0/1/23/45"));
     }
 
+    #[tokio::test]
+    async fn test_complete_replay_can_replace_recovered_one() {
+        let root = tempfile::tempdir().unwrap();
+        let dir = SavedReplayDirectory::new(root.path().to_str().unwrap());
+        let (_, path) = dir.touch_and_return_recovered_file(1234567).await.unwrap();
+        let (_, sidecar) = dir.touch_and_return_sidecar_file(1234567, "foo.json").await.unwrap();
+        assert!(dir.touch_and_return_file(1234567).await.is_err());
+
+        dir.remove_recovered_replay(1234567, &["foo.json"]).await.unwrap();
+        assert!(!path.exists());
+        assert!(!sidecar.exists());
+        dir.touch_and_return_file(1234567).await.unwrap();
+        dir.touch_and_return_sidecar_file(1234567, "foo.json").await.unwrap();
+
+        // Replays we didn't recover are left alone.
+        dir.remove_recovered_replay(1234567, &["foo.json"]).await.unwrap();
+        assert!(path.exists());
+        assert!(sidecar.exists());
+    }
+
     pub fn test_directory() -> SavedReplayDirectory {
         let mut f = SavedReplayDirectory::faux();
         faux::when!(f.touch_and_return_file).then(
@@ -83,6 +185,15 @@ pub mod test {
                 (Box::new(sink()), PathBuf::from("/tmp/foo/0/1/23/45/1234567.fafreplay"))
             )
         );
+        faux::when!(f.touch_and_return_sidecar_file).then(|(_, ext)| {
+            Ok((
+                Box::new(sink()),
+                PathBuf::from(format!("/tmp/foo/0/1/23/45/1234567.{}", ext)),
+            ))
+        });
+        faux::when!(f.open_replay_file).then(|_| Err(std::io::ErrorKind::NotFound.into()));
+        faux::when!(f.remove_snapshot_file).then(|_| ());
+        faux::when!(f.remove_recovered_replay).then(|_| Ok(()));
         f
     }
 }
@@ -64,6 +64,11 @@ impl ReplayJsonHeader {
             version: 2,
         })
     }
+
+    // For replays we only have a part of, e.g. ones we recovered after a crash.
+    pub fn mark_incomplete(&mut self) {
+        self.complete = false;
+    }
 }
 
 #[cfg(test)]
@@ -1,10 +1,12 @@
 pub mod directory;
 mod json_header;
 mod saver;
+mod spool;
 mod writer;
 pub use directory::SavedReplayDirectory;
 pub use json_header::ReplayJsonHeader;
 pub use saver::{InnerReplaySaver, ReplaySaver};
+pub use spool::SpoolLock;
 
 #[cfg(test)]
 pub use writer::test;
@@ -1,35 +1,60 @@
-use std::{io::Read, sync::Arc};
+use std::{cell::RefCell, io::Read, rc::Rc, sync::Arc};
+
+use tokio::io::{AsyncRead, AsyncWriteExt};
+use tokio_util::sync::CancellationToken;
 
 use crate::{
-    config::Settings, database::database::Database, database::queries::Queries, metrics, replay::streams::MReplayRef,
+    config::Settings, database::queries::Queries, metrics, replay::receive::MergeReport, replay::streams::MReplayRef,
     util::buf_traits::ReadAtExt,
 };
 
-use super::{writer::write_replay_file, ReplayJsonHeader, SavedReplayDirectory};
+use super::spool::{ReplaySpool, SpoolLock};
+use super::writer::{read_replay_file, write_replay_file};
+use super::{ReplayJsonHeader, SavedReplayDirectory};
 use faf_replay_parser::{self, SCFA};
 
 pub type ReplaySaver = Arc<InnerReplaySaver>;
 
+const TICK_INDEX: &str = "tickindex.json";
+const MERGE_REPORT: &str = "merge.json";
+const SIDECARS: [&str; 2] = [TICK_INDEX, MERGE_REPORT];
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum SaveKind {
+    Complete,
+    // Left in the spool by a server that crashed.
+    Recovered,
+    // Of a replay that's still going.
+    Snapshot,
+}
+
 #[cfg_attr(test, faux::create)]
 pub struct InnerReplaySaver {
-    db: Queries,
+    db: Arc<Queries>,
     save_dir: SavedReplayDirectory,
+    spool: Option<ReplaySpool>,
     compression_level: u32,
 }
 
 impl InnerReplaySaver {
-    pub fn new(db: Database, save_dir: SavedReplayDirectory, config: &Settings) -> Arc<Self> {
+    pub fn new(db: Arc<Queries>, save_dir: SavedReplayDirectory, config: &Settings) -> Arc<Self> {
         Arc::new(Self::new_inner(db, save_dir, config))
     }
 }
 
 #[cfg_attr(test, faux::methods)]
 impl InnerReplaySaver {
-    fn new_inner(db: Database, save_dir: SavedReplayDirectory, config: &Settings) -> Self {
+    fn new_inner(db: Arc<Queries>, save_dir: SavedReplayDirectory, config: &Settings) -> Self {
         let compression_level = config.storage.compression_level;
+        let spool = config
+            .storage
+            .spool_path
+            .as_ref()
+            .map(|p| ReplaySpool::new(p, config.replay.update_interval_s));
         Self {
-            db: Queries::new(db),
+            db,
             save_dir,
+            spool,
             compression_level,
         }
     }
@@ -51,19 +76,27 @@ impl InnerReplaySaver {
         }
     }
 
-    async fn save_replay_to_disk(&self, replay: MReplayRef, id: u64) -> bool {
+    async fn save_replay_to_disk(&self, replay: MReplayRef, id: u64, kind: SaveKind) -> bool {
         if replay.borrow().get_header().is_none() {
             log::info!("Replay {} is empty, not saving.", id);
             return false;
         }
-        let json_header = match ReplayJsonHeader::from_id_and_db(&self.db, id).await {
+        let mut json_header = match ReplayJsonHeader::from_id_and_db(&self.db, id).await {
             Err(e) => {
                 log::info!("Failed to fetch game {} stats from database: {}", id, e);
                 return false;
             }
             Ok(r) => r,
         };
-        let (target_file, target_path) = match self.save_dir.touch_and_return_file(id).await {
+        if kind != SaveKind::Complete {
+            json_header.mark_incomplete();
+        }
+        let target = match kind {
+            SaveKind::Complete => self.save_dir.touch_and_return_file(id).await,
+            SaveKind::Recovered => self.save_dir.touch_and_return_recovered_file(id).await,
+            SaveKind::Snapshot => self.save_dir.replace_and_return_snapshot_file(id).await,
+        };
+        let (target_file, target_path) = match target {
             Err(e) => {
                 log::warn!("Failed to create file for replay {}: {}", id, e);
                 return false;
@@ -74,17 +107,140 @@ impl InnerReplaySaver {
             log::warn!("Failed to write out replay {}: {}", id, e);
             return false;
         }
-        metrics::SAVED_REPLAYS.inc();
+        if kind != SaveKind::Snapshot {
+            metrics::SAVED_REPLAYS.inc();
+        }
         log::debug!("Saved replay {} at {}", id, target_path.to_str().unwrap_or("<unknown>"));
         return true;
     }
 
-    pub async fn save_replay(&self, replay: MReplayRef, id: u64) {
-        let replay_saved = self.save_replay_to_disk(replay.clone(), id).await;
+    // Offsets in the index are relative to replay body, so we also say where the body starts in
+    // the saved stream.
+    fn tick_index_json(&self, replay: &MReplayRef) -> serde_json::Result<Vec<u8>> {
+        let r = replay.borrow();
+        serde_json::to_vec(&serde_json::json!({
+            "body_offset": r.header_len(),
+            "index": r.get_tick_index(),
+        }))
+    }
+
+    // `what` names the file in log messages, e.g. "tick index".
+    async fn save_sidecar(&self, id: u64, extension: &str, what: &str, data: serde_json::Result<Vec<u8>>) {
+        let data = match data {
+            Err(e) => {
+                log::warn!("Failed to serialize {} for replay {}: {}", what, id, e);
+                return;
+            }
+            Ok(d) => d,
+        };
+        let (mut target_file, _) = match self.save_dir.touch_and_return_sidecar_file(id, extension).await {
+            Err(e) => {
+                log::warn!("Failed to create {} file for replay {}: {}", what, id, e);
+                return;
+            }
+            Ok(f) => f,
+        };
+        let res = async {
+            target_file.write_all(&data).await?;
+            target_file.shutdown().await
+        }
+        .await;
+        if let Err(e) = res {
+            log::warn!("Failed to write out {} for replay {}: {}", what, id, e);
+        }
+    }
+
+    // Stream of a replay we saved earlier, same as what its readers got.
+    pub async fn open_saved_replay(&self, id: u64) -> std::io::Result<Box<dyn AsyncRead + Unpin>> {
+        let file = self.save_dir.open_replay_file(id).await?;
+        Ok(Box::new(read_replay_file(file).await?))
+    }
+
+    async fn store_replay(&self, replay: MReplayRef, id: u64, kind: SaveKind, report: Option<&MergeReport>) -> bool {
+        let replay_saved = self.save_replay_to_disk(replay.clone(), id, kind).await;
+        if replay_saved {
+            let tick_index = self.tick_index_json(&replay);
+            self.save_sidecar(id, TICK_INDEX, "tick index", tick_index).await;
+            if let Some(r) = report {
+                self.save_sidecar(id, MERGE_REPORT, "merge report", r.to_json()).await;
+            }
+        }
         let ticks = self.count_ticks(replay, id);
         if let Err(e) = self.db.update_game_stats(id, ticks, replay_saved).await {
             log::info!("Failed to update game stats for replay {}: {}", id, e);
         }
+        replay_saved
+    }
+
+    pub async fn save_replay(&self, replay: MReplayRef, id: u64, report: MergeReport) {
+        // We might've recovered this replay after a crash, and then its writers came back.
+        if let Err(e) = self.save_dir.remove_recovered_replay(id, &SIDECARS).await {
+            log::warn!("Failed to remove recovered replay {}: {}", id, e);
+        }
+        if self.store_replay(replay, id, SaveKind::Complete, Some(&report)).await {
+            self.save_dir.remove_snapshot_file(id).await;
+        }
+        // Saved or not, we're done with it.
+        if let Some(spool) = &self.spool {
+            spool.remove(id).await;
+        }
+    }
+
+    // Saves what a running replay has so far next to where it'll be saved, marked incomplete like
+    // a recovered one. Game stats are left alone, the replay isn't done yet.
+    pub async fn save_snapshot(&self, replay: MReplayRef, id: u64) {
+        let snapshot = Rc::new(RefCell::new(replay.borrow().snapshot()));
+        self.save_replay_to_disk(snapshot, id, SaveKind::Snapshot).await;
+    }
+
+    // Keeps a running replay in the spool until told to stop. Returns right away if spooling is
+    // off, or once spooling fails. Keep the returned lock until save_replay is done.
+    pub async fn spool_replay(&self, replay: MReplayRef, id: u64, stop: CancellationToken) -> Option<SpoolLock> {
+        match &self.spool {
+            Some(spool) => spool.spool_replay(replay, id, stop).await,
+            None => None,
+        }
+    }
+
+    pub async fn spooled_replays(&self) -> Vec<u64> {
+        let spool = match &self.spool {
+            None => return Vec::new(),
+            Some(s) => s,
+        };
+        spool.spooled_replays().await.unwrap_or_else(|e| {
+            log::warn!("Failed to look for spooled replays: {}", e);
+            Vec::new()
+        })
+    }
+
+    // Saves a replay left in the spool by a server that crashed. We don't know how it ended, so
+    // it's saved as incomplete.
+    pub async fn recover_spooled_replay(&self, id: u64) {
+        let spool = match &self.spool {
+            None => return,
+            Some(s) => s,
+        };
+        let _lock = match spool.read_orphan(id).await {
+            Ok(None) => {
+                log::debug!("Replay {} is spooled or recovered by another server, leaving it be", id);
+                return;
+            }
+            Ok(Some((replay, lock))) => {
+                log::info!("Recovering replay {} from spool", id);
+                // We don't know how merging went, so there's no merge report.
+                self.store_replay(replay, id, SaveKind::Recovered, None).await;
+                Some(lock)
+            }
+            Err(e) => {
+                log::warn!("Failed to recover spooled replay {}: {}", id, e);
+                if let Err(e) = self.db.update_game_stats(id, None, false).await {
+                    log::info!("Failed to update game stats for replay {}: {}", id, e);
+                }
+                None
+            }
+        };
+        // Only let go of the lock once the file's gone, so nobody else saves it again.
+        spool.remove(id).await;
     }
 }
 
@@ -92,13 +248,14 @@ impl InnerReplaySaver {
 mod test {
     use super::*;
     use crate::config::test::default_config;
+    use crate::database::database::Database;
     use crate::util::test::get_file;
 
     #[test]
     fn saver_can_read_example_replay_ticks() {
         let config = Arc::new(default_config());
         let example_replay = get_file("example_body");
-        let mock_db = Database::faux();
+        let mock_db = Arc::new(Queries::new(Database::faux()));
         let mock_dir = SavedReplayDirectory::faux();
         let saver = InnerReplaySaver::new_inner(mock_db, mock_dir, &config);
         let ticks = saver.get_ticks(&example_replay[..], 1);
@@ -0,0 +1,240 @@
+use std::cell::RefCell;
+use std::convert::{TryFrom, TryInto};
+use std::os::unix::fs::MetadataExt;
+use std::os::unix::io::AsRawFd;
+use std::path::PathBuf;
+use std::rc::Rc;
+
+use tokio::fs::{File, OpenOptions};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::time::Duration;
+use tokio_util::sync::CancellationToken;
+
+use crate::replay::streams::{MReplayRef, MergedReplay, ReplayHeader, ReplayStreamRef, WriterReplay};
+use crate::util::buf_traits::ChunkedBufExt;
+use crate::util::timeout::cancellable;
+
+// Merged replays live in memory until they're saved, so a crash would lose every running game. To
+// avoid that, we append merged data to a spool file as it comes in. Once a replay is saved, its
+// spool file is removed. Spool files left behind after a crash are saved when we start again.
+//
+// A spool file holds the replay header's length (u32 LE), the header, then replay data. We write
+// it out every update interval, so a crash loses at most that much. We only spool data that's past
+// the public delay, since a recovered replay is served to everyone even if the game's still going.
+//
+// A spooling server holds a lock on the file. That way a server started while another one drains
+// (see SIGUSR2) won't mistake replays that are still running for orphans.
+pub struct ReplaySpool {
+    root: PathBuf,
+    interval: Duration,
+}
+
+// Lock on a replay's spool file. Hold it until the spool file is removed, so that nobody recovers
+// a replay we're still saving.
+pub struct SpoolLock {
+    _file: File,
+}
+
+fn try_lock(f: &File) -> std::io::Result<()> {
+    // Safe, we pass a valid fd and flock doesn't touch memory.
+    let res = unsafe { libc::flock(f.as_raw_fd(), libc::LOCK_EX | libc::LOCK_NB) };
+    if res == 0 {
+        Ok(())
+    } else {
+        Err(std::io::Error::last_os_error())
+    }
+}
+
+impl ReplaySpool {
+    pub fn new(root: &str, interval: Duration) -> Self {
+        Self {
+            root: PathBuf::from(root),
+            interval,
+        }
+    }
+
+    fn spool_path(&self, id: u64) -> PathBuf {
+        let mut path = self.root.clone();
+        path.push(format!("{}.spool", id));
+        path
+    }
+
+    async fn create_spool_file(&self, id: u64, header: &[u8]) -> std::io::Result<File> {
+        tokio::fs::create_dir_all(&self.root).await?;
+        let mut file = OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(self.spool_path(id))
+            .await?;
+        // Don't truncate before we have the lock, the file might be someone else's.
+        try_lock(&file)?;
+        file.set_len(0).await?;
+        // Headers are limited to a megabyte, see ReplayHeader.
+        let header_len = u32::try_from(header.len()).unwrap();
+        file.write_all(&header_len.to_le_bytes()).await?;
+        file.write_all(header).await?;
+        Ok(file)
+    }
+
+    async fn do_spool_replay(&self, replay: &MReplayRef, id: u64, file: &mut Option<File>) -> std::io::Result<()> {
+        let mut written = 0;
+        loop {
+            if file.is_none() {
+                let header = replay.borrow().get_header().map(|h| h.data.clone());
+                if let Some(h) = header {
+                    *file = Some(self.create_spool_file(id, &h).await?);
+                }
+            }
+            if let Some(f) = file.as_mut() {
+                let len = replay.delayed_data_len();
+                let mut new_data = Vec::with_capacity(len - written);
+                for chunk in replay.get_data().iter_chunks(written, len) {
+                    new_data.extend_from_slice(chunk);
+                }
+                f.write_all(&new_data).await?;
+                f.flush().await?;
+                written = len;
+            }
+            tokio::time::sleep(self.interval).await;
+        }
+    }
+
+    // Runs until stopped, or until spooling fails. Returns the spool file's lock if we got it.
+    pub async fn spool_replay(&self, replay: MReplayRef, id: u64, stop: CancellationToken) -> Option<SpoolLock> {
+        let mut file = None;
+        if let Some(Err(e)) = cancellable(self.do_spool_replay(&replay, id, &mut file), &stop).await {
+            log::warn!("Failed to spool replay {}, it won't survive a crash: {}", id, e);
+        }
+        file.map(|f| SpoolLock { _file: f })
+    }
+
+    pub async fn remove(&self, id: u64) {
+        match tokio::fs::remove_file(self.spool_path(id)).await {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => {
+                log::warn!("Failed to remove spool file of replay {}: {}", id, e);
+            }
+            _ => (),
+        }
+    }
+
+    // IDs of replays with spool files.
+    pub async fn spooled_replays(&self) -> std::io::Result<Vec<u64>> {
+        tokio::fs::create_dir_all(&self.root).await?;
+        let mut entries = tokio::fs::read_dir(&self.root).await?;
+        let mut ids = Vec::new();
+        while let Some(entry) = entries.next_entry().await? {
+            let name = entry.file_name();
+            let id = name
+                .to_str()
+                .and_then(|n| n.strip_suffix(".spool"))
+                .and_then(|n| n.parse::<u64>().ok());
+            if let Some(id) = id {
+                ids.push(id);
+            }
+        }
+        ids.sort_unstable();
+        Ok(ids)
+    }
+
+    // Reads back a replay that nobody's spooling anymore. Returns None if someone still is, or if
+    // someone else recovered it in the meantime. Keep the lock until the spool file is removed.
+    pub async fn read_orphan(&self, id: u64) -> std::io::Result<Option<(MReplayRef, SpoolLock)>> {
+        let path = self.spool_path(id);
+        let mut file = File::open(&path).await?;
+        if let Err(e) = try_lock(&file) {
+            if e.kind() == std::io::ErrorKind::WouldBlock {
+                return Ok(None);
+            }
+            return Err(e);
+        }
+        // Whoever held the lock before us might've removed the file after we opened it.
+        match tokio::fs::metadata(&path).await {
+            Ok(m) if m.ino() == file.metadata().await?.ino() => (),
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => return Err(e),
+            _ => return Ok(None),
+        }
+        let mut contents = Vec::new();
+        file.read_to_end(&mut contents).await?;
+
+        let truncated = || std::io::Error::new(std::io::ErrorKind::UnexpectedEof, "Replay header is truncated");
+        let header_len = contents.get(..4).ok_or_else(truncated)?;
+        let header_len = u32::from_le_bytes(header_len.try_into().unwrap()) as usize;
+        let header = contents.get(4..4 + header_len).ok_or_else(truncated)?.to_vec();
+        let data = &contents[4 + header_len..];
+
+        let mut writer = WriterReplay::new();
+        writer.add_data(data);
+        let mut replay = MergedReplay::new();
+        replay.add_header(ReplayHeader { data: header });
+        replay.add_data(&writer, data.len());
+        replay.advance_delayed_data(data.len());
+        replay.finish();
+        Ok(Some((Rc::new(RefCell::new(replay)), SpoolLock { _file: file })))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::replay::streams::MReplayReader;
+    use crate::util::test::setup_logging;
+    use tempfile::tempdir;
+
+    #[tokio::test]
+    async fn test_spooled_replay_can_be_read_back() {
+        setup_logging();
+        let dir = tempdir().unwrap();
+        let spool = ReplaySpool::new(dir.path().to_str().unwrap(), Duration::from_millis(10));
+        let replay = Rc::new(RefCell::new(MergedReplay::new()));
+        let mut writer = WriterReplay::new();
+        writer.add_data(&[3, 4, 5, 6]);
+
+        let stop = CancellationToken::new();
+        let spooling = async {
+            tokio::time::sleep(Duration::from_millis(25)).await;
+            replay.borrow_mut().add_header(ReplayHeader { data: vec![1, 2] });
+            replay.borrow_mut().add_data(&writer, 2);
+            replay.borrow_mut().advance_delayed_data(2);
+            tokio::time::sleep(Duration::from_millis(25)).await;
+            replay.borrow_mut().add_data(&writer, 4);
+            replay.borrow_mut().advance_delayed_data(3);
+            tokio::time::sleep(Duration::from_millis(25)).await;
+
+            // We're still spooling, so it's not an orphan.
+            assert_eq!(spool.spooled_replays().await.unwrap(), vec![12]);
+            assert!(spool.read_orphan(12).await.unwrap().is_none());
+            stop.cancel();
+        };
+        let (lock, _) = tokio::join!(spool.spool_replay(replay.clone(), 12, stop.clone()), spooling);
+
+        // Not until we let go of the lock either.
+        assert!(spool.read_orphan(12).await.unwrap().is_none());
+        drop(lock);
+
+        let (orphan, lock) = spool.read_orphan(12).await.unwrap().unwrap();
+        let mut out = Vec::new();
+        MReplayReader::new(orphan).read_to_end(&mut out).await.unwrap();
+        // Data that's still delayed stays out of the spool.
+        assert_eq!(out, vec![1, 2, 3, 4, 5]);
+
+        // Someone else recovering it has to wait until we're done.
+        assert!(spool.read_orphan(12).await.unwrap().is_none());
+        spool.remove(12).await;
+        drop(lock);
+        assert!(spool.spooled_replays().await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_spool_with_truncated_header() {
+        setup_logging();
+        let dir = tempdir().unwrap();
+        let spool = ReplaySpool::new(dir.path().to_str().unwrap(), Duration::from_millis(10));
+        tokio::fs::write(dir.path().join("12.spool"), [10, 0, 0, 0, 1, 2])
+            .await
+            .unwrap();
+        tokio::fs::write(dir.path().join("unrelated.txt"), [1]).await.unwrap();
+        assert_eq!(spool.spooled_replays().await.unwrap(), vec![12]);
+        assert!(spool.read_orphan(12).await.is_err());
+    }
+}
@@ -1,7 +1,8 @@
 use crate::replay::streams::MReplayReader;
 use crate::replay::streams::MReplayRef;
+use async_compression::tokio::bufread::ZstdDecoder;
 use async_compression::tokio::write::ZstdEncoder;
-use tokio::io::{AsyncWrite, AsyncWriteExt};
+use tokio::io::{AsyncBufReadExt, AsyncRead, AsyncWrite, AsyncWriteExt, BufReader};
 
 pub async fn write_replay_file(
     mut to: impl AsyncWrite + Unpin,
@@ -18,6 +19,20 @@ pub async fn write_replay_file(
     Ok(())
 }
 
+// Gives back the replay stream we saved, without the JSON header.
+pub async fn read_replay_file(from: impl AsyncRead + Unpin) -> std::io::Result<impl AsyncRead + Unpin> {
+    let mut from = BufReader::new(from);
+    let mut json = Vec::new();
+    from.read_until(b'\n', &mut json).await?;
+    if json.last() != Some(&b'\n') {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::UnexpectedEof,
+            "Replay file ended before the end of its JSON header",
+        ));
+    }
+    Ok(ZstdDecoder::new(from))
+}
+
 #[cfg(test)]
 pub mod test {
     use async_compression::tokio::bufread::ZstdDecoder;
@@ -1,2 +1,4 @@
+mod saved;
 mod sender;
+pub use saved::send_saved_replay;
 pub use sender::ReplaySender;
@@ -0,0 +1,39 @@
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWriteExt};
+
+use crate::server::connection::Connection;
+
+// Readers of replays that already ended get them from the vault. They get the same data as live
+// readers would, so clients don't have to care whether the game is still going.
+pub async fn send_saved_replay(c: &mut Connection, mut replay: impl AsyncRead + Unpin) {
+    let offset = c.get_header().fields.resume_offset.unwrap_or(0);
+    let res: std::io::Result<()> = async {
+        tokio::io::copy(&mut (&mut replay).take(offset), &mut tokio::io::sink()).await?;
+        tokio::io::copy(&mut replay, c).await?;
+        c.flush().await
+    }
+    .await;
+    if let Err(e) = res {
+        log::info!("Saved replay send error: {}", e);
+    };
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::accept::header::{ConnectionHeader, ConnectionType};
+    use crate::server::connection::test::test_connection;
+
+    #[tokio::test]
+    async fn test_send_saved_replay_from_offset() {
+        let (mut c, mut reader, _w) = test_connection();
+        let mut header = ConnectionHeader::new(ConnectionType::Reader, 1, "foo".into());
+        header.fields.resume_offset = Some(3);
+        c.set_header(header);
+
+        send_saved_replay(&mut c, &b"foobarbaz"[..]).await;
+        drop(c);
+        let mut received = Vec::new();
+        reader.read_to_end(&mut received).await.unwrap();
+        assert_eq!(received, b"barbaz");
+    }
+}
@@ -1,29 +1,120 @@
+use std::cell::{Cell, RefCell};
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::rc::Rc;
+
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::time::Duration;
 use tokio_util::sync::CancellationToken;
 
+use crate::accept::header::ReaderClass;
+use crate::replay::budget::{LoadBudget, TrackedReader};
 use crate::replay::streams::MReplayReader;
+use crate::util::buf_traits::ChunkedBuf;
 use crate::{replay::streams::MReplayRef, server::connection::Connection, util::timeout::cancellable};
 
+// What we know about a reader for load shedding purposes.
+struct ReaderState {
+    position: Rc<Cell<usize>>,
+    class: ReaderClass,
+    // As of the last check. None until then.
+    backlog: Option<usize>,
+    load: TrackedReader,
+}
+
 pub struct ReplaySender {
     merged_replay: MReplayRef,
     shutdown_token: CancellationToken,
+    budget: LoadBudget,
+    update_interval: Duration,
+    readers: RefCell<HashMap<u64, ReaderState>>,
+    next_reader_id: Cell<u64>,
 }
 
 impl ReplaySender {
-    pub fn new(merged_replay: MReplayRef, shutdown_token: CancellationToken) -> Self {
+    pub fn new(
+        merged_replay: MReplayRef,
+        shutdown_token: CancellationToken,
+        budget: LoadBudget,
+        update_interval: Duration,
+    ) -> Self {
         Self {
             merged_replay,
             shutdown_token,
+            budget,
+            update_interval,
+            readers: RefCell::new(HashMap::new()),
+            next_reader_id: Cell::new(0),
         }
     }
 
     pub async fn handle_connection(&self, c: &mut Connection) {
-        cancellable(self.send_replay_to_connection(c), &self.shutdown_token).await;
+        let id = self.next_reader_id.get();
+        self.next_reader_id.set(id + 1);
+        let position = Rc::new(Cell::new(0));
+        let disconnect = self.shutdown_token.child_token();
+        self.readers.borrow_mut().insert(
+            id,
+            ReaderState {
+                position: position.clone(),
+                class: c.get_header().fields.reader_class,
+                backlog: None,
+                load: self.budget.track_reader(disconnect.clone()),
+            },
+        );
+
+        cancellable(self.send_replay_to_connection(c, &position), &disconnect).await;
+
+        self.readers.borrow_mut().remove(&id);
     }
 
-    async fn send_replay_to_connection(&self, c: &mut Connection) {
-        let mut reader = MReplayReader::new(self.merged_replay.clone());
-        if let Err(e) = tokio::io::copy(&mut reader, c).await {
+    async fn send_replay_to_connection(&self, c: &mut Connection, position: &Cell<usize>) {
+        let fields = c.get_header().fields;
+        let offset = usize::try_from(fields.resume_offset.unwrap_or(0)).unwrap_or(usize::MAX);
+        let class = fields.reader_class;
+        let mut reader = MReplayReader::new_at(self.merged_replay.clone(), offset, class);
+        position.set(std::cmp::min(
+            offset,
+            self.merged_replay.borrow().for_reader(class).len(),
+        ));
+        let mut buf = vec![0; 8192];
+        let res: std::io::Result<()> = async {
+            loop {
+                let read = reader.read(&mut buf).await?;
+                if read == 0 {
+                    return c.flush().await;
+                }
+                c.write_all(&buf[..read]).await?;
+                position.set(position.get() + read);
+            }
+        }
+        .await;
+        if let Err(e) = res {
             log::info!("Replay send error: {}", e);
         };
     }
+
+    // Readers whose backlog shrank since the last check are catching up, so they're not stuck
+    // with any data. New readers get one check to start catching up.
+    fn update_backlogs(&self) {
+        let replay = self.merged_replay.borrow();
+        for reader in self.readers.borrow_mut().values_mut() {
+            let available = replay.for_reader(reader.class).len();
+            let backlog = available.saturating_sub(reader.position.get());
+            let catching_up = reader.backlog.is_none_or(|b| backlog < b);
+            reader.load.set_stuck_bytes(if catching_up { 0 } else { backlog });
+            reader.backlog = Some(backlog);
+        }
+    }
+
+    // Runs for as long as the replay does. The budget decides whom to drop.
+    pub async fn track_load(&self) {
+        let tracking = async {
+            loop {
+                tokio::time::sleep(self.update_interval).await;
+                self.update_backlogs();
+            }
+        };
+        cancellable(tracking, &self.shutdown_token).await;
+    }
 }
@@ -4,6 +4,7 @@ use std::{cell::RefCell, io::Write, rc::Rc};
 
 use tokio::io::AsyncRead;
 
+use crate::accept::header::ReaderClass;
 use crate::util::event::Event;
 use crate::{
     util::buf_traits::ChunkedBuf,
@@ -11,14 +12,22 @@ use crate::{
 };
 
 use super::ReplayStream;
-use super::{writer_replay::WriterReplay, ReplayHeader};
+use super::{tick_index::TickIndex, writer_replay::WriterReplay, ReplayHeader};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CommandPosition {
+    pub tick: u32,
+    pub start: usize,
+}
 
 pub struct MergedReplay {
     data: BufDeque,
     header: Option<ReplayHeader>,
     delayed_data_len: usize,
+    privileged_delayed_data_len: usize,
     finished: bool,
     read_event: Event,
+    tick_index: TickIndex,
 }
 
 impl ReplayStream for MergedReplay {
@@ -32,6 +41,10 @@ impl ReplayStream for MergedReplay {
         self.delayed_data_len
     }
 
+    fn privileged_delayed_data_len(&self) -> usize {
+        self.privileged_delayed_data_len
+    }
+
     fn get_data(&self) -> &Self::Buf {
         &self.data
     }
@@ -41,23 +54,30 @@ impl ReplayStream for MergedReplay {
     }
 }
 
-// Access to replay header + data, for AsyncRead implementation.
-impl ChunkedBuf for MergedReplay {
+// Access to replay header + data, as seen by a reader of a given class.
+pub struct ReaderView<'a> {
+    replay: &'a MergedReplay,
+    class: ReaderClass,
+}
+
+impl ChunkedBuf for ReaderView<'_> {
     fn len(&self) -> usize {
-        self.delayed_data_len() + self.header_len()
+        self.replay.readable_len(self.class)
     }
 
-    fn get_chunk(&self, mut start: usize) -> &[u8] {
-        if start < self.header_len() {
-            &self.get_header().unwrap().data[start..]
-        } else {
-            start -= self.header_len();
-            let delay_limit = self.delayed_data_len - start;
+    fn get_chunk(&self, start: usize) -> &[u8] {
+        self.replay.get_readable_chunk(self.class, start)
+    }
+}
 
-            let chunk = self.data.get_chunk(start);
-            let read_max = std::cmp::min(chunk.len(), delay_limit);
-            &chunk[..read_max]
-        }
+// Same as above, for regular readers.
+impl ChunkedBuf for MergedReplay {
+    fn len(&self) -> usize {
+        self.readable_len(ReaderClass::Regular)
+    }
+
+    fn get_chunk(&self, start: usize) -> &[u8] {
+        self.get_readable_chunk(ReaderClass::Regular, start)
     }
 }
 
@@ -67,8 +87,10 @@ impl MergedReplay {
             data: BufDeque::new(),
             header: None,
             delayed_data_len: 0,
+            privileged_delayed_data_len: 0,
             finished: false,
             read_event: Event::new(),
+            tick_index: TickIndex::new(),
         }
     }
 
@@ -83,6 +105,65 @@ impl MergedReplay {
         self.notify_read_event();
     }
 
+    pub fn get_tick_index(&self) -> &TickIndex {
+        &self.tick_index
+    }
+
+    // Game tick the byte at this data offset belongs to, and where the command it's in starts. The
+    // index only knows every few ticks, so we count the rest from the nearest entry. None if the
+    // data before the offset isn't a valid command stream.
+    pub fn command_at(&self, offset: usize) -> Option<CommandPosition> {
+        if offset > self.data.len() {
+            return None;
+        }
+        let entries = self.tick_index.entries();
+        // First entry is at offset 0.
+        let from = entries[entries.partition_point(|e| e.offset <= offset) - 1];
+        let mut index = TickIndex::new();
+        for chunk in self.data.iter_chunks(from.offset, offset) {
+            index.feed(chunk);
+        }
+        if index.is_broken() {
+            return None;
+        }
+        Some(CommandPosition {
+            tick: from.tick.checked_add(index.ticks())?,
+            start: from.offset + index.frame_start(),
+        })
+    }
+
+    pub fn tick_at(&self, offset: usize) -> Option<u32> {
+        self.command_at(offset).map(|c| c.tick)
+    }
+
+    pub fn for_reader(&self, class: ReaderClass) -> ReaderView<'_> {
+        ReaderView { replay: self, class }
+    }
+
+    fn readable_data_len(&self, class: ReaderClass) -> usize {
+        match class {
+            ReaderClass::Regular => self.delayed_data_len,
+            ReaderClass::Privileged => self.privileged_delayed_data_len,
+        }
+    }
+
+    fn readable_len(&self, class: ReaderClass) -> usize {
+        self.readable_data_len(class) + self.header_len()
+    }
+
+    fn get_readable_chunk(&self, class: ReaderClass, mut start: usize) -> &[u8] {
+        if start < self.header_len() {
+            &self.get_header().unwrap().data[start..]
+        } else {
+            start -= self.header_len();
+            let delay_limit = self.readable_data_len(class) - start;
+
+            let chunk = self.data.get_chunk(start);
+            let read_max = std::cmp::min(chunk.len(), delay_limit);
+            &chunk[..read_max]
+        }
+    }
+
     pub fn header_len(&self) -> usize {
         self.get_header().map_or(0, |h| h.data.len())
     }
@@ -103,6 +184,7 @@ impl MergedReplay {
         let from = self.data.len();
         for chunk in writer_data.iter_chunks(from, until) {
             self.data.write_all(chunk).unwrap();
+            self.tick_index.feed(chunk);
         }
     }
 
@@ -110,6 +192,15 @@ impl MergedReplay {
         debug_assert!(len <= self.data.len());
         debug_assert!(!self.finished);
         self.delayed_data_len = len;
+        self.privileged_delayed_data_len = std::cmp::max(len, self.privileged_delayed_data_len);
+        self.notify_read_event();
+    }
+
+    pub fn advance_privileged_delayed_data(&mut self, len: usize) {
+        debug_assert!(len <= self.data.len());
+        debug_assert!(len >= self.delayed_data_len);
+        debug_assert!(!self.finished);
+        self.privileged_delayed_data_len = len;
         self.notify_read_event();
     }
 
@@ -117,6 +208,22 @@ impl MergedReplay {
         self.finished = true;
         self.notify_read_event();
     }
+
+    // A finished copy of everything merged so far, delayed or not. Lets us save a replay that's
+    // still going without waiting for it to end.
+    pub fn snapshot(&self) -> Self {
+        let mut copy = Self::new();
+        if let Some(h) = self.get_header() {
+            copy.add_header(ReplayHeader { data: h.data.clone() });
+        }
+        for chunk in self.data.iter_chunks(0, self.data.len()) {
+            copy.data.write_all(chunk).unwrap();
+            copy.tick_index.feed(chunk);
+        }
+        copy.advance_delayed_data(copy.data.len());
+        copy.finish();
+        copy
+    }
 }
 
 pub type MReplayRef = Rc<RefCell<MergedReplay>>;
@@ -128,11 +235,28 @@ pub type MReplayRef = Rc<RefCell<MergedReplay>>;
 pub struct MReplayReader {
     replay: MReplayRef,
     position: usize,
+    class: ReaderClass,
 }
 
 impl MReplayReader {
     pub fn new(replay: MReplayRef) -> Self {
-        Self { replay, position: 0 }
+        Self {
+            replay,
+            position: 0,
+            class: ReaderClass::Regular,
+        }
+    }
+
+    // Start reading from a given position instead of the very start, e.g. to let a reader resume
+    // after a dropped connection. Position counts header bytes too. We can't jump ahead of data
+    // available to the reader, so the position is clamped to that.
+    pub fn new_at(replay: MReplayRef, position: usize, class: ReaderClass) -> Self {
+        let position = std::cmp::min(position, replay.borrow().for_reader(class).len());
+        Self {
+            replay,
+            position,
+            class,
+        }
     }
 }
 
@@ -144,7 +268,7 @@ impl AsyncRead for MReplayReader {
     ) -> std::task::Poll<std::io::Result<()>> {
         let mut r = self.replay.borrow_mut();
 
-        if r.len() <= self.position {
+        if r.readable_len(self.class) <= self.position {
             if r.is_finished() {
                 Poll::Ready(Ok(()))
             } else {
@@ -153,7 +277,7 @@ impl AsyncRead for MReplayReader {
             }
         } else {
             // TODO maybe we could do a loop here
-            let chunk = r.get_chunk(self.position);
+            let chunk = r.get_readable_chunk(self.class, self.position);
             let copyable = std::cmp::min(chunk.len(), buf.remaining());
             buf.put_slice(&chunk[..copyable]);
             drop(r);
@@ -167,4 +291,124 @@ impl AsyncRead for MReplayReader {
 // can't though since we don't *own* the buffer, we have to borrow through a RefCell and can't
 // return a bare &[u8]. I don't think there's a way around this.
 
-// TODO tests
+#[cfg(test)]
+mod test {
+    use tokio::io::AsyncReadExt;
+
+    use super::*;
+
+    fn replay_with_data(header: &[u8], data: &[u8], delayed: usize) -> MReplayRef {
+        let mut writer = WriterReplay::new();
+        writer.add_data(data);
+        let mut replay = MergedReplay::new();
+        replay.add_header(ReplayHeader { data: header.to_vec() });
+        replay.add_data(&writer, data.len());
+        replay.advance_delayed_data(delayed);
+        Rc::new(RefCell::new(replay))
+    }
+
+    #[test]
+    fn test_tick_at_offset() {
+        let mut data = Vec::new();
+        for _ in 0..25 {
+            data.extend([1, 4, 0, 1]); // SetCommandSource
+            data.extend([0, 7, 0, 1, 0, 0, 0]); // Advance by 1
+        }
+        let replay = replay_with_data(&[1, 2], &data, 0);
+        let replay = replay.borrow();
+        assert_eq!(replay.tick_at(0), Some(0));
+        assert_eq!(replay.tick_at(10), Some(0));
+        assert_eq!(replay.tick_at(11), Some(1));
+        assert_eq!(replay.tick_at(135), Some(12));
+        assert_eq!(replay.tick_at(data.len()), Some(25));
+        assert_eq!(replay.tick_at(data.len() + 1), None);
+
+        assert_eq!(replay.command_at(13), Some(CommandPosition { tick: 1, start: 11 }));
+        assert_eq!(replay.command_at(22), Some(CommandPosition { tick: 2, start: 22 }));
+
+        let garbage = replay_with_data(&[1, 2], &[0, 1, 0, 1, 2, 3], 0);
+        assert_eq!(garbage.borrow().tick_at(5), None);
+    }
+
+    #[tokio::test]
+    async fn test_reader_from_start() {
+        let replay = replay_with_data(&[1, 2], &[3, 4, 5, 6], 4);
+        replay.borrow_mut().finish();
+        let mut out = Vec::new();
+        MReplayReader::new(replay).read_to_end(&mut out).await.unwrap();
+        assert_eq!(out, vec![1, 2, 3, 4, 5, 6]);
+    }
+
+    #[tokio::test]
+    async fn test_reader_at_offset() {
+        let replay = replay_with_data(&[1, 2], &[3, 4, 5, 6], 4);
+        replay.borrow_mut().finish();
+
+        let mut out = Vec::new();
+        MReplayReader::new_at(replay.clone(), 1, ReaderClass::Regular)
+            .read_to_end(&mut out)
+            .await
+            .unwrap();
+        assert_eq!(out, vec![2, 3, 4, 5, 6]);
+
+        out.clear();
+        MReplayReader::new_at(replay, 4, ReaderClass::Regular)
+            .read_to_end(&mut out)
+            .await
+            .unwrap();
+        assert_eq!(out, vec![5, 6]);
+    }
+
+    #[tokio::test]
+    async fn test_reader_offset_clamped_to_delayed_data() {
+        let replay = replay_with_data(&[1, 2], &[3, 4, 5, 6], 2);
+        let mut reader = MReplayReader::new_at(replay.clone(), 100, ReaderClass::Regular);
+        assert_eq!(reader.position, 4);
+
+        replay.borrow_mut().advance_delayed_data(4);
+        replay.borrow_mut().finish();
+        let mut out = Vec::new();
+        reader.read_to_end(&mut out).await.unwrap();
+        assert_eq!(out, vec![5, 6]);
+    }
+
+    #[tokio::test]
+    async fn test_snapshot_has_all_data_so_far() {
+        let replay = replay_with_data(&[1, 2], &[3, 4, 5, 6], 1);
+        let snapshot = Rc::new(RefCell::new(replay.borrow().snapshot()));
+        assert!(!replay.borrow().is_finished());
+        let mut out = Vec::new();
+        MReplayReader::new(snapshot).read_to_end(&mut out).await.unwrap();
+        assert_eq!(out, vec![1, 2, 3, 4, 5, 6]);
+    }
+
+    #[tokio::test]
+    async fn test_privileged_reader_sees_less_delayed_data() {
+        let replay = replay_with_data(&[1, 2], &[3, 4, 5, 6], 1);
+        replay.borrow_mut().advance_privileged_delayed_data(3);
+        assert_eq!(replay.borrow().len(), 3);
+        assert_eq!(replay.borrow().for_reader(ReaderClass::Privileged).len(), 5);
+
+        let mut regular = MReplayReader::new_at(replay.clone(), 100, ReaderClass::Regular);
+        let mut privileged = MReplayReader::new_at(replay.clone(), 0, ReaderClass::Privileged);
+        assert_eq!(regular.position, 3);
+        let mut buf = [0; 16];
+        let mut read = 0;
+        while read < 5 {
+            read += privileged.read(&mut buf[read..]).await.unwrap();
+        }
+        assert_eq!(&buf[..5], &[1, 2, 3, 4, 5]);
+
+        // Privileged readers are never behind regular ones.
+        replay.borrow_mut().advance_delayed_data(4);
+        assert_eq!(replay.borrow().for_reader(ReaderClass::Privileged).len(), 6);
+
+        replay.borrow_mut().finish();
+        let mut out = Vec::new();
+        privileged.read_to_end(&mut out).await.unwrap();
+        assert_eq!(out, vec![6]);
+        out.clear();
+        regular.read_to_end(&mut out).await.unwrap();
+        assert_eq!(out, vec![4, 5, 6]);
+    }
+}
@@ -1,5 +1,6 @@
 mod header;
 mod merged_replay;
+mod tick_index;
 mod writer_replay;
 
 use std::cell::Ref;
@@ -10,6 +11,7 @@ use crate::util::buf_traits::ChunkedBuf;
 
 pub use self::header::ReplayHeader;
 pub use self::merged_replay::{MReplayReader, MReplayRef, MergedReplay};
+pub use self::tick_index::{frame_size, FRAME_HEADER_SIZE};
 pub use self::writer_replay::{read_data, read_header, WReplayRef, WriterReplay};
 
 // Some common behaviour for WriterReplay and MergedReplay, that is:
@@ -18,11 +20,14 @@ pub use self::writer_replay::{read_data, read_header, WReplayRef, WriterReplay};
 // * Total length of replay data.
 // * "Delayed" length of replay data that's less than total length. In general this means that data
 //   beyond that length should not be available to readers yet.
+// * Same as above, for privileged readers (casters, referees) who watch with a shorter delay. Never
+//   less than the regular delayed length.
 // * Whether the replay is "finished" or not. Finished replays will never generate more data.
 pub trait ReplayStream {
     type Buf: ChunkedBuf;
     fn data_len(&self) -> usize;
     fn delayed_data_len(&self) -> usize;
+    fn privileged_delayed_data_len(&self) -> usize;
     fn get_data(&self) -> &Self::Buf;
     fn is_finished(&self) -> bool;
 }
@@ -32,6 +37,7 @@ pub trait ReplayStreamRef {
     type Buf: ChunkedBuf;
     fn data_len(&self) -> usize;
     fn delayed_data_len(&self) -> usize;
+    fn privileged_delayed_data_len(&self) -> usize;
     fn is_finished(&self) -> bool;
     fn get_data(&self) -> Ref<'_, Self::Buf>;
 }
@@ -46,6 +52,10 @@ impl<S: ReplayStream> ReplayStreamRef for Rc<RefCell<S>> {
         self.borrow().delayed_data_len()
     }
 
+    fn privileged_delayed_data_len(&self) -> usize {
+        self.borrow().privileged_delayed_data_len()
+    }
+
     fn get_data(&self) -> Ref<'_, Self::Buf> {
         Ref::map(self.borrow(), |x| x.get_data())
     }
@@ -0,0 +1,213 @@
+use faf_replay_parser::{body_ticks, has_frame};
+
+// Replay body is a sequence of command frames. Each frame starts with a command type (u8) and
+// frame size (u16 LE, including these 3 bytes). Advance commands move game time forward by some
+// number of ticks.
+//
+// We decode that framing as data is appended to the merged replay, and every INTERVAL ticks we
+// note down the offset at which the next tick starts. That lets us find the data for a given point
+// in game time without parsing the whole replay.
+pub const FRAME_HEADER_SIZE: usize = 3;
+pub const TICK_INDEX_INTERVAL: u32 = 10;
+
+// Size of the frame starting with this header, or None if it's not a valid frame.
+pub fn frame_size(header: &[u8; FRAME_HEADER_SIZE]) -> Option<usize> {
+    has_frame(header).ok()?;
+    Some(u16::from_le_bytes([header[1], header[2]]) as usize)
+}
+
+#[derive(serde::Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TickIndexEntry {
+    pub tick: u32,
+    pub offset: usize, // Offset in replay body (i.e. not counting the header).
+}
+
+#[derive(serde::Serialize)]
+pub struct TickIndex {
+    interval: u32,
+    ticks: u32,
+    entries: Vec<TickIndexEntry>,
+    #[serde(skip)]
+    frame: Vec<u8>, // Incomplete frame we're still reading.
+    #[serde(skip)]
+    frame_start: usize,
+    #[serde(skip)]
+    broken: bool,
+}
+
+impl TickIndex {
+    pub fn new() -> Self {
+        Self {
+            interval: TICK_INDEX_INTERVAL,
+            ticks: 0,
+            entries: vec![TickIndexEntry { tick: 0, offset: 0 }],
+            frame: Vec::new(),
+            frame_start: 0,
+            broken: false,
+        }
+    }
+
+    pub fn entries(&self) -> &[TickIndexEntry] {
+        &self.entries
+    }
+
+    pub fn ticks(&self) -> u32 {
+        self.ticks
+    }
+
+    // Whether we stopped indexing because of data that's not a valid command stream. Entries
+    // before that point are still good.
+    pub fn is_broken(&self) -> bool {
+        self.broken
+    }
+
+    // Where the frame we didn't finish reading yet starts.
+    pub fn frame_start(&self) -> usize {
+        self.frame_start
+    }
+
+    fn bytes_missing_from_frame(&self) -> usize {
+        if self.frame.len() < FRAME_HEADER_SIZE {
+            FRAME_HEADER_SIZE - self.frame.len()
+        } else {
+            let size = u16::from_le_bytes([self.frame[1], self.frame[2]]) as usize;
+            size - self.frame.len()
+        }
+    }
+
+    fn frame_complete(&mut self) {
+        // Garbage can claim to advance by any number of ticks, don't overflow.
+        match body_ticks(&self.frame).ok().and_then(|t| self.ticks.checked_add(t)) {
+            None => {
+                self.broken = true;
+                return;
+            }
+            Some(t) => self.ticks = t,
+        }
+        self.frame_start += self.frame.len();
+        self.frame.clear();
+        let last_indexed = self.entries.last().unwrap().tick;
+        if self.ticks >= last_indexed.saturating_add(self.interval) {
+            self.entries.push(TickIndexEntry {
+                tick: self.ticks,
+                offset: self.frame_start,
+            });
+        }
+    }
+
+    pub fn feed(&mut self, mut data: &[u8]) {
+        while !data.is_empty() && !self.broken {
+            let taken = std::cmp::min(self.bytes_missing_from_frame(), data.len());
+            self.frame.extend_from_slice(&data[..taken]);
+            data = &data[taken..];
+            match has_frame(&self.frame) {
+                Err(_) => self.broken = true,
+                Ok(false) => (),
+                Ok(true) => self.frame_complete(),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::replay::receive::test::{advance, set_command_source};
+    use crate::util::test::get_file;
+    use faf_replay_parser::{parser::parse_body_ticks, SCFA};
+
+    #[test]
+    fn test_frame_size() {
+        assert_eq!(frame_size(&[0, 7, 0]), Some(7));
+        assert_eq!(frame_size(&[1, 3, 0]), Some(3));
+        assert_eq!(frame_size(&[2, 0, 1]), Some(256));
+        // Too small to hold its own header.
+        assert_eq!(frame_size(&[0, 2, 0]), None);
+        // No such command.
+        assert_eq!(frame_size(&[200, 7, 0]), None);
+    }
+
+    #[test]
+    fn test_tick_index_starts_at_zero() {
+        let index = TickIndex::new();
+        assert_eq!(index.ticks(), 0);
+        assert_eq!(index.entries(), &[TickIndexEntry { tick: 0, offset: 0 }]);
+    }
+
+    #[test]
+    fn test_tick_index_records_every_interval() {
+        let mut data = Vec::new();
+        for _ in 0..25 {
+            data.extend(set_command_source(1));
+            data.extend(advance(1));
+        }
+        let mut index = TickIndex::new();
+        index.feed(&data);
+        assert_eq!(index.ticks(), 25);
+        assert_eq!(
+            index.entries(),
+            &[
+                TickIndexEntry { tick: 0, offset: 0 },
+                TickIndexEntry { tick: 10, offset: 110 },
+                TickIndexEntry { tick: 20, offset: 220 },
+            ]
+        );
+        assert!(!index.is_broken());
+    }
+
+    #[test]
+    fn test_tick_index_byte_by_byte() {
+        let mut data = Vec::new();
+        for _ in 0..25 {
+            data.extend(set_command_source(1));
+            data.extend(advance(1));
+        }
+        let mut whole = TickIndex::new();
+        whole.feed(&data);
+        let mut split = TickIndex::new();
+        for b in data.iter() {
+            split.feed(&[*b]);
+        }
+        assert_eq!(whole.ticks(), split.ticks());
+        assert_eq!(whole.entries(), split.entries());
+    }
+
+    #[test]
+    fn test_tick_index_stops_at_garbage() {
+        let mut data = advance(15);
+        data.extend(&[200, 200, 200, 200]);
+        data.extend(advance(15));
+        let mut index = TickIndex::new();
+        index.feed(&data);
+        assert!(index.is_broken());
+        assert_eq!(index.ticks(), 15);
+        assert_eq!(index.entries().last().unwrap(), &TickIndexEntry { tick: 15, offset: 7 });
+    }
+
+    #[test]
+    fn test_tick_index_tick_overflow() {
+        let mut data = advance(15);
+        data.extend(advance(u32::MAX));
+        let mut index = TickIndex::new();
+        index.feed(&data);
+        assert!(index.is_broken());
+        assert_eq!(index.ticks(), 15);
+    }
+
+    #[test]
+    fn test_tick_index_on_example_replay() {
+        let body = get_file("example_body");
+        let mut index = TickIndex::new();
+        for chunk in body.chunks(100) {
+            index.feed(chunk);
+        }
+        let expected_ticks = parse_body_ticks::<SCFA>(&mut &body[..]).unwrap();
+        assert!(!index.is_broken());
+        assert_eq!(index.ticks(), expected_ticks);
+        for pair in index.entries().windows(2) {
+            assert!(pair[0].tick < pair[1].tick);
+            assert!(pair[0].offset < pair[1].offset);
+            assert!(pair[1].offset <= body.len());
+        }
+    }
+}
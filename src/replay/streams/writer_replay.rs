@@ -13,6 +13,7 @@ pub struct WriterReplay {
     header: Option<ReplayHeader>,
     data: BufDeque,
     delayed_data_len: usize,
+    privileged_delayed_data_len: usize,
     finished: bool,
 }
 
@@ -27,6 +28,10 @@ impl ReplayStream for WriterReplay {
         self.delayed_data_len
     }
 
+    fn privileged_delayed_data_len(&self) -> usize {
+        self.privileged_delayed_data_len
+    }
+
     fn get_data(&self) -> &Self::Buf {
         &self.data
     }
@@ -42,6 +47,7 @@ impl WriterReplay {
             header: None,
             data: BufDeque::new(),
             delayed_data_len: 0,
+            privileged_delayed_data_len: 0,
             finished: false,
         }
     }
@@ -50,6 +56,10 @@ impl WriterReplay {
         self.header = Some(h);
     }
 
+    pub fn get_header(&self) -> Option<&ReplayHeader> {
+        self.header.as_ref()
+    }
+
     pub fn take_header(&mut self) -> ReplayHeader {
         std::mem::replace(&mut self.header, None).expect("Cannot take header")
     }
@@ -61,6 +71,12 @@ impl WriterReplay {
     pub fn set_delayed_data_len(&mut self, new: usize) {
         debug_assert!(self.delayed_data_len <= new);
         self.delayed_data_len = new;
+        self.privileged_delayed_data_len = std::cmp::max(new, self.privileged_delayed_data_len);
+    }
+
+    pub fn set_privileged_delayed_data_len(&mut self, new: usize) {
+        debug_assert!(self.privileged_delayed_data_len <= new);
+        self.privileged_delayed_data_len = new;
     }
 
     pub fn discard(&mut self, until: usize) {
@@ -0,0 +1,167 @@
+use futures::StreamExt;
+use http::{Method, StatusCode};
+use serde_json::{json, Value};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::time::Duration;
+use tokio_stream::wrappers::TcpListenerStream;
+
+use crate::replay::runner::ReplayInspector;
+use crate::replay::ReplayCommand;
+use crate::util::timeout::timeout;
+
+// A little HTTP API for operators, so they don't have to wait hours for stuck games to time out:
+// * GET /replays - running replays,
+// * GET /replays/<id> - a running replay and its connections,
+// * POST /replays/<id>/end - ends the replay right away, same as when it times out,
+// * POST /replays/<id>/stop-writers - stops accepting new connections,
+// * POST /replays/<id>/save - saves a snapshot of what we have so far, writers stay connected.
+// Responses are JSON. There's no authentication, so we only listen on localhost.
+//
+// We don't need a real HTTP server for this. We handle one request per connection, then close it.
+
+const MAX_REQUEST_SIZE: usize = 8192;
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Debug, PartialEq, Eq)]
+enum Route {
+    ListReplays,
+    ReplayDetails(u64),
+    Command(u64, ReplayCommand),
+}
+
+fn route(method: &str, path: &str) -> Result<Route, StatusCode> {
+    let path = path.split('?').next().unwrap_or("");
+    let parts: Vec<&str> = path.trim_end_matches('/').split('/').skip(1).collect();
+    let parse_id = |id: &str| id.parse::<u64>().map_err(|_| StatusCode::NOT_FOUND);
+    let (expected_method, route) = match parts[..] {
+        ["replays"] => (Method::GET, Route::ListReplays),
+        ["replays", id] => (Method::GET, Route::ReplayDetails(parse_id(id)?)),
+        ["replays", id, action] => {
+            let command = match action {
+                "end" => ReplayCommand::ForceEnd,
+                "stop-writers" => ReplayCommand::StopAcceptingWriters,
+                "save" => ReplayCommand::SaveNow,
+                _ => return Err(StatusCode::NOT_FOUND),
+            };
+            (Method::POST, Route::Command(parse_id(id)?, command))
+        }
+        _ => return Err(StatusCode::NOT_FOUND),
+    };
+    if method != expected_method.as_str() {
+        return Err(StatusCode::METHOD_NOT_ALLOWED);
+    }
+    Ok(route)
+}
+
+// Returns request method and path. We ignore headers and body, we don't need them.
+async fn read_request(s: &mut TcpStream) -> Option<(String, String)> {
+    let mut buf = Vec::new();
+    loop {
+        let mut chunk = [0; 1024];
+        let read = s.read(&mut chunk).await.ok()?;
+        if read == 0 {
+            return None;
+        }
+        buf.extend_from_slice(&chunk[..read]);
+        let mut headers = [httparse::EMPTY_HEADER; 32];
+        let mut req = httparse::Request::new(&mut headers);
+        match req.parse(&buf) {
+            Ok(httparse::Status::Complete(_)) => {
+                return Some((req.method?.to_owned(), req.path?.to_owned()));
+            }
+            Ok(httparse::Status::Partial) if buf.len() < MAX_REQUEST_SIZE => (),
+            _ => return None,
+        }
+    }
+}
+
+fn error_body(status: StatusCode) -> Value {
+    json!({ "error": status.canonical_reason() })
+}
+
+async fn respond(route: Route, replays: &ReplayInspector) -> (StatusCode, Value) {
+    let not_running = (StatusCode::NOT_FOUND, json!({ "error": "Replay is not running" }));
+    match route {
+        Route::ListReplays => (StatusCode::OK, json!({ "replays": replays.list_replays().await })),
+        Route::ReplayDetails(id) => match replays.replay_details(id).await {
+            Some(d) => (StatusCode::OK, json!(d)),
+            None => not_running,
+        },
+        Route::Command(id, command) => match replays.run_replay_command(id, command).await {
+            true => (StatusCode::OK, json!({})),
+            false => not_running,
+        },
+    }
+}
+
+async fn handle_admin_connection(mut s: TcpStream, replays: &ReplayInspector) {
+    let request = timeout(read_request(&mut s), REQUEST_TIMEOUT).await.flatten();
+    let route = request.ok_or(StatusCode::BAD_REQUEST).and_then(|(method, path)| {
+        log::debug!("Admin API request: {} {}", method, path);
+        route(&method, &path)
+    });
+    let (status, body) = match route {
+        Err(status) => (status, error_body(status)),
+        Ok(r) => respond(r, replays).await,
+    };
+    let body = body.to_string();
+    let response = format!(
+        "HTTP/1.1 {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        body.len(),
+        body
+    );
+    let res = async {
+        s.write_all(response.as_bytes()).await?;
+        s.shutdown().await
+    }
+    .await;
+    if let Err(e) = res {
+        log::debug!("Failed to send admin API response: {}", e);
+    }
+}
+
+pub async fn admin_listener(port: u16) -> std::io::Result<TcpListener> {
+    TcpListener::bind(("127.0.0.1", port)).await
+}
+
+pub async fn serve_admin_api(listener: TcpListener, replays: &ReplayInspector) {
+    TcpListenerStream::new(listener)
+        .for_each_concurrent(None, |s| async move {
+            match s {
+                Ok(s) => handle_admin_connection(s, replays).await,
+                Err(e) => log::info!("Failed to accept admin API connection: {}", e),
+            }
+        })
+        .await
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_admin_routes() {
+        assert_eq!(route("GET", "/replays"), Ok(Route::ListReplays));
+        assert_eq!(route("GET", "/replays/"), Ok(Route::ListReplays));
+        assert_eq!(route("GET", "/replays/12?pretty"), Ok(Route::ReplayDetails(12)));
+        assert_eq!(
+            route("POST", "/replays/12/end"),
+            Ok(Route::Command(12, ReplayCommand::ForceEnd))
+        );
+        assert_eq!(
+            route("POST", "/replays/12/stop-writers"),
+            Ok(Route::Command(12, ReplayCommand::StopAcceptingWriters))
+        );
+        assert_eq!(
+            route("POST", "/replays/12/save"),
+            Ok(Route::Command(12, ReplayCommand::SaveNow))
+        );
+        assert_eq!(route("POST", "/replays"), Err(StatusCode::METHOD_NOT_ALLOWED));
+        assert_eq!(route("GET", "/replays/12/end"), Err(StatusCode::METHOD_NOT_ALLOWED));
+        assert_eq!(route("GET", "/replays/foo"), Err(StatusCode::NOT_FOUND));
+        assert_eq!(route("POST", "/replays/12/explode"), Err(StatusCode::NOT_FOUND));
+        assert_eq!(route("GET", "/"), Err(StatusCode::NOT_FOUND));
+    }
+}
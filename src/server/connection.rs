@@ -1,10 +1,8 @@
 use rand::Rng;
 use std::fmt::Display;
+use std::net::SocketAddr;
 
-use crate::{
-    accept::header::{ConnectionHeader, ConnectionType},
-    metrics,
-};
+use crate::{accept::header::ConnectionHeader, accept::limits::IpSlot, metrics};
 use tokio::{io::AsyncBufRead, io::AsyncBufReadExt, io::AsyncRead, io::AsyncWrite, io::BufReader, net::TcpStream};
 
 pub type ReaderType = Box<dyn AsyncBufRead + Send>;
@@ -15,6 +13,8 @@ pub struct Connection {
     writer: WriterType,
     header: Option<ConnectionHeader>,
     id: String,
+    peer_addr: Option<SocketAddr>,
+    ip_slot: Option<IpSlot>,
 }
 
 impl Connection {
@@ -35,6 +35,8 @@ impl Connection {
             writer,
             header: None,
             id,
+            peer_addr: None,
+            ip_slot: None,
         };
         s.set_metric();
         log::debug!("New {}", s);
@@ -44,10 +46,7 @@ impl Connection {
     fn header2label(&self) -> &str {
         match &self.header {
             None => "initial",
-            Some(h) => match h.type_ {
-                ConnectionType::Reader => "reader",
-                ConnectionType::Writer => "writer",
-            },
+            Some(h) => h.type_.as_str(),
         }
     }
 
@@ -64,10 +63,36 @@ impl Connection {
         self.set_metric();
     }
 
+    pub fn has_header(&self) -> bool {
+        self.header.is_some()
+    }
+
     pub fn get_header(&self) -> ConnectionHeader {
         self.header.clone().unwrap()
     }
 
+    pub fn get_id(&self) -> &str {
+        &self.id
+    }
+
+    // Real address of the other side, past any proxies. Not known for e.g. test connections.
+    pub fn set_peer_addr(&mut self, addr: Option<SocketAddr>) {
+        self.peer_addr = addr;
+    }
+
+    pub fn get_peer_addr(&self) -> Option<SocketAddr> {
+        self.peer_addr
+    }
+
+    // Counts us towards the per-IP connection limit until we're dropped.
+    pub fn set_ip_slot(&mut self, slot: IpSlot) {
+        self.ip_slot = Some(slot);
+    }
+
+    pub fn has_ip_slot(&self) -> bool {
+        self.ip_slot.is_some()
+    }
+
     fn get_buf_reader(&mut self) -> &mut dyn AsyncBufRead {
         &mut *self.reader
     }
@@ -78,19 +103,13 @@ impl Connection {
 
 impl Display for Connection {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Connection {}", self.id)?;
+        if let Some(addr) = &self.peer_addr {
+            write!(f, " from {}", addr)?;
+        }
         match &self.header {
-            None => write!(f, "Connection {}", self.id),
-            Some(h) => write!(
-                f,
-                "Connection {}, {} '{}' for replay {}",
-                self.id,
-                match h.type_ {
-                    ConnectionType::Reader => "reader",
-                    ConnectionType::Writer => "writer",
-                },
-                h.name,
-                h.id
-            ),
+            None => Ok(()),
+            Some(h) => write!(f, ", {} '{}' for replay {}", h.type_.as_str(), h.name, h.id),
         }
     }
 }
@@ -179,6 +198,7 @@ pub mod test {
     use tokio::{io::AsyncWriteExt, io::BufReader, join};
 
     use super::*;
+    use crate::accept::header::ConnectionType;
 
     #[tokio::test]
     async fn read_until_exact_normal() {
@@ -244,8 +264,20 @@ pub mod test {
         };
     }
 
+    #[test]
+    fn test_connection_display_includes_peer_addr() {
+        let (mut c, _r, _w) = test_connection();
+        assert!(!c.to_string().contains(" from "));
+        c.set_peer_addr(Some("10.0.0.1:1234".parse().unwrap()));
+        c.set_header(ConnectionHeader::new(ConnectionType::Reader, 2, "foo".into()));
+        assert!(c
+            .to_string()
+            .ends_with(" from 10.0.0.1:1234, reader 'foo' for replay 2"));
+    }
+
     /* Connection, reader, writer */
     pub type MockConnection = (Connection, tokio::io::DuplexStream, tokio::io::DuplexStream);
+
     pub fn test_connection() -> MockConnection {
         // NOTE: setting too small buffer sizes below interacts badly with tokio::time::pause.
         // As long as they're larger than the largest possible read, everything seems OK.
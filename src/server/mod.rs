@@ -1,3 +1,4 @@
+pub mod admin;
 pub mod connection;
 pub mod server;
 pub mod websocket_stream;
@@ -1,26 +1,54 @@
+use std::net::SocketAddr;
 use std::pin::Pin;
+use std::sync::Arc;
 
+use super::admin::{admin_listener, serve_admin_api};
 use super::connection::Connection;
-use crate::accept::header::read_initial_header;
-use crate::accept::producer::websocket_listen;
-use crate::config::ServerSettings;
+use super::websocket_stream::Keepalive;
+use crate::accept::activation::InheritedListeners;
+use crate::accept::auth::{ReaderAuth, WriterAuth};
+use crate::accept::header::{read_initial_header, ConnectionType};
+use crate::accept::limits::ConnectionLimits;
+use crate::accept::producer::ListenerOptions;
+use crate::accept::producer::{tcp_listen_on, unix_listen, unix_listen_on, websocket_listen_on};
+use crate::accept::tls::ReloadableTls;
+use crate::config::{ListenerProtocol, ListenerSettings, ServerSettings};
 use crate::database::database::Database;
-use crate::replay::runner::ReplayRunner;
-use crate::util::timeout::cancellable;
-use crate::{accept::producer::tcp_listen, config::Settings, replay::save::InnerReplaySaver};
+use crate::database::queries::Queries;
+use crate::error::ConnResult;
+use crate::replay::receive::GameLookup;
+use crate::replay::runner::{ReplayInspector, ReplayRunner};
+use crate::util::timeout::until;
+use crate::{config::Settings, replay::save::InnerReplaySaver};
 use crate::{metrics, replay::save::SavedReplayDirectory};
 use futures::{Stream, StreamExt};
+use tokio::io::AsyncWriteExt;
+use tokio::net::{TcpListener, UnixListener};
+use tokio::{join, select};
 use tokio_stream::StreamMap;
 use tokio_util::sync::CancellationToken;
 
 pub struct Server<C: Stream<Item = Connection>> {
     config: Settings,
     shutdown_token: CancellationToken,
+    drain_token: CancellationToken,
+    admin_listener: Option<TcpListener>,
+    limits: ConnectionLimits,
     connections: C,
     db: Database,
     dir: SavedReplayDirectory,
 }
 
+// Sends a JSON list of running replays, one line, then closes.
+async fn send_replay_list(c: &mut Connection, replays: &ReplayInspector) -> ConnResult<()> {
+    let replays = replays.list_replays().await;
+    let mut data = serde_json::to_vec(&serde_json::json!({ "replays": replays })).unwrap();
+    data.push(b'\n');
+    c.write_all(&data).await?;
+    c.shutdown().await?;
+    Ok(())
+}
+
 impl<C: Stream<Item = Connection>> Server<C> {
     fn new(
         config: Settings,
@@ -29,9 +57,13 @@ impl<C: Stream<Item = Connection>> Server<C> {
         db: Database,
         dir: SavedReplayDirectory,
     ) -> Self {
+        let limits = ConnectionLimits::new(&config.server);
         Self {
             config,
             shutdown_token,
+            drain_token: CancellationToken::new(),
+            admin_listener: None,
+            limits,
             connections,
             db,
             dir,
@@ -39,53 +71,236 @@ impl<C: Stream<Item = Connection>> Server<C> {
     }
 
     pub async fn run(self) {
-        let saver = InnerReplaySaver::new(self.db, self.dir, &self.config);
-        let runner = ReplayRunner::new(self.config.clone(), self.shutdown_token.clone(), saver);
+        let queries = Arc::new(Queries::new(self.db));
+        let saver = InnerReplaySaver::new(queries.clone(), self.dir, &self.config);
+        let games = GameLookup::new(queries, &self.config);
+        let runner = ReplayRunner::new(self.config.clone(), self.shutdown_token.clone(), saver, games);
+        let replays = runner.inspector();
+        let budget = runner.budget();
+        let shedding_interval = self.config.replay.update_interval_s;
 
         let initial_timeout = self.config.server.connection_accept_timeout_s;
-        let accept_connections = self.connections.for_each_concurrent(None, |mut c| async {
-            match read_initial_header(&mut c, initial_timeout).await {
-                Err(e) => {
-                    // Ignore connections with no data. Anyone who joins then leaves a lobby is one
-                    // such connection, for example.
-                    if e.is_no_data() {
-                        return;
+        let writer_auth = WriterAuth::new(self.config.server.writer_token_secret.as_deref());
+        let reader_auth = ReaderAuth::new(self.config.server.privileged_reader_token_secret.as_deref());
+        let limits = self.limits;
+        let drain_timeout = self.config.server.drain_timeout_s;
+        let shutdown_token = self.shutdown_token;
+        let drain_token = self.drain_token;
+        let stop_accepting = async {
+            select! {
+                _ = shutdown_token.cancelled() => (),
+                _ = drain_token.cancelled() => (),
+            }
+        };
+        let connections = self.connections;
+        let serve = async {
+            let accept_connections = connections.for_each_concurrent(None, |mut c| async {
+                let accepted = async {
+                    limits.admit(&mut c)?;
+                    let _pending = limits.start_reading_header()?;
+                    read_initial_header(&mut c, initial_timeout, &writer_auth, &reader_auth).await
+                };
+                match accepted.await {
+                    Err(e) => {
+                        // Ignore connections with no data. Anyone who joins then leaves a lobby is
+                        // one such connection, for example.
+                        if e.is_no_data() {
+                            return;
+                        }
+                        log::info!("Could not accept {}: {}", c, e);
+                        metrics::inc_served_conns(Some(e));
                     }
-                    log::info!("Could not accept connection: {}", e);
-                    metrics::inc_served_conns(Some(e));
+                    Ok(_) if c.get_header().type_ == ConnectionType::List => {
+                        let res = send_replay_list(&mut c, &replays).await;
+                        metrics::inc_served_conns(res.err());
+                    }
+                    Ok(_) => runner.dispatch_connection(c).await,
                 }
-                Ok(_) => runner.dispatch_connection(c).await,
+            });
+
+            // Dropping the connection stream closes our listeners, so a new instance can take them
+            // over.
+            match until(accept_connections, stop_accepting).await {
+                Some(_) => log::warn!("Server stopped accepting connections for some reason!"),
+                None if shutdown_token.is_cancelled() => log::info!("Server shutting down"),
+                None => log::info!("Server draining, waiting for running replays to finish"),
             }
-        });
 
-        match cancellable(accept_connections, &self.shutdown_token).await {
-            Some(_) => log::warn!("Server stopped accepting connections for some reason!"),
-            None => log::info!("Server shutting down"),
-        }
+            // Workers exit once they have no replays left.
+            let workers_done = tokio::task::spawn_blocking(|| runner.shutdown());
+            let drain_deadline = async {
+                if let Some(t) = drain_timeout {
+                    tokio::time::sleep(t).await;
+                    log::info!("Replays did not finish draining in time, ending them");
+                    shutdown_token.cancel();
+                }
+                std::future::pending::<()>().await
+            };
+            select! {
+                _ = workers_done => (),
+                _ = drain_deadline => (),
+            }
+        };
+
+        let admin_listener = self.admin_listener;
+        // The admin API keeps going while we drain, it's how we see what's left.
+        let admin_api = async {
+            match admin_listener {
+                Some(l) => serve_admin_api(l, &replays).await,
+                None => std::future::pending().await,
+            }
+        };
+        // One for the whole server, workers only keep it up to date.
+        let shed_load = budget.shed_load(shedding_interval);
+        until(serve, async { join!(admin_api, shed_load) }).await;
+    }
+
+    // Once the token is cancelled, the server stops accepting connections and waits for running
+    // replays to end on their own, within configured drain time.
+    pub fn with_drain_token(mut self, drain_token: CancellationToken) -> Self {
+        self.drain_token = drain_token;
+        self
+    }
+
+    // Serves the admin API until the server exits, including while it drains.
+    pub fn with_admin_listener(mut self, listener: TcpListener) -> Self {
+        self.admin_listener = Some(listener);
+        self
+    }
+
+    // Limits shared with our listeners, so that sockets they admitted aren't counted twice.
+    pub fn with_connection_limits(mut self, limits: ConnectionLimits) -> Self {
+        self.limits = limits;
+        self
+    }
+}
+
+// A listener we actually bound. Ports are the ones we got, e.g. if we asked for port 0.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BoundListener {
+    pub protocol: ListenerProtocol,
+    // IP address for TCP and websocket, socket path for Unix.
+    pub address: String,
+    pub port: Option<u16>,
+}
 
-        let _ = tokio::task::spawn_blocking(|| runner.shutdown()).await;
+impl std::fmt::Display for BoundListener {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.port {
+            Some(p) if self.address.contains(':') => write!(f, "{:?} on [{}]:{}", self.protocol, self.address, p),
+            Some(p) => write!(f, "{:?} on {}:{}", self.protocol, self.address, p),
+            None => write!(f, "{:?} on {}", self.protocol, self.address),
+        }
     }
 }
 
 #[derive(Default)]
 pub struct PortInfo {
-    pub tcp: Option<u16>,
-    pub websocket: Option<u16>,
+    pub listeners: Vec<BoundListener>,
+    pub admin: Option<u16>,
 }
 
-async fn collect_server_connections(config: &ServerSettings) -> (impl Stream<Item = Connection>, PortInfo) {
-    let mut all_connections: StreamMap<u32, Pin<Box<dyn Stream<Item = Connection>>>> = StreamMap::new();
-    let mut port_info: PortInfo = Default::default();
-    // Constructor of config guarantees at least one port is not None
-    if let Some(p) = config.port {
-        let (stream, port) = tcp_listen(format!("0.0.0.0:{}", p)).await;
-        port_info.tcp = Some(port);
-        all_connections.insert(0, Box::pin(stream));
+impl PortInfo {
+    fn first(&self, protocol: ListenerProtocol) -> Option<&BoundListener> {
+        self.listeners.iter().find(|l| l.protocol == protocol)
+    }
+
+    // Shortcuts for when there's one listener of a given protocol.
+    pub fn tcp(&self) -> Option<u16> {
+        self.first(ListenerProtocol::Tcp).and_then(|l| l.port)
+    }
+
+    pub fn websocket(&self) -> Option<u16> {
+        self.first(ListenerProtocol::Ws).and_then(|l| l.port)
+    }
+
+    pub fn unix_socket(&self) -> Option<String> {
+        self.first(ListenerProtocol::Unix).map(|l| l.address.clone())
     }
-    if let Some(p) = config.websocket_port {
-        let (stream, port) = websocket_listen(format!("0.0.0.0:{}", p)).await;
-        port_info.websocket = Some(port);
-        all_connections.insert(1, Box::pin(stream));
+}
+
+async fn tcp_listener(inherited: &mut InheritedListeners, addr: SocketAddr) -> TcpListener {
+    match inherited.take_tcp(addr) {
+        Some(l) => {
+            log::info!("Using inherited socket for {}", addr);
+            l.set_nonblocking(true).unwrap();
+            TcpListener::from_std(l).unwrap()
+        }
+        None => TcpListener::bind(addr).await.unwrap(),
+    }
+}
+
+async fn listen_on(
+    l: &ListenerSettings,
+    options: ListenerOptions,
+    inherited: &mut InheritedListeners,
+) -> (Pin<Box<dyn Stream<Item = Connection>>>, BoundListener) {
+    // Constructor of config guarantees that addresses are valid and ports are set where needed
+    let address = match l.protocol {
+        ListenerProtocol::Unix => l.address.clone().unwrap(),
+        _ => l.address.clone().unwrap_or_else(|| "0.0.0.0".into()),
+    };
+    let bind_addr = |a: &str| SocketAddr::new(a.parse().unwrap(), l.port.unwrap());
+    let (stream, port): (Pin<Box<dyn Stream<Item = Connection>>>, _) = match l.protocol {
+        ListenerProtocol::Tcp => {
+            let (stream, port) = tcp_listen_on(tcp_listener(inherited, bind_addr(&address)).await, options);
+            (Box::pin(stream), Some(port))
+        }
+        ListenerProtocol::Ws => {
+            let (stream, port) = websocket_listen_on(tcp_listener(inherited, bind_addr(&address)).await, options);
+            (Box::pin(stream), Some(port))
+        }
+        ListenerProtocol::Unix => match inherited.take_unix(&address) {
+            Some(listener) => {
+                log::info!("Using inherited socket for {}", address);
+                listener.set_nonblocking(true).unwrap();
+                (
+                    Box::pin(unix_listen_on(UnixListener::from_std(listener).unwrap())),
+                    None,
+                )
+            }
+            None => (Box::pin(unix_listen(address.clone()).await), None),
+        },
+    };
+    let bound = BoundListener {
+        protocol: l.protocol,
+        address,
+        port,
+    };
+    (stream, bound)
+}
+
+// Sockets we inherited, e.g. from systemd, that match a listener get used instead of binding.
+async fn collect_server_connections(
+    config: &ServerSettings,
+    mut inherited: InheritedListeners,
+    limits: &ConnectionLimits,
+) -> (impl Stream<Item = Connection>, PortInfo) {
+    let mut all_connections: StreamMap<usize, Pin<Box<dyn Stream<Item = Connection>>>> = StreamMap::new();
+    let mut port_info: PortInfo = Default::default();
+    let listeners = config.all_listeners();
+    // Constructor of config guarantees we have certificate paths if we need them
+    let tls = match (&config.tls_cert_path, &config.tls_key_path) {
+        (Some(cert), Some(key)) if listeners.iter().any(|l| l.tls) => {
+            Some(ReloadableTls::new(cert, key).unwrap_or_else(|e| panic!("Failed to set up TLS: {}", e)))
+        }
+        _ => None,
+    };
+    let keepalive = config.websocket_ping_interval_s.map(|ping_interval| Keepalive {
+        ping_interval,
+        pong_timeout: config.websocket_pong_timeout_s.unwrap_or(ping_interval),
+    });
+    // Constructor of config guarantees at least one listener is configured
+    for (i, l) in listeners.iter().enumerate() {
+        let options = ListenerOptions {
+            proxy_protocol: l.proxy_protocol,
+            tls: tls.clone().filter(|_| l.tls),
+            keepalive: keepalive.filter(|_| l.protocol == ListenerProtocol::Ws),
+            limits: Some(limits.clone()),
+        };
+        let (stream, bound) = listen_on(l, options, &mut inherited).await;
+        port_info.listeners.push(bound);
+        all_connections.insert(i, stream);
     }
     let all_connections_ignore_idx = tokio_stream::StreamExt::map(all_connections, |(_, v)| v);
     return (all_connections_ignore_idx, port_info);
@@ -94,16 +309,39 @@ async fn collect_server_connections(config: &ServerSettings) -> (impl Stream<Ite
 pub async fn server_with_real_deps(
     config: Settings,
     shutdown_token: CancellationToken,
+    inherited: InheritedListeners,
 ) -> (Server<impl Stream<Item = Connection>>, PortInfo) {
-    let (all_connections, port_info) = collect_server_connections(&config.server).await;
+    let limits = ConnectionLimits::new(&config.server);
+    let (all_connections, port_info) = collect_server_connections(&config.server, inherited, &limits).await;
     let db = Database::new(&config.database);
     let dir = SavedReplayDirectory::new(config.storage.vault_path.as_ref());
-    (Server::new(config, shutdown_token, all_connections, db, dir), port_info)
+    let mut port_info = port_info;
+    let mut server =
+        Server::new(config.clone(), shutdown_token, all_connections, db, dir).with_connection_limits(limits);
+    if let Some(port) = config.server.admin_port {
+        let listener = admin_listener(port)
+            .await
+            .unwrap_or_else(|e| panic!("Failed to bind admin API port: {}", e));
+        port_info.admin = Some(listener.local_addr().unwrap().port());
+        server = server.with_admin_listener(listener);
+    }
+    (server, port_info)
 }
 
-pub async fn run_server(config: Settings, shutdown_token: CancellationToken) {
-    let (server, _) = server_with_real_deps(config, shutdown_token).await;
-    server.run().await;
+pub async fn run_server(
+    config: Settings,
+    inherited: InheritedListeners,
+    shutdown_token: CancellationToken,
+    drain_token: CancellationToken,
+) {
+    let (server, port_info) = server_with_real_deps(config, shutdown_token, inherited).await;
+    for l in port_info.listeners.iter() {
+        log::info!("Accepting connections: {}", l);
+    }
+    if let Some(p) = port_info.admin {
+        log::info!("Admin API on localhost port {}", p);
+    }
+    server.with_drain_token(drain_token).run().await;
 }
 
 #[cfg(test)]
@@ -117,6 +355,8 @@ mod test {
     use async_stream::stream;
     use futures::future::join_all;
     use std::{
+        future::Future,
+        path::PathBuf,
         rc::Rc,
         sync::{
             atomic::{AtomicBool, Ordering},
@@ -131,6 +371,7 @@ mod test {
     };
 
     use super::*;
+    use crate::config::InnerSettings;
     use crate::replay::save::directory::test::test_directory;
     use crate::replay::save::test::unpack_replay;
     use crate::util::test::compare_bufs;
@@ -142,6 +383,116 @@ mod test {
         (tmp_dir, dir)
     }
 
+    #[tokio::test]
+    async fn test_server_binds_all_listeners() {
+        let sock_dir = tempdir().unwrap();
+        let sock_path = sock_dir.path().join("replayserver.sock").to_str().unwrap().to_owned();
+        let mut conf = default_config().server;
+        conf.port = Some(0);
+        conf.websocket_port = None;
+        let listener = |protocol, address: &str, port| ListenerSettings {
+            protocol,
+            address: Some(address.into()),
+            port,
+            proxy_protocol: false,
+            tls: false,
+        };
+        conf.listeners = vec![
+            listener(ListenerProtocol::Tcp, "127.0.0.1", Some(0)),
+            listener(ListenerProtocol::Ws, "127.0.0.1", Some(0)),
+            listener(ListenerProtocol::Unix, &sock_path, None),
+        ];
+
+        let (conns, port_info) =
+            collect_server_connections(&conf, Default::default(), &ConnectionLimits::new(&conf)).await;
+        let bound: Vec<_> = port_info
+            .listeners
+            .iter()
+            .map(|l| (l.protocol, l.address.clone()))
+            .collect();
+        assert_eq!(
+            bound,
+            vec![
+                (ListenerProtocol::Tcp, "0.0.0.0".into()),
+                (ListenerProtocol::Tcp, "127.0.0.1".into()),
+                (ListenerProtocol::Ws, "127.0.0.1".into()),
+                (ListenerProtocol::Unix, sock_path.clone()),
+            ]
+        );
+        assert_eq!(port_info.unix_socket(), Some(sock_path.clone()));
+
+        // Every listener accepts connections.
+        let mut conns = Box::pin(conns);
+        for l in port_info
+            .listeners
+            .iter()
+            .filter(|l| l.protocol == ListenerProtocol::Tcp)
+        {
+            let _client = tokio::net::TcpStream::connect(format!("127.0.0.1:{}", l.port.unwrap()))
+                .await
+                .unwrap();
+            conns.next().await.unwrap();
+        }
+        let _client = tokio::net::UnixStream::connect(&sock_path).await.unwrap();
+        conns.next().await.unwrap();
+    }
+
+    // Builds servers with a mock database that save replays to a temporary directory. Servers run
+    // replays on worker threads, so tests that have any can't use tokio::time::pause.
+    struct TestServer {
+        token: CancellationToken,
+        vault: TempDir,
+    }
+
+    impl TestServer {
+        fn new() -> Self {
+            Self {
+                token: CancellationToken::new(),
+                vault: tempdir().unwrap(),
+            }
+        }
+
+        fn server<C: Stream<Item = Connection>>(&self, conf: InnerSettings, connections: C) -> Server<C> {
+            self.server_with_db(conf, connections, mock_database())
+        }
+
+        fn server_with_db<C: Stream<Item = Connection>>(
+            &self,
+            conf: InnerSettings,
+            connections: C,
+            db: Database,
+        ) -> Server<C> {
+            let dir = SavedReplayDirectory::new(self.vault.path().to_str().unwrap());
+            Server::new(Arc::new(conf), self.token.clone(), connections, db, dir)
+        }
+
+        // Replays with small IDs all end up in the same directory.
+        fn vault_file(&self, name: &str) -> PathBuf {
+            self.vault.path().join("0/0/0/0").join(name)
+        }
+
+        // Replay file's JSON header and replay data.
+        async fn saved_replay(&self, id: u64) -> (Vec<u8>, Vec<u8>) {
+            let file = File::open(self.vault_file(&format!("{}.fafreplay", id))).await.unwrap();
+            unpack_replay(file).await.unwrap()
+        }
+
+        fn merge_report(&self, id: u64) -> serde_json::Value {
+            let report = std::fs::read(self.vault_file(&format!("{}.merge.json", id))).unwrap();
+            serde_json::from_slice(&report).unwrap()
+        }
+    }
+
+    // Runs the server on its own task, so that it can't hold up the clients.
+    async fn run_server<C>(server: Server<C>, clients: impl Future<Output = ()>)
+    where
+        C: Stream<Item = Connection> + Send + 'static,
+    {
+        let server_thread = tokio::spawn(server.run());
+        clients.await;
+        server_thread.await.unwrap();
+    }
+
     #[tokio::test]
     async fn test_server_single_empty_connection() {
         setup_logging();
@@ -232,6 +583,406 @@ mod test {
         compare_bufs(example_replay_file, saved_replay);
     }
 
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_server_saves_tick_index() {
+        setup_logging();
+
+        let (c_write, _reader, mut writer) = test_connection();
+        let mut conf = default_config();
+        let test_server = TestServer::new();
+
+        conf.replay.time_with_zero_writers_to_end_replay_s = Duration::from_secs(1);
+
+        let conn_source = stream! {
+            yield c_write;
+        };
+        let server = test_server.server(conf, conn_source);
+
+        let example_replay_file = get_file("example");
+        let example_body = get_file("example_body");
+        let replay_writing = async {
+            writer.write_all(b"P/2/foo\0").await.unwrap();
+            writer.write_all(&example_replay_file).await.unwrap();
+            drop(writer);
+        };
+        run_server(server, replay_writing).await;
+
+        let index = std::fs::read(test_server.vault_file("2.tickindex.json")).unwrap();
+        let index: serde_json::Value = serde_json::from_slice(&index).unwrap();
+        let body_offset = (example_replay_file.len() - example_body.len()) as u64;
+        assert_eq!(index["body_offset"], body_offset);
+        assert!(index["index"]["ticks"].as_u64().unwrap() > 0);
+        assert_eq!(index["index"]["entries"][0]["offset"], 0);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_server_saves_merge_report() {
+        setup_logging();
+
+        let (c_write, _reader, mut writer) = test_connection();
+        let mut conf = default_config();
+        let test_server = TestServer::new();
+
+        conf.replay.time_with_zero_writers_to_end_replay_s = Duration::from_secs(1);
+
+        let conn_source = stream! {
+            yield c_write;
+        };
+        let server = test_server.server(conf, conn_source);
+
+        let example_replay_file = get_file("example");
+        let body_len = get_file("example_body").len();
+        let replay_writing = async {
+            writer.write_all(b"P/2/foo\0").await.unwrap();
+            writer.write_all(&example_replay_file).await.unwrap();
+            drop(writer);
+        };
+        run_server(server, replay_writing).await;
+
+        let report = test_server.merge_report(2);
+        assert_eq!(report["merged_bytes"], body_len);
+        assert_eq!(
+            report["writers"],
+            serde_json::json!([{"name": "foo", "player_id": null, "bytes": body_len, "header_accepted": true, "diverged": null}])
+        );
+    }
+
+    fn spool_file(header: &[u8], body: &[u8]) -> Vec<u8> {
+        let mut data = (header.len() as u32).to_le_bytes().to_vec();
+        data.extend_from_slice(header);
+        data.extend_from_slice(body);
+        data
+    }
+
+    #[tokio::test]
+    async fn test_server_recovers_spooled_replays() {
+        setup_logging();
+
+        let mut conf = default_config();
+        let test_server = TestServer::new();
+        let spool_dir = tempdir().unwrap();
+        conf.storage.spool_path = Some(spool_dir.path().to_str().unwrap().into());
+
+        let example_replay_file = get_file("example");
+        let example_body = get_file("example_body");
+        let header = &example_replay_file[..example_replay_file.len() - example_body.len()];
+        let cut = example_body.len() / 2;
+        let spooled = spool_file(header, &example_body[..cut]);
+        tokio::fs::write(spool_dir.path().join("2.spool"), spooled)
+            .await
+            .unwrap();
+
+        // Workers recover spooled replays even if we shut down right away.
+        let conn_source = stream! {
+            std::future::pending::<()>().await;
+            yield test_connection().0;
+        };
+        test_server.token.cancel();
+        let server = test_server.server(conf, conn_source);
+        tokio::time::timeout(Duration::from_secs(5), server.run())
+            .await
+            .unwrap();
+
+        let (json, saved_replay) = test_server.saved_replay(2).await;
+        let json: serde_json::Value = serde_json::from_slice(&json).unwrap();
+        assert_eq!(json["complete"], false);
+        compare_bufs(&example_replay_file[..header.len() + cut], saved_replay);
+        assert!(!spool_dir.path().join("2.spool").exists());
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_server_drain_lets_replays_finish() {
+        setup_logging();
+
+        let (c_write, _reader, mut writer) = test_connection();
+        let (c_never_accepted, mut r_never_accepted, _w) = test_connection();
+        let mut conf = default_config();
+        let test_server = TestServer::new();
+        let drain_token = CancellationToken::new();
+        conf.replay.time_with_zero_writers_to_end_replay_s = Duration::from_millis(100);
+
+        let conn_source = stream! {
+            yield c_write;
+            std::future::pending::<()>().await;
+            yield c_never_accepted;
+        };
+        let server = test_server
+            .server(conf, conn_source)
+            .with_drain_token(drain_token.clone())
+            .run();
+
+        let example_replay_file = get_file("example");
+        let replay_writing = async {
+            writer.write_all(b"P/2/foo\0").await.unwrap();
+            sleep_ms(30).await;
+            drain_token.cancel();
+            // No more connections once we drain.
+            let mut buf = Vec::new();
+            assert_eq!(r_never_accepted.read_to_end(&mut buf).await.unwrap(), 0);
+            // Running replays go on.
+            for data in example_replay_file.chunks(1000) {
+                writer.write_all(data).await.unwrap();
+                sleep_ms(3).await;
+            }
+            drop(writer);
+        };
+        let ran = tokio::time::timeout(Duration::from_secs(5), async { join!(server, replay_writing) }).await;
+        assert!(ran.is_ok(), "Server should have exited after draining");
+        assert!(!test_server.token.is_cancelled());
+
+        let (_, saved_replay) = test_server.saved_replay(2).await;
+        compare_bufs(example_replay_file, saved_replay);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_server_drain_ends_replays_after_timeout() {
+        setup_logging();
+
+        let (c_write, _reader, mut writer) = test_connection();
+        let mut conf = default_config();
+        let test_server = TestServer::new();
+        let drain_token = CancellationToken::new();
+        conf.server.drain_timeout_s = Some(Duration::from_millis(100));
+
+        let conn_source = stream! { yield c_write; };
+        let server = test_server
+            .server(conf, conn_source)
+            .with_drain_token(drain_token.clone())
+            .run();
+
+        // Writer never finishes.
+        let replay_writing = async {
+            writer.write_all(b"P/2/foo\0").await.unwrap();
+            writer.write_all(&get_file("example")).await.unwrap();
+            sleep_ms(30).await;
+            drain_token.cancel();
+        };
+        let ran = tokio::time::timeout(Duration::from_secs(5), async { join!(server, replay_writing) }).await;
+        assert!(ran.is_ok(), "Server should have exited after drain timeout");
+        assert!(test_server.token.is_cancelled());
+        assert!(test_server.vault_file("2.fafreplay").exists());
+    }
+
+    #[tokio::test]
+    async fn test_server_closes_connections_over_limit() {
+        setup_logging();
+        tokio::time::pause();
+
+        let (c_idle, _r_idle, _w_idle) = test_connection();
+        let (c_over, mut r_over, _w_over) = test_connection();
+        let mut conf = default_config();
+        let test_server = TestServer::new();
+
+        conf.server.max_pending_connection_headers = Some(1);
+
+        let conn_source = stream! {
+            yield c_idle;
+            yield c_over;
+        };
+        let server = test_server.server(conf, conn_source).run();
+
+        let check_closed = async {
+            let mut buf = Vec::new();
+            let read = tokio::time::timeout(Duration::from_millis(100), r_over.read_to_end(&mut buf)).await;
+            assert!(matches!(read, Ok(Ok(0))));
+            test_server.token.cancel();
+        };
+        join! { server, check_closed };
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_server_lists_running_replays() {
+        setup_logging();
+
+        let (c_write_1, _r1, writer_1) = test_connection();
+        let (c_write_2, _r2, writer_2) = test_connection();
+        let (c_list, mut list_reader, mut list_writer) = test_connection();
+        let mut conf = default_config();
+        let test_server = TestServer::new();
+
+        conf.replay.time_with_zero_writers_to_end_replay_s = Duration::from_millis(100);
+
+        let conn_source = stream! {
+            yield c_write_1;
+            yield c_write_2;
+            tokio::time::sleep(Duration::from_millis(100)).await;
+            yield c_list;
+        };
+        let server = test_server.server(conf, conn_source);
+
+        let example_replay_file = get_file("example");
+        let replay_writing = |w: &'static [u8], mut writer: tokio::io::DuplexStream| {
+            let data = example_replay_file.clone();
+            async move {
+                writer.write_all(w).await.unwrap();
+                writer.write_all(&data[..500]).await.unwrap();
+                tokio::time::sleep(Duration::from_millis(300)).await;
+                drop(writer);
+            }
+        };
+        let mut listing = Vec::<u8>::new();
+        let list_reading = async {
+            list_writer.write_all(b"L/\0").await.unwrap();
+            list_reader.read_to_end(&mut listing).await.unwrap();
+        };
+
+        run_server(server, async {
+            join!(
+                replay_writing(b"P/2/foo\0", writer_1),
+                replay_writing(b"P/3/foo\0", writer_2),
+                list_reading,
+            );
+        })
+        .await;
+
+        assert_eq!(listing.last(), Some(&b'\n'));
+        let listing: serde_json::Value = serde_json::from_slice(&listing).unwrap();
+        let replays = listing["replays"].as_array().unwrap();
+        assert_eq!(replays.len(), 2);
+        for (replay, id) in replays.iter().zip([2, 3]) {
+            assert_eq!(replay["id"], id);
+            assert_eq!(replay["writers"], 1);
+            assert_eq!(replay["readers"], 0);
+            assert_eq!(replay["accepts_connections"], true);
+            assert!(replay["start_time"].as_u64().unwrap() > 0);
+        }
+    }
+
+    async fn admin_request(port: u16, method: &str, path: &str) -> (u16, serde_json::Value) {
+        let mut s = tokio::net::TcpStream::connect(("127.0.0.1", port)).await.unwrap();
+        let request = format!("{} {} HTTP/1.1\r\nHost: localhost\r\n\r\n", method, path);
+        s.write_all(request.as_bytes()).await.unwrap();
+        let mut response = String::new();
+        s.read_to_string(&mut response).await.unwrap();
+        let (head, body) = response.split_once("\r\n\r\n").unwrap();
+        let status = head.split(' ').nth(1).unwrap().parse().unwrap();
+        (status, serde_json::from_str(body).unwrap())
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_server_admin_api() {
+        setup_logging();
+
+        let (c_write, _reader, mut writer) = test_connection();
+        let mut conf = default_config();
+        let test_server = TestServer::new();
+
+        conf.replay.time_with_zero_writers_to_end_replay_s = Duration::from_secs(1);
+
+        let conn_source = stream! {
+            yield c_write;
+            std::future::pending::<()>().await;
+        };
+        let listener = admin_listener(0).await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let server = test_server
+            .server(conf, conn_source)
+            .with_admin_listener(listener)
+            .run();
+
+        let example_replay_file = get_file("example");
+        let replay_writing = async {
+            writer.write_all(b"P/2/foo\0").await.unwrap();
+            writer.write_all(&example_replay_file).await.unwrap();
+            // Stuck game, we never disconnect on our own.
+            tokio::time::sleep(Duration::from_millis(2000)).await;
+        };
+        let admin = async {
+            tokio::time::sleep(Duration::from_millis(100)).await;
+            let (status, listing) = admin_request(port, "GET", "/replays").await;
+            assert_eq!(status, 200);
+            assert_eq!(listing["replays"][0]["id"], 2);
+
+            let (status, details) = admin_request(port, "GET", "/replays/2").await;
+            assert_eq!(status, 200);
+            assert_eq!(details["writers"], 1);
+            let connections = details["connections"].as_array().unwrap();
+            assert_eq!(connections.len(), 1);
+            assert_eq!(connections[0]["type"], "writer");
+            assert_eq!(connections[0]["name"], "foo");
+
+            assert_eq!(admin_request(port, "GET", "/replays/3").await.0, 404);
+            assert_eq!(admin_request(port, "GET", "/replays/2/save").await.0, 405);
+            assert_eq!(admin_request(port, "POST", "/replays/2/save").await.0, 200);
+
+            // We got a snapshot, marked incomplete. The writer is still there.
+            tokio::time::sleep(Duration::from_millis(200)).await;
+            let snapshot_path = test_server.vault_file("2.snapshot.fafreplay");
+            let (json, snapshot) = unpack_replay(File::open(&snapshot_path).await.unwrap()).await.unwrap();
+            assert!(String::from_utf8(json).unwrap().contains(r#""complete":false"#));
+            // Only what we merged so far.
+            assert!(!snapshot.is_empty());
+            assert!(example_replay_file.starts_with(&snapshot));
+            let (status, details) = admin_request(port, "GET", "/replays/2").await;
+            assert_eq!(status, 200);
+            assert_eq!(details["writers"], 1);
+
+            // Saving for good replaces the snapshot.
+            assert_eq!(admin_request(port, "POST", "/replays/2/end").await.0, 200);
+            tokio::time::sleep(Duration::from_millis(200)).await;
+            assert_eq!(admin_request(port, "GET", "/replays/2").await.0, 404);
+            let (_, saved_replay) = test_server.saved_replay(2).await;
+            compare_bufs(example_replay_file.clone(), saved_replay);
+            assert!(!snapshot_path.exists());
+            test_server.token.cancel();
+        };
+
+        let server_thread = tokio::spawn(server);
+        select! {
+            _ = replay_writing => panic!("Replay should have been saved while the writer was connected"),
+            _ = admin => (),
+        }
+        server_thread.await.unwrap();
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_server_admin_api_works_while_draining() {
+        setup_logging();
+
+        let (c_write, _reader, mut writer) = test_connection();
+        let mut conf = default_config();
+        let test_server = TestServer::new();
+        let drain_token = CancellationToken::new();
+        conf.replay.time_with_zero_writers_to_end_replay_s = Duration::from_secs(1);
+
+        let conn_source = stream! {
+            yield c_write;
+            std::future::pending::<()>().await;
+        };
+        let listener = admin_listener(0).await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let server = test_server
+            .server(conf, conn_source)
+            .with_drain_token(drain_token.clone())
+            .with_admin_listener(listener)
+            .run();
+
+        let replay_writing = async {
+            writer.write_all(b"P/2/foo\0").await.unwrap();
+            writer.write_all(&get_file("example")).await.unwrap();
+            // Stuck game, we never disconnect on our own.
+            std::future::pending::<()>().await
+        };
+        let admin = async {
+            tokio::time::sleep(Duration::from_millis(100)).await;
+            drain_token.cancel();
+            tokio::time::sleep(Duration::from_millis(100)).await;
+            let (status, listing) = admin_request(port, "GET", "/replays").await;
+            assert_eq!(status, 200);
+            assert_eq!(listing["replays"][0]["id"], 2);
+            assert_eq!(admin_request(port, "POST", "/replays/2/end").await.0, 200);
+        };
+
+        let server_thread = tokio::spawn(server);
+        select! {
+            _ = replay_writing => (),
+            _ = admin => (),
+        }
+        let ran = tokio::time::timeout(Duration::from_secs(5), server_thread).await;
+        assert!(ran.is_ok(), "Server should have exited once the replay ended");
+        assert!(!test_server.token.is_cancelled());
+    }
+
     #[tokio::test(flavor = "multi_thread")]
     async fn test_server_ends_quickly() {
         setup_logging();
@@ -1,15 +1,21 @@
+use std::collections::VecDeque;
+
 use futures::{Sink, SinkExt, Stream, StreamExt};
-use tokio::{
-    io::{AsyncBufRead, AsyncWrite},
-    net::TcpStream,
-};
+use http::Uri;
+use tokio::io::{AsyncBufRead, AsyncRead, AsyncWrite};
+use tokio::select;
+use tokio::sync::mpsc;
+use tokio::time::{interval_at, sleep_until, Duration, Instant, Interval};
+use tokio_stream::wrappers::ReceiverStream;
 use tokio_util::{
     bytes::Bytes,
     io::{CopyToBytes, SinkWriter, StreamReader},
+    sync::PollSender,
 };
 use tokio_websockets::{CloseCode, Error, Message, ServerBuilder, WebSocketStream};
 
 use super::connection::{ReaderType, WriterType};
+use crate::metrics;
 
 trait WebsocketSink: Sink<Message, Error = Error> {}
 impl<T: Sink<Message, Error = Error>> WebsocketSink for T {}
@@ -17,12 +23,19 @@ impl<T: Sink<Message, Error = Error>> WebsocketSink for T {}
 trait WebsocketStream: Stream<Item = Result<Message, Error>> {}
 impl<T: Stream<Item = Result<Message, Error>>> WebsocketStream for T {}
 
-async fn make_websocket(stream: TcpStream) -> Result<WebSocketStream<TcpStream>, Error> {
-    // Ignore the HTTP stuff
-    ServerBuilder::new().accept(stream).await.map(|(_, s)| s)
+// Whatever we run the websocket over, e.g. a TCP or TLS stream.
+pub(crate) trait SocketStream: AsyncRead + AsyncWrite + Unpin + Send + 'static {}
+impl<T: AsyncRead + AsyncWrite + Unpin + Send + 'static> SocketStream for T {}
+
+async fn make_websocket<S: SocketStream>(stream: S) -> Result<(Uri, WebSocketStream<S>), Error> {
+    // We only care about the URL, it might carry the connection header
+    ServerBuilder::new()
+        .accept(stream)
+        .await
+        .map(|(req, s)| (req.uri().clone(), s))
 }
 
-fn split_websocket(socket: WebSocketStream<TcpStream>) -> (impl WebsocketSink, impl WebsocketStream) {
+fn split_websocket<S: SocketStream>(socket: WebSocketStream<S>) -> (impl WebsocketSink, impl WebsocketStream) {
     socket.split()
 }
 
@@ -48,23 +61,25 @@ fn map_websocket_sink_to_bytes(sink: impl WebsocketSink) -> impl for<'a> Sink<&'
     CopyToBytes::new(bytes_sink)
 }
 
-fn map_websocket_stream_to_bytes(stream: impl WebsocketStream) -> impl Stream<Item = Result<Bytes, std::io::Error>> {
-    stream.map(|i| {
-        i.map_err(map_message_error_to_io_error)
-            .and_then(|mes| {
-                if let Some((code, reason)) = mes.as_close() {
-                    if code != CloseCode::NORMAL_CLOSURE {
-                        Err(std::io::Error::other(reason))
-                    } else {
-                        Ok(Bytes::new())
-                    }
-                } else if mes.is_ping() || mes.is_pong() {
-                    Ok(Bytes::new())
+fn message_to_bytes(i: Result<Message, Error>) -> Result<Bytes, std::io::Error> {
+    i.map_err(map_message_error_to_io_error)
+        .and_then(|mes| {
+            if let Some((code, reason)) = mes.as_close() {
+                if code != CloseCode::NORMAL_CLOSURE {
+                    Err(std::io::Error::other(reason))
                 } else {
-                    Ok(mes.into_payload().into())
+                    Ok(Bytes::new())
                 }
-            })
-    })
+            } else if mes.is_ping() || mes.is_pong() {
+                Ok(Bytes::new())
+            } else {
+                Ok(mes.into_payload().into())
+            }
+        })
+}
+
+fn map_websocket_stream_to_bytes(stream: impl WebsocketStream) -> impl Stream<Item = Result<Bytes, std::io::Error>> {
+    stream.map(message_to_bytes)
 }
 
 fn make_websocket_sink_a_stream(sink: impl WebsocketSink) -> impl AsyncWrite {
@@ -75,10 +90,299 @@ fn make_websocket_stream_a_stream(stream: impl WebsocketStream) -> impl AsyncBuf
     StreamReader::new(map_websocket_stream_to_bytes(stream))
 }
 
-pub(crate) async fn make_split_websocket_from_tcp(stream: TcpStream) -> Result<(ReaderType, WriterType), Error> {
-    let ws = make_websocket(stream).await?;
+// Server-side pings. Half-open connections are otherwise impossible to notice if we don't write
+// to them, e.g. readers waiting for replay data.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Keepalive {
+    pub ping_interval: Duration,
+    pub pong_timeout: Duration,
+}
+
+// We can't ping from the connection itself, since nobody might read from it or write to it for a
+// long time. Instead, a task owns the websocket and passes data to and from the connection.
+//
+// The task lives as long as the connection does, or until the peer stops answering pings. In the
+// latter case reading from the connection returns an error, and so does writing to it.
+struct KeepaliveTask<S: SocketStream> {
+    ws: WebSocketStream<S>,
+    keepalive: Keepalive,
+    to_reader: mpsc::Sender<std::io::Result<Bytes>>,
+    from_writer: mpsc::Receiver<Bytes>,
+    // Received data the connection didn't take yet. We keep reading from the websocket anyway,
+    // pongs come after the data, and readers never read what their peer sends.
+    pending_read: VecDeque<std::io::Result<Bytes>>,
+    pending_read_bytes: usize,
+    peer_closed: bool,
+    ping_sent: Option<(Instant, u64)>,
+    next_ping_id: u64,
+}
+
+enum KeepaliveEvent {
+    Received(Option<Result<Message, Error>>),
+    Delivered,
+    ConnectionClosed,
+    Write(Option<Bytes>),
+    PingTime,
+    PongMissed,
+}
+
+const KEEPALIVE_CHANNEL_SIZE: usize = 16;
+// Past this much data the connection didn't take, we give up on the connection.
+const MAX_PENDING_READ_BYTES: usize = 1024 * 1024;
+
+fn peer_not_responding() -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::TimedOut, "Websocket peer is not responding")
+}
+
+impl<S: SocketStream> KeepaliveTask<S> {
+    fn spawn(ws: WebSocketStream<S>, keepalive: Keepalive) -> (ReaderType, WriterType) {
+        let (to_reader, reader) = mpsc::channel(KEEPALIVE_CHANNEL_SIZE);
+        let (writer, from_writer) = mpsc::channel(KEEPALIVE_CHANNEL_SIZE);
+        let task = Self {
+            ws,
+            keepalive,
+            to_reader,
+            from_writer,
+            pending_read: VecDeque::new(),
+            pending_read_bytes: 0,
+            peer_closed: false,
+            ping_sent: None,
+            next_ping_id: 0,
+        };
+        tokio::spawn(task.run());
+        let r = Box::new(StreamReader::new(ReceiverStream::new(reader)));
+        let writer = PollSender::new(writer)
+            .sink_map_err(|_| std::io::Error::new(std::io::ErrorKind::BrokenPipe, "Websocket connection closed"));
+        let w = Box::new(SinkWriter::new(CopyToBytes::new(writer)));
+        (r, w)
+    }
+
+    // No deadline here, a slow peer is still there. We only time out waiting for pongs.
+    async fn send(&mut self, m: Message) -> std::io::Result<()> {
+        self.ws.send(m).await.map_err(map_message_error_to_io_error)
+    }
+
+    async fn send_ping(&mut self) -> std::io::Result<()> {
+        let id = self.next_ping_id;
+        self.next_ping_id += 1;
+        self.send(Message::ping(Bytes::copy_from_slice(&id.to_le_bytes())))
+            .await?;
+        // Sending might've taken a while if the peer reads slowly, so wait for a pong from here.
+        self.ping_sent = Some((Instant::now(), id));
+        Ok(())
+    }
+
+    fn handle_message(&mut self, m: Option<Result<Message, Error>>) -> std::io::Result<()> {
+        match m {
+            None => self.peer_closed = true,
+            Some(Ok(m)) if m.is_pong() => {
+                if let Some((sent, id)) = self.ping_sent {
+                    if m.as_payload()[..] == id.to_le_bytes() {
+                        metrics::WEBSOCKET_PING_RTT.observe(sent.elapsed().as_secs_f64());
+                        self.ping_sent = None;
+                    }
+                }
+            }
+            Some(m) => {
+                self.peer_closed = m.is_err();
+                let data = message_to_bytes(m);
+                self.pending_read_bytes += data.as_ref().map_or(0, |d| d.len());
+                self.pending_read.push_back(data);
+                if self.pending_read_bytes > MAX_PENDING_READ_BYTES {
+                    return Err(std::io::Error::other("Too much unread data from websocket peer"));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    async fn handle_event(&mut self, e: KeepaliveEvent) -> std::io::Result<bool> {
+        match e {
+            KeepaliveEvent::Received(m) => self.handle_message(m)?,
+            KeepaliveEvent::Delivered => (),
+            // Connection is gone, nothing more to do.
+            KeepaliveEvent::ConnectionClosed => return Ok(false),
+            KeepaliveEvent::Write(None) => {
+                self.ws.close().await.map_err(map_message_error_to_io_error)?;
+                return Ok(false);
+            }
+            KeepaliveEvent::Write(Some(data)) => self.send(Message::binary(data)).await?,
+            KeepaliveEvent::PingTime => self.send_ping().await?,
+            KeepaliveEvent::PongMissed => return Err(peer_not_responding()),
+        }
+        Ok(!self.peer_closed || !self.pending_read.is_empty())
+    }
+
+    async fn next_event(&mut self, ping_timer: &mut Interval) -> KeepaliveEvent {
+        let pong_deadline = self.ping_sent.map(|(sent, _)| sent + self.keepalive.pong_timeout);
+        // Look for a pong before giving up on one, it might've come while we were sending.
+        select! {
+            biased;
+            m = self.ws.next(), if !self.peer_closed => KeepaliveEvent::Received(m),
+            permit = self.to_reader.reserve(), if !self.pending_read.is_empty() => match permit {
+                Err(_) => KeepaliveEvent::ConnectionClosed,
+                Ok(p) => {
+                    let data = self.pending_read.pop_front().unwrap();
+                    self.pending_read_bytes -= data.as_ref().map_or(0, |d| d.len());
+                    p.send(data);
+                    KeepaliveEvent::Delivered
+                }
+            },
+            data = self.from_writer.recv() => KeepaliveEvent::Write(data),
+            _ = ping_timer.tick(), if self.ping_sent.is_none() => KeepaliveEvent::PingTime,
+            _ = sleep_until(pong_deadline.unwrap_or_else(Instant::now)), if pong_deadline.is_some() => {
+                KeepaliveEvent::PongMissed
+            }
+        }
+    }
+
+    async fn run(mut self) {
+        let interval = self.keepalive.ping_interval;
+        let mut ping_timer = interval_at(Instant::now() + interval, interval);
+        let res = loop {
+            let event = self.next_event(&mut ping_timer).await;
+            match self.handle_event(event).await {
+                Ok(true) => (),
+                Ok(false) => break Ok(()),
+                Err(e) => break Err(e),
+            }
+        };
+        if let Err(e) = res {
+            log::debug!("Websocket keepalive ended: {}", e);
+            let _ = self.to_reader.try_send(Err(e));
+        }
+    }
+}
+
+pub(crate) async fn make_split_websocket<S: SocketStream>(
+    stream: S,
+    keepalive: Option<Keepalive>,
+) -> Result<(ReaderType, WriterType, Uri), Error> {
+    let (uri, ws) = make_websocket(stream).await?;
+    if let Some(k) = keepalive {
+        let (r, w) = KeepaliveTask::spawn(ws, k);
+        return Ok((r, w, uri));
+    }
     let (rw, rr) = split_websocket(ws);
     let r = Box::new(make_websocket_stream_a_stream(rr));
     let w = Box::new(make_websocket_sink_a_stream(rw));
-    Ok((r, w))
+    Ok((r, w, uri))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::server::connection::Connection;
+    use crate::util::timeout::timeout;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::join;
+    use tokio::time::sleep;
+    use tokio_websockets::ClientBuilder;
+
+    fn keepalive() -> Option<Keepalive> {
+        Some(Keepalive {
+            ping_interval: Duration::from_millis(20),
+            pong_timeout: Duration::from_millis(50),
+        })
+    }
+
+    async fn connect(keepalive: Option<Keepalive>) -> (Connection, WebSocketStream<tokio::io::DuplexStream>) {
+        let (server_side, client_side) = tokio::io::duplex(16384);
+        let client = ClientBuilder::from_uri(Uri::from_static("ws://localhost/"));
+        let (server, client) = join! {
+            make_split_websocket(server_side, keepalive),
+            client.connect_on(client_side),
+        };
+        let (r, w, _) = server.unwrap();
+        (Connection::new_from(r, w), client.unwrap().0)
+    }
+
+    #[tokio::test]
+    async fn test_keepalive_passes_data_and_pings() {
+        let pings_before = metrics::WEBSOCKET_PING_RTT.get_sample_count();
+        let (mut c, mut ws) = connect(keepalive()).await;
+        let server = async {
+            c.write_all(b"hello").await.unwrap();
+            c.flush().await.unwrap();
+            let mut buf = [0; 5];
+            c.read_exact(&mut buf).await.unwrap();
+            assert_eq!(&buf, b"world");
+        };
+        // The client answers pings as long as we read from it.
+        let client = async {
+            let m = ws.next().await.unwrap().unwrap();
+            assert_eq!(&m.as_payload()[..], b"hello");
+            let reading = async {
+                while let Some(m) = ws.next().await {
+                    m.unwrap();
+                }
+            };
+            timeout(reading, Duration::from_millis(200)).await;
+            ws.send(Message::binary(&b"world"[..])).await.unwrap();
+        };
+        join!(server, client);
+        assert!(metrics::WEBSOCKET_PING_RTT.get_sample_count() > pings_before);
+    }
+
+    #[tokio::test]
+    async fn test_keepalive_keeps_slow_readers() {
+        let (mut c, mut ws) = connect(keepalive()).await;
+        let data = vec![1; 65536];
+        let server = async {
+            c.write_all(&data).await.unwrap();
+            c.flush().await.unwrap();
+            sleep(Duration::from_millis(200)).await;
+            c.write_all(b"hello").await.unwrap();
+            c.flush().await.unwrap();
+        };
+        // Several pong timeouts before we start reading.
+        let client = async {
+            sleep(Duration::from_millis(200)).await;
+            let mut received = Vec::new();
+            while received.len() < data.len() + 5 {
+                let m = ws.next().await.unwrap().unwrap();
+                received.extend_from_slice(&m.as_payload()[..]);
+            }
+            assert_eq!(&received[data.len()..], b"hello");
+        };
+        join!(server, client);
+    }
+
+    #[tokio::test]
+    async fn test_keepalive_drops_unresponsive_peer() {
+        let (mut c, _ws) = connect(keepalive()).await;
+        let mut buf = [0; 5];
+        let res = timeout(c.read_exact(&mut buf), Duration::from_secs(1)).await.unwrap();
+        assert_eq!(res.err().unwrap().kind(), std::io::ErrorKind::TimedOut);
+        assert!(c.write_all(b"hello").await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_keepalive_keeps_reading_pongs_while_data_is_not_read() {
+        let (mut c, mut ws) = connect(keepalive()).await;
+        let frames = KEEPALIVE_CHANNEL_SIZE * 2;
+        for _ in 0..frames {
+            ws.send(Message::binary(&b"hello"[..])).await.unwrap();
+        }
+        // Several pong timeouts. The client answers pings as long as we read from it.
+        let reading = async {
+            while let Some(m) = ws.next().await {
+                m.unwrap();
+            }
+        };
+        timeout(reading, Duration::from_millis(300)).await;
+        let mut data = vec![0; frames * 5];
+        c.read_exact(&mut data).await.unwrap();
+        assert_eq!(data, b"hello".repeat(frames));
+    }
+
+    #[tokio::test]
+    async fn test_keepalive_connection_ends_with_peer() {
+        let (mut c, mut ws) = connect(keepalive()).await;
+        ws.send(Message::binary(&b"hello"[..])).await.unwrap();
+        ws.close().await.unwrap();
+        let mut data = Vec::new();
+        c.read_to_end(&mut data).await.unwrap();
+        assert_eq!(data, b"hello");
+    }
 }
@@ -25,7 +25,6 @@ impl EmptyCounter {
     }
 
     // Little interface for introspection.
-    #[cfg(test)]
     pub fn count(&self) -> usize {
         *self.counter.borrow()
     }
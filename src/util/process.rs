@@ -1,7 +1,8 @@
 // This file has some stuff we do with Rust runtime and the process that's impractical to test with
 // cargo test. We build and test separate executables for this and ignore this file for coverage.
 
-use signal_hook::consts::signal::{SIGINT, SIGTERM};
+use signal_hook::consts::signal::{SIGINT, SIGTERM, SIGUSR2};
+use std::os::raw::c_int;
 use std::os::unix::net::UnixStream;
 use tokio::io::AsyncReadExt;
 use tokio::net::UnixStream as AsyncUnixStream;
@@ -14,12 +15,23 @@ pub fn setup_process_exit_on_panic() {
     }));
 }
 
-pub async fn wait_for_signals() {
+async fn wait_for_any_of(signals: &[c_int]) {
     let (r, w) = UnixStream::pair().unwrap();
     r.set_nonblocking(true).unwrap();
     let mut async_read = AsyncUnixStream::from_std(r).unwrap();
-    signal_hook::low_level::pipe::register(SIGINT, w.try_clone().unwrap()).unwrap();
-    signal_hook::low_level::pipe::register(SIGTERM, w).unwrap();
+    for s in signals {
+        signal_hook::low_level::pipe::register(*s, w.try_clone().unwrap()).unwrap();
+    }
     let mut buf: [u8; 1] = [0];
     async_read.read_exact(&mut buf).await.unwrap();
 }
+
+pub async fn wait_for_signals() {
+    wait_for_any_of(&[SIGINT, SIGTERM]).await
+}
+
+// Asks us to stop taking new work and exit once running replays are done, e.g. to hand our
+// sockets over to a new instance.
+pub async fn wait_for_drain_signal() {
+    wait_for_any_of(&[SIGUSR2]).await
+}
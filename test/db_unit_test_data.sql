@@ -97,3 +97,15 @@ values (1001, 1000,   1,        0,  0,       1,     1,    1,     1500, 500),
        (1081, 1080,   2,        1,  1,       2,     2,    2,     1000, 0),
        (2000, 2000,   1,        0,  0,       1,     1,    1,     1500, 500),
        (2001, 2001,   1,        0,  0,       1,     1,    1,     1500, 500);
+
+insert into leaderboard
+       (id, technical_name, name_key,             description_key)
+values (61, 'ladder_1v1',   'leaderboard.ladder', 'leaderboard.ladder.desc');
+
+insert into matchmaker_queue
+       (id, technical_name, featured_mod_id, leaderboard_id, name_key,           team_size)
+values (71, 'ladder1v1',    32,              61,             'matchmaker.ladder', 1);
+
+insert into matchmaker_queue_game
+       (matchmaker_queue_id, game_stats_id)
+values (71,                  1020);